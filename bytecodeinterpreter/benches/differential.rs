@@ -0,0 +1,65 @@
+// The same programs on the tree walking interpreter and on the bytecode VM
+// at each optimization level, compiled from the AST and through the IR
+// (`ir-O2` runs the SSA and loop optimizations). A different result stops
+// the bench.
+// Run with `cargo bench --bench differential`.
+use bytecodeinterpreter::differential::{compare, compare_ir};
+
+const PROGRAMS: &[(&str, &str)] = &[
+    ("fib", r#"
+fn fib(n: u64) -> u64 {
+    if n < 2u64 { n } else { fib(n - 1u64) + fib(n - 2u64) }
+}
+fn main() -> u64 {
+    fib(22u64)
+}
+"#),
+    ("loop", r#"
+fn main() -> u64 {
+    var sum = 0u64
+    for i in 0u64..300000u64 {
+        sum = sum + i
+    }
+    sum
+}
+"#),
+    ("branch", r#"
+fn main() -> i64 {
+    var n = 0i64
+    var acc = 0i64
+    while n < 100000i64 {
+        if (n / 3i64) * 3i64 == n || (n / 5i64) * 5i64 == n { acc = acc + n } else { acc = acc - 1i64 }
+        n = n + 1i64
+    }
+    acc
+}
+"#),
+    ("invariant", r#"
+fn main() -> u64 {
+    val n = 1000u64
+    val k = 7u64
+    var sum = 0u64
+    var i = 0u64
+    while i < n * k / 2u64 {
+        for j in 0u64..4u64 { sum = sum + j * k }
+        i = i + 1u64
+    }
+    sum
+}
+"#),
+];
+
+fn main() {
+    println!("{:<9} {:>5} {:>12} {:>12} {:>8} {:>10}", "program", "opt", "interpreter", "vm", "speedup", "executed");
+    for (name, code) in PROGRAMS {
+        for (opt, ir) in [("-O0", false), ("-O1", false), ("-O2", false), ("ir-O0", true), ("ir-O2", true)] {
+            let opt_level = opt.as_bytes()[opt.len() - 1] - b'0';
+            let c = if ir { compare_ir(code, opt_level) } else { compare(code, opt_level) };
+            let c = c.unwrap_or_else(|e| panic!("{}: {}: {}", name, opt, e));
+            println!(
+                "{:<9} {:>5} {:>12.1?} {:>12.1?} {:>7.1}x {:>10}",
+                name, opt, c.interpreter, c.vm, c.speedup(), c.vm_instructions
+            );
+        }
+    }
+}
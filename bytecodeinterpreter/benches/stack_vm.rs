@@ -0,0 +1,50 @@
+// Throughput of the stack machine on call heavy and loop heavy programs.
+// Run with `cargo bench --bench stack_vm`.
+use bytecodeinterpreter::compiler::Compiler;
+use bytecodeinterpreter::processor::Processor;
+use std::time::Instant;
+
+const FIB: &str = r#"
+fn fib(n: u64) -> u64 {
+    if n < 2u64 { n } else { fib(n - 1u64) + fib(n - 2u64) }
+}
+fn main() -> u64 {
+    fib(25u64)
+}
+"#;
+
+const LOOP: &str = r#"
+fn main() -> u64 {
+    var sum = 0u64
+    for i in 0u64..1000000u64 {
+        sum = sum + i
+    }
+    sum
+}
+"#;
+
+fn bench(name: &str, code: &str, opt_level: u8) {
+    let program = frontend::Parser::new(code).parse_program().unwrap();
+    let mut compiler = Compiler::new();
+    compiler.set_opt_level(opt_level);
+    let module = compiler.compile_program(&program);
+    let codes: usize = module.functions.iter().map(|f| f.codes.len()).sum();
+
+    let mut p = Processor::new();
+    let start = Instant::now();
+    let result = p.run_module(&module).unwrap();
+    let elapsed = start.elapsed();
+    println!(
+        "{:<6} -O{} {:?}: {} codes, {} executed in {:.1?} ({:.1} M instructions/s)",
+        name, opt_level, result, codes, p.executed(), elapsed,
+        p.executed() as f64 / elapsed.as_secs_f64() / 1e6
+    );
+}
+
+fn main() {
+    // -O2 adds superinstructions to -O1
+    for opt_level in 1..=2 {
+        bench("fib", FIB, opt_level);
+        bench("loop", LOOP, opt_level);
+    }
+}
@@ -1,6 +1,10 @@
-use frontend;
+use crate::dce;
+use crate::ir;
+use crate::ssa;
 use frontend::ast::*;
-use std::collections::HashMap;
+use frontend::line::LineIndex;
+use interpreter::overflow::{self, ArithOp, OverflowMode};
+use std::collections::{HashMap, HashSet};
 
 pub enum Code {
     Op(BCode),
@@ -16,6 +20,8 @@ pub enum BCode {
     PUSH_NULL,
     PUSH_INT(i64),
     PUSH_UINT(u64),
+    PUSH_BOOL(bool),
+    PUSH_LITERAL(u32), // push(constants[x]) of the module
 
     PUSH_CONST(u32),
 
@@ -31,9 +37,87 @@ pub enum BCode {
     BINARY_SUB,
     BINARY_MUL,
     BINARY_DIV,
+    BINARY_EQ,
+    BINARY_NE,
+    BINARY_LT,
+    BINARY_LE,
+    BINARY_GT,
+    BINARY_GE,
+    INCREMENT, // add 1 to the integer on the top of the stack
+
+    // jump offsets are relative to the jump instruction itself
+    JUMP(i32),
+    JUMP_IF_FALSE(i32), // pop a bool and jump if it is false
 
     PRINT0,
     PRINT,
+
+    POP,       // discard the top of the stack
+    CALL(u32), // call the function at the index of `Module::functions`, arguments are on the stack
+    RET,       // return the top of the stack to the caller
+
+    // superinstructions emitted by -O2 for the hot sequences of loops
+    ADD_VAR(u32),        // LOAD_IDENT_VAR(x), BINARY_ADD
+    ADD_CONST(u32),      // LOAD_IDENT_CONST(x), BINARY_ADD
+    INCREMENT_VAR(u32),  // LOAD_IDENT_VAR(x), INCREMENT, LOAD_IDENT(x)
+    JUMP_IF_NOT_LT(i32), // BINARY_LT, JUMP_IF_FALSE
+}
+
+// Compiled code of a function. The arguments are bound to the
+// constants 0..arity in order.
+#[derive(Debug, PartialEq, Clone)]
+pub struct CodeObject {
+    pub name: String,
+    pub arity: u32,
+    pub const_slots: u32, // number of constant (`val` and argument) ids
+    pub var_slots: u32,   // number of variable ids
+    pub codes: Vec<BCode>,
+    // source of each instruction, empty if compiled without the source
+    pub locations: Vec<Option<SourceLocation>>,
+}
+
+impl CodeObject {
+    pub fn location(&self, pc: usize) -> Option<SourceLocation> {
+        self.locations.get(pc).copied().flatten()
+    }
+}
+
+// 1-origin line and column of the expression an instruction is compiled from
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct SourceLocation {
+    pub line: u32,
+    pub column: u32,
+}
+
+impl std::fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+// Literal in the constant pool of a module
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Constant {
+    Int64(i64),
+    UInt64(u64),
+}
+
+// Compiled program, `CALL(n)` refers to `functions[n]`
+// and `PUSH_LITERAL(n)` refers to `constants[n]`
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Module {
+    pub functions: Vec<CodeObject>,
+    pub constants: Vec<Constant>,
+}
+
+impl Module {
+    pub fn index(&self, name: &str) -> Option<u32> {
+        self.functions.iter().position(|f| f.name == name).map(|i| i as u32)
+    }
+
+    pub fn function(&self, name: &str) -> Option<&CodeObject> {
+        self.functions.iter().find(|f| f.name == name)
+    }
 }
 
 pub enum SymbolType {
@@ -42,14 +126,31 @@ pub enum SymbolType {
     Local,
 }
 
+#[allow(dead_code)]
 pub struct Symbol {
     kind: SymbolType,
     pos: u32,
 }
 
+#[derive(Clone)]
 pub struct Compiler {
     codes: Vec<BCode>,
-    names: HashMap<String, u32>,
+    names: HashMap<String, u32>, // in scope, a block restores them at its end
+    var_names: HashMap<String, u32>,
+    const_count: u32, // a `val` gets a new id even if it shadows another one
+    var_count: u32, // ids of variables are not reused while compiling a function
+    functions: HashMap<String, u32>,
+    constants: Option<Vec<Constant>>, // pool of the module being compiled
+    opt_level: u8,
+    ir: bool, // compile through the mid-level IR
+    const_vals: HashMap<String, Constant>, // `val` bound to a value known at compile time
+    lines: Option<LineIndex>, // of the source of the program, for the locations
+}
+
+impl Default for Compiler {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 // byte code compiler
@@ -58,60 +159,325 @@ impl Compiler {
         Compiler {
             codes: Vec::new(),
             names: HashMap::new(),
+            var_names: HashMap::new(),
+            const_count: 0,
+            var_count: 0,
+            functions: HashMap::new(),
+            constants: None,
+            opt_level: 0,
+            ir: false,
+            const_vals: HashMap::new(),
+            lines: None,
         }
     }
 
+    // Source of the programs compiled next. The code objects get the
+    // location of each instruction from the location pool of the program.
+    pub fn set_source(&mut self, source: &str) {
+        self.lines = Some(LineIndex::new(source));
+    }
+
+    // 0: no optimization
+    // 1 (-O): constant folding and propagation, then dead code elimination
+    //         and peephole optimization of each function until nothing changes
+    // 2: superinstructions in addition to 1, and the SSA optimizations
+    //    when compiling through the IR
+    pub fn set_opt_level(&mut self, level: u8) {
+        self.opt_level = level;
+    }
+
+    // Compile the functions of a program through the mid-level IR (see
+    // `ir`) instead of directly from the AST
+    pub fn set_ir(&mut self, ir: bool) {
+        self.ir = ir;
+    }
+
     // TODO: Change 2-pass or more pass compiler
 
     pub fn get_program(&mut self) -> &Vec<BCode> {
-        return &self.codes;
+        &self.codes
     }
 
-    pub fn compile_code(&mut self, expr: &Expr) {
-        self.codes = self.compile(expr);
+    pub fn compile_code(&mut self, pool: &ExprPool, expr: ExprRef) {
+        self.codes = self.compile(pool, expr);
     }
 
-    pub fn append(&mut self, expr: &Expr) {
-        let mut codes = self.compile(expr);
+    pub fn append(&mut self, pool: &ExprPool, expr: ExprRef) {
+        let mut codes = self.compile(pool, expr);
         self.codes.append(&mut codes);
     }
 
-    pub fn compile(&mut self, expr: &Expr) -> Vec<BCode> {
-        let print_string0 = "print0".to_string();
-        let print_string = "print".to_string();
+    // Compile all functions of the type checked program. Each function
+    // has its own names, so the ids of constants and variables restart from 0.
+    pub fn compile_program(&mut self, program: &Program) -> Module {
+        self.functions = program.function.iter().enumerate()
+            .map(|(i, f)| (f.name.clone(), i as u32))
+            .collect();
+        self.constants = Some(vec![]);
+        let mut module = Module::default();
+        for f in &program.function {
+            self.names = f.parameter.iter().enumerate()
+                .map(|(i, (name, _))| (name.clone(), i as u32))
+                .collect();
+            self.var_names.clear();
+            self.const_count = self.names.len() as u32;
+            self.var_count = 0;
+            self.const_vals.clear();
+            let Emitted { mut codes, mut origin } = if self.ir {
+                let mut function = ir::build(program, f, &self.functions);
+                if self.opt_level >= 2 {
+                    ssa::optimize(&mut function);
+                }
+                self.var_count = function.registers - function.arity;
+                let (codes, origin) = ir::lower(&function, &mut |constant| self.literal(constant));
+                Emitted { codes, origin }
+            } else {
+                let mut emitted = self.emit(&program.expression, f.code);
+                if !Self::has_value(&program.expression, f.code) {
+                    emitted.push(BCode::PUSH_NULL, f.code);
+                }
+                emitted.push(BCode::RET, f.code);
+                emitted
+            };
+            if self.opt_level >= 1 {
+                let (optimized, from) = optimize_with_origin(&codes);
+                codes = optimized;
+                origin = from.iter().map(|pc| origin[*pc]).collect();
+            }
+            if self.opt_level >= 2 {
+                let (fused, from) = fuse_with_origin(&codes);
+                codes = fused;
+                origin = from.iter().map(|pc| origin[*pc]).collect();
+            }
+            let locations = match &self.lines {
+                Some(lines) => origin.iter().map(|e| {
+                    program.location.get(*e).map(|node| {
+                        let (line, column) = lines.line_col(node.start());
+                        SourceLocation { line: line as u32, column: column as u32 }
+                    })
+                }).collect(),
+                None => vec![],
+            };
+            module.functions.push(CodeObject {
+                name: f.name.clone(),
+                arity: f.parameter.len() as u32,
+                const_slots: self.const_count,
+                var_slots: self.var_count,
+                codes,
+                locations,
+            });
+        }
+        module.constants = self.constants.take().unwrap_or_default();
+        module
+    }
+
+    // Integer literals which do not fit in 16 bits are shared in the
+    // constant pool when a module is compiled. Expressions compiled
+    // for the REPL have no module, so they keep the literals inline.
+    fn literal(&mut self, constant: Constant) -> BCode {
+        let small = match constant {
+            Constant::Int64(i) => i16::try_from(i).is_ok(),
+            Constant::UInt64(u) => u16::try_from(u).is_ok(),
+        };
+        match &mut self.constants {
+            Some(pool) if !small => {
+                let index = match pool.iter().position(|c| *c == constant) {
+                    Some(index) => index,
+                    None => {
+                        pool.push(constant);
+                        pool.len() - 1
+                    }
+                };
+                BCode::PUSH_LITERAL(index as u32)
+            }
+            _ => match constant {
+                Constant::Int64(i) => BCode::PUSH_INT(i),
+                Constant::UInt64(u) => BCode::PUSH_UINT(u),
+            },
+        }
+    }
+
+    // whether the code of `expr` leaves its value on the stack
+    pub(crate) fn has_value(pool: &ExprPool, expr: ExprRef) -> bool {
+        match pool.get(expr.0 as usize) {
+            Some(Expr::Val(..)) | Some(Expr::Var(..)) | Some(Expr::Binary(Operator::Assign, _, _)) => false,
+            Some(Expr::Block(b)) => b.last().is_some_and(|e| Self::has_value(pool, *e)),
+            Some(Expr::IfElse(_, then_block, else_block)) =>
+                Self::has_value(pool, *then_block) && Self::has_value(pool, *else_block),
+            Some(Expr::While(..)) | Some(Expr::For(..)) => false,
+            _ => true,
+        }
+    }
+
+    fn new_var(&mut self) -> u32 {
+        let id = self.var_count;
+        self.var_count += 1;
+        id
+    }
+
+    // Code of `expr` which leaves a value only if `value` is true
+    fn compile_as(&mut self, pool: &ExprPool, expr: ExprRef, value: bool) -> Emitted {
+        let mut codes = self.emit(pool, expr);
+        if !value && Self::has_value(pool, expr) {
+            codes.push(BCode::POP, expr);
+        }
+        codes
+    }
+
+    fn compile_if(&mut self, pool: &ExprPool, expr: ExprRef, cond: ExprRef, then_block: ExprRef, else_block: ExprRef) -> Emitted {
+        let value = Self::has_value(pool, expr);
+        let mut then_codes = self.compile_as(pool, then_block, value);
+        let mut else_codes = self.compile_as(pool, else_block, value);
+        let mut codes = self.emit(pool, cond);
+        codes.push(BCode::JUMP_IF_FALSE(then_codes.len() as i32 + 2), expr);
+        codes.append(&mut then_codes);
+        codes.push(BCode::JUMP(else_codes.len() as i32 + 1), expr);
+        codes.append(&mut else_codes);
+        codes
+    }
+
+    fn compile_while(&mut self, pool: &ExprPool, expr: ExprRef, cond: ExprRef, body: ExprRef) -> Emitted {
+        let mut codes = self.emit(pool, cond);
+        let mut body = self.compile_as(pool, body, false);
+        codes.push(BCode::JUMP_IF_FALSE(body.len() as i32 + 2), expr);
+        codes.append(&mut body);
+        codes.push(BCode::JUMP(-(codes.len() as i32)), expr);
+        codes
+    }
+
+    // `for i in start..end` is compiled as a while loop over the hidden end variable
+    fn compile_for(&mut self, pool: &ExprPool, expr: ExprRef, name: &str, start: ExprRef, end: ExprRef, body: ExprRef) -> Emitted {
+        let id = self.new_var();
+        let end_id = self.new_var();
+        let mut codes = self.emit(pool, start);
+        codes.push(BCode::LOAD_IDENT(id), expr);
+        codes.append(&mut self.emit(pool, end));
+        codes.push(BCode::LOAD_IDENT(end_id), expr);
+
+        let outer = self.var_names.insert(name.to_string(), id);
+        let mut body = self.compile_as(pool, body, false);
+        match outer {
+            Some(outer) => self.var_names.insert(name.to_string(), outer),
+            None => self.var_names.remove(name),
+        };
+
+        let mut loop_codes = Emitted::of(expr, &[
+            BCode::LOAD_IDENT_VAR(id), BCode::LOAD_IDENT_VAR(end_id), BCode::BINARY_LT,
+            BCode::JUMP_IF_FALSE(body.len() as i32 + 5),
+        ]);
+        loop_codes.append(&mut body);
+        loop_codes.append(&mut Emitted::of(expr, &[BCode::LOAD_IDENT_VAR(id), BCode::INCREMENT, BCode::LOAD_IDENT(id)]));
+        loop_codes.push(BCode::JUMP(-(loop_codes.len() as i32)), expr);
+        codes.append(&mut loop_codes);
+        codes
+    }
+
+    // Value of an integer expression made of literals and constant `val`s.
+    // The literals have their types from the type checker, so both operands
+    // of a foldable operator have the same type. Overflow and division by
+    // zero are not folded and are left for the runtime.
+    fn const_value(&self, pool: &ExprPool, expr: ExprRef) -> Option<Constant> {
+        match pool.get(expr.0 as usize)? {
+            Expr::Int64(i) => Some(Constant::Int64(*i)),
+            Expr::UInt64(u) => Some(Constant::UInt64(*u)),
+            Expr::Identifier(name) if !self.var_names.contains_key(name) => self.const_vals.get(name).copied(),
+            Expr::Binary(op, lhs, rhs) => {
+                let op = match op {
+                    Operator::IAdd => ArithOp::Add,
+                    Operator::ISub => ArithOp::Sub,
+                    Operator::IMul => ArithOp::Mul,
+                    Operator::IDiv => ArithOp::Div,
+                    _ => return None,
+                };
+                match (self.const_value(pool, *lhs)?, self.const_value(pool, *rhs)?) {
+                    (Constant::Int64(l), Constant::Int64(r)) =>
+                        overflow::apply(OverflowMode::Trap, op, l, r).map(Constant::Int64),
+                    (Constant::UInt64(l), Constant::UInt64(r)) =>
+                        overflow::apply(OverflowMode::Trap, op, l, r).map(Constant::UInt64),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    pub fn compile(&mut self, pool: &ExprPool, expr: ExprRef) -> Vec<BCode> {
+        self.emit(pool, expr).codes
+    }
 
-        let codes: Vec<BCode> = match expr {
-            Expr::IfElse(expr, thenBlock, elseBlock) => {
-                let mut codes = self.compile(&expr);
-                //let mut then_codes = self.compile(thenBlock);
-                //let mut else_codes = self.compile(elseBlock);
-                //codes.append(&mut then_codes);
-                //codes.append(&mut else_codes);
+    // Code of `expr` with the expression each instruction is compiled from
+    fn emit(&mut self, pool: &ExprPool, expr: ExprRef) -> Emitted {
+        if self.opt_level >= 1 {
+            if let Some(constant) = self.const_value(pool, expr) {
+                return Emitted::of(expr, &[self.literal(constant)]);
+            }
+        }
+        let codes: Emitted = match pool.get(expr.0 as usize).unwrap() {
+            Expr::IfElse(cond, then_block, else_block) => self.compile_if(pool, expr, *cond, *then_block, *else_block),
+            Expr::While(cond, body) => self.compile_while(pool, expr, *cond, *body),
+            Expr::For(name, start, end, body) => self.compile_for(pool, expr, name, *start, *end, *body),
+            Expr::Binary(Operator::LogicalAnd, lhs, rhs) => {
+                // short circuit: `false` without evaluating rhs
+                let mut rhs = self.emit(pool, *rhs);
+                let mut codes = self.emit(pool, *lhs);
+                codes.push(BCode::JUMP_IF_FALSE(rhs.len() as i32 + 2), expr);
+                codes.append(&mut rhs);
+                codes.append(&mut Emitted::of(expr, &[BCode::JUMP(2), BCode::PUSH_BOOL(false)]));
                 codes
             }
-            Expr::Binary(bop) => {
-                let mut codes = Vec::new();
-                let mut lhs = self.compile(&bop.lhs);
+            Expr::Binary(Operator::LogicalOr, lhs, rhs) => {
+                let mut rhs = self.emit(pool, *rhs);
+                let mut codes = self.emit(pool, *lhs);
+                codes.append(&mut Emitted::of(expr, &[BCode::JUMP_IF_FALSE(3), BCode::PUSH_BOOL(true), BCode::JUMP(rhs.len() as i32 + 1)]));
+                codes.append(&mut rhs);
+                codes
+            }
+            Expr::Binary(Operator::Assign, lhs, rhs) => {
+                let id = match pool.get(lhs.0 as usize) {
+                    Some(Expr::Identifier(name)) => match self.var_names.get(name) {
+                        Some(id) => *id,
+                        None => panic!("error, variable name is invalid: `{}`", name),
+                    },
+                    x => panic!("left hand side of assignment must be identifier but {:?}", x),
+                };
+                let mut codes = self.emit(pool, *rhs);
+                codes.push(BCode::LOAD_IDENT(id), expr);
+                codes
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                let mut codes = Emitted::default();
+                let mut lhs = self.emit(pool, *lhs);
                 codes.append(&mut lhs);
-                let mut rhs = self.compile(&bop.rhs);
+                let mut rhs = self.emit(pool, *rhs);
                 codes.append(&mut rhs);
 
-                match bop.op {
-                    Operator::IAdd => codes.push(BCode::BINARY_ADD),
-                    Operator::ISub => codes.push(BCode::BINARY_SUB),
-                    Operator::IMul => codes.push(BCode::BINARY_MUL),
-                    Operator::IDiv => codes.push(BCode::BINARY_DIV),
-                    // TODO: assign
+                let code = match op {
+                    Operator::IAdd => BCode::BINARY_ADD,
+                    Operator::ISub => BCode::BINARY_SUB,
+                    Operator::IMul => BCode::BINARY_MUL,
+                    Operator::IDiv => BCode::BINARY_DIV,
+                    Operator::EQ => BCode::BINARY_EQ,
+                    Operator::NE => BCode::BINARY_NE,
+                    Operator::LT => BCode::BINARY_LT,
+                    Operator::LE => BCode::BINARY_LE,
+                    Operator::GT => BCode::BINARY_GT,
+                    Operator::GE => BCode::BINARY_GE,
                     _ => panic!("not implemented yet (Binary Operator)"),
-                }
+                };
+                codes.push(code, expr);
                 codes
             }
-            Expr::Int64(i) => vec![BCode::PUSH_INT(*i)],
-            Expr::UInt64(u) => vec![BCode::PUSH_UINT(*u)],
+            Expr::Int64(i) => Emitted::of(expr, &[self.literal(Constant::Int64(*i))]),
+            Expr::UInt64(u) => Emitted::of(expr, &[self.literal(Constant::UInt64(*u))]),
             Expr::Int(i) => {
-                // TODO: support multiple-precision integer
-                let i = i.parse::<i64>().unwrap_or_else(|_| 0i64);
-                vec![BCode::PUSH_INT(i)]
+                // literals are resolved by the parser, so this is a fallback
+                match i.parse::<i64>() {
+                    Ok(i) => Emitted::of(expr, &[self.literal(Constant::Int64(i))]),
+                    Err(_) => panic!("invalid integer literal: {}", i),
+                }
+            }
+            Expr::Identifier(name) if self.var_names.contains_key(name) => {
+                Emitted::of(expr, &[BCode::LOAD_IDENT_VAR(self.var_names[name])])
             }
             Expr::Identifier(name) => {
                 let id = self.names.get(name);
@@ -119,49 +485,566 @@ impl Compiler {
                     panic!("error, variable/constant name is invalid: `{}`", name);
                 }
                 let id = id.unwrap() as &u32;
-                vec![BCode::LOAD_IDENT_CONST(*id)] // TODO(suma): Use env
+                Emitted::of(expr, &[BCode::LOAD_IDENT_CONST(*id)]) // TODO(suma): Use env
             }
-            Expr::Call(print_string0, _) => {
-                vec![BCode::PRINT0]
+            Expr::Call(name, args) if name == "print0" || name == "print" => {
+                let mut codes = Emitted::default();
+                if let Some(Expr::Block(args)) = pool.get(args.0 as usize) {
+                    for e in args {
+                        let mut res = self.emit(pool, *e);
+                        codes.append(&mut res);
+                        codes.push(BCode::PRINT0, expr);
+                    }
+                }
+                // the value of the call is `()`
+                codes.push(BCode::PUSH_NULL, expr);
+                codes
             }
-            Expr::Call(print_string, a) => {
-                let mut codes: Vec<BCode> = vec![];
-                for e in a {
-                    let mut res = self.compile(&e);
-                    codes.append(&mut res);
+            Expr::Call(name, args) if self.functions.contains_key(name) => {
+                let mut codes = Emitted::default();
+                if let Some(Expr::Block(args)) = pool.get(args.0 as usize) {
+                    for e in args {
+                        let mut res = self.emit(pool, *e);
+                        codes.append(&mut res);
+                    }
                 }
-                vec![BCode::PRINT]
+                codes.push(BCode::CALL(self.functions[name]), expr);
+                codes
             }
+            Expr::Call(name, _) => panic!("not implemented yet (Call {})", name),
+            Expr::Spawn(_) => panic!("not implemented yet (spawn)"),
+            Expr::Function(f) => panic!("not implemented yet (nested fn {})", f.name),
             Expr::Block(b) => {
-                let mut codes: Vec<BCode> = vec![];
-                for e in b {
-                    let mut res: Vec<BCode> = self.compile(&e);
+                // the bindings of the block are not visible after it
+                let scope = (self.names.clone(), self.var_names.clone(), self.const_vals.clone());
+                let mut codes = Emitted::default();
+                for (i, e) in b.iter().enumerate() {
+                    let mut res = self.emit(pool, *e);
                     codes.append(&mut res);
+                    // only the last expression is the value of the block
+                    if i + 1 < b.len() && Self::has_value(pool, *e) {
+                        codes.push(BCode::POP, *e);
+                    }
                 }
+                (self.names, self.var_names, self.const_vals) = scope;
                 codes
             }
-            Expr::Null => vec![BCode::PUSH_NULL],
-            Expr::Val(name, _ty, expr) => {
-                match expr {
-                    Some(expr) => {
-                        let id = self.names.get(name);
-                        if id.is_some() {
-                            panic!("already defined constant `{}`", name)
-                        }
-                        let id = self.names.len() as u32;
+            Expr::Null => Emitted::of(expr, &[BCode::PUSH_NULL]),
+            Expr::Val(name, _ty, value) => {
+                match value {
+                    Some(value) => {
+                        // the value refers to a binding it shadows
+                        let constant = if self.opt_level >= 1 { self.const_value(pool, *value) } else { None };
+                        let mut val = self.emit(pool, *value);
+                        let id = self.const_count;
+                        self.const_count += 1;
                         self.names.insert(name.clone(), id);
-
-                        let mut inst: Vec<BCode> = vec![BCode::PUSH_CONST(id)];
-                        let mut val = self.compile(expr);
-                        val.append(&mut inst);
+                        self.var_names.remove(name);
+                        match constant {
+                            Some(constant) => self.const_vals.insert(name.clone(), constant),
+                            None => self.const_vals.remove(name),
+                        };
+                        val.push(BCode::PUSH_CONST(id), expr);
                         val
                     }
                     _ => panic!("value is not set: {}", name), // error
                 }
             }
+            Expr::Var(name, _ty, value) => {
+                let mut codes = match value {
+                    Some(value) => self.emit(pool, *value),
+                    None => Emitted::of(expr, &[BCode::PUSH_NULL]),
+                };
+                self.const_vals.remove(name);
+                let id = self.new_var();
+                self.var_names.insert(name.clone(), id);
+                codes.push(BCode::LOAD_IDENT(id), expr);
+                codes
+            }
         };
 
-        return codes;
+        codes
+    }
+}
+
+// Instructions with the expression each one is compiled from, for the
+// locations of `CodeObject`
+#[derive(Default)]
+struct Emitted {
+    codes: Vec<BCode>,
+    origin: Vec<ExprRef>,
+}
+
+impl Emitted {
+    fn of(expr: ExprRef, codes: &[BCode]) -> Self {
+        Emitted { codes: codes.to_vec(), origin: vec![expr; codes.len()] }
+    }
+
+    fn push(&mut self, code: BCode, expr: ExprRef) {
+        self.codes.push(code);
+        self.origin.push(expr);
+    }
+
+    fn append(&mut self, other: &mut Emitted) {
+        self.codes.append(&mut other.codes);
+        self.origin.append(&mut other.origin);
+    }
+
+    fn len(&self) -> usize {
+        self.codes.len()
+    }
+}
+
+// Whether the compiler can compile the type checked program. The VM has no
+// nested functions, no tasks and no builtins but `print`, which the type checker may
+// accept with the context of another backend, so they are rejected here
+// instead of failing in `compile_program`.
+pub fn check_supported(program: &Program) -> Result<(), String> {
+    // in the order of the source, so a nested function is found before its calls
+    let mut pending: Vec<ExprRef> = program.function.iter().map(|f| f.code).collect();
+    let mut next = 0;
+    while let Some(e) = pending.get(next) {
+        next += 1;
+        let Some(expr) = program.expression.get(e.0 as usize) else { continue };
+        match expr {
+            Expr::Function(f) => return Err(format!("nested function `{}` is not supported", f.name)),
+            Expr::Spawn(_) => return Err("`spawn` is not supported".to_string()),
+            Expr::Call(name, _) if name != "print" && name != "print0" && !program.function.iter().any(|f| f.name == *name) =>
+                return Err(format!("call of `{}` is not supported", name)),
+            _ => pending.extend(expr.children()),
+        }
+    }
+    Ok(())
+}
+
+// One instruction per line with its position, jumps also show the target
+pub fn disassemble(codes: &[BCode]) -> String {
+    let mut text = String::new();
+    for (pc, code) in codes.iter().enumerate() {
+        match code {
+            BCode::JUMP(offset) | BCode::JUMP_IF_FALSE(offset) | BCode::JUMP_IF_NOT_LT(offset) =>
+                text += &format!("{:4} {:?} -> {}\n", pc, code, pc as i64 + *offset as i64),
+            _ => text += &format!("{:4} {:?}\n", pc, code),
+        }
+    }
+    text
+}
+
+pub(crate) fn jump_offset(code: &BCode) -> Option<i32> {
+    match code {
+        BCode::JUMP(offset) | BCode::JUMP_IF_FALSE(offset) | BCode::JUMP_IF_NOT_LT(offset) => Some(*offset),
+        _ => None,
+    }
+}
+
+// The same jump with another offset
+pub(crate) fn with_jump_offset(code: BCode, offset: i32) -> BCode {
+    match code {
+        BCode::JUMP(_) => BCode::JUMP(offset),
+        BCode::JUMP_IF_FALSE(_) => BCode::JUMP_IF_FALSE(offset),
+        BCode::JUMP_IF_NOT_LT(_) => BCode::JUMP_IF_NOT_LT(offset),
+        code => code,
+    }
+}
+
+fn jump_targets(codes: &[BCode]) -> HashSet<usize> {
+    codes.iter().enumerate()
+        .filter_map(|(pc, code)| jump_offset(code).map(|offset| (pc as i64 + offset as i64) as usize))
+        .collect()
+}
+
+// Replace the hot sequences in loops by superinstructions to cut the
+// number of dispatches. Like the peephole optimizer, a sequence is not
+// fused if a jump lands inside it.
+pub fn fuse(codes: &[BCode]) -> Vec<BCode> {
+    fuse_with_origin(codes).0
+}
+
+// `fuse` with the position in `codes` of each instruction of the result.
+// A superinstruction comes from the last instruction it replaces.
+pub(crate) fn fuse_with_origin(codes: &[BCode]) -> (Vec<BCode>, Vec<usize>) {
+    let targets = jump_targets(codes);
+    let mut codes = codes.to_vec();
+    let mut keep = vec![true; codes.len()];
+    let mut origin: Vec<usize> = (0..codes.len()).collect();
+    let mut pc = 0;
+    while pc < codes.len() {
+        let free = |len: usize| (pc + 1..pc + len).all(|p| !targets.contains(&p));
+        let fused = match codes[pc..] {
+            [BCode::LOAD_IDENT_VAR(load), BCode::INCREMENT, BCode::LOAD_IDENT(store), ..] if load == store && free(3) =>
+                Some((BCode::INCREMENT_VAR(load), 3)),
+            [BCode::LOAD_IDENT_VAR(id), BCode::BINARY_ADD, ..] if free(2) => Some((BCode::ADD_VAR(id), 2)),
+            [BCode::LOAD_IDENT_CONST(id), BCode::BINARY_ADD, ..] if free(2) => Some((BCode::ADD_CONST(id), 2)),
+            // the offset is from the position of BINARY_LT
+            [BCode::BINARY_LT, BCode::JUMP_IF_FALSE(offset), ..] if free(2) => Some((BCode::JUMP_IF_NOT_LT(offset + 1), 2)),
+            _ => None,
+        };
+        match fused {
+            Some((code, len)) => {
+                codes[pc] = code;
+                origin[pc] = pc + len - 1;
+                for k in &mut keep[pc + 1..pc + len] {
+                    *k = false;
+                }
+                pc += len;
+            }
+            None => pc += 1,
+        }
+    }
+    let origin = origin.into_iter().zip(&keep).filter(|(_, keep)| **keep).map(|(pc, _)| pc).collect();
+    (dce::remove(&codes, &keep), origin)
+}
+
+// Result of `lhs op rhs` for two inline literals. Arithmetic is folded only
+// when it neither overflows nor divides by zero, so the runtime errors and
+// the overflow mode of the processor are not affected.
+fn fold(lhs: &BCode, rhs: &BCode, op: &BCode) -> Option<BCode> {
+    fn arith<T: overflow::Integer>(op: &BCode, l: T, r: T) -> Option<T> {
+        let op = match op {
+            BCode::BINARY_ADD => ArithOp::Add,
+            BCode::BINARY_SUB => ArithOp::Sub,
+            BCode::BINARY_MUL => ArithOp::Mul,
+            BCode::BINARY_DIV => ArithOp::Div,
+            _ => return None,
+        };
+        overflow::apply(OverflowMode::Trap, op, l, r)
+    }
+    fn compare<T: PartialOrd>(op: &BCode, l: T, r: T) -> Option<BCode> {
+        let b = match op {
+            BCode::BINARY_EQ => l == r,
+            BCode::BINARY_NE => l != r,
+            BCode::BINARY_LT => l < r,
+            BCode::BINARY_LE => l <= r,
+            BCode::BINARY_GT => l > r,
+            BCode::BINARY_GE => l >= r,
+            _ => return None,
+        };
+        Some(BCode::PUSH_BOOL(b))
+    }
+    match (*lhs, *rhs) {
+        (BCode::PUSH_INT(l), BCode::PUSH_INT(r)) =>
+            arith(op, l, r).map(BCode::PUSH_INT).or_else(|| compare(op, l, r)),
+        (BCode::PUSH_UINT(l), BCode::PUSH_UINT(r)) =>
+            arith(op, l, r).map(BCode::PUSH_UINT).or_else(|| compare(op, l, r)),
+        _ => None,
+    }
+}
+
+// The -O pipeline over the code of one function
+pub fn optimize(codes: &[BCode]) -> Vec<BCode> {
+    optimize_with_origin(codes).0
+}
+
+// `optimize` with the position in `codes` of each instruction of the result
+pub(crate) fn optimize_with_origin(codes: &[BCode]) -> (Vec<BCode>, Vec<usize>) {
+    let mut codes = codes.to_vec();
+    let mut origin: Vec<usize> = (0..codes.len()).collect();
+    loop {
+        let (eliminated, from_dce) = dce::eliminate_with_origin(&codes);
+        let (optimized, from_peephole) = peephole_with_origin(&eliminated);
+        if optimized == codes {
+            return (codes, origin);
+        }
+        origin = from_peephole.iter().map(|pc| origin[from_dce[*pc]]).collect();
+        codes = optimized;
+    }
+}
+
+// Peephole optimization until nothing changes:
+//   * `PUSH a, PUSH b, op` of inline literals becomes one PUSH
+//   * a value pushed only to be discarded by POP is not pushed
+//   * loading a variable and storing it back to itself is removed
+//   * NOP and jumps to the next instruction are removed
+// Instructions which are jump targets are not merged with the previous
+// ones, and jump offsets are recalculated after the removal.
+pub fn peephole(codes: &[BCode]) -> Vec<BCode> {
+    peephole_with_origin(codes).0
+}
+
+fn peephole_with_origin(codes: &[BCode]) -> (Vec<BCode>, Vec<usize>) {
+    let mut codes = codes.to_vec();
+    let mut origin: Vec<usize> = (0..codes.len()).collect();
+    loop {
+        let (optimized, from) = peephole_pass(&codes);
+        if optimized == codes {
+            return (codes, origin);
+        }
+        origin = from.iter().map(|pc| origin[*pc]).collect();
+        codes = optimized;
+    }
+}
+
+// The optimized code and the position in `codes` of each instruction of it
+fn peephole_pass(codes: &[BCode]) -> (Vec<BCode>, Vec<usize>) {
+    let targets = jump_targets(codes);
+    // a window of instructions can be replaced only if the code inside
+    // the window is not reached by a jump
+    let inner_free = |start: usize, len: usize| {
+        start + len <= codes.len() && (start + 1..start + len).all(|pc| !targets.contains(&pc))
+    };
+
+    let mut out: Vec<(BCode, usize)> = vec![]; // optimized code and its original position
+    let mut map = vec![0; codes.len() + 1]; // original position -> optimized position
+    let mut pc = 0;
+    while pc < codes.len() {
+        map[pc] = out.len();
+        if inner_free(pc, 3) {
+            if let Some(folded) = fold(&codes[pc], &codes[pc + 1], &codes[pc + 2]) {
+                map[pc + 1] = out.len();
+                map[pc + 2] = out.len();
+                out.push((folded, pc));
+                pc += 3;
+                continue;
+            }
+        }
+        if inner_free(pc, 2) {
+            let removable = match (codes[pc], codes[pc + 1]) {
+                (BCode::PUSH_NULL | BCode::PUSH_INT(_) | BCode::PUSH_UINT(_) | BCode::PUSH_BOOL(_) |
+                 BCode::PUSH_LITERAL(_) | BCode::LOAD_IDENT_VAR(_) | BCode::LOAD_IDENT_CONST(_), BCode::POP) => true,
+                (BCode::LOAD_IDENT_VAR(load), BCode::LOAD_IDENT(store)) => load == store,
+                _ => false,
+            };
+            if removable {
+                map[pc + 1] = out.len();
+                pc += 2;
+                continue;
+            }
+        }
+        match codes[pc] {
+            BCode::NOP | BCode::JUMP(1) => (),
+            code => out.push((code, pc)),
+        }
+        pc += 1;
+    }
+    map[codes.len()] = out.len();
+
+    let optimized = out.iter().enumerate().map(|(new_pc, (code, pc))| {
+        match jump_offset(code) {
+            Some(offset) => with_jump_offset(*code, (map[(*pc as i64 + offset as i64) as usize] as i64 - new_pc as i64) as i32),
+            None => *code,
+        }
+    }).collect();
+    let origin = out.iter().map(|(_, pc)| *pc).collect();
+    (optimized, origin)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compile_program() {
+        let code = r#"
+fn add(a: u64, b: u64) -> u64 {
+    a + b
+}
+fn main() -> u64 {
+    val x = 1u64
+    add(x, 2u64)
+    add(x, 3u64)
+}
+        "#;
+        let program = frontend::Parser::new(code).parse_program().unwrap();
+        let module = Compiler::new().compile_program(&program);
+        assert_eq!(2, module.functions.len());
+        assert_eq!(Some(1), module.index("main"));
+
+        let add = module.function("add").unwrap();
+        assert_eq!(2, add.arity);
+        assert_eq!((2, 0), (add.const_slots, add.var_slots));
+        assert_eq!(vec![BCode::LOAD_IDENT_CONST(0), BCode::LOAD_IDENT_CONST(1), BCode::BINARY_ADD, BCode::RET], add.codes);
+
+        let main = module.function("main").unwrap();
+        assert_eq!(vec![
+            BCode::PUSH_UINT(1), BCode::PUSH_CONST(0),
+            BCode::LOAD_IDENT_CONST(0), BCode::PUSH_UINT(2), BCode::CALL(0), BCode::POP,
+            BCode::LOAD_IDENT_CONST(0), BCode::PUSH_UINT(3), BCode::CALL(0),
+            BCode::RET,
+        ], main.codes);
+    }
+
+    #[test]
+    fn compile_constant_pool() {
+        let code = "fn main() -> u64 {\nval a = 100000u64 * 3u64\na + 100000u64 + 18446744073709551615u64\n}";
+        let program = frontend::Parser::new(code).parse_program().unwrap();
+        let module = Compiler::new().compile_program(&program);
+        assert_eq!(vec![Constant::UInt64(100000), Constant::UInt64(u64::MAX)], module.constants);
+        assert_eq!(vec![
+            BCode::PUSH_LITERAL(0), BCode::PUSH_UINT(3), BCode::BINARY_MUL, BCode::PUSH_CONST(0),
+            BCode::LOAD_IDENT_CONST(0), BCode::PUSH_LITERAL(0), BCode::BINARY_ADD, BCode::PUSH_LITERAL(1), BCode::BINARY_ADD,
+            BCode::RET,
+        ], module.functions[0].codes);
+
+        // expressions for the REPL keep literals inline
+        let (e, pool) = frontend::Parser::new("100000u64").parse_expression().unwrap();
+        assert_eq!(vec![BCode::PUSH_UINT(100000)], Compiler::new().compile(&pool, e));
+    }
+
+    #[test]
+    fn compile_program_without_value() {
+        let code = "fn main() -> i64 {\nvar a = 1i64\na = a + 2i64\n}";
+        let program = frontend::Parser::new(code).parse_program().unwrap();
+        let module = Compiler::new().compile_program(&program);
+        assert_eq!(vec![
+            BCode::PUSH_INT(1), BCode::LOAD_IDENT(0),
+            BCode::LOAD_IDENT_VAR(0), BCode::PUSH_INT(2), BCode::BINARY_ADD, BCode::LOAD_IDENT(0),
+            BCode::PUSH_NULL, BCode::RET,
+        ], module.functions[0].codes);
+    }
+
+    fn compile_with(code: &str, opt_level: u8) -> Module {
+        let program = frontend::Parser::new(code).parse_program().unwrap();
+        let mut compiler = Compiler::new();
+        compiler.set_opt_level(opt_level);
+        compiler.compile_program(&program)
+    }
+
+    #[test]
+    fn reject_unsupported() {
+        let program = |code: &str| frontend::Parser::new(code).parse_program().unwrap();
+        let nested = program("fn main() -> u64 {\nfn f() -> u64 {\n1u64\n}\nf()\n}");
+        assert_eq!(Err("nested function `f` is not supported".to_string()), check_supported(&nested));
+        let native = program("fn main() -> u64 {\ndbg(1u64)\n}");
+        assert_eq!(Err("call of `dbg` is not supported".to_string()), check_supported(&native));
+        let spawn = program("fn f() -> u64 {\n1u64\n}\nfn main() -> u64 {\nspawn f()\n0u64\n}");
+        assert_eq!(Err("`spawn` is not supported".to_string()), check_supported(&spawn));
+        assert_eq!(Ok(()), check_supported(&program("fn f() -> u64 {\n1u64\n}\nfn main() -> u64 {\nprint(f())\nf()\n}")));
+    }
+
+    #[test]
+    fn peephole_fold_and_remove() {
+        let code = "fn main() -> u64 {\nvar a = 2u64 * 3u64 + 1u64\n5u64\na = a\na / 0u64\n}";
+        assert_eq!(concat!(
+            "   0 PUSH_UINT(2)\n",
+            "   1 PUSH_UINT(3)\n",
+            "   2 BINARY_MUL\n",
+            "   3 PUSH_UINT(1)\n",
+            "   4 BINARY_ADD\n",
+            "   5 LOAD_IDENT(0)\n",
+            "   6 PUSH_UINT(5)\n",
+            "   7 POP\n",
+            "   8 LOAD_IDENT_VAR(0)\n",
+            "   9 LOAD_IDENT(0)\n",
+            "  10 LOAD_IDENT_VAR(0)\n",
+            "  11 PUSH_UINT(0)\n",
+            "  12 BINARY_DIV\n",
+            "  13 RET\n",
+        ), disassemble(&compile_with(code, 0).functions[0].codes));
+        // division by zero is left for the runtime error
+        assert_eq!(concat!(
+            "   0 PUSH_UINT(7)\n",
+            "   1 LOAD_IDENT(0)\n",
+            "   2 LOAD_IDENT_VAR(0)\n",
+            "   3 PUSH_UINT(0)\n",
+            "   4 BINARY_DIV\n",
+            "   5 RET\n",
+        ), disassemble(&compile_with(code, 1).functions[0].codes));
+
+        // overflow is left for the processor
+        let code = "fn main() -> u64 {\n18446744073709551615u64 + 1u64\n}";
+        assert_eq!(compile_with(code, 0).functions[0].codes, compile_with(code, 1).functions[0].codes);
+    }
+
+    #[test]
+    fn peephole_keep_jump_targets() {
+        let code = "fn main() -> u64 {\nvar n = 0u64\nwhile n < 2u64 * 5u64 { n = n + 1u64 }\nif 1u64 < 2u64 { n } else { 0u64 }\n}";
+        assert_eq!(concat!(
+            "   0 PUSH_UINT(0)\n",
+            "   1 LOAD_IDENT(0)\n",
+            "   2 LOAD_IDENT_VAR(0)\n",
+            "   3 PUSH_UINT(10)\n",
+            "   4 BINARY_LT\n",
+            "   5 JUMP_IF_FALSE(6) -> 11\n",
+            "   6 LOAD_IDENT_VAR(0)\n",
+            "   7 PUSH_UINT(1)\n",
+            "   8 BINARY_ADD\n",
+            "   9 LOAD_IDENT(0)\n",
+            "  10 JUMP(-8) -> 2\n",
+            "  11 PUSH_BOOL(true)\n",
+            "  12 JUMP_IF_FALSE(3) -> 15\n",
+            "  13 LOAD_IDENT_VAR(0)\n",
+            "  14 JUMP(2) -> 16\n",
+            "  15 PUSH_UINT(0)\n",
+            "  16 RET\n",
+        ), disassemble(&peephole(&compile_with(code, 0).functions[0].codes)));
+
+        // the else branch is removed by -O
+        assert_eq!(concat!(
+            "   0 PUSH_UINT(0)\n",
+            "   1 LOAD_IDENT(0)\n",
+            "   2 LOAD_IDENT_VAR(0)\n",
+            "   3 PUSH_UINT(10)\n",
+            "   4 BINARY_LT\n",
+            "   5 JUMP_IF_FALSE(6) -> 11\n",
+            "   6 LOAD_IDENT_VAR(0)\n",
+            "   7 PUSH_UINT(1)\n",
+            "   8 BINARY_ADD\n",
+            "   9 LOAD_IDENT(0)\n",
+            "  10 JUMP(-8) -> 2\n",
+            "  11 LOAD_IDENT_VAR(0)\n",
+            "  12 RET\n",
+        ), disassemble(&compile_with(code, 1).functions[0].codes));
+
+        let mut p = crate::processor::Processor::new();
+        assert_eq!(Ok(crate::processor::Object::UInt64(10)), p.run_module(&compile_with(code, 1)));
+    }
+
+    #[test]
+    fn constant_folding() {
+        let code = "fn f(x: u64) -> u64 {\n2u64 * 3u64 + x\n}";
+        assert_eq!(vec![BCode::PUSH_UINT(6), BCode::LOAD_IDENT_CONST(0), BCode::BINARY_ADD, BCode::RET],
+                   compile_with(code, 1).functions[0].codes);
+
+        // a `val` of a constant expression is propagated to its uses,
+        // and the stores of `a` and `b` are removed as dead code
+        let code = "fn f(x: u64) -> u64 {\nval a = 1000u64\nval b = a * a\nb + x * b\n}";
+        assert_eq!(vec![
+            BCode::PUSH_LITERAL(0), BCode::LOAD_IDENT_CONST(0), BCode::PUSH_LITERAL(0), BCode::BINARY_MUL, BCode::BINARY_ADD,
+            BCode::RET,
+        ], compile_with(code, 1).functions[0].codes);
+        assert_eq!(vec![Constant::UInt64(1000000)], compile_with(code, 1).constants);
+
+        // a variable is not a constant even if its initial value is
+        let code = "fn main() -> i64 {\nvar a = 4i64\na = a - 1i64\na * 2i64\n}";
+        let mut p = crate::processor::Processor::new();
+        assert_eq!(Ok(crate::processor::Object::Int64(6)), p.run_module(&compile_with(code, 1)));
+
+        // overflow is not folded
+        let code = "fn main() -> i64 {\nval a = 9223372036854775807i64\na + 1i64\n}";
+        let mut p = crate::processor::Processor::new();
+        assert_eq!(Err(crate::processor::ProcessorError::Overflow { pc: 2 }), p.run_module(&compile_with(code, 1)));
+    }
+
+    #[test]
+    fn superinstructions() {
+        let code = "fn main() -> u64 {\nvar sum = 0u64\nfor i in 0u64..10u64 {\nsum = sum + i\n}\nsum\n}";
+        assert_eq!(concat!(
+            "   0 PUSH_UINT(0)\n",
+            "   1 LOAD_IDENT(0)\n",
+            "   2 PUSH_UINT(0)\n",
+            "   3 LOAD_IDENT(1)\n",
+            "   4 PUSH_UINT(10)\n",
+            "   5 LOAD_IDENT(2)\n",
+            "   6 LOAD_IDENT_VAR(1)\n",
+            "   7 LOAD_IDENT_VAR(2)\n",
+            "   8 JUMP_IF_NOT_LT(6) -> 14\n",
+            "   9 LOAD_IDENT_VAR(0)\n",
+            "  10 ADD_VAR(1)\n",
+            "  11 LOAD_IDENT(0)\n",
+            "  12 INCREMENT_VAR(1)\n",
+            "  13 JUMP(-7) -> 6\n",
+            "  14 LOAD_IDENT_VAR(0)\n",
+            "  15 RET\n",
+        ), disassemble(&compile_with(code, 2).functions[0].codes));
+
+        // a jump into the middle of a pair keeps it as is
+        assert_eq!(vec![BCode::JUMP_IF_FALSE(2), BCode::LOAD_IDENT_VAR(0), BCode::BINARY_ADD, BCode::RET],
+                   fuse(&[BCode::JUMP_IF_FALSE(2), BCode::LOAD_IDENT_VAR(0), BCode::BINARY_ADD, BCode::RET]));
+
+        let code = "fn add(a: i64, b: i64) -> i64 {\na + b\n}\nfn main() -> i64 {\nvar n = 0i64\nwhile n < 7i64 { n = add(n, 2i64) }\nfor i in -3i64..n { n = n + i }\nn\n}";
+        for opt_level in 0..=2 {
+            let mut p = crate::processor::Processor::new();
+            assert_eq!(Ok(crate::processor::Object::Int64(30)), p.run_module(&compile_with(code, opt_level)));
+        }
     }
-    //self.codes.append(&mut codes);
 }
@@ -0,0 +1,172 @@
+use crate::compiler::{jump_offset, with_jump_offset, BCode};
+use std::collections::HashSet;
+
+// Basic block: the instructions start..end run in sequence and only the
+// last one can jump
+#[derive(Debug, PartialEq)]
+pub struct Block {
+    pub start: usize,
+    pub end: usize,
+    pub successors: Vec<usize>, // indices of the blocks
+}
+
+// Control flow graph of the code of one function
+pub fn build_cfg(codes: &[BCode]) -> Vec<Block> {
+    let target = |pc: usize, offset: i32| (pc as i64 + offset as i64) as usize;
+    let mut leaders: Vec<usize> = vec![0];
+    for (pc, code) in codes.iter().enumerate() {
+        if let Some(offset) = jump_offset(code) {
+            leaders.push(target(pc, offset));
+            leaders.push(pc + 1);
+        } else if *code == BCode::RET {
+            leaders.push(pc + 1);
+        }
+    }
+    leaders.retain(|pc| *pc < codes.len());
+    leaders.sort();
+    leaders.dedup();
+
+    let block_of = |pc: usize| leaders.binary_search(&pc).ok();
+    let mut blocks = vec![];
+    for (i, start) in leaders.iter().enumerate() {
+        let end = leaders.get(i + 1).copied().unwrap_or(codes.len());
+        let last = end - 1;
+        let mut successors = vec![];
+        match codes[last] {
+            BCode::RET => (),
+            BCode::JUMP(offset) => successors.extend(block_of(target(last, offset))),
+            BCode::JUMP_IF_FALSE(offset) | BCode::JUMP_IF_NOT_LT(offset) => {
+                successors.extend(block_of(end));
+                successors.extend(block_of(target(last, offset)));
+            }
+            _ => successors.extend(block_of(end)),
+        }
+        blocks.push(Block { start: *start, end, successors });
+    }
+    blocks
+}
+
+// Dead code elimination of one function:
+//   * a branch on a constant bool becomes an unconditional jump (or nothing)
+//   * blocks which are not reachable from the entry are removed
+//   * a store to a constant or variable id which is never loaded is
+//     replaced by POP, so the computation of an unused value can be
+//     removed by the peephole optimizer
+pub fn eliminate(codes: &[BCode]) -> Vec<BCode> {
+    eliminate_with_origin(codes).0
+}
+
+// `eliminate` with the position in `codes` of each instruction of the result
+pub(crate) fn eliminate_with_origin(codes: &[BCode]) -> (Vec<BCode>, Vec<usize>) {
+    let mut codes = codes.to_vec();
+    let targets: HashSet<usize> = codes.iter().enumerate()
+        .filter_map(|(pc, code)| jump_offset(code).map(|offset| (pc as i64 + offset as i64) as usize))
+        .collect();
+    for pc in 1..codes.len() {
+        if targets.contains(&pc) {
+            continue;
+        }
+        // the length is kept, so the jump offsets stay valid
+        match (codes[pc - 1], codes[pc]) {
+            (BCode::PUSH_BOOL(true), BCode::JUMP_IF_FALSE(_)) => {
+                codes[pc - 1] = BCode::NOP;
+                codes[pc] = BCode::NOP;
+            }
+            (BCode::PUSH_BOOL(false), BCode::JUMP_IF_FALSE(offset)) => {
+                codes[pc - 1] = BCode::NOP;
+                codes[pc] = BCode::JUMP(offset);
+            }
+            _ => (),
+        }
+    }
+
+    let loaded_consts: HashSet<u32> = codes.iter().filter_map(|c| match c {
+        BCode::LOAD_IDENT_CONST(id) | BCode::ADD_CONST(id) => Some(*id),
+        _ => None,
+    }).collect();
+    let loaded_vars: HashSet<u32> = codes.iter().filter_map(|c| match c {
+        BCode::LOAD_IDENT_VAR(id) | BCode::ADD_VAR(id) | BCode::INCREMENT_VAR(id) => Some(*id),
+        _ => None,
+    }).collect();
+    for code in codes.iter_mut() {
+        match *code {
+            BCode::PUSH_CONST(id) | BCode::LOAD_CONST(id) if !loaded_consts.contains(&id) => *code = BCode::POP,
+            BCode::LOAD_IDENT(id) if !loaded_vars.contains(&id) => *code = BCode::POP,
+            _ => (),
+        }
+    }
+
+    let blocks = build_cfg(&codes);
+    let mut reachable = vec![false; blocks.len()];
+    let mut work = vec![0];
+    while let Some(b) = work.pop() {
+        if b < blocks.len() && !reachable[b] {
+            reachable[b] = true;
+            work.extend(&blocks[b].successors);
+        }
+    }
+    let mut keep = vec![false; codes.len()];
+    for (block, reachable) in blocks.iter().zip(reachable) {
+        for k in &mut keep[block.start..block.end] {
+            *k = reachable;
+        }
+    }
+    let origin = (0..codes.len()).filter(|pc| keep[*pc]).collect();
+    (remove(&codes, &keep), origin)
+}
+
+// Remove the instructions which are not kept and recalculate the jump offsets.
+// A jump to a removed instruction goes to the next kept one.
+pub(crate) fn remove(codes: &[BCode], keep: &[bool]) -> Vec<BCode> {
+    let mut map = vec![0; codes.len() + 1];
+    let mut count = 0;
+    for pc in 0..codes.len() {
+        map[pc] = count;
+        if keep[pc] {
+            count += 1;
+        }
+    }
+    map[codes.len()] = count;
+
+    codes.iter().enumerate().filter(|(pc, _)| keep[*pc]).map(|(pc, code)| {
+        match jump_offset(code) {
+            Some(offset) => with_jump_offset(*code, (map[(pc as i64 + offset as i64) as usize] as i64 - map[pc] as i64) as i32),
+            None => *code,
+        }
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use BCode::*;
+
+    #[test]
+    fn cfg_of_branch() {
+        let codes = vec![PUSH_BOOL(true), JUMP_IF_FALSE(3), PUSH_INT(1), JUMP(2), PUSH_INT(2), RET];
+        assert_eq!(vec![
+            Block { start: 0, end: 2, successors: vec![1, 2] },
+            Block { start: 2, end: 4, successors: vec![3] },
+            Block { start: 4, end: 5, successors: vec![3] },
+            Block { start: 5, end: 6, successors: vec![] },
+        ], build_cfg(&codes));
+    }
+
+    #[test]
+    fn eliminate_dead_code() {
+        // the else branch is never taken
+        let codes = vec![PUSH_BOOL(true), JUMP_IF_FALSE(3), PUSH_INT(1), JUMP(2), PUSH_INT(2), RET];
+        assert_eq!(vec![NOP, NOP, PUSH_INT(1), JUMP(1), RET], eliminate(&codes));
+
+        // the then branch is never taken
+        let codes = vec![PUSH_BOOL(false), JUMP_IF_FALSE(3), PUSH_INT(1), JUMP(2), PUSH_INT(2), RET];
+        assert_eq!(vec![NOP, JUMP(1), PUSH_INT(2), RET], eliminate(&codes));
+
+        // code after RET
+        assert_eq!(vec![PUSH_INT(1), RET], eliminate(&[PUSH_INT(1), RET, PUSH_INT(2), RET]));
+
+        // stores which are never loaded
+        let codes = vec![PUSH_INT(1), LOAD_IDENT(0), PUSH_INT(2), PUSH_CONST(0), LOAD_IDENT_CONST(0), RET];
+        assert_eq!(vec![PUSH_INT(1), POP, PUSH_INT(2), PUSH_CONST(0), LOAD_IDENT_CONST(0), RET], eliminate(&codes));
+    }
+}
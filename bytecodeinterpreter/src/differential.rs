@@ -0,0 +1,202 @@
+use crate::compiler::Compiler;
+use crate::processor::{self, Processor, ProcessorError};
+use interpreter::error::InterpreterError;
+use interpreter::object::Object;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+// Differential testing of the bytecode VM against the tree walking
+// interpreter: the same program runs on both, the outcomes must agree and
+// the time of each is reported. See `benches/differential.rs`.
+
+// Result of `main` in the terms of the interpreter. The VM returns null
+// for a function without value, which is unit here.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Outcome {
+    Value(Object),
+    DivisionByZero,
+    Overflow,
+    UndefinedFunction(String),
+    Error(String), // any other error, which the two sides are not expected to share
+}
+
+#[derive(Debug, PartialEq)]
+pub enum DifferentialError {
+    Parse(String),
+    Mismatch { interpreter: Outcome, vm: Outcome },
+}
+
+impl fmt::Display for DifferentialError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DifferentialError::Parse(message) => write!(f, "parse error: {}", message),
+            DifferentialError::Mismatch { interpreter, vm } =>
+                write!(f, "interpreter returned {:?} but VM returned {:?}", interpreter, vm),
+        }
+    }
+}
+
+impl std::error::Error for DifferentialError {}
+
+#[derive(Debug)]
+pub struct Comparison {
+    pub outcome: Outcome,
+    pub interpreter: Duration,
+    pub vm: Duration, // excluding the compilation
+    pub vm_instructions: u64,
+}
+
+impl Comparison {
+    // how many times the VM is faster than the interpreter
+    pub fn speedup(&self) -> f64 {
+        self.interpreter.as_secs_f64() / self.vm.as_secs_f64()
+    }
+}
+
+// Run `main` of `code` on both sides, the VM with the code compiled at `opt_level`
+pub fn compare(code: &str, opt_level: u8) -> Result<Comparison, DifferentialError> {
+    compare_with(code, opt_level, false)
+}
+
+// `compare` with the code compiled through the mid-level IR
+pub fn compare_ir(code: &str, opt_level: u8) -> Result<Comparison, DifferentialError> {
+    compare_with(code, opt_level, true)
+}
+
+fn compare_with(code: &str, opt_level: u8, ir: bool) -> Result<Comparison, DifferentialError> {
+    let program = frontend::Parser::new(code).parse_program()
+        .map_err(|e| DifferentialError::Parse(e.to_string()))?;
+
+    let start = Instant::now();
+    let expected = interpreter::processor::Processor::new().execute_program(&program);
+    let interpreter = start.elapsed();
+
+    // the AST passes are checked with the compiler at -O2
+    let mut program = program;
+    if opt_level >= 2 {
+        frontend::optimizer::PassManager::standard().run(&mut program);
+    }
+    let mut compiler = Compiler::new();
+    compiler.set_opt_level(opt_level);
+    compiler.set_ir(ir);
+    let module = compiler.compile_program(&program);
+    let mut p = Processor::new();
+    let start = Instant::now();
+    let actual = p.run_module(&module);
+    let vm = start.elapsed();
+
+    let (expected, actual) = (interpreter_outcome(expected), vm_outcome(actual));
+    if expected != actual {
+        return Err(DifferentialError::Mismatch { interpreter: expected, vm: actual });
+    }
+    Ok(Comparison { outcome: expected, interpreter, vm, vm_instructions: p.executed() })
+}
+
+fn interpreter_outcome(result: Result<Object, InterpreterError>) -> Outcome {
+    match result {
+        Ok(Object::Null) => Outcome::Value(Object::Unit),
+        Ok(object) => Outcome::Value(object),
+        Err(InterpreterError::DivisionByZero(_)) => Outcome::DivisionByZero,
+        Err(InterpreterError::Overflow(_)) => Outcome::Overflow,
+        Err(InterpreterError::UndefinedFunction(name)) => Outcome::UndefinedFunction(name),
+        Err(e) => Outcome::Error(e.to_string()),
+    }
+}
+
+fn vm_outcome(result: Result<processor::Object, ProcessorError>) -> Outcome {
+    match result {
+        Ok(object) => match object.to_value() {
+            Some(value) => Outcome::Value(value),
+            None => Outcome::Error(format!("unexpected result {:?}", object)),
+        },
+        Err(ProcessorError::DivisionByZero { .. }) => Outcome::DivisionByZero,
+        Err(ProcessorError::Overflow { .. }) => Outcome::Overflow,
+        Err(ProcessorError::UndefinedFunction(name)) => Outcome::UndefinedFunction(name),
+        Err(e) => Outcome::Error(format!("{:?}", e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compare_outcomes() {
+        let code = r#"
+fn collatz(n: u64) -> u64 {
+    var x = n
+    var steps = 0u64
+    while x != 1u64 {
+        if (x / 2u64) * 2u64 == x { x = x / 2u64 } else { x = x * 3u64 + 1u64 }
+        steps = steps + 1u64
+    }
+    steps
+}
+fn main() -> u64 {
+    var sum = 0u64
+    for i in 1u64..30u64 { sum = sum + collatz(i) }
+    sum
+}
+        "#;
+        for opt_level in 0..=2 {
+            assert_eq!(Outcome::Value(Object::UInt64(423)), compare(code, opt_level).unwrap().outcome);
+            assert_eq!(Outcome::Value(Object::UInt64(423)), compare_ir(code, opt_level).unwrap().outcome);
+        }
+
+        // errors agree too
+        let code = "fn main() -> i64 {\nval a = 0i64\n9223372036854775807i64 / a\n}";
+        assert_eq!(Outcome::DivisionByZero, compare(code, 1).unwrap().outcome);
+        let code = "fn main() -> u64 {\nvar a = 18446744073709551615u64\na + 1u64\n}";
+        assert_eq!(Outcome::Overflow, compare(code, 2).unwrap().outcome);
+        assert_eq!(Outcome::Overflow, compare_ir(code, 0).unwrap().outcome);
+        assert_eq!(Outcome::UndefinedFunction("main".to_string()),
+                   compare("fn f() -> u64 {\n1u64\n}", 0).unwrap().outcome);
+        assert!(matches!(compare("fn main() u64 {\n1u64\n}", 0), Err(DifferentialError::Parse(_))));
+    }
+
+    #[test]
+    fn compare_scoped_bindings() {
+        // sibling blocks bind the same names, inner bindings shadow outer ones
+        let code = r#"
+fn f(s: u64) -> u64 {
+    val x = 1u64
+    var y = 2u64
+    if s > 1u64 { val t = 1u64
+        val x = x + t
+        var y = 10u64
+        y = y + x } else { val t = 2u64
+        y = y + t }
+    for i in 0u64..2u64 { val x = s
+        y = y + x }
+    x + y
+}
+fn main() -> u64 {
+    f(2u64) * 100u64 + f(0u64)
+}
+        "#;
+        for opt_level in 0..=2 {
+            assert_eq!(Outcome::Value(Object::UInt64(705)), compare(code, opt_level).unwrap().outcome);
+            assert_eq!(Outcome::Value(Object::UInt64(705)), compare_ir(code, opt_level).unwrap().outcome);
+        }
+    }
+
+    #[test]
+    fn compare_print_value() {
+        // `print` gives `()` like any other call
+        let code = "fn main() -> u64 {\nval x = print(1u64)\nprint(print(x))\n3u64\n}";
+        for opt_level in 0..=2 {
+            assert_eq!(Outcome::Value(Object::UInt64(3)), compare(code, opt_level).unwrap().outcome);
+            assert_eq!(Outcome::Value(Object::UInt64(3)), compare_ir(code, opt_level).unwrap().outcome);
+        }
+    }
+
+    #[test]
+    fn report_mismatch() {
+        let error = DifferentialError::Mismatch {
+            interpreter: Outcome::Value(Object::Int64(1)),
+            vm: Outcome::Overflow,
+        };
+        assert_eq!("interpreter returned Value(Int64(1)) but VM returned Overflow", error.to_string());
+        assert_eq!(Outcome::Value(Object::Unit), vm_outcome(Ok(processor::Object::Null)));
+    }
+}
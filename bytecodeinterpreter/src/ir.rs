@@ -0,0 +1,665 @@
+use crate::compiler::{BCode, Compiler, Constant};
+use frontend::ast::{Expr, ExprPool, ExprRef, Operator, Program};
+use std::collections::HashMap;
+use std::fmt;
+
+// Mid-level IR between the typed AST and the bytecode. The code of a
+// function is a graph of basic blocks of three-address instructions over
+// an unlimited number of registers; each block ends with a jump, a branch
+// or a return. The analyses and optimizations run on it, then it is
+// lowered to the bytecode of the stack machine (`lower`).
+//
+// The arguments are the registers 0..arity. Each `val`, `var` and each
+// value computed by an expression gets its own register, and a use of a
+// variable copies it into a new register, so that the value of an
+// operand cannot be changed by the evaluation of the other operand.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Reg(pub u32);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Int64(i64),
+    UInt64(u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Inst {
+    Const(Reg, Value),
+    Copy(Reg, Reg),
+    Binary(Reg, BinOp, Reg, Reg),
+    Increment(Reg, Reg),      // add 1 of the type of the operand
+    Call(Reg, u32, Vec<Reg>), // index of `Module::functions`
+    Print(Reg),
+    Phi(Reg, Vec<(usize, Reg)>), // the value from each predecessor block, only in SSA form
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Terminator {
+    Jump(usize),
+    Branch(Reg, usize, usize), // to the first block if the register is true
+    Return(Reg),
+}
+
+// Instructions and terminator with the expression each one comes from,
+// for the locations of the code object
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    pub insts: Vec<(Inst, ExprRef)>,
+    pub terminator: (Terminator, ExprRef),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function {
+    pub name: String,
+    pub arity: u32,
+    pub registers: u32,
+    pub blocks: Vec<Block>, // the entry is blocks[0]
+}
+
+impl Inst {
+    // The register written by the instruction
+    pub fn def(&self) -> Option<Reg> {
+        match self {
+            Inst::Const(d, _) | Inst::Copy(d, _) | Inst::Binary(d, ..) | Inst::Increment(d, _) | Inst::Call(d, ..) |
+            Inst::Phi(d, _) => Some(*d),
+            Inst::Print(_) => None,
+        }
+    }
+
+    pub fn map_def(&mut self, f: &mut impl FnMut(Reg) -> Reg) {
+        match self {
+            Inst::Const(d, _) | Inst::Copy(d, _) | Inst::Binary(d, ..) | Inst::Increment(d, _) | Inst::Call(d, ..) |
+            Inst::Phi(d, _) => *d = f(*d),
+            Inst::Print(_) => (),
+        }
+    }
+
+    pub fn map_uses(&mut self, f: &mut impl FnMut(Reg) -> Reg) {
+        match self {
+            Inst::Const(..) => (),
+            Inst::Copy(_, s) | Inst::Increment(_, s) | Inst::Print(s) => *s = f(*s),
+            Inst::Binary(_, _, a, b) => {
+                *a = f(*a);
+                *b = f(*b);
+            }
+            Inst::Call(_, _, args) => args.iter_mut().for_each(|a| *a = f(*a)),
+            Inst::Phi(_, operands) => operands.iter_mut().for_each(|(_, r)| *r = f(*r)),
+        }
+    }
+
+    // The registers read by the instruction
+    pub fn uses(&self) -> Vec<Reg> {
+        match self {
+            Inst::Const(..) => vec![],
+            Inst::Copy(_, s) | Inst::Increment(_, s) | Inst::Print(s) => vec![*s],
+            Inst::Binary(_, _, a, b) => vec![*a, *b],
+            Inst::Call(_, _, args) => args.clone(),
+            Inst::Phi(_, operands) => operands.iter().map(|(_, r)| *r).collect(),
+        }
+    }
+}
+
+impl Terminator {
+    pub fn successors(&self) -> Vec<usize> {
+        match self {
+            Terminator::Jump(b) => vec![*b],
+            Terminator::Branch(_, t, e) => vec![*t, *e],
+            Terminator::Return(_) => vec![],
+        }
+    }
+
+    pub fn uses(&self) -> Option<Reg> {
+        match self {
+            Terminator::Jump(_) => None,
+            Terminator::Branch(c, ..) | Terminator::Return(c) => Some(*c),
+        }
+    }
+
+    pub fn map_uses(&mut self, f: &mut impl FnMut(Reg) -> Reg) {
+        match self {
+            Terminator::Jump(_) => (),
+            Terminator::Branch(c, ..) | Terminator::Return(c) => *c = f(*c),
+        }
+    }
+}
+
+impl Function {
+    // Blocks reachable from the entry in reverse postorder, the entry
+    // first and a block before its successors except along back edges
+    pub fn reverse_postorder(&self) -> Vec<usize> {
+        let mut visited = vec![false; self.blocks.len()];
+        let mut order = vec![];
+        let mut work = vec![(0, false)];
+        while let Some((b, finished)) = work.pop() {
+            if finished {
+                order.push(b);
+                continue;
+            }
+            if visited[b] {
+                continue;
+            }
+            visited[b] = true;
+            work.push((b, true));
+            // pushed in order, so that the first successor is placed first
+            work.extend(self.blocks[b].terminator.0.successors().into_iter().filter(|s| !visited[*s]).map(|s| (s, false)));
+        }
+        order.reverse();
+        order
+    }
+
+    // Predecessors of each block, among the reachable ones
+    pub fn predecessors(&self) -> Vec<Vec<usize>> {
+        let mut predecessors = vec![vec![]; self.blocks.len()];
+        for b in self.reverse_postorder() {
+            for s in self.blocks[b].terminator.0.successors() {
+                if !predecessors[s].contains(&b) {
+                    predecessors[s].push(b);
+                }
+            }
+        }
+        predecessors
+    }
+}
+
+// Block being built, the terminator is set when the block is finished
+type OpenBlock = (Vec<(Inst, ExprRef)>, Option<(Terminator, ExprRef)>);
+
+struct Builder<'a> {
+    pool: &'a ExprPool,
+    functions: &'a HashMap<String, u32>,
+    names: HashMap<String, Reg>,
+    registers: u32,
+    blocks: Vec<OpenBlock>,
+    current: usize,
+}
+
+// IR of each function of the type checked program
+pub fn build_program(program: &Program) -> Vec<Function> {
+    let functions: HashMap<String, u32> = program.function.iter().enumerate()
+        .map(|(i, f)| (f.name.clone(), i as u32))
+        .collect();
+    program.function.iter().map(|f| build(program, f, &functions)).collect()
+}
+
+// IR of the function `f` of the program, `functions` is the index of
+// each function in the module
+pub fn build(program: &Program, f: &frontend::ast::Function, functions: &HashMap<String, u32>) -> Function {
+    let mut builder = Builder {
+        pool: &program.expression,
+        functions,
+        names: f.parameter.iter().enumerate().map(|(i, (name, _))| (name.clone(), Reg(i as u32))).collect(),
+        registers: f.parameter.len() as u32,
+        blocks: vec![(vec![], None)],
+        current: 0,
+    };
+    let result = builder.expr(f.code);
+    let result = builder.value(result, f.code);
+    builder.terminate(Terminator::Return(result), f.code);
+    let blocks = builder.blocks.into_iter()
+        .map(|(insts, terminator)| Block { insts, terminator: terminator.expect("every block is terminated") })
+        .collect();
+    Function { name: f.name.clone(), arity: f.parameter.len() as u32, registers: builder.registers, blocks }
+}
+
+impl Builder<'_> {
+    fn new_reg(&mut self) -> Reg {
+        self.registers += 1;
+        Reg(self.registers - 1)
+    }
+
+    fn new_block(&mut self) -> usize {
+        self.blocks.push((vec![], None));
+        self.blocks.len() - 1
+    }
+
+    fn push(&mut self, inst: Inst, e: ExprRef) {
+        self.blocks[self.current].0.push((inst, e));
+    }
+
+    fn terminate(&mut self, terminator: Terminator, e: ExprRef) {
+        self.blocks[self.current].1 = Some((terminator, e));
+    }
+
+    fn constant(&mut self, value: Value, e: ExprRef) -> Reg {
+        let r = self.new_reg();
+        self.push(Inst::Const(r, value), e);
+        r
+    }
+
+    // The register of a value, `()` for an expression without value
+    fn value(&mut self, r: Option<Reg>, e: ExprRef) -> Reg {
+        match r {
+            Some(r) => r,
+            None => self.constant(Value::Null, e),
+        }
+    }
+
+    // Register holding the value of `e`, None if it has no value. The
+    // register is a new one, which nothing else writes.
+    fn expr(&mut self, e: ExprRef) -> Option<Reg> {
+        let pool = self.pool;
+        match pool.get(e.0 as usize).unwrap() {
+            Expr::IfElse(cond, then_block, else_block) => {
+                let value = Compiler::has_value(pool, e);
+                let c = self.expr(*cond)?;
+                let (then_b, else_b, join) = (self.new_block(), self.new_block(), self.new_block());
+                self.terminate(Terminator::Branch(c, then_b, else_b), e);
+                let r = value.then(|| self.new_reg());
+                for (block, branch) in [(then_b, *then_block), (else_b, *else_block)] {
+                    self.current = block;
+                    let v = self.expr(branch);
+                    if let (Some(r), Some(v)) = (r, v) {
+                        self.push(Inst::Copy(r, v), e);
+                    }
+                    self.terminate(Terminator::Jump(join), e);
+                }
+                self.current = join;
+                r
+            }
+            Expr::While(cond, body) => {
+                let (head, body_b, exit) = (self.new_block(), self.new_block(), self.new_block());
+                self.terminate(Terminator::Jump(head), e);
+                self.current = head;
+                let c = self.expr(*cond)?;
+                self.terminate(Terminator::Branch(c, body_b, exit), e);
+                self.current = body_b;
+                self.expr(*body);
+                self.terminate(Terminator::Jump(head), e);
+                self.current = exit;
+                None
+            }
+            Expr::For(name, start, end, body) => {
+                let (i, end_r) = (self.new_reg(), self.new_reg());
+                let start = self.expr(*start)?;
+                self.push(Inst::Copy(i, start), e);
+                let end = self.expr(*end)?;
+                self.push(Inst::Copy(end_r, end), e);
+                let (head, body_b, exit) = (self.new_block(), self.new_block(), self.new_block());
+                self.terminate(Terminator::Jump(head), e);
+                self.current = head;
+                let c = self.new_reg();
+                self.push(Inst::Binary(c, BinOp::Lt, i, end_r), e);
+                self.terminate(Terminator::Branch(c, body_b, exit), e);
+                self.current = body_b;
+                let outer = self.names.insert(name.clone(), i);
+                self.expr(*body);
+                match outer {
+                    Some(outer) => self.names.insert(name.clone(), outer),
+                    None => self.names.remove(name),
+                };
+                self.push(Inst::Increment(i, i), e);
+                self.terminate(Terminator::Jump(head), e);
+                self.current = exit;
+                None
+            }
+            Expr::Binary(op @ (Operator::LogicalAnd | Operator::LogicalOr), lhs, rhs) => {
+                // short circuit: the rhs is evaluated only if the lhs doesn't decide
+                let r = self.new_reg();
+                let l = self.expr(*lhs)?;
+                let (rhs_b, short_b, join) = (self.new_block(), self.new_block(), self.new_block());
+                let (short, branch) = match op {
+                    Operator::LogicalAnd => (false, Terminator::Branch(l, rhs_b, short_b)),
+                    _ => (true, Terminator::Branch(l, short_b, rhs_b)),
+                };
+                self.terminate(branch, e);
+                self.current = rhs_b;
+                let v = self.expr(*rhs)?;
+                self.push(Inst::Copy(r, v), e);
+                self.terminate(Terminator::Jump(join), e);
+                self.current = short_b;
+                self.push(Inst::Const(r, Value::Bool(short)), e);
+                self.terminate(Terminator::Jump(join), e);
+                self.current = join;
+                Some(r)
+            }
+            Expr::Binary(Operator::Assign, lhs, rhs) => {
+                let target = match pool.get(lhs.0 as usize) {
+                    Some(Expr::Identifier(name)) => match self.names.get(name) {
+                        Some(r) => *r,
+                        None => panic!("error, variable name is invalid: `{}`", name),
+                    },
+                    x => panic!("left hand side of assignment must be identifier but {:?}", x),
+                };
+                let v = self.expr(*rhs)?;
+                self.push(Inst::Copy(target, v), e);
+                None
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                let op = match op {
+                    Operator::IAdd => BinOp::Add,
+                    Operator::ISub => BinOp::Sub,
+                    Operator::IMul => BinOp::Mul,
+                    Operator::IDiv => BinOp::Div,
+                    Operator::EQ => BinOp::Eq,
+                    Operator::NE => BinOp::Ne,
+                    Operator::LT => BinOp::Lt,
+                    Operator::LE => BinOp::Le,
+                    Operator::GT => BinOp::Gt,
+                    Operator::GE => BinOp::Ge,
+                    _ => panic!("not implemented yet (Binary Operator)"),
+                };
+                let a = self.expr(*lhs)?;
+                let b = self.expr(*rhs)?;
+                let r = self.new_reg();
+                self.push(Inst::Binary(r, op, a, b), e);
+                Some(r)
+            }
+            Expr::Int64(i) => Some(self.constant(Value::Int64(*i), e)),
+            Expr::UInt64(u) => Some(self.constant(Value::UInt64(*u), e)),
+            Expr::Int(i) => match i.parse::<i64>() {
+                // literals are resolved by the parser, so this is a fallback
+                Ok(i) => Some(self.constant(Value::Int64(i), e)),
+                Err(_) => panic!("invalid integer literal: {}", i),
+            },
+            Expr::Identifier(name) => {
+                let v = match self.names.get(name) {
+                    Some(v) => *v,
+                    None => panic!("error, variable/constant name is invalid: `{}`", name),
+                };
+                let r = self.new_reg();
+                self.push(Inst::Copy(r, v), e);
+                Some(r)
+            }
+            Expr::Call(name, args) => {
+                let args: Vec<ExprRef> = match pool.get(args.0 as usize) {
+                    Some(Expr::Block(args)) => args.clone(),
+                    _ => vec![],
+                };
+                let mut regs = vec![];
+                for arg in args {
+                    let v = self.expr(arg);
+                    regs.push(self.value(v, arg));
+                }
+                if name == "print0" || name == "print" {
+                    for r in regs {
+                        self.push(Inst::Print(r), e);
+                    }
+                    return Some(self.constant(Value::Null, e));
+                }
+                let index = match self.functions.get(name) {
+                    Some(index) => *index,
+                    None => panic!("not implemented yet (Call {})", name),
+                };
+                let r = self.new_reg();
+                self.push(Inst::Call(r, index, regs), e);
+                Some(r)
+            }
+            Expr::Spawn(_) => panic!("not implemented yet (spawn)"),
+            Expr::Function(f) => panic!("not implemented yet (nested fn {})", f.name),
+            Expr::Block(b) => {
+                let outer = self.names.clone();
+                let mut last = None;
+                for s in b {
+                    last = self.expr(*s);
+                }
+                self.names = outer;
+                last
+            }
+            Expr::Null => Some(self.constant(Value::Null, e)),
+            Expr::Val(name, _, value) => {
+                let value = match value {
+                    Some(value) => *value,
+                    None => panic!("value is not set: {}", name),
+                };
+                // the register of the value is new, so it is the one of the name
+                let v = self.expr(value);
+                let v = self.value(v, value);
+                self.names.insert(name.clone(), v);
+                None
+            }
+            Expr::Var(name, _, value) => {
+                let v = match value {
+                    Some(value) => self.expr(*value),
+                    None => None,
+                };
+                let v = self.value(v, e);
+                self.names.insert(name.clone(), v);
+                None
+            }
+        }
+    }
+}
+
+// Bytecode of the function with the expression each instruction comes
+// from. The argument registers are the constant ids 0..arity and the
+// other ones are the variable ids from 0. The blocks are laid out in
+// reverse postorder, and a jump to the next block is left out.
+pub fn lower(f: &Function, literal: &mut impl FnMut(Constant) -> BCode) -> (Vec<BCode>, Vec<ExprRef>) {
+    let order = f.reverse_postorder();
+    let mut position = vec![0; f.blocks.len()];
+    let mut codes: Vec<BCode> = vec![];
+    let mut origin = vec![];
+    let mut jumps = vec![]; // position of each jump and its target block
+    let load = |r: Reg| if r.0 < f.arity { BCode::LOAD_IDENT_CONST(r.0) } else { BCode::LOAD_IDENT_VAR(r.0 - f.arity) };
+    let store = |r: Reg| if r.0 < f.arity { BCode::PUSH_CONST(r.0) } else { BCode::LOAD_IDENT(r.0 - f.arity) };
+    for (k, b) in order.iter().enumerate() {
+        position[*b] = codes.len();
+        let block = &f.blocks[*b];
+        for (inst, e) in &block.insts {
+            let emitted = match inst {
+                Inst::Const(d, value) => {
+                    let push = match value {
+                        Value::Null => BCode::PUSH_NULL,
+                        Value::Bool(b) => BCode::PUSH_BOOL(*b),
+                        Value::Int64(i) => literal(Constant::Int64(*i)),
+                        Value::UInt64(u) => literal(Constant::UInt64(*u)),
+                    };
+                    vec![push, store(*d)]
+                }
+                Inst::Copy(d, s) => vec![load(*s), store(*d)],
+                Inst::Binary(d, op, a, b) => {
+                    let op = match op {
+                        BinOp::Add => BCode::BINARY_ADD,
+                        BinOp::Sub => BCode::BINARY_SUB,
+                        BinOp::Mul => BCode::BINARY_MUL,
+                        BinOp::Div => BCode::BINARY_DIV,
+                        BinOp::Eq => BCode::BINARY_EQ,
+                        BinOp::Ne => BCode::BINARY_NE,
+                        BinOp::Lt => BCode::BINARY_LT,
+                        BinOp::Le => BCode::BINARY_LE,
+                        BinOp::Gt => BCode::BINARY_GT,
+                        BinOp::Ge => BCode::BINARY_GE,
+                    };
+                    vec![load(*a), load(*b), op, store(*d)]
+                }
+                Inst::Increment(d, s) => vec![load(*s), BCode::INCREMENT, store(*d)],
+                Inst::Call(d, index, args) => {
+                    let mut emitted: Vec<BCode> = args.iter().map(|a| load(*a)).collect();
+                    emitted.extend([BCode::CALL(*index), store(*d)]);
+                    emitted
+                }
+                Inst::Print(s) => vec![load(*s), BCode::PRINT0],
+                Inst::Phi(..) => unreachable!("phi is removed when leaving the SSA form"),
+            };
+            origin.extend(std::iter::repeat_n(*e, emitted.len()));
+            codes.extend(emitted);
+        }
+        let (terminator, e) = &block.terminator;
+        let next = order.get(k + 1).copied();
+        let mut jump = |codes: &mut Vec<BCode>, code: BCode, target: usize| {
+            jumps.push((codes.len(), target));
+            codes.push(code);
+        };
+        let start = codes.len();
+        match terminator {
+            Terminator::Jump(target) if Some(*target) == next => (),
+            Terminator::Jump(target) => jump(&mut codes, BCode::JUMP(0), *target),
+            Terminator::Branch(c, then_b, else_b) => {
+                codes.push(load(*c));
+                jump(&mut codes, BCode::JUMP_IF_FALSE(0), *else_b);
+                if Some(*then_b) != next {
+                    jump(&mut codes, BCode::JUMP(0), *then_b);
+                }
+            }
+            Terminator::Return(r) => codes.extend([load(*r), BCode::RET]),
+        }
+        origin.extend(std::iter::repeat_n(*e, codes.len() - start));
+    }
+    for (pc, target) in jumps {
+        let offset = position[target] as i32 - pc as i32;
+        codes[pc] = crate::compiler::with_jump_offset(codes[pc], offset);
+    }
+    (codes, origin)
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Null => write!(f, "()"),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Int64(i) => write!(f, "{}i64", i),
+            Value::UInt64(u) => write!(f, "{}u64", u),
+        }
+    }
+}
+
+impl fmt::Display for Reg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "r{}", self.0)
+    }
+}
+
+impl fmt::Display for Inst {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Inst::Const(d, v) => write!(f, "{} = {}", d, v),
+            Inst::Copy(d, s) => write!(f, "{} = {}", d, s),
+            Inst::Binary(d, op, a, b) => write!(f, "{} = {:?} {}, {}", d, op, a, b),
+            Inst::Increment(d, s) => write!(f, "{} = {} + 1", d, s),
+            Inst::Call(d, index, args) => {
+                let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+                write!(f, "{} = call {}({})", d, index, args.join(", "))
+            }
+            Inst::Print(s) => write!(f, "print {}", s),
+            Inst::Phi(d, operands) => {
+                let operands: Vec<String> = operands.iter().map(|(b, r)| format!("b{}: {}", b, r)).collect();
+                write!(f, "{} = phi [{}]", d, operands.join(", "))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Terminator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Terminator::Jump(b) => write!(f, "jump b{}", b),
+            Terminator::Branch(c, t, e) => write!(f, "branch {}, b{}, b{}", c, t, e),
+            Terminator::Return(r) => write!(f, "return {}", r),
+        }
+    }
+}
+
+// One block after another, in reverse postorder
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "fn {} (arity {})", self.name, self.arity)?;
+        for b in self.reverse_postorder() {
+            writeln!(f, "b{}:", b)?;
+            for (inst, _) in &self.blocks[b].insts {
+                writeln!(f, "    {}", inst)?;
+            }
+            writeln!(f, "    {}", self.blocks[b].terminator.0)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::{Object, Processor};
+
+    fn run(code: &str, ir: bool) -> Object {
+        let program = frontend::Parser::new(code).parse_program().unwrap();
+        let mut compiler = Compiler::new();
+        compiler.set_ir(ir);
+        Processor::new().run_module(&compiler.compile_program(&program)).unwrap()
+    }
+
+    #[test]
+    fn build_and_lower() {
+        let code = "fn f(n: u64) -> u64 {\nvar x = 0u64\nif n > 1u64 && n < 5u64 { x = n } else { x = 1u64 }\nx\n}";
+        let program = frontend::Parser::new(code).parse_program().unwrap();
+        let functions = build_program(&program);
+        assert_eq!(concat!(
+            "fn f (arity 1)\n",
+            "b0:\n",
+            "    r1 = 0u64\n",
+            "    r3 = r0\n",
+            "    r4 = 1u64\n",
+            "    r5 = Gt r3, r4\n",
+            "    branch r5, b1, b2\n",
+            "b1:\n",
+            "    r6 = r0\n",
+            "    r7 = 5u64\n",
+            "    r8 = Lt r6, r7\n",
+            "    r2 = r8\n",
+            "    jump b3\n",
+            "b2:\n",
+            "    r2 = false\n",
+            "    jump b3\n",
+            "b3:\n",
+            "    branch r2, b4, b5\n",
+            "b4:\n",
+            "    r9 = r0\n",
+            "    r1 = r9\n",
+            "    jump b6\n",
+            "b5:\n",
+            "    r10 = 1u64\n",
+            "    r1 = r10\n",
+            "    jump b6\n",
+            "b6:\n",
+            "    r11 = r1\n",
+            "    return r11\n",
+        ), functions[0].to_string());
+
+        let (codes, origin) = lower(&functions[0], &mut |c| match c {
+            Constant::Int64(i) => BCode::PUSH_INT(i),
+            Constant::UInt64(u) => BCode::PUSH_UINT(u),
+        });
+        assert_eq!(codes.len(), origin.len());
+        // the argument is a constant id, the other registers are variable ids
+        assert_eq!(&[
+            BCode::PUSH_UINT(0), BCode::LOAD_IDENT(0),
+            BCode::LOAD_IDENT_CONST(0), BCode::LOAD_IDENT(2),
+            BCode::PUSH_UINT(1), BCode::LOAD_IDENT(3),
+            BCode::LOAD_IDENT_VAR(2), BCode::LOAD_IDENT_VAR(3), BCode::BINARY_GT, BCode::LOAD_IDENT(4),
+            BCode::LOAD_IDENT_VAR(4), BCode::JUMP_IF_FALSE(12),
+        ], &codes[..12]);
+        assert_eq!(BCode::RET, *codes.last().unwrap());
+
+        let code = r#"
+fn add(a: u64, b: u64) -> u64 {
+    a + b
+}
+fn main() -> u64 {
+    var sum = 0u64
+    for i in 0u64..10u64 {
+        if i / 2u64 * 2u64 == i || i == 7u64 { sum = add(sum, i) } else { print(i) }
+    }
+    var j = 0u64
+    while j < 3u64 { j = j + 1u64 }
+    sum + j
+}
+"#;
+        assert_eq!(run(code, false), run(code, true));
+        assert_eq!(Object::UInt64(10), run(code, true));
+    }
+}
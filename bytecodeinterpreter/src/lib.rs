@@ -1,2 +1,10 @@
 pub mod compiler;
+pub mod dce;
+pub mod ir;
+pub mod loops;
+pub mod differential;
 pub mod processor;
+pub mod ssa;
+pub mod tbc;
+pub mod trace;
+pub mod verifier;
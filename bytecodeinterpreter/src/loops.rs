@@ -0,0 +1,309 @@
+use crate::ir::{BinOp, Function, Inst, Reg, Terminator, Value};
+use crate::ssa::dominators;
+use std::collections::{HashMap, HashSet};
+
+// Loop optimizations on the SSA form of the IR, run with the passes of
+// `ssa::optimize`:
+//   * loop-invariant code motion: an instruction of a loop whose operands
+//     are computed outside the loop moves to the block before the loop
+//   * unrolling: a loop of one block over `i < n` whose counter starts
+//     from a constant and goes up by 1 to a constant is replaced by the
+//     iterations one after another, if they are few and small
+// Both keep the order of the instructions which can fail or have effects.
+
+// Most iterations and instructions of an unrolled loop
+const UNROLL_ITERATIONS: u64 = 16;
+const UNROLL_INSTRUCTIONS: usize = 128;
+
+struct Loop {
+    header: usize,
+    blocks: HashSet<usize>,
+    preheader: usize, // the only block entering the loop, which jumps only to the header
+}
+
+// Natural loops with a preheader, the inner ones first
+fn loops(f: &Function) -> Vec<Loop> {
+    let order = f.reverse_postorder();
+    let predecessors = f.predecessors();
+    let idom = dominators(&order, &predecessors);
+    let dominates = |a: usize, mut b: usize| loop {
+        if a == b {
+            return true;
+        }
+        match idom[b] {
+            Some(d) if d != b => b = d,
+            _ => return false,
+        }
+    };
+    let mut found: HashMap<usize, HashSet<usize>> = HashMap::new();
+    for b in &order {
+        for h in f.blocks[*b].terminator.0.successors() {
+            if !dominates(h, *b) {
+                continue;
+            }
+            // the blocks reaching the back edge without going through the header
+            let blocks = found.entry(h).or_insert_with(|| HashSet::from([h]));
+            let mut work = vec![*b];
+            while let Some(x) = work.pop() {
+                if blocks.insert(x) {
+                    work.extend(predecessors[x].iter().copied());
+                }
+            }
+        }
+    }
+    let mut loops: Vec<Loop> = found.into_iter().filter_map(|(header, blocks)| {
+        let outside: Vec<usize> = predecessors[header].iter().filter(|p| !blocks.contains(p)).copied().collect();
+        match outside[..] {
+            [p] if f.blocks[p].terminator.0.successors() == [header] => Some(Loop { header, blocks, preheader: p }),
+            _ => None,
+        }
+    }).collect();
+    loops.sort_by_key(|l| (l.blocks.len(), l.header));
+    loops
+}
+
+// Whether the instruction can fail or has effects
+fn has_effect(inst: &Inst) -> bool {
+    match inst {
+        Inst::Const(..) | Inst::Copy(..) | Inst::Phi(..) => false,
+        Inst::Binary(_, op, _, _) => matches!(op, BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::Div),
+        Inst::Increment(..) | Inst::Call(..) | Inst::Print(_) => true,
+    }
+}
+
+// The instructions of the header before the first one which stays, and
+// the ones of the other blocks which neither fail nor have effects, are
+// moved if their operands are computed outside the loop. The header runs
+// each time the preheader does, so a moved instruction which can fail
+// fails at the same point as before.
+pub fn hoist_invariants(f: &mut Function) -> bool {
+    let mut changed = false;
+    for l in loops(f) {
+        let mut defined: HashSet<Reg> = HashSet::new();
+        for b in &l.blocks {
+            defined.extend(f.blocks[*b].insts.iter().filter_map(|(inst, _)| inst.def()));
+        }
+        let order: Vec<usize> = f.reverse_postorder().into_iter().filter(|b| l.blocks.contains(b)).collect();
+        let mut hoisted = vec![];
+        for b in order {
+            let mut in_order = b == l.header;
+            let insts = std::mem::take(&mut f.blocks[b].insts);
+            for (inst, origin) in insts {
+                let invariant = !matches!(inst, Inst::Phi(..) | Inst::Call(..) | Inst::Print(_))
+                    && inst.uses().iter().all(|r| !defined.contains(r))
+                    && (in_order || !has_effect(&inst));
+                if invariant {
+                    defined.remove(&inst.def().unwrap());
+                    hoisted.push((inst, origin));
+                } else {
+                    in_order &= !has_effect(&inst);
+                    f.blocks[b].insts.push((inst, origin));
+                }
+            }
+        }
+        changed |= !hoisted.is_empty();
+        f.blocks[l.preheader].insts.extend(hoisted);
+    }
+    changed
+}
+
+fn definitions(f: &Function) -> HashMap<Reg, Inst> {
+    f.blocks.iter()
+        .flat_map(|block| block.insts.iter())
+        .filter_map(|(inst, _)| inst.def().map(|d| (d, inst.clone())))
+        .collect()
+}
+
+// Number of iterations of the loop of the header `h` and the body `b`,
+// if the branch of the header is `i < n` where `i` goes from a constant
+// up by 1 in the body and `n` is a constant
+fn trip_count(f: &Function, definitions: &HashMap<Reg, Inst>, h: usize, b: usize) -> Option<u64> {
+    let c = match f.blocks[h].terminator.0 {
+        Terminator::Branch(c, then_b, _) if then_b == b => c,
+        _ => return None,
+    };
+    let (i, n) = match definitions.get(&c)? {
+        Inst::Binary(_, BinOp::Lt, i, n) => (*i, *n),
+        _ => return None,
+    };
+    let (start, step) = match f.blocks[h].insts.iter().find(|(inst, _)| inst.def() == Some(i))? {
+        (Inst::Phi(_, operands), _) if operands.len() == 2 => {
+            let start = operands.iter().find(|(p, _)| *p != b)?.1;
+            let step = operands.iter().find(|(p, _)| *p == b)?.1;
+            (start, step)
+        }
+        _ => return None,
+    };
+    if !f.blocks[b].insts.iter().any(|(inst, _)| *inst == Inst::Increment(step, i)) {
+        return None;
+    }
+    match (definitions.get(&start)?, definitions.get(&n)?) {
+        (Inst::Const(_, Value::Int64(start)), Inst::Const(_, Value::Int64(n))) =>
+            Some(if n > start { n.abs_diff(*start) } else { 0 }),
+        (Inst::Const(_, Value::UInt64(start)), Inst::Const(_, Value::UInt64(n))) =>
+            Some(n.saturating_sub(*start)),
+        _ => None,
+    }
+}
+
+// The iterations of a loop of one block with a known number of them run
+// one after another in the preheader: for each one, the instructions of
+// the header then the ones of the body, with new registers; then the
+// instructions of the header once more, as the loop ends in the header.
+pub fn unroll(f: &mut Function) -> bool {
+    let definitions = definitions(f);
+    let predecessors = f.predecessors();
+    for l in loops(f) {
+        let h = l.header;
+        let b = match l.blocks.iter().find(|b| **b != h) {
+            Some(b) if l.blocks.len() == 2 && predecessors[*b] == [h] => *b,
+            _ => continue,
+        };
+        let exit = match f.blocks[h].terminator.0 {
+            Terminator::Branch(_, _, exit) => exit,
+            _ => continue,
+        };
+        let iterations = match trip_count(f, &definitions, h, b) {
+            Some(n) => n,
+            None => continue,
+        };
+        let size = f.blocks[h].insts.len() + f.blocks[b].insts.len();
+        if iterations > UNROLL_ITERATIONS || (iterations as usize + 1) * size > UNROLL_INSTRUCTIONS {
+            continue;
+        }
+
+        let (header, body) = (f.blocks[h].clone(), f.blocks[b].clone());
+        let mut current: HashMap<Reg, Reg> = HashMap::new();
+        for (inst, _) in &header.insts {
+            if let Inst::Phi(d, operands) = inst {
+                current.insert(*d, operands.iter().find(|(p, _)| *p != b).unwrap().1);
+            }
+        }
+        let mut unrolled = vec![];
+        for iteration in 0..=iterations {
+            let mut blocks = vec![&header];
+            if iteration < iterations {
+                blocks.push(&body);
+            }
+            for (inst, origin) in blocks.into_iter().flat_map(|block| block.insts.iter()) {
+                if matches!(inst, Inst::Phi(..)) {
+                    continue;
+                }
+                let mut inst = inst.clone();
+                inst.map_uses(&mut |r| current.get(&r).copied().unwrap_or(r));
+                if let Some(d) = inst.def() {
+                    f.registers += 1;
+                    let new = Reg(f.registers - 1);
+                    current.insert(d, new);
+                    inst.map_def(&mut |_| new);
+                }
+                unrolled.push((inst, *origin));
+            }
+            // the values of the phis in the next iteration
+            let next: Vec<(Reg, Reg)> = header.insts.iter().filter_map(|(inst, _)| match inst {
+                Inst::Phi(d, operands) => {
+                    let r = operands.iter().find(|(p, _)| *p == b).unwrap().1;
+                    Some((*d, current.get(&r).copied().unwrap_or(r)))
+                }
+                _ => None,
+            }).collect();
+            if iteration < iterations {
+                current.extend(next);
+            }
+        }
+
+        f.blocks[l.preheader].insts.extend(unrolled);
+        f.blocks[l.preheader].terminator.0 = Terminator::Jump(exit);
+        for removed in [h, b] {
+            f.blocks[removed].insts.clear();
+            f.blocks[removed].terminator.0 = Terminator::Jump(removed);
+        }
+        // after the loop, the registers of the header are the ones of its last copy
+        for block in &mut f.blocks {
+            for (inst, _) in &mut block.insts {
+                inst.map_uses(&mut |r| current.get(&r).copied().unwrap_or(r));
+                if let Inst::Phi(_, operands) = inst {
+                    operands.iter_mut().filter(|(p, _)| *p == h).for_each(|(p, _)| *p = l.preheader);
+                }
+            }
+            block.terminator.0.map_uses(&mut |r| current.get(&r).copied().unwrap_or(r));
+        }
+        merge_blocks(f);
+        // the other loops changed, they are found again in the next round
+        return true;
+    }
+    false
+}
+
+// A block which is the only successor of its only predecessor is
+// appended to it, so that a loop around an unrolled one can have one block
+fn merge_blocks(f: &mut Function) {
+    loop {
+        let predecessors = f.predecessors();
+        let merged = f.reverse_postorder().into_iter().find_map(|p| match f.blocks[p].terminator.0 {
+            Terminator::Jump(s) if s != 0 && s != p && predecessors[s] == [p] => Some((p, s)),
+            _ => None,
+        });
+        let (p, s) = match merged {
+            Some(merged) => merged,
+            None => return,
+        };
+        let block = std::mem::take(&mut f.blocks[s].insts);
+        // a phi of a block with one predecessor is a copy
+        f.blocks[p].insts.extend(block.into_iter().map(|(inst, origin)| match inst {
+            Inst::Phi(d, operands) => (Inst::Copy(d, operands[0].1), origin),
+            inst => (inst, origin),
+        }));
+        f.blocks[p].terminator = f.blocks[s].terminator.clone();
+        f.blocks[s].terminator.0 = Terminator::Jump(s);
+        for block in &mut f.blocks {
+            for (inst, _) in &mut block.insts {
+                if let Inst::Phi(_, operands) = inst {
+                    operands.iter_mut().filter(|(q, _)| *q == s).for_each(|(q, _)| *q = p);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::differential::compare_ir;
+
+    fn optimized(code: &str) -> String {
+        let program = frontend::Parser::new(code).parse_program().unwrap();
+        let mut f = crate::ir::build_program(&program).remove(0);
+        crate::ssa::optimize(&mut f);
+        f.to_string()
+    }
+
+    #[test]
+    fn hoist_and_unroll() {
+        // the bound is computed once before the loop, the division stays in the header
+        let code = "fn f(n: u64, k: u64) -> u64 {\nvar i = 0u64\nwhile i < n * k / 2u64 { i = i + k }\ni\n}";
+        let text = optimized(code);
+        let loop_start = text.find("Lt").unwrap();
+        assert!(text.find("Mul").unwrap() < loop_start && text.find("Div").unwrap() < loop_start, "{}", text);
+
+        // an invariant comparison in the body moves, an addition which may overflow doesn't
+        let code = "fn f(n: u64, k: u64) -> u64 {\nvar i = 0u64\nvar b = 0u64\nwhile i < n { if n < k { b = b + 1u64 } else { b = b + (n + k) }\ni = i + 1u64 }\nb\n}";
+        let text = optimized(code);
+        let head = &text[..text.find("branch").unwrap()];
+        assert!(head.contains("Lt r0, r1"), "{}", text);
+        assert_eq!(1, text.matches("Add r0, r1").count() + text.matches("Add r1, r0").count(), "{}", text);
+
+        // the loop of 4 iterations is gone, and the one around it too
+        let code = "fn main() -> u64 {\nvar sum = 0u64\nfor j in 0u64..2u64 {\nfor i in 1u64..5u64 { sum = sum + i * j }\n}\nsum\n}";
+        let text = optimized(code);
+        assert!(!text.contains("branch"), "{}", text);
+        assert_eq!(8, text.matches("Mul").count());
+        assert_eq!(compare_ir(code, 0).unwrap().outcome, compare_ir(code, 2).unwrap().outcome);
+
+        // a loop without iterations keeps what its header computes, and a
+        // long loop is not unrolled
+        let code = "fn main() -> i64 {\nvar sum = 0i64\nfor i in 5i64..-5i64 { sum = sum + i }\nfor i in 0i64..100i64 { sum = sum + i }\nsum\n}";
+        let text = optimized(code);
+        assert_eq!(1, text.matches("branch").count(), "{}", text);
+        assert_eq!(compare_ir(code, 0).unwrap().outcome, compare_ir(code, 2).unwrap().outcome);
+    }
+}
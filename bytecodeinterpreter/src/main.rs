@@ -1,11 +1,185 @@
-#![feature(box_patterns)]
-
 use bytecodeinterpreter::compiler::*;
 use bytecodeinterpreter::processor::Processor;
-use frontend;
+use bytecodeinterpreter::trace::WriteSink;
+use frontend::doc::DocFormat;
+use frontend::optimizer::{ConstantCalls, PassManager};
+use frontend::prelude::Prelude;
+use frontend::type_checker::TypeCheckContext;
+use interpreter::cli::{self, Command, Failure, Phase};
+use interpreter::object::Object;
+use interpreter::timings::{self, Timings};
 use std::io::{self, Write};
 
+// Usage (see `interpreter::cli` for the commands):
+//   bytecodeinterpreter [repl]
+//   bytecodeinterpreter run [-O|-O2] [--ir] [--trace] [--timings] file.toy  compile the file and run `main`
+//   bytecodeinterpreter run [--trace] [--timings] file.tbc           run `main` of a compiled module
+//   bytecodeinterpreter disasm [-O|-O2] [--ir] file.toy|file.tbc
+//   bytecodeinterpreter check [--timings] file.toy
+//   bytecodeinterpreter ast file.toy
+//   bytecodeinterpreter fmt [--check] file.toy
+//   bytecodeinterpreter doc [--html] file.toy
+//   bytecodeinterpreter --explain code
+// --trace writes each executed instruction to stderr
+// --timings prints the time spent in each phase to stderr
+// --ir compiles through the mid-level IR (`bytecodeinterpreter::ir`)
+// -O2 also runs the AST passes of `frontend::optimizer` before the compiler,
+// after replacing the calls of `const fn`s by their values
+// --cfg=feature enables a feature for `#[cfg]`, with any command
+// Of the prelude (see `frontend::prelude`) only `print` is supported: the
+// VM has no `assert`, `dbg`, `len`, `to_i64` or `to_u64`, and `check` and
+// `run` report a call of them as an undefined function (E0003).
 fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args = cli::parse_args(&args).unwrap_or_else(|e| usage(e));
+    let mut option = RunOption { features: args.features.clone(), ..RunOption::default() };
+    for arg in &args.options {
+        match (arg.as_str(), args.command) {
+            ("-O", Command::Run | Command::Disasm) => option.opt_level = 1,
+            ("-O2", Command::Run | Command::Disasm) => option.opt_level = 2,
+            ("--ir", Command::Run | Command::Disasm) => option.ir = true,
+            ("--trace", Command::Run) => option.trace = true,
+            ("--timings", Command::Run | Command::Check) => option.timings = Some(Timings::new()),
+            ("--check", Command::Fmt) => option.check = true,
+            ("--html", Command::Doc) => option.doc_format = DocFormat::Html,
+            _ => usage(Failure::new(Phase::Usage, format!("unknown option {}", arg))),
+        }
+    }
+    if let Some(timings) = &option.timings {
+        timings.install().unwrap_or_else(|e| eprintln!("--timings is not available: {}", e));
+    }
+    let file = args.file.unwrap_or_default();
+    let result = match args.command {
+        Command::Repl => {
+            repl();
+            Ok(Object::Unit)
+        }
+        Command::Run => run_file(&file, &option),
+        Command::Check => check_file(&file, &option).map(|_| Object::Unit),
+        Command::Ast => check_file(&file, &option).map(|program| {
+            print!("{}", cli::ast(&program));
+            Object::Unit
+        }),
+        Command::Disasm => load_module(&file, &option).map(|module| {
+            for f in &module.functions {
+                println!("fn {} (arity {})", f.name, f.arity);
+                print!("{}", disassemble(&f.codes));
+            }
+            Object::Unit
+        }),
+        Command::Explain => cli::explain(&file).map(|text| {
+            print!("{}", text);
+            Object::Unit
+        }),
+        Command::Doc => cli::doc(&file, option.doc_format, &mut TypeCheckContext::new()).map(|doc| {
+            print!("{}", doc);
+            Object::Unit
+        }),
+        Command::Test => Err(Failure::new(Phase::Usage, "test is available in interpreter")),
+        // only `--check` tells by the exit status whether the file was formatted
+        Command::Fmt => cli::fmt(&file, option.check).map(|formatted| if option.check { Object::Bool(formatted) } else { Object::Unit }),
+    };
+    if let Some(timings) = &option.timings {
+        eprint!("{}", timings);
+    }
+    match result {
+        // the result of `main` is the exit status of the process, as the interpreter does
+        Ok(result) => std::process::exit(result.to_exit_code()),
+        Err(e) => e.exit(),
+    }
+}
+
+fn usage(failure: Failure) -> ! {
+    eprintln!("usage: bytecodeinterpreter [repl | run [-O|-O2] [--ir] [--trace] [--timings] file | disasm [-O|-O2] [--ir] file | check [--timings] file | ast file | fmt [--check] file | doc [--html] file | --explain code]");
+    failure.exit()
+}
+
+struct RunOption {
+    opt_level: u8,
+    ir: bool,
+    trace: bool,
+    check: bool,
+    doc_format: DocFormat,
+    timings: Option<Timings>,
+    features: Vec<String>, // of `--cfg`
+}
+
+impl Default for RunOption {
+    fn default() -> Self {
+        RunOption { opt_level: 0, ir: false, trace: false, check: false, doc_format: DocFormat::Markdown, timings: None, features: vec![] }
+    }
+}
+
+// Type checker with the features of the options and the prelude of the VM,
+// which is `print` only
+fn context(option: &RunOption) -> TypeCheckContext {
+    let mut ctx = TypeCheckContext::new();
+    let mut prelude = Prelude::new();
+    prelude.retain(|name| name == "print");
+    ctx.set_prelude(prelude);
+    for feature in &option.features {
+        ctx.set_feature(feature);
+    }
+    ctx
+}
+
+fn check_file(file: &str, option: &RunOption) -> Result<frontend::ast::Program, Failure> {
+    load_source(file, &mut context(option)).map(|(_, program)| program)
+}
+
+fn load_source(file: &str, ctx: &mut TypeCheckContext) -> Result<(String, frontend::ast::Program), Failure> {
+    if file.ends_with(".tbc") {
+        return Err(Failure::new(Phase::Usage, format!("{} is not a source file", file)));
+    }
+    cli::load(file, ctx)
+}
+
+fn load_module(file: &str, option: &RunOption) -> Result<Module, Failure> {
+    if file.ends_with(".tbc") {
+        return Module::load(file).map_err(|e| Failure::new(Phase::Read, format!("cannot load {}: {}", file, e)));
+    }
+    let mut ctx = context(option);
+    let (source, mut program) = load_source(file, &mut ctx)?;
+    let _span = tracing::info_span!(timings::COMPILE).entered();
+    check_supported(&program).map_err(|e| Failure::new(Phase::Compile, e))?;
+    if option.opt_level >= 2 {
+        // the calls of `const fn`s become literals, which the other passes fold
+        let mut manager = PassManager::new();
+        manager.add(Box::new(ConstantCalls::new(ctx.constants().clone())));
+        manager.run(&mut program);
+        PassManager::standard().run(&mut program);
+    }
+    let mut compiler = Compiler::new();
+    compiler.set_opt_level(option.opt_level);
+    compiler.set_ir(option.ir);
+    compiler.set_source(&source);
+    Ok(compiler.compile_program(&program))
+}
+
+fn run_file(file: &str, option: &RunOption) -> Result<Object, Failure> {
+    let module = load_module(file, option)?;
+    let mut p = Processor::new();
+    if option.trace {
+        p.set_trace(Some(Box::new(WriteSink::new(io::stderr()))));
+    }
+    let result = match tracing::info_span!(timings::EXECUTE).in_scope(|| p.run_module(&module)) {
+        Ok(result) => result,
+        Err(e) => {
+            // the location is known if the module is compiled with the source
+            let at = e.pc().and_then(|pc| p.location(pc)).map_or(String::new(), |location| format!(" at {}:{}", file, location));
+            return Err(Failure::new(Phase::Run, format!("run_module failed {:?}{}", e, at)));
+        }
+    };
+    match result.to_value() {
+        Some(result) => {
+            println!("Result: {}", result);
+            Ok(result)
+        }
+        None => Err(Failure::new(Phase::Run, format!("main returned {:?}", result))),
+    }
+}
+
+fn repl() {
     let mut compiler = Compiler::new();
     let mut interpreter = Processor::new();
 
@@ -17,17 +191,50 @@ fn main() {
         io::stdin()
             .read_line(&mut line)
             .expect("Failed to read line `read_line`");
+        if let Some(command) = line.trim().strip_prefix(':') {
+            meta_command(command, &compiler);
+            continue;
+        }
 
         let mut parser = frontend::Parser::new(line.as_str());
-        let expr = parser.parse_expr();
-        if expr.is_err() {
-            println!("parser_expr failed {}", expr.unwrap_err());
-            return;
+        let (expr, pool) = match parser.parse_expression() {
+            Ok(expr) => expr,
+            Err(e) => {
+                println!("parse_expression failed {}", e);
+                return;
+            }
+        };
+        let codes: Vec<BCode> = compiler.compile(&pool, expr);
+        if let Err(e) = interpreter.append(codes) {
+            println!("evaluate failed {:?}", e);
+            continue;
         }
-        let expr = expr.unwrap();
-        let codes: Vec<BCode> = compiler.compile(&expr).clone();
-        interpreter.append(codes);
-        interpreter.evaluate();
         println!("Evaluate expression: {:?}", interpreter);
     }
 }
+
+// REPL commands starting with `:`. They don't run anything.
+//   :disasm expr  bytecode of the expression, with the variables defined so far
+//   :ast expr     tree of the expression
+fn meta_command(command: &str, compiler: &Compiler) {
+    let (name, arg) = command.split_once(' ').unwrap_or((command, ""));
+    match name {
+        "disasm" | "ast" => {
+            let (expr, pool) = match frontend::Parser::new(arg).parse_expression() {
+                Ok(expr) => expr,
+                Err(e) => {
+                    println!("parse_expression failed {}", e);
+                    return;
+                }
+            };
+            if name == "ast" {
+                print!("{}", frontend::ast::dump(&pool, expr));
+            } else {
+                // compiled by a copy, so the ids of new variables are not kept
+                print!("{}", disassemble(&compiler.clone().compile(&pool, expr)));
+            }
+        }
+        "type" | "env" => println!(":{} is available in the interpreter REPL", name),
+        _ => println!("unknown command :{} (available: :disasm, :ast)", name),
+    }
+}
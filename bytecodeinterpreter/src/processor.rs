@@ -1,21 +1,93 @@
 use crate::compiler::*;
+use crate::trace::{State, TraceSink};
+use crate::verifier::{self, VerifyError};
+use interpreter::cancel::CancellationToken;
+use interpreter::overflow::{self, ArithOp, OverflowMode};
 use std::collections::HashMap;
 
+// The cancellation token is checked once per this number of instructions
+const CANCELLATION_CHECK_INTERVAL: u64 = 1024;
+
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Object {
     UInt64(u64),
     Int64(i64),
+    Bool(bool),
     Ident(u32),
     Null,
 }
 
+impl Object {
+    // The same value as an object of the tree walking interpreter.
+    // A function without value returns null, which is unit there.
+    pub fn to_value(&self) -> Option<interpreter::object::Object> {
+        match *self {
+            Object::UInt64(u) => Some(interpreter::object::Object::UInt64(u)),
+            Object::Int64(i) => Some(interpreter::object::Object::Int64(i)),
+            Object::Bool(b) => Some(interpreter::object::Object::Bool(b)),
+            Object::Null => Some(interpreter::object::Object::Unit),
+            Object::Ident(_) => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ProcessorError {
+    // instruction budget set by `set_fuel` is used up.
+    // The processor stops at the next instruction and can be resumed by `evaluate`.
+    FuelExhausted,
+    // stopped by `CancellationToken`, resumable in the same way
+    Cancelled,
+    // `pc` is the index of the BINARY_DIV instruction
+    DivisionByZero { pc: usize },
+    // integer overflow in `OverflowMode::Trap` at the arithmetic instruction `pc`
+    Overflow { pc: usize },
+    // the entry function is not in the module
+    UndefinedFunction(String),
+    // the module is rejected by the verifier before running
+    Verify(VerifyError),
+}
+
+impl ProcessorError {
+    // position of the instruction which failed, see `Processor::location`
+    pub fn pc(&self) -> Option<usize> {
+        match self {
+            ProcessorError::DivisionByZero { pc } | ProcessorError::Overflow { pc } => Some(*pc),
+            _ => None,
+        }
+    }
+}
+
+// Saved state of the caller while a function is running
+#[derive(Debug)]
+struct Frame {
+    return_pc: usize,
+    var: HashMap<u32, Object>,
+    val: HashMap<u32, Object>,
+}
+
 #[derive(Debug)]
 pub struct Processor {
     program: Vec<BCode>,
+    locations: Vec<Option<SourceLocation>>, // source of each instruction of the program
     stack: Vec<Object>,
     var: HashMap<u32, Object>,
     val: HashMap<u32, Object>,
     pos: usize,
+    fuel: Option<u64>, // remaining instructions, unlimited if None
+    executed: u64,     // number of executed instructions
+    cancellation: Option<CancellationToken>,
+    overflow: OverflowMode,
+    constants: Vec<Object>, // constant pool of the loaded module
+    functions: Vec<(usize, u32)>, // entry position and arity of the functions of the module
+    frames: Vec<Frame>,
+    trace: Option<Box<dyn TraceSink>>, // receives each executed instruction if set
+}
+
+impl Default for Processor {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 // Stack machine interpreter
@@ -23,119 +95,596 @@ impl Processor {
     pub fn new() -> Self {
         Processor {
             program: Vec::new(),
+            locations: Vec::new(),
             stack: Vec::new(),
             var: HashMap::new(),
             val: HashMap::new(),
             pos: 0,
+            fuel: None,
+            executed: 0,
+            cancellation: None,
+            overflow: OverflowMode::default(),
+            constants: Vec::new(),
+            functions: Vec::new(),
+            frames: Vec::new(),
+            trace: None,
         }
     }
 
-    pub fn append(&mut self, mut codes: Vec<BCode>) -> u64 {
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
+    }
+
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    pub fn set_cancellation(&mut self, token: Option<CancellationToken>) {
+        self.cancellation = token;
+    }
+
+    // The constant pool referred by PUSH_LITERAL
+    pub fn set_constants(&mut self, constants: &[Constant]) {
+        self.constants = constants.iter().map(|c| match *c {
+            Constant::Int64(i) => Object::Int64(i),
+            Constant::UInt64(u) => Object::UInt64(u),
+        }).collect();
+    }
+
+    // Run `main` of the module and return its result. The code of all
+    // functions is placed in one program and CALL jumps to the entry.
+    // Variables and constants (including arguments) are local to each call.
+    // The module is verified first, so a broken module does not start.
+    pub fn run_module(&mut self, module: &Module) -> Result<Object, ProcessorError> {
+        verifier::verify(module).map_err(ProcessorError::Verify)?;
+        let main = match module.index("main") {
+            Some(main) => main as usize,
+            None => return Err(ProcessorError::UndefinedFunction("main".to_string())),
+        };
+        self.set_constants(&module.constants);
+        self.program.clear();
+        self.locations.clear();
+        self.functions.clear();
+        for f in &module.functions {
+            self.functions.push((self.program.len(), f.arity));
+            self.program.extend_from_slice(&f.codes);
+            self.locations.extend((0..f.codes.len()).map(|pc| f.location(pc)));
+        }
+        self.stack.clear();
+        self.frames.clear();
+        self.var.clear();
+        self.val.clear();
+        self.pos = self.functions[main].0;
+        self.evaluate()?;
+        Ok(self.stack.pop().unwrap_or(Object::Null))
+    }
+
+    pub fn set_overflow_mode(&mut self, mode: OverflowMode) {
+        self.overflow = mode;
+    }
+
+    pub fn overflow_mode(&self) -> OverflowMode {
+        self.overflow
+    }
+
+    // Trace mode for debugging the compiler: `sink` gets each executed
+    // instruction with the stack and registers before and after it
+    pub fn set_trace(&mut self, sink: Option<Box<dyn TraceSink>>) {
+        self.trace = sink;
+    }
+
+    fn state(&self) -> State {
+        State::of(&self.stack, &self.var, &self.val)
+    }
+
+    pub fn executed(&self) -> u64 {
+        self.executed
+    }
+
+    // Source of the instruction at `pc` of the program (e.g. of an error),
+    // known if the module is compiled with the source
+    pub fn location(&self, pc: usize) -> Option<SourceLocation> {
+        self.locations.get(pc).copied().flatten()
+    }
+
+    pub fn append(&mut self, mut codes: Vec<BCode>) -> Result<u64, ProcessorError> {
+        self.locations.resize(self.program.len() + codes.len(), None);
         self.program.append(&mut codes);
-        return self.evaluate();
+        self.evaluate()
     }
 
-    pub fn evaluate(&mut self) -> u64 {
+    pub fn evaluate(&mut self) -> Result<u64, ProcessorError> {
         let mut i = self.pos;
         let plen = self.program.len();
         loop {
             if i >= plen {
                 break;
             }
-            let code: &BCode = &self.program[i];
-            match code {
-                BCode::NOP => i += 1,
-                BCode::PUSH_NULL => {
-                    self.stack.push(Object::Null);
-                    i += 1;
+            if let Some(fuel) = self.fuel {
+                if fuel == 0 {
+                    self.pos = i;
+                    return Err(ProcessorError::FuelExhausted);
                 }
-                BCode::PUSH_INT(int) => {
-                    self.stack.push(Object::Int64(*int));
-                    i += 1;
+                self.fuel = Some(fuel - 1);
+            }
+            if self.executed.is_multiple_of(CANCELLATION_CHECK_INTERVAL) {
+                if let Some(token) = &self.cancellation {
+                    if token.is_cancelled() {
+                        self.pos = i;
+                        return Err(ProcessorError::Cancelled);
+                    }
                 }
-                BCode::PUSH_UINT(u) => {
-                    self.stack.push(Object::UInt64(*u));
-                    i += 1;
+            }
+            self.executed += 1;
+            let before = self.trace.is_some().then(|| self.state());
+            let next = self.step(i, plen);
+            if let (Some(before), Some(sink)) = (before, &mut self.trace) {
+                let after = State::of(&self.stack, &self.var, &self.val);
+                sink.on_instruction(i, &self.program[i], self.locations.get(i).copied().flatten(), &before, &after);
+            }
+            match next {
+                Ok(next) => i = next,
+                Err(e) => {
+                    self.pos = i;
+                    return Err(e);
                 }
-                BCode::PUSH_CONST(id) => {
-                    let top = self.stack.pop().unwrap();
-                    self.val.insert(*id, top);
-                    i += 1;
+            }
+        }
+
+        self.pos = i;
+        Ok(0)
+    }
+
+    // Execute the instruction at `i` and return the position of the next one
+    fn step(&mut self, mut i: usize, plen: usize) -> Result<usize, ProcessorError> {
+        let code: &BCode = &self.program[i];
+        match code {
+            BCode::NOP => i += 1,
+            BCode::POP => {
+                self.stack.pop();
+                i += 1;
+            }
+            BCode::PUSH_NULL => {
+                self.stack.push(Object::Null);
+                i += 1;
+            }
+            BCode::PUSH_INT(int) => {
+                self.stack.push(Object::Int64(*int));
+                i += 1;
+            }
+            BCode::PUSH_UINT(u) => {
+                self.stack.push(Object::UInt64(*u));
+                i += 1;
+            }
+            BCode::PUSH_LITERAL(index) => {
+                match self.constants.get(*index as usize) {
+                    Some(c) => self.stack.push(*c),
+                    None => panic!("PUSH_LITERAL: constant {} is not defined", index),
                 }
-                BCode::LOAD_IDENT(id) => {
-                    let value = self.stack.pop().unwrap();
-                    self.var.insert(*id, value);
-                    i += 1;
+                i += 1;
+            }
+            BCode::PUSH_BOOL(b) => {
+                self.stack.push(Object::Bool(*b));
+                i += 1;
+            }
+            BCode::PUSH_CONST(id) => {
+                let top = self.stack.pop().unwrap();
+                self.val.insert(*id, top);
+                i += 1;
+            }
+            BCode::LOAD_IDENT(id) => {
+                let value = self.stack.pop().unwrap();
+                self.var.insert(*id, value);
+                i += 1;
+            }
+            BCode::LOAD_CONST(id) => {
+                let value = self.stack.pop().unwrap();
+                self.val.insert(*id, value);
+                i += 1;
+            }
+            BCode::LOAD_IDENT_VAR(id) => {
+                let v = self.var.get(id);
+                match v {
+                    Some(v) => self.stack.push(*v),
+                    _ => panic!("LOAD IDENT var"),
+                };
+                i += 1;
+            }
+            BCode::LOAD_IDENT_CONST(id) => {
+                let v = self.val.get(id);
+                match v {
+                    Some(v) => self.stack.push(*v),
+                    _ => panic!("LOAD IDENT val"),
+                };
+                i += 1;
+            }
+
+            BCode::PRINT0 => {
+                let top = self.stack.pop();
+                match top {
+                    Some(Object::UInt64(u)) => println!("{} (u64)", u),
+                    Some(Object::Int64(int)) => println!("{} (i64)", int),
+                    Some(Object::Bool(b)) => println!("{} (bool)", b),
+                    Some(Object::Null) => println!("()"),
+                    Some(Object::Ident(id)) => {
+                        // TODO: identify id for const(val) or variable
+                        let val = self.val.get(&id);
+                        match val {
+                            Some(Object::UInt64(u)) => println!("val {} (u64)", u),
+                            Some(Object::Int64(int)) => println!("val {} (i64)", int),
+                            Some(Object::Null) => println!("Null"),
+                            x => println!("{:?} const", x),
+                        }
+                    }
+                    x => todo!("PRINT (not implemented yet) : {:?}", x),
                 }
-                BCode::LOAD_CONST(id) => {
-                    let value = self.stack.pop().unwrap();
-                    self.val.insert(*id, value);
-                    i += 1;
+                i += 1;
+            }
+
+            BCode::BINARY_ADD | BCode::BINARY_SUB | BCode::BINARY_MUL | BCode::BINARY_DIV => {
+                let op = *code;
+                // rhs is on the top of the stack
+                let rhs = self.stack.pop();
+                let lhs = self.stack.pop();
+                if lhs.is_none() || rhs.is_none() {
+                    panic!("{:?}: Stack is empty", op)
                 }
-                BCode::LOAD_IDENT_VAR(id) => {
-                    let v = self.var.get(&id);
-                    match v {
-                        Some(v) => self.stack.push(*v),
-                        _ => panic!("LOAD IDENT var"),
-                    };
+                let arith = match op {
+                    BCode::BINARY_ADD => ArithOp::Add,
+                    BCode::BINARY_SUB => ArithOp::Sub,
+                    BCode::BINARY_MUL => ArithOp::Mul,
+                    _ => ArithOp::Div,
+                };
+                let result = self.arith(arith, lhs.unwrap(), rhs.unwrap(), i)?;
+                self.stack.push(result);
+                i += 1;
+            }
+            BCode::ADD_VAR(id) | BCode::ADD_CONST(id) => {
+                let rhs = match code {
+                    BCode::ADD_VAR(_) => self.var.get(id),
+                    _ => self.val.get(id),
+                };
+                let (lhs, rhs) = match (self.stack.pop(), rhs) {
+                    (Some(lhs), Some(rhs)) => (lhs, *rhs),
+                    _ => panic!("{:?}: Stack is empty or undefined identifier", code),
+                };
+                let result = self.arith(ArithOp::Add, lhs, rhs, i)?;
+                self.stack.push(result);
+                i += 1;
+            }
+            BCode::INCREMENT_VAR(id) => {
+                let value = match self.var.get(id) {
+                    Some(Object::UInt64(u)) => Object::UInt64(u + 1),
+                    Some(Object::Int64(int)) => Object::Int64(int + 1),
+                    x => panic!("INCREMENT_VAR: expected integer but {:?}", x),
+                };
+                self.var.insert(*id, value);
+                i += 1;
+            }
+            BCode::JUMP_IF_NOT_LT(offset) => {
+                let rhs = self.stack.pop();
+                let lhs = self.stack.pop();
+                let less = match (lhs, rhs) {
+                    (Some(Object::UInt64(l)), Some(Object::UInt64(r))) => l < r,
+                    (Some(Object::Int64(l)), Some(Object::Int64(r))) => l < r,
+                    (l, r) => panic!("JUMP_IF_NOT_LT: invalid operands {:?} {:?}", l, r),
+                };
+                if less {
                     i += 1;
+                } else {
+                    i = (i as i64 + *offset as i64) as usize;
                 }
-                BCode::LOAD_IDENT_CONST(id) => {
-                    let v = self.val.get(&id);
-                    match v {
-                        Some(v) => self.stack.push(*v),
-                        _ => panic!("LOAD IDENT val"),
-                    };
-                    i += 1;
+            }
+            BCode::BINARY_EQ | BCode::BINARY_NE | BCode::BINARY_LT |
+            BCode::BINARY_LE | BCode::BINARY_GT | BCode::BINARY_GE => {
+                let op = *code;
+                let rhs = self.stack.pop();
+                let lhs = self.stack.pop();
+                let result = match (lhs, rhs) {
+                    (Some(Object::UInt64(l)), Some(Object::UInt64(r))) => Self::compare(op, l, r),
+                    (Some(Object::Int64(l)), Some(Object::Int64(r))) => Self::compare(op, l, r),
+                    (Some(Object::Bool(l)), Some(Object::Bool(r)))
+                        if op == BCode::BINARY_EQ || op == BCode::BINARY_NE => Self::compare(op, l, r),
+                    (l, r) => panic!("{:?}: invalid operands {:?} {:?}", op, l, r),
+                };
+                self.stack.push(Object::Bool(result));
+                i += 1;
+            }
+            BCode::INCREMENT => {
+                let top = match self.stack.pop() {
+                    Some(Object::UInt64(u)) => Object::UInt64(u + 1),
+                    Some(Object::Int64(int)) => Object::Int64(int + 1),
+                    x => panic!("INCREMENT: expected integer but {:?}", x),
+                };
+                self.stack.push(top);
+                i += 1;
+            }
+            BCode::CALL(index) => {
+                let (entry, arity) = match self.functions.get(*index as usize) {
+                    Some(f) => *f,
+                    None => panic!("CALL: function {} is not defined", index),
+                };
+                if self.stack.len() < arity as usize {
+                    panic!("CALL: Stack is empty")
                 }
-
-                BCode::PRINT0 => {
-                    let top = self.stack.pop();
-                    match top {
-                        Some(Object::UInt64(u)) => println!("{} (u64)", u),
-                        Some(Object::Int64(int)) => println!("{} (i64)", int),
-                        Some(Object::Ident(id)) => {
-                            // TODO: identify id for const(val) or variable
-                            let val = self.val.get(&id);
-                            match val {
-                                Some(Object::UInt64(u)) => println!("val {} (u64)", u),
-                                Some(Object::Int64(int)) => println!("val {} (i64)", int),
-                                Some(Object::Null) => println!("Null"),
-                                x => println!("{:?} const", x),
-                            }
-                        }
-                        x => todo!("PRINT (not implemented yet) : {:?}", x),
+                let args = self.stack.split_off(self.stack.len() - arity as usize);
+                self.frames.push(Frame {
+                    return_pc: i + 1,
+                    var: std::mem::take(&mut self.var),
+                    val: std::mem::take(&mut self.val),
+                });
+                self.val = args.into_iter().enumerate().map(|(id, arg)| (id as u32, arg)).collect();
+                i = entry;
+            }
+            BCode::RET => {
+                match self.frames.pop() {
+                    Some(frame) => {
+                        self.var = frame.var;
+                        self.val = frame.val;
+                        i = frame.return_pc;
                     }
-                    i += 1;
+                    // return from the entry function, the result is left on the stack
+                    None => i = plen,
                 }
-
-                BCode::BINARY_ADD => {
-                    let lhs = self.stack.pop();
-                    let rhs = self.stack.pop();
-                    if lhs.is_none() || rhs.is_none() {
-                        panic!("BINARY_ADD: Stack is empty")
-                    }
-                    match (lhs.unwrap(), rhs.unwrap()) {
-                        (Object::UInt64(lhs), Object::UInt64(rhs)) => {
-                            self.stack.push(Object::UInt64(lhs + rhs));
-                            i += 1;
-                        }
-                        (Object::Int64(lhs), Object::Int64(rhs)) => {
-                            self.stack.push(Object::Int64(lhs + rhs));
-                            i += 1;
-                        }
-                        _ => panic!("Binary ADD operator found non integer object"),
-                    }
+            }
+            BCode::JUMP(offset) => {
+                i = (i as i64 + *offset as i64) as usize;
+            }
+            BCode::JUMP_IF_FALSE(offset) => {
+                match self.stack.pop() {
+                    Some(Object::Bool(true)) => i += 1,
+                    Some(Object::Bool(false)) => i = (i as i64 + *offset as i64) as usize,
+                    x => panic!("JUMP_IF_FALSE: expected bool but {:?}", x),
                 }
-                x => {
-                    panic!("not implemented yet: {:?}", x)
-                } //BCode::BINARY_SUB => {}
-                  //BCode::BINARY_MUL => {}
-                  //BCode::BINARY_DIV => {}
+            }
+            x => {
+                panic!("not implemented yet: {:?}", x)
             }
         }
+        Ok(i)
+    }
 
-        self.pos = i;
-        return 0;
+    // `pc` is for the error
+    fn arith(&self, op: ArithOp, lhs: Object, rhs: Object, pc: usize) -> Result<Object, ProcessorError> {
+        let result = match (lhs, rhs) {
+            (Object::UInt64(_), Object::UInt64(0)) | (Object::Int64(_), Object::Int64(0)) if op == ArithOp::Div =>
+                return Err(ProcessorError::DivisionByZero { pc }),
+            (Object::UInt64(lhs), Object::UInt64(rhs)) =>
+                overflow::apply(self.overflow, op, lhs, rhs).map(Object::UInt64),
+            (Object::Int64(lhs), Object::Int64(rhs)) =>
+                overflow::apply(self.overflow, op, lhs, rhs).map(Object::Int64),
+            (lhs, rhs) => panic!("{:?} operator found non integer object {:?} {:?}", op, lhs, rhs),
+        };
+        result.ok_or(ProcessorError::Overflow { pc })
+    }
+
+    fn compare<T: PartialOrd>(op: BCode, l: T, r: T) -> bool {
+        match op {
+            BCode::BINARY_EQ => l == r,
+            BCode::BINARY_NE => l != r,
+            BCode::BINARY_LT => l < r,
+            BCode::BINARY_LE => l <= r,
+            BCode::BINARY_GT => l > r,
+            _ => l >= r,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evaluate_with_fuel() {
+        let mut p = Processor::new();
+        p.set_fuel(Some(2));
+        let codes = vec![BCode::PUSH_UINT(1), BCode::PUSH_UINT(2), BCode::BINARY_ADD, BCode::LOAD_IDENT(0)];
+        assert_eq!(Err(ProcessorError::FuelExhausted), p.append(codes));
+        assert_eq!(2, p.executed());
+
+        // resume from the stopped instruction
+        p.set_fuel(Some(10));
+        assert_eq!(Ok(0), p.evaluate());
+        assert_eq!(4, p.executed());
+        assert_eq!(Some(&Object::UInt64(3)), p.var.get(&0));
+    }
+
+    #[test]
+    fn evaluate_cancelled() {
+        let mut p = Processor::new();
+        let token = CancellationToken::new();
+        p.set_cancellation(Some(token.clone()));
+        token.cancel();
+        assert_eq!(Err(ProcessorError::Cancelled), p.append(vec![BCode::PUSH_UINT(1), BCode::LOAD_IDENT(0)]));
+        assert_eq!(0, p.executed());
+    }
+
+    #[test]
+    fn evaluate_arithmetic() {
+        let mut p = Processor::new();
+        let codes = vec![BCode::PUSH_INT(7), BCode::PUSH_INT(2), BCode::BINARY_SUB, BCode::PUSH_INT(3), BCode::BINARY_MUL,
+                         BCode::PUSH_INT(4), BCode::BINARY_DIV, BCode::LOAD_IDENT(0)];
+        assert_eq!(Ok(0), p.append(codes));
+        assert_eq!(Some(&Object::Int64(3)), p.var.get(&0));
+    }
+
+    #[test]
+    fn evaluate_division_by_zero() {
+        let mut p = Processor::new();
+        let codes = vec![BCode::PUSH_UINT(1), BCode::PUSH_UINT(0), BCode::BINARY_DIV];
+        assert_eq!(Err(ProcessorError::DivisionByZero { pc: 2 }), p.append(codes));
+    }
+
+    #[test]
+    fn evaluate_overflow_mode() {
+        let codes = || vec![BCode::PUSH_INT(i64::MAX), BCode::PUSH_INT(1), BCode::BINARY_ADD, BCode::LOAD_IDENT(0)];
+
+        let mut p = Processor::new();
+        assert_eq!(Err(ProcessorError::Overflow { pc: 2 }), p.append(codes()));
+
+        let mut p = Processor::new();
+        p.set_overflow_mode(OverflowMode::Wrap);
+        assert_eq!(Ok(0), p.append(codes()));
+        assert_eq!(Some(&Object::Int64(i64::MIN)), p.var.get(&0));
+
+        let mut p = Processor::new();
+        p.set_overflow_mode(OverflowMode::Saturate);
+        assert_eq!(Ok(0), p.append(codes()));
+        assert_eq!(Some(&Object::Int64(i64::MAX)), p.var.get(&0));
+
+        let mut p = Processor::new();
+        p.set_overflow_mode(OverflowMode::Saturate);
+        let codes = vec![BCode::PUSH_UINT(0), BCode::PUSH_UINT(1), BCode::BINARY_SUB, BCode::LOAD_IDENT(0)];
+        assert_eq!(Ok(0), p.append(codes));
+        assert_eq!(Some(&Object::UInt64(0)), p.var.get(&0));
+    }
+
+    // compile and run each line like the REPL
+    fn run(lines: &[&str]) -> Processor {
+        let mut compiler = Compiler::new();
+        let mut p = Processor::new();
+        for line in lines {
+            let (e, pool) = frontend::Parser::new(line).parse_expression().unwrap();
+            assert_eq!(Ok(0), p.append(compiler.compile(&pool, e)));
+        }
+        p
+    }
+
+    #[test]
+    fn evaluate_branch() {
+        let p = run(&["var a = 0u64",
+            "if 1u64 < 2u64 && 3u64 > 4u64 { a = 10u64 } else if 1u64 == 1u64 || a / 0u64 > 0u64 { a = 20u64 } else { a = 30u64 }"]);
+        assert_eq!(Some(&Object::UInt64(20)), p.var.get(&0));
+
+        let p = run(&["if 1i64 >= 2i64 { 1i64 } else { 2i64 }"]);
+        assert_eq!(vec![Object::Int64(2)], p.stack);
+
+        // without else, the value of the then block is discarded
+        let p = run(&["var a = 0i64", "if a <= 0i64 { a = 5i64\n a }"]);
+        assert_eq!(Some(&Object::Int64(5)), p.var.get(&0));
+        assert!(p.stack.is_empty());
+    }
+
+    #[test]
+    fn evaluate_loop() {
+        let p = run(&["var n = 0u64", "while n < 10u64 { n = n + 3u64 }"]);
+        assert_eq!(Some(&Object::UInt64(12)), p.var.get(&0));
+
+        let p = run(&["var sum = 0i64", "for i in -2i64..3i64 { sum = sum + i * i }"]);
+        assert_eq!(Some(&Object::Int64(10)), p.var.get(&0));
+        assert!(p.stack.is_empty());
+
+        let p = run(&["var sum = 0u64", "for i in 0u64..3u64 { for j in i..3u64 { sum = sum + 1u64 } }"]);
+        assert_eq!(Some(&Object::UInt64(6)), p.var.get(&0));
+    }
+
+    #[test]
+    fn evaluate_literal() {
+        let mut p = Processor::new();
+        p.set_constants(&[Constant::Int64(-100000), Constant::UInt64(1 << 40)]);
+        let codes = vec![BCode::PUSH_LITERAL(1), BCode::LOAD_IDENT(0), BCode::PUSH_LITERAL(0), BCode::LOAD_IDENT(1)];
+        assert_eq!(Ok(0), p.append(codes));
+        assert_eq!(Some(&Object::UInt64(1 << 40)), p.var.get(&0));
+        assert_eq!(Some(&Object::Int64(-100000)), p.var.get(&1));
+    }
+
+    #[test]
+    fn run_module_with_calls() {
+        let code = r#"
+fn fib(n: u64) -> u64 {
+    if n < 2u64 { n } else { fib(n - 1u64) + fib(n - 2u64) }
+}
+fn sum(n: i64) -> i64 {
+    var s = 0i64
+    for i in 0i64..n {
+        s = s + i * 1000000i64
+    }
+    s
+}
+fn main() -> u64 {
+    val a = fib(15u64)
+    sum(4i64)
+    a + fib(3u64)
+}
+        "#;
+        let program = frontend::Parser::new(code).parse_program().unwrap();
+        let module = Compiler::new().compile_program(&program);
+        let mut p = Processor::new();
+        assert_eq!(Ok(Object::UInt64(612)), p.run_module(&module));
+        assert!(p.stack.is_empty());
+
+        // same result as the tree walking interpreter
+        let expected = interpreter::processor::Processor::new().execute_program(&program);
+        assert_eq!(Ok(interpreter::object::Object::UInt64(612)), expected);
+
+        let module = Compiler::new().compile_program(&frontend::Parser::new("fn f() -> u64 {\n1u64\n}").parse_program().unwrap());
+        assert_eq!(Err(ProcessorError::UndefinedFunction("main".to_string())), p.run_module(&module));
+
+        let mut module = Compiler::new().compile_program(&program);
+        module.functions[0].codes.insert(0, BCode::POP);
+        assert!(matches!(p.run_module(&module), Err(ProcessorError::Verify(_))));
+    }
+
+    #[test]
+    fn trace_instructions() {
+        use crate::trace::{State, TraceSink};
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        #[derive(Debug)]
+        struct Recorder(Rc<RefCell<Vec<String>>>);
+        impl TraceSink for Recorder {
+            fn on_instruction(&mut self, pc: usize, code: &BCode, location: Option<SourceLocation>, before: &State, after: &State) {
+                self.0.borrow_mut().push(crate::trace::format(pc, code, location, before, after));
+            }
+        }
+
+        let code = "fn add(a: i64, b: i64) -> i64 {\na + b\n}\nfn main() -> i64 {\nval x = add(1i64, 2i64)\nx + 9223372036854775807i64\n}";
+        let program = frontend::Parser::new(code).parse_program().unwrap();
+        let module = Compiler::new().compile_program(&program);
+        let lines = Rc::new(RefCell::new(vec![]));
+        let mut p = Processor::new();
+        p.set_trace(Some(Box::new(Recorder(lines.clone()))));
+        // the instruction stopped by overflow is traced too
+        assert_eq!(Err(ProcessorError::Overflow { pc: 10 }), p.run_module(&module));
+        assert_eq!(vec![
+            "   4 PUSH_INT(1)              [] -> [Int64(1)] var {} val {}",
+            "   5 PUSH_INT(2)              [Int64(1)] -> [Int64(1), Int64(2)] var {} val {}",
+            "   6 CALL(0)                  [Int64(1), Int64(2)] -> [] var {} val {} -> {0: Int64(1), 1: Int64(2)}",
+            "   0 LOAD_IDENT_CONST(0)      [] -> [Int64(1)] var {} val {0: Int64(1), 1: Int64(2)}",
+            "   1 LOAD_IDENT_CONST(1)      [Int64(1)] -> [Int64(1), Int64(2)] var {} val {0: Int64(1), 1: Int64(2)}",
+            "   2 BINARY_ADD               [Int64(1), Int64(2)] -> [Int64(3)] var {} val {0: Int64(1), 1: Int64(2)}",
+            "   3 RET                      [Int64(3)] -> [Int64(3)] var {} val {0: Int64(1), 1: Int64(2)} -> {}",
+            "   7 PUSH_CONST(0)            [Int64(3)] -> [] var {} val {} -> {0: Int64(3)}",
+            "   8 LOAD_IDENT_CONST(0)      [] -> [Int64(3)] var {} val {0: Int64(3)}",
+            "   9 PUSH_LITERAL(0)          [Int64(3)] -> [Int64(3), Int64(9223372036854775807)] var {} val {0: Int64(3)}",
+            "  10 BINARY_ADD               [Int64(3), Int64(9223372036854775807)] -> [] var {} val {0: Int64(3)}",
+        ], *lines.borrow());
+
+        // no trace after it is removed
+        p.set_trace(None);
+        lines.borrow_mut().clear();
+        p.run_module(&module).unwrap_err();
+        assert!(lines.borrow().is_empty());
+    }
+
+    #[test]
+    fn error_location() {
+        let code = "fn main() -> u64 {\nval a = 10u64\nvar b = 0u64\nfor i in 0u64..3u64 {\nb = b + i\n}\na / (b - 3u64)\n}";
+        let program = frontend::Parser::new(code).parse_program().unwrap();
+        // the source map follows the optimized code
+        for opt_level in 0..=2 {
+            let mut compiler = Compiler::new();
+            compiler.set_opt_level(opt_level);
+            compiler.set_source(code);
+            let mut p = Processor::new();
+            let e = p.run_module(&compiler.compile_program(&program)).unwrap_err();
+            assert_eq!(Some(SourceLocation { line: 7, column: 1 }), e.pc().and_then(|pc| p.location(pc)), "-O{}", opt_level);
+        }
+
+        // without the source
+        let mut p = Processor::new();
+        let e = p.run_module(&Compiler::new().compile_program(&program)).unwrap_err();
+        assert_eq!((true, None), (e.pc().is_some(), e.pc().and_then(|pc| p.location(pc))));
     }
 }
@@ -0,0 +1,455 @@
+use crate::ir::{BinOp, Function, Inst, Reg, Value};
+use crate::loops;
+use std::collections::{HashMap, HashSet};
+
+// SSA form of the IR and the optimizations which rely on it, run at -O2
+// on the code compiled through the IR:
+//   * copy propagation: a use of the copy of a register uses the register
+//   * common subexpression elimination: a constant or an operation which is
+//     computed again where an earlier result dominates it is replaced by a
+//     copy of that result
+//   * dead store elimination: a register which is never read is not
+//     written, unless computing its value can fail (arithmetic may
+//     overflow or divide by zero) or has effects (calls)
+//   * the loop optimizations of `loops`
+// `optimize` builds the SSA form, runs the passes until nothing changes
+// and leaves the SSA form, so that the function can be lowered as before.
+
+pub fn optimize(f: &mut Function) {
+    construct(f);
+    while propagate_copies(f) | eliminate_common_subexpressions(f) | eliminate_dead_stores(f) |
+        loops::hoist_invariants(f) | loops::unroll(f) {}
+    destruct(f);
+}
+
+// Immediate dominator of each block reachable from the entry, the entry
+// is its own (Cooper, Harvey and Kennedy, "A Simple, Fast Dominance Algorithm")
+pub(crate) fn dominators(order: &[usize], predecessors: &[Vec<usize>]) -> Vec<Option<usize>> {
+    let mut index = vec![usize::MAX; predecessors.len()];
+    for (i, b) in order.iter().enumerate() {
+        index[*b] = i;
+    }
+    let mut idom: Vec<Option<usize>> = vec![None; predecessors.len()];
+    idom[0] = Some(0);
+    let intersect = |idom: &[Option<usize>], mut a: usize, mut b: usize| {
+        while a != b {
+            while index[a] > index[b] {
+                a = idom[a].unwrap();
+            }
+            while index[b] > index[a] {
+                b = idom[b].unwrap();
+            }
+        }
+        a
+    };
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for b in order.iter().skip(1) {
+            let new = predecessors[*b].iter()
+                .filter(|p| idom[**p].is_some())
+                .fold(None, |new, p| Some(new.map_or(*p, |new| intersect(&idom, *p, new))));
+            if new != idom[*b] {
+                idom[*b] = new;
+                changed = true;
+            }
+        }
+    }
+    idom
+}
+
+// Blocks of the dominator tree in preorder, with the number of blocks
+// under each one (itself included), so that the subtree can be left
+fn dominator_tree(order: &[usize], idom: &[Option<usize>]) -> Vec<(usize, usize)> {
+    let mut children = vec![vec![]; idom.len()];
+    for b in order.iter().skip(1) {
+        children[idom[*b].unwrap()].push(*b);
+    }
+    let mut tree = vec![];
+    let mut work = vec![(0, false, 0)];
+    while let Some((b, finished, start)) = work.pop() {
+        if finished {
+            tree[start] = (b, tree.len() - start);
+            continue;
+        }
+        work.push((b, true, tree.len()));
+        tree.push((b, 0));
+        work.extend(children[b].iter().rev().map(|c| (*c, false, 0)));
+    }
+    tree
+}
+
+// Registers read in each block before they are written there, and the
+// registers which are live at the start of each block
+fn live_in(f: &Function, order: &[usize]) -> Vec<HashSet<Reg>> {
+    let mut used = vec![HashSet::new(); f.blocks.len()];
+    let mut defined = vec![HashSet::new(); f.blocks.len()];
+    for b in order {
+        let block = &f.blocks[*b];
+        for (inst, _) in &block.insts {
+            used[*b].extend(inst.uses().into_iter().filter(|r| !defined[*b].contains(r)));
+            defined[*b].extend(inst.def());
+        }
+        used[*b].extend(block.terminator.0.uses().filter(|r| !defined[*b].contains(r)));
+    }
+    let mut live = used.clone();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for b in order.iter().rev() {
+            for s in f.blocks[*b].terminator.0.successors() {
+                let added: Vec<Reg> = live[s].iter().filter(|r| !defined[*b].contains(r) && !live[*b].contains(r)).copied().collect();
+                changed |= !added.is_empty();
+                live[*b].extend(added);
+            }
+        }
+    }
+    live
+}
+
+fn new_reg(f: &mut Function) -> Reg {
+    f.registers += 1;
+    Reg(f.registers - 1)
+}
+
+// Pruned SSA: a phi is placed at the dominance frontier of the writes of
+// a register where the register is live, then each write gets a new
+// register along the dominator tree. A register read on a path where it
+// is not written reads `()`.
+fn construct(f: &mut Function) {
+    let order = f.reverse_postorder();
+    let predecessors = f.predecessors();
+    let idom = dominators(&order, &predecessors);
+    let live = live_in(f, &order);
+
+    let mut frontier = vec![HashSet::new(); f.blocks.len()];
+    for b in &order {
+        if predecessors[*b].len() < 2 {
+            continue;
+        }
+        for p in &predecessors[*b] {
+            let mut runner = *p;
+            while Some(runner) != idom[*b] {
+                frontier[runner].insert(*b);
+                runner = idom[runner].unwrap();
+            }
+        }
+    }
+
+    let mut written: HashMap<Reg, Vec<usize>> = HashMap::new();
+    for b in &order {
+        for (inst, _) in &f.blocks[*b].insts {
+            if let Some(d) = inst.def() {
+                written.entry(d).or_default().push(*b);
+            }
+        }
+    }
+    let mut phis: Vec<Vec<Reg>> = vec![vec![]; f.blocks.len()]; // register of each phi
+    let mut registers: Vec<Reg> = written.keys().copied().collect();
+    registers.sort();
+    for r in registers {
+        let mut work = written[&r].clone();
+        while let Some(b) = work.pop() {
+            for d in &frontier[b] {
+                if live[*d].contains(&r) && !phis[*d].contains(&r) {
+                    phis[*d].push(r);
+                    work.push(*d);
+                }
+            }
+        }
+    }
+    for b in &order {
+        let origin = f.blocks[*b].terminator.1;
+        let operands: Vec<(usize, Reg)> = predecessors[*b].iter().map(|p| (*p, Reg(u32::MAX))).collect();
+        let placed = phis[*b].iter().map(|r| (Inst::Phi(*r, operands.clone()), origin));
+        f.blocks[*b].insts.splice(0..0, placed);
+    }
+
+    // renaming, the arguments keep their registers
+    let mut current: HashMap<Reg, Vec<Reg>> = (0..f.arity).map(|r| (Reg(r), vec![Reg(r)])).collect();
+    let mut undefined = None;
+    let tree = dominator_tree(&order, &idom);
+    let mut exits: Vec<(usize, Vec<Reg>)> = vec![]; // end of a subtree and the registers to pop there
+    for (i, (b, size)) in tree.iter().enumerate() {
+        while exits.last().is_some_and(|(end, _)| *end == i) {
+            for r in exits.pop().unwrap().1 {
+                current.get_mut(&r).unwrap().pop();
+            }
+        }
+        let mut pushed = vec![];
+        let mut insts = std::mem::take(&mut f.blocks[*b].insts);
+        for (inst, _) in &mut insts {
+            if !matches!(inst, Inst::Phi(..)) {
+                inst.map_uses(&mut |r| read(&current, &mut undefined, f, r));
+            }
+            if let Some(d) = inst.def() {
+                let new = new_reg(f);
+                current.entry(d).or_default().push(new);
+                pushed.push(d);
+                inst.map_def(&mut |_| new);
+            }
+        }
+        f.blocks[*b].insts = insts;
+        let mut terminator = f.blocks[*b].terminator.0.clone();
+        terminator.map_uses(&mut |r| read(&current, &mut undefined, f, r));
+        for s in terminator.successors() {
+            for (k, r) in phis[s].clone().into_iter().enumerate() {
+                let value = read(&current, &mut undefined, f, r);
+                if let Inst::Phi(_, operands) = &mut f.blocks[s].insts[k].0 {
+                    operands.iter_mut().filter(|(p, _)| p == b).for_each(|(_, operand)| *operand = value);
+                }
+            }
+        }
+        f.blocks[*b].terminator.0 = terminator;
+        exits.push((i + size, pushed));
+    }
+    if let Some(undefined) = undefined {
+        let origin = f.blocks[0].terminator.1;
+        f.blocks[0].insts.insert(0, (Inst::Const(undefined, Value::Null), origin));
+    }
+}
+
+// The register of the last write of `r` on the way to the current block
+fn read(current: &HashMap<Reg, Vec<Reg>>, undefined: &mut Option<Reg>, f: &mut Function, r: Reg) -> Reg {
+    match current.get(&r).and_then(|stack| stack.last()) {
+        Some(r) => *r,
+        None => *undefined.get_or_insert_with(|| new_reg(f)),
+    }
+}
+
+fn map_all_uses(f: &mut Function, map: &mut impl FnMut(Reg) -> Reg) {
+    for block in &mut f.blocks {
+        for (inst, _) in &mut block.insts {
+            inst.map_uses(map);
+        }
+        block.terminator.0.map_uses(map);
+    }
+}
+
+fn propagate_copies(f: &mut Function) -> bool {
+    // a phi of one register (and of itself) is a copy of it
+    for block in &mut f.blocks {
+        for (inst, _) in &mut block.insts {
+            if let Inst::Phi(d, operands) = inst {
+                let mut sources = operands.iter().map(|(_, r)| *r).filter(|r| r != d);
+                if let Some(first) = sources.next() {
+                    if sources.all(|r| r == first) {
+                        *inst = Inst::Copy(*d, first);
+                    }
+                }
+            }
+        }
+    }
+    let copies: HashMap<Reg, Reg> = f.blocks.iter()
+        .flat_map(|block| block.insts.iter())
+        .filter_map(|(inst, _)| match inst {
+            Inst::Copy(d, s) => Some((*d, *s)),
+            _ => None,
+        })
+        .collect();
+    if copies.is_empty() {
+        return false;
+    }
+    let source = |mut r: Reg| {
+        while let Some(s) = copies.get(&r) {
+            r = *s;
+        }
+        r
+    };
+    for block in &mut f.blocks {
+        block.insts.retain(|(inst, _)| !matches!(inst, Inst::Copy(..)));
+    }
+    map_all_uses(f, &mut |r| source(r));
+    true
+}
+
+#[derive(PartialEq, Eq, Hash)]
+enum Computation {
+    Const(Value),
+    Binary(BinOp, Reg, Reg),
+    Increment(Reg),
+}
+
+// What the instruction computes into its register, if it can be reused
+fn computation(inst: &Inst) -> Option<(Reg, Computation)> {
+    match inst {
+        Inst::Const(d, value) => Some((*d, Computation::Const(*value))),
+        // the operands of a commutative operation are in the order of the registers
+        Inst::Binary(d, op @ (BinOp::Add | BinOp::Mul | BinOp::Eq | BinOp::Ne), a, b) =>
+            Some((*d, Computation::Binary(*op, (*a).min(*b), (*a).max(*b)))),
+        Inst::Binary(d, op, a, b) => Some((*d, Computation::Binary(*op, *a, *b))),
+        Inst::Increment(d, s) => Some((*d, Computation::Increment(*s))),
+        _ => None,
+    }
+}
+
+fn eliminate_common_subexpressions(f: &mut Function) -> bool {
+    let order = f.reverse_postorder();
+    let idom = dominators(&order, &f.predecessors());
+    let mut available: HashMap<Computation, Reg> = HashMap::new();
+    let mut exits: Vec<(usize, Vec<Computation>)> = vec![]; // end of a subtree and what became available in it
+    let mut changed = false;
+    for (i, (b, size)) in dominator_tree(&order, &idom).into_iter().enumerate() {
+        while exits.last().is_some_and(|(end, _)| *end == i) {
+            for c in exits.pop().unwrap().1 {
+                available.remove(&c);
+            }
+        }
+        let mut added = vec![];
+        for (inst, _) in &mut f.blocks[b].insts {
+            let (d, c) = match computation(inst) {
+                Some(computed) => computed,
+                None => continue,
+            };
+            match available.get(&c) {
+                Some(r) => {
+                    *inst = Inst::Copy(d, *r);
+                    changed = true;
+                }
+                None => {
+                    available.insert(c, d);
+                    added.push(computation(inst).unwrap().1);
+                }
+            }
+        }
+        exits.push((i + size, added));
+    }
+    changed
+}
+
+fn eliminate_dead_stores(f: &mut Function) -> bool {
+    let mut changed = false;
+    loop {
+        let mut read: HashSet<Reg> = HashSet::new();
+        for block in &f.blocks {
+            for (inst, _) in &block.insts {
+                read.extend(inst.uses());
+            }
+            read.extend(block.terminator.0.uses());
+        }
+        let mut removed = false;
+        for block in &mut f.blocks {
+            block.insts.retain(|(inst, _)| {
+                let pure = match inst {
+                    Inst::Const(..) | Inst::Copy(..) | Inst::Phi(..) => true,
+                    Inst::Binary(_, op, _, _) => !matches!(op, BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::Div),
+                    Inst::Increment(..) | Inst::Call(..) | Inst::Print(_) => false,
+                };
+                let dead = pure && inst.def().is_some_and(|d| !read.contains(&d));
+                removed |= dead;
+                !dead
+            });
+        }
+        if !removed {
+            return changed;
+        }
+        changed = true;
+    }
+}
+
+// Each phi becomes a copy from a new register, which is written at the end
+// of each predecessor. As the new register is read only by the phi, the
+// copies of the phis of a block don't overwrite each other's sources
+// (the swap problem) and the branch of the predecessor still reads what
+// it did (the lost copy problem). The registers are numbered again from
+// the arguments, in the order of the blocks.
+fn destruct(f: &mut Function) {
+    for b in 0..f.blocks.len() {
+        for k in 0..f.blocks[b].insts.len() {
+            let (d, operands, origin) = match &f.blocks[b].insts[k] {
+                (Inst::Phi(d, operands), origin) => (*d, operands.clone(), *origin),
+                _ => continue,
+            };
+            let t = new_reg(f);
+            for (p, r) in operands {
+                f.blocks[p].insts.push((Inst::Copy(t, r), origin));
+            }
+            f.blocks[b].insts[k].0 = Inst::Copy(d, t);
+        }
+    }
+
+    let mut numbers: HashMap<Reg, Reg> = (0..f.arity).map(|r| (Reg(r), Reg(r))).collect();
+    let mut next = f.arity;
+    let mut number = |r: Reg| *numbers.entry(r).or_insert_with(|| {
+        next += 1;
+        Reg(next - 1)
+    });
+    for b in f.reverse_postorder() {
+        let block = &mut f.blocks[b];
+        for (inst, _) in &mut block.insts {
+            inst.map_uses(&mut number);
+            inst.map_def(&mut number);
+        }
+        block.terminator.0.map_uses(&mut number);
+    }
+    f.registers = next;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::differential::{compare_ir, Outcome};
+    use interpreter::object::Object;
+
+    fn optimized(code: &str) -> String {
+        let program = frontend::Parser::new(code).parse_program().unwrap();
+        let mut f = crate::ir::build_program(&program).remove(0);
+        optimize(&mut f);
+        f.to_string()
+    }
+
+    #[test]
+    fn ssa_optimizations() {
+        // the copies are gone, `n + 1u64` and `1u64` are computed once, the
+        // unused comparison is removed, `10u64` moves out of the loop and the
+        // loop variable goes through a phi
+        let code = "fn f(n: u64) -> u64 {\nvar x = n + 1u64\nval y = n + 1u64\nval unused = x < y\nwhile x < 10u64 { x = x + y }\nx\n}";
+        assert_eq!(concat!(
+            "fn f (arity 1)\n",
+            "b0:\n",
+            "    r1 = 1u64\n",
+            "    r2 = Add r0, r1\n",
+            "    r3 = 10u64\n",
+            "    r4 = r2\n",
+            "    jump b1\n",
+            "b1:\n",
+            "    r5 = r4\n",
+            "    r6 = Lt r5, r3\n",
+            "    branch r6, b2, b3\n",
+            "b2:\n",
+            "    r7 = Add r5, r2\n",
+            "    r4 = r7\n",
+            "    jump b1\n",
+            "b3:\n",
+            "    return r5\n",
+        ), optimized(code));
+
+        // a division whose result is not used still fails
+        let code = "fn main() -> u64 {\nval a = 0u64\nval b = 1u64 / a\n1u64\n}";
+        assert!(optimized(code).contains("Div"));
+        assert_eq!(Outcome::DivisionByZero, compare_ir(code, 2).unwrap().outcome);
+
+        let code = r#"
+fn g(a: i64, b: i64) -> i64 {
+    var x = a
+    var y = b
+    var i = 0i64
+    while i < 5i64 {
+        val t = x
+        x = y
+        y = t
+        i = i + 1i64
+    }
+    if x * 2i64 > y * 2i64 && a * b > 0i64 { x * 2i64 - y } else { (a * b) + x }
+}
+fn main() -> i64 {
+    var sum = 0i64
+    for k in 0i64..6i64 { sum = sum + g(k, 3i64 - k) }
+    sum
+}
+"#;
+        let expected = compare_ir(code, 0).unwrap().outcome;
+        assert_eq!(expected, compare_ir(code, 2).unwrap().outcome);
+        assert!(matches!(expected, Outcome::Value(Object::Int64(_))));
+    }
+}
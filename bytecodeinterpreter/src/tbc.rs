@@ -0,0 +1,346 @@
+use crate::compiler::*;
+use std::fmt;
+use std::path::Path;
+
+// Binary format of a compiled module (`.tbc`). All integers are little endian.
+//
+//   header   := "TBC\0" version:u16
+//   module   := header count:u32 constant* count:u32 function*
+//   constant := tag:u8 value:u64    (tag 0 = i64, 1 = u64)
+//   function := name:string arity:u32 const_slots:u32 var_slots:u32 count:u32 code*
+//               count:u32 location*
+//   string   := len:u32 utf8-bytes
+//   code     := opcode:u8 operand?
+//   location := line:u32 column:u32    (0 = unknown)
+//
+// The locations are the source map of the codes, the count is 0 for code
+// compiled without the source.
+//
+// The version is bumped whenever the layout or the opcode numbering changes,
+// and files of another version are rejected.
+pub const MAGIC: &[u8; 4] = b"TBC\0";
+pub const VERSION: u16 = 5;
+
+#[derive(Debug, PartialEq)]
+pub enum TbcError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    UnexpectedEof,
+    InvalidOpcode { opcode: u8, offset: usize },
+    InvalidConstant { tag: u8, offset: usize },
+    InvalidString,
+    Io(String),
+}
+
+impl fmt::Display for TbcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TbcError::BadMagic => write!(f, "not a toylang bytecode file"),
+            TbcError::UnsupportedVersion(v) => write!(f, "unsupported bytecode version {} (expected {})", v, VERSION),
+            TbcError::UnexpectedEof => write!(f, "unexpected end of bytecode file"),
+            TbcError::InvalidOpcode { opcode, offset } => write!(f, "invalid opcode {:#04x} at offset {}", opcode, offset),
+            TbcError::InvalidConstant { tag, offset } => write!(f, "invalid constant tag {:#04x} at offset {}", tag, offset),
+            TbcError::InvalidString => write!(f, "invalid utf-8 string"),
+            TbcError::Io(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for TbcError {}
+
+impl Module {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut e = Encoder { buf: Vec::new() };
+        e.buf.extend_from_slice(MAGIC);
+        e.u16(VERSION);
+        e.u32(self.constants.len() as u32);
+        for constant in &self.constants {
+            match *constant {
+                Constant::Int64(i) => { e.u8(0); e.u64(i as u64) }
+                Constant::UInt64(u) => { e.u8(1); e.u64(u) }
+            }
+        }
+        e.u32(self.functions.len() as u32);
+        for f in &self.functions {
+            e.string(&f.name);
+            e.u32(f.arity);
+            e.u32(f.const_slots);
+            e.u32(f.var_slots);
+            e.u32(f.codes.len() as u32);
+            for code in &f.codes {
+                e.code(code);
+            }
+            e.u32(f.locations.len() as u32);
+            for location in &f.locations {
+                let (line, column) = location.map_or((0, 0), |l| (l.line, l.column));
+                e.u32(line);
+                e.u32(column);
+            }
+        }
+        e.buf
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Module, TbcError> {
+        let mut d = Decoder { data, pos: 0 };
+        if d.bytes(MAGIC.len())? != MAGIC {
+            return Err(TbcError::BadMagic);
+        }
+        let version = d.u16()?;
+        if version != VERSION {
+            return Err(TbcError::UnsupportedVersion(version));
+        }
+        let mut module = Module::default();
+        for _ in 0..d.u32()? {
+            let offset = d.pos;
+            let constant = match d.u8()? {
+                0 => Constant::Int64(d.u64()? as i64),
+                1 => Constant::UInt64(d.u64()?),
+                tag => return Err(TbcError::InvalidConstant { tag, offset }),
+            };
+            module.constants.push(constant);
+        }
+        for _ in 0..d.u32()? {
+            let name = d.string()?;
+            let arity = d.u32()?;
+            let const_slots = d.u32()?;
+            let var_slots = d.u32()?;
+            let count = d.u32()?;
+            let mut codes = Vec::new();
+            for _ in 0..count {
+                codes.push(d.code()?);
+            }
+            let mut locations = Vec::new();
+            for _ in 0..d.u32()? {
+                let (line, column) = (d.u32()?, d.u32()?);
+                locations.push((line != 0).then_some(SourceLocation { line, column }));
+            }
+            module.functions.push(CodeObject { name, arity, const_slots, var_slots, codes, locations });
+        }
+        Ok(module)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), TbcError> {
+        std::fs::write(path, self.to_bytes()).map_err(|e| TbcError::Io(e.to_string()))
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Module, TbcError> {
+        let data = std::fs::read(path).map_err(|e| TbcError::Io(e.to_string()))?;
+        Module::from_bytes(&data)
+    }
+}
+
+struct Encoder {
+    buf: Vec<u8>,
+}
+
+impl Encoder {
+    fn u8(&mut self, v: u8) {
+        self.buf.push(v);
+    }
+
+    fn u16(&mut self, v: u16) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn u32(&mut self, v: u32) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn u64(&mut self, v: u64) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn string(&mut self, s: &str) {
+        self.u32(s.len() as u32);
+        self.buf.extend_from_slice(s.as_bytes());
+    }
+
+    // The numbering of opcodes is a part of the format (see VERSION)
+    fn code(&mut self, code: &BCode) {
+        match *code {
+            BCode::NOP => self.u8(0x00),
+            BCode::PUSH_NULL => self.u8(0x01),
+            BCode::PUSH_INT(i) => { self.u8(0x02); self.u64(i as u64) }
+            BCode::PUSH_UINT(u) => { self.u8(0x03); self.u64(u) }
+            BCode::PUSH_BOOL(b) => { self.u8(0x04); self.u8(b as u8) }
+            BCode::PUSH_LITERAL(index) => { self.u8(0x0a); self.u32(index) }
+            BCode::PUSH_CONST(id) => { self.u8(0x05); self.u32(id) }
+            BCode::LOAD_IDENT(id) => { self.u8(0x06); self.u32(id) }
+            BCode::LOAD_CONST(id) => { self.u8(0x07); self.u32(id) }
+            BCode::LOAD_IDENT_VAR(id) => { self.u8(0x08); self.u32(id) }
+            BCode::LOAD_IDENT_CONST(id) => { self.u8(0x09); self.u32(id) }
+            BCode::BINARY_ADD => self.u8(0x10),
+            BCode::BINARY_SUB => self.u8(0x11),
+            BCode::BINARY_MUL => self.u8(0x12),
+            BCode::BINARY_DIV => self.u8(0x13),
+            BCode::BINARY_EQ => self.u8(0x14),
+            BCode::BINARY_NE => self.u8(0x15),
+            BCode::BINARY_LT => self.u8(0x16),
+            BCode::BINARY_LE => self.u8(0x17),
+            BCode::BINARY_GT => self.u8(0x18),
+            BCode::BINARY_GE => self.u8(0x19),
+            BCode::INCREMENT => self.u8(0x1a),
+            BCode::JUMP(offset) => { self.u8(0x20); self.u32(offset as u32) }
+            BCode::JUMP_IF_FALSE(offset) => { self.u8(0x21); self.u32(offset as u32) }
+            BCode::PRINT0 => self.u8(0x30),
+            BCode::PRINT => self.u8(0x31),
+            BCode::POP => self.u8(0x40),
+            BCode::CALL(id) => { self.u8(0x41); self.u32(id) }
+            BCode::RET => self.u8(0x42),
+            BCode::ADD_VAR(id) => { self.u8(0x50); self.u32(id) }
+            BCode::ADD_CONST(id) => { self.u8(0x51); self.u32(id) }
+            BCode::INCREMENT_VAR(id) => { self.u8(0x52); self.u32(id) }
+            BCode::JUMP_IF_NOT_LT(offset) => { self.u8(0x53); self.u32(offset as u32) }
+        }
+    }
+}
+
+struct Decoder<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], TbcError> {
+        let end = self.pos.checked_add(len).filter(|end| *end <= self.data.len());
+        match end {
+            Some(end) => {
+                let bytes = &self.data[self.pos..end];
+                self.pos = end;
+                Ok(bytes)
+            }
+            None => Err(TbcError::UnexpectedEof),
+        }
+    }
+
+    fn u8(&mut self) -> Result<u8, TbcError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, TbcError> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, TbcError> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, TbcError> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> Result<String, TbcError> {
+        let len = self.u32()? as usize;
+        let bytes = self.bytes(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| TbcError::InvalidString)
+    }
+
+    fn code(&mut self) -> Result<BCode, TbcError> {
+        let offset = self.pos;
+        let code = match self.u8()? {
+            0x00 => BCode::NOP,
+            0x01 => BCode::PUSH_NULL,
+            0x02 => BCode::PUSH_INT(self.u64()? as i64),
+            0x03 => BCode::PUSH_UINT(self.u64()?),
+            0x04 => BCode::PUSH_BOOL(self.u8()? != 0),
+            0x05 => BCode::PUSH_CONST(self.u32()?),
+            0x06 => BCode::LOAD_IDENT(self.u32()?),
+            0x07 => BCode::LOAD_CONST(self.u32()?),
+            0x08 => BCode::LOAD_IDENT_VAR(self.u32()?),
+            0x09 => BCode::LOAD_IDENT_CONST(self.u32()?),
+            0x0a => BCode::PUSH_LITERAL(self.u32()?),
+            0x10 => BCode::BINARY_ADD,
+            0x11 => BCode::BINARY_SUB,
+            0x12 => BCode::BINARY_MUL,
+            0x13 => BCode::BINARY_DIV,
+            0x14 => BCode::BINARY_EQ,
+            0x15 => BCode::BINARY_NE,
+            0x16 => BCode::BINARY_LT,
+            0x17 => BCode::BINARY_LE,
+            0x18 => BCode::BINARY_GT,
+            0x19 => BCode::BINARY_GE,
+            0x1a => BCode::INCREMENT,
+            0x20 => BCode::JUMP(self.u32()? as i32),
+            0x21 => BCode::JUMP_IF_FALSE(self.u32()? as i32),
+            0x30 => BCode::PRINT0,
+            0x31 => BCode::PRINT,
+            0x40 => BCode::POP,
+            0x41 => BCode::CALL(self.u32()?),
+            0x42 => BCode::RET,
+            0x50 => BCode::ADD_VAR(self.u32()?),
+            0x51 => BCode::ADD_CONST(self.u32()?),
+            0x52 => BCode::INCREMENT_VAR(self.u32()?),
+            0x53 => BCode::JUMP_IF_NOT_LT(self.u32()? as i32),
+            opcode => return Err(TbcError::InvalidOpcode { opcode, offset }),
+        };
+        Ok(code)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn module() -> Module {
+        let code = r#"
+fn add(a: i64, b: i64) -> i64 {
+    a + b
+}
+fn main() -> i64 {
+    var sum = 0i64
+    for i in -3i64..3i64 {
+        if i < 0i64 { sum = add(sum, i * 1000000i64) }
+    }
+    sum
+}
+        "#;
+        let program = frontend::Parser::new(code).parse_program().unwrap();
+        let mut compiler = Compiler::new();
+        compiler.set_source(code);
+        compiler.compile_program(&program)
+    }
+
+    #[test]
+    fn round_trip() {
+        let module = module();
+        let bytes = module.to_bytes();
+        assert_eq!(b"TBC\0\x05\x00", &bytes[0..6]);
+        assert_eq!(Some(SourceLocation { line: 3, column: 5 }), module.functions[0].location(0));
+        assert_eq!(vec![Constant::Int64(1000000)], module.constants);
+        assert_eq!(Ok(module), Module::from_bytes(&bytes));
+    }
+
+    #[test]
+    fn save_and_load() {
+        let module = module();
+        let path = std::env::temp_dir().join(format!("toylang-{}.tbc", std::process::id()));
+        module.save(&path).unwrap();
+        let loaded = Module::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(Ok(module), loaded);
+    }
+
+    #[test]
+    fn reject_invalid_file() {
+        let bytes = module().to_bytes();
+        assert_eq!(Err(TbcError::BadMagic), Module::from_bytes(b"ELF\0\x01\x00"));
+
+        let mut old = bytes.clone();
+        old[4] = 0;
+        assert_eq!(Err(TbcError::UnsupportedVersion(0)), Module::from_bytes(&old));
+
+        assert_eq!(Err(TbcError::UnexpectedEof), Module::from_bytes(&bytes[..bytes.len() - 1]));
+
+        // the tag of the first constant
+        let offset = 6 + 4;
+        let mut broken = bytes.clone();
+        broken[offset] = 0xff;
+        assert_eq!(Err(TbcError::InvalidConstant { tag: 0xff, offset }), Module::from_bytes(&broken));
+
+        // the first code of the first function: header, constants, count, name "add", arity, slots, count
+        let offset = 6 + 4 + 9 + 4 + 4 + 3 + 4 + 8 + 4;
+        let mut broken = bytes;
+        broken[offset] = 0xff;
+        assert_eq!(Err(TbcError::InvalidOpcode { opcode: 0xff, offset }), Module::from_bytes(&broken));
+    }
+}
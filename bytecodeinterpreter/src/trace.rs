@@ -0,0 +1,86 @@
+use crate::compiler::{BCode, SourceLocation};
+use crate::processor::Object;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io::Write;
+
+// Operand stack and registers (variables and constants of the running
+// function) at one point of the execution. The registers are sorted by id
+// so that traces can be compared as text.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct State {
+    pub stack: Vec<Object>,
+    pub var: BTreeMap<u32, Object>,
+    pub val: BTreeMap<u32, Object>,
+}
+
+impl State {
+    pub fn of(stack: &[Object], var: &HashMap<u32, Object>, val: &HashMap<u32, Object>) -> Self {
+        State {
+            stack: stack.to_vec(),
+            var: var.iter().map(|(id, v)| (*id, *v)).collect(),
+            val: val.iter().map(|(id, v)| (*id, *v)).collect(),
+        }
+    }
+}
+
+// Receiver of the execution trace, see `Processor::set_trace`.
+// It is called after each executed instruction, also after the one
+// which stops with an error (e.g. overflow).
+pub trait TraceSink: fmt::Debug {
+    fn on_instruction(&mut self, pc: usize, code: &BCode, location: Option<SourceLocation>, before: &State, after: &State);
+}
+
+// One line per instruction:
+//   pc instruction  stack before -> after  registers  [at line:column]
+// The registers are printed as `before -> after` only when they changed.
+// The location is printed if the module is compiled with the source.
+pub fn format(pc: usize, code: &BCode, location: Option<SourceLocation>, before: &State, after: &State) -> String {
+    let mut line = format!("{:4} {:<24} {:?} -> {:?}", pc, format!("{:?}", code), before.stack, after.stack);
+    for (name, before, after) in [("var", &before.var, &after.var), ("val", &before.val, &after.val)] {
+        if before == after {
+            line += &format!(" {} {:?}", name, after);
+        } else {
+            line += &format!(" {} {:?} -> {:?}", name, before, after);
+        }
+    }
+    if let Some(location) = location {
+        line += &format!(" at {}", location);
+    }
+    line
+}
+
+// Writes the trace formatted by `format` (e.g. to stderr)
+#[derive(Debug)]
+pub struct WriteSink<W> {
+    writer: W,
+}
+
+impl<W: Write> WriteSink<W> {
+    pub fn new(writer: W) -> Self {
+        WriteSink { writer }
+    }
+}
+
+impl<W: Write + fmt::Debug> TraceSink for WriteSink<W> {
+    fn on_instruction(&mut self, pc: usize, code: &BCode, location: Option<SourceLocation>, before: &State, after: &State) {
+        // the trace is for debugging, a broken writer must not stop the program
+        let _ = writeln!(self.writer, "{}", format(pc, code, location, before, after));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_line() {
+        let before = State { stack: vec![Object::UInt64(1)], ..State::default() };
+        let after = State { stack: vec![], var: [(0, Object::UInt64(1))].into_iter().collect(), ..State::default() };
+        assert_eq!(
+            "   3 LOAD_IDENT(0)            [UInt64(1)] -> [] var {} -> {0: UInt64(1)} val {}",
+            format(3, &BCode::LOAD_IDENT(0), None, &before, &after)
+        );
+        assert!(format(3, &BCode::LOAD_IDENT(0), Some(SourceLocation { line: 2, column: 5 }), &before, &after).ends_with(" at 2:5"));
+    }
+}
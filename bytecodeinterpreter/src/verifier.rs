@@ -0,0 +1,225 @@
+use crate::compiler::*;
+use std::fmt;
+
+// Static checks of a module before it runs, so that a broken or
+// handcrafted .tbc file is rejected instead of panicking in the middle
+// of the execution. For each function:
+//   * jump targets are inside the function
+//   * the operand stack never underflows and has the same depth on
+//     every path reaching an instruction
+//   * every path ends with RET
+//   * constant pool indices, function indices and local ids are in range
+#[derive(Debug, PartialEq, Clone)]
+pub enum VerifyErrorKind {
+    JumpOutOfRange(i32),
+    StackUnderflow,
+    StackMismatch { expected: usize, actual: usize },
+    MissingReturn,
+    LiteralOutOfRange(u32),
+    ConstSlotOutOfRange(u32),
+    VarSlotOutOfRange(u32),
+    UndefinedFunction(u32),
+    InvalidArity,
+    Unsupported(BCode),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct VerifyError {
+    pub function: String,
+    pub pc: usize,
+    pub kind: VerifyErrorKind,
+}
+
+impl fmt::Display for VerifyErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VerifyErrorKind::JumpOutOfRange(offset) => write!(f, "jump offset {} is out of the function", offset),
+            VerifyErrorKind::StackUnderflow => write!(f, "operand stack underflow"),
+            VerifyErrorKind::StackMismatch { expected, actual } =>
+                write!(f, "operand stack depth {} does not match {} of another path", actual, expected),
+            VerifyErrorKind::MissingReturn => write!(f, "function ends without RET"),
+            VerifyErrorKind::LiteralOutOfRange(index) => write!(f, "constant pool index {} is out of range", index),
+            VerifyErrorKind::ConstSlotOutOfRange(id) => write!(f, "constant id {} is out of range", id),
+            VerifyErrorKind::VarSlotOutOfRange(id) => write!(f, "variable id {} is out of range", id),
+            VerifyErrorKind::UndefinedFunction(index) => write!(f, "function index {} is out of range", index),
+            VerifyErrorKind::InvalidArity => write!(f, "arguments do not fit in the constant slots"),
+            VerifyErrorKind::Unsupported(code) => write!(f, "unsupported instruction {:?}", code),
+        }
+    }
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}: {}", self.function, self.pc, self.kind)
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+pub fn verify(module: &Module) -> Result<(), VerifyError> {
+    for f in &module.functions {
+        verify_function(module, f)?;
+    }
+    Ok(())
+}
+
+// (number of popped values, number of pushed values)
+fn stack_effect(module: &Module, f: &CodeObject, code: &BCode) -> Result<(usize, usize), VerifyErrorKind> {
+    let const_slot = |id: u32| if id < f.const_slots { Ok(()) } else { Err(VerifyErrorKind::ConstSlotOutOfRange(id)) };
+    let var_slot = |id: u32| if id < f.var_slots { Ok(()) } else { Err(VerifyErrorKind::VarSlotOutOfRange(id)) };
+    let effect = match *code {
+        BCode::NOP | BCode::JUMP(_) => (0, 0),
+        BCode::PUSH_NULL | BCode::PUSH_INT(_) | BCode::PUSH_UINT(_) | BCode::PUSH_BOOL(_) => (0, 1),
+        BCode::PUSH_LITERAL(index) => {
+            if index as usize >= module.constants.len() {
+                return Err(VerifyErrorKind::LiteralOutOfRange(index));
+            }
+            (0, 1)
+        }
+        BCode::PUSH_CONST(id) | BCode::LOAD_CONST(id) => {
+            const_slot(id)?;
+            (1, 0)
+        }
+        BCode::LOAD_IDENT(id) => {
+            var_slot(id)?;
+            (1, 0)
+        }
+        BCode::LOAD_IDENT_VAR(id) => {
+            var_slot(id)?;
+            (0, 1)
+        }
+        BCode::LOAD_IDENT_CONST(id) => {
+            const_slot(id)?;
+            (0, 1)
+        }
+        BCode::BINARY_ADD | BCode::BINARY_SUB | BCode::BINARY_MUL | BCode::BINARY_DIV |
+        BCode::BINARY_EQ | BCode::BINARY_NE | BCode::BINARY_LT | BCode::BINARY_LE |
+        BCode::BINARY_GT | BCode::BINARY_GE => (2, 1),
+        BCode::INCREMENT => (1, 1),
+        BCode::ADD_VAR(id) => {
+            var_slot(id)?;
+            (1, 1)
+        }
+        BCode::ADD_CONST(id) => {
+            const_slot(id)?;
+            (1, 1)
+        }
+        BCode::INCREMENT_VAR(id) => {
+            var_slot(id)?;
+            (0, 0)
+        }
+        BCode::JUMP_IF_NOT_LT(_) => (2, 0),
+        BCode::JUMP_IF_FALSE(_) | BCode::PRINT0 | BCode::POP | BCode::RET => (1, 0),
+        BCode::CALL(index) => match module.functions.get(index as usize) {
+            Some(callee) => (callee.arity as usize, 1),
+            None => return Err(VerifyErrorKind::UndefinedFunction(index)),
+        },
+        BCode::PRINT => return Err(VerifyErrorKind::Unsupported(*code)),
+    };
+    Ok(effect)
+}
+
+fn verify_function(module: &Module, f: &CodeObject) -> Result<(), VerifyError> {
+    let error = |pc: usize, kind: VerifyErrorKind| VerifyError { function: f.name.clone(), pc, kind };
+    if f.arity > f.const_slots {
+        return Err(error(0, VerifyErrorKind::InvalidArity));
+    }
+
+    // stack depth before each instruction, found by following all paths
+    let mut depth: Vec<Option<usize>> = vec![None; f.codes.len()];
+    let mut work = vec![(0usize, 0usize)];
+    while let Some((pc, d)) = work.pop() {
+        let code = match f.codes.get(pc) {
+            Some(code) => code,
+            None => return Err(error(pc, VerifyErrorKind::MissingReturn)),
+        };
+        match depth[pc] {
+            Some(expected) if expected == d => continue,
+            Some(expected) => return Err(error(pc, VerifyErrorKind::StackMismatch { expected, actual: d })),
+            None => depth[pc] = Some(d),
+        }
+
+        let (pop, push) = stack_effect(module, f, code).map_err(|kind| error(pc, kind))?;
+        if d < pop {
+            return Err(error(pc, VerifyErrorKind::StackUnderflow));
+        }
+        let next = d - pop + push;
+
+        let jump = |offset: i32| {
+            let target = pc as i64 + offset as i64;
+            if target < 0 || target >= f.codes.len() as i64 {
+                return Err(error(pc, VerifyErrorKind::JumpOutOfRange(offset)));
+            }
+            Ok(target as usize)
+        };
+        match *code {
+            BCode::RET => (),
+            BCode::JUMP(offset) => work.push((jump(offset)?, next)),
+            BCode::JUMP_IF_FALSE(offset) | BCode::JUMP_IF_NOT_LT(offset) => {
+                work.push((jump(offset)?, next));
+                work.push((pc + 1, next));
+            }
+            _ => work.push((pc + 1, next)),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn module(code: &str) -> Module {
+        let program = frontend::Parser::new(code).parse_program().unwrap();
+        Compiler::new().compile_program(&program)
+    }
+
+    fn function(codes: Vec<BCode>) -> Module {
+        Module {
+            functions: vec![CodeObject { name: "main".to_string(), arity: 0, const_slots: 1, var_slots: 1, codes, locations: vec![] }],
+            constants: vec![Constant::UInt64(1 << 20)],
+        }
+    }
+
+    fn kind(module: &Module) -> Option<(usize, VerifyErrorKind)> {
+        verify(module).err().map(|e| (e.pc, e.kind))
+    }
+
+    #[test]
+    fn verify_compiled_module() {
+        let code = r#"
+fn fib(n: u64) -> u64 {
+    if n < 2u64 { n } else { fib(n - 1u64) + fib(n - 2u64) }
+}
+fn main() -> u64 {
+    var sum = 0u64
+    for i in 0u64..10u64 {
+        while sum > 1000000u64 && i != 3u64 { sum = sum - 1u64 }
+        sum = sum + fib(i)
+    }
+    sum
+}
+        "#;
+        assert_eq!(Ok(()), verify(&module(code)));
+    }
+
+    #[test]
+    fn verify_broken_code() {
+        use BCode::*;
+        assert_eq!(None, kind(&function(vec![PUSH_LITERAL(0), RET])));
+        assert_eq!(Some((0, VerifyErrorKind::LiteralOutOfRange(1))), kind(&function(vec![PUSH_LITERAL(1), RET])));
+        assert_eq!(Some((1, VerifyErrorKind::StackUnderflow)), kind(&function(vec![PUSH_INT(1), BINARY_ADD, RET])));
+        assert_eq!(Some((1, VerifyErrorKind::JumpOutOfRange(5))), kind(&function(vec![PUSH_NULL, JUMP(5), RET])));
+        assert_eq!(Some((1, VerifyErrorKind::MissingReturn)), kind(&function(vec![PUSH_NULL])));
+        assert_eq!(Some((0, VerifyErrorKind::VarSlotOutOfRange(1))), kind(&function(vec![LOAD_IDENT_VAR(1), RET])));
+        assert_eq!(Some((0, VerifyErrorKind::ConstSlotOutOfRange(3))), kind(&function(vec![LOAD_IDENT_CONST(3), RET])));
+        assert_eq!(Some((0, VerifyErrorKind::UndefinedFunction(1))), kind(&function(vec![CALL(1), RET])));
+
+        // the branch pushes a value only on one path
+        let codes = vec![PUSH_BOOL(true), JUMP_IF_FALSE(2), PUSH_INT(1), PUSH_INT(2), RET];
+        assert_eq!(Some((3, VerifyErrorKind::StackMismatch { expected: 1, actual: 0 })), kind(&function(codes)));
+
+        let err = verify(&function(vec![POP, RET])).unwrap_err();
+        assert_eq!("main: 0: operand stack underflow", err.to_string());
+    }
+}
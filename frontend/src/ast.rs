@@ -1,5 +1,8 @@
+use std::collections::HashMap;
+
 #[derive (Clone, Copy, Debug, PartialEq)]
 pub struct ExprRef(pub u32);
+#[derive(Debug, PartialEq, Clone)]
 pub struct ExprPool(pub Vec<Expr>);
 
 #[derive(Debug, PartialEq)]
@@ -12,6 +15,10 @@ pub struct Node {
     end: usize,
 }
 
+// Source range of each expression, indexed by ExprRef like ExprPool
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct LocationPool(pub Vec<Node>);
+
 impl ExprPool {
     pub fn new() -> ExprPool {
         ExprPool(Vec::new())
@@ -37,6 +44,16 @@ impl ExprPool {
     pub fn len(&self) -> usize {
         self.0.len()
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl Default for ExprPool {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Node {
@@ -46,15 +63,50 @@ impl Node {
             end,
         }
     }
+
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    pub fn end(&self) -> usize {
+        self.end
+    }
 }
 
+impl LocationPool {
+    pub fn new() -> LocationPool {
+        LocationPool(Vec::new())
+    }
+
+    pub fn add(&mut self, node: Node) -> ExprRef {
+        let len = self.0.len();
+        self.0.push(node);
+        ExprRef(len as u32)
+    }
+
+    pub fn get(&self, e: ExprRef) -> Option<&Node> {
+        self.0.get(e.0 as usize)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+#[derive(Clone)]
 pub struct Program {
     pub node: Node,
-    pub import: Vec<String>,
+    pub import: Vec<String>, // files of `include "file.toy"`, as written
     pub function: Vec<Function>,
     //pub expression: Vec<ExprRef>,
 
     pub expression: ExprPool,
+    pub location: LocationPool,
+    pub attributes: HashMap<u32, Vec<Attribute>>, // of the statements, by ExprRef
 }
 
 impl Program {
@@ -66,11 +118,8 @@ impl Program {
     pub fn get_block(&self, i: u32) -> Option<Vec<&crate::ast::Expr>> {
         let mut expression_block: Vec<&crate::ast::Expr> = vec![];
         match self.get(i) {
-            Some(e) => match e {
-                crate::ast::Expr::Block(expressions) => {
-                    expressions.iter().for_each(|x| expression_block.push(&self.get(x.0).unwrap()));
-                }
-                _ => return Option::None,
+            Some(crate::ast::Expr::Block(expressions)) => {
+                expressions.iter().for_each(|x| expression_block.push(self.get(x.0).unwrap()));
             }
             _ => return Option::None,
         }
@@ -81,6 +130,92 @@ impl Program {
         self.expression.0.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.expression.0.is_empty()
+    }
+
+    // Attribute `name` of the statement `e`
+    pub fn attribute(&self, e: ExprRef, name: &str) -> Option<&Attribute> {
+        self.attributes.get(&e.0)?.iter().find(|a| a.name == name)
+    }
+
+    // Move the functions of `other` into this program to run several
+    // modules as one. The source offsets of `other` are moved by `offset`
+    // so that the locations of the modules don't overlap.
+    pub fn append(&mut self, other: Program, offset: usize) {
+        let base = self.expression.len() as u32;
+        let shift = |e: ExprRef| ExprRef(e.0 + base);
+        let moved = |node: &Node| Node::new(node.start + offset, node.end + offset);
+        // keep the locations indexed like the expressions
+        self.location.0.resize(base as usize, Node::new(0, 0));
+        for expr in other.expression.0 {
+            self.expression.push(match expr.map_children(shift) {
+                Expr::Function(mut f) => {
+                    f.node = moved(&f.node);
+                    Expr::Function(f)
+                }
+                expr => expr,
+            });
+        }
+        self.location.0.extend(other.location.0.iter().map(moved));
+        for (e, mut attributes) in other.attributes {
+            for a in &mut attributes {
+                a.node = moved(&a.node);
+            }
+            self.attributes.insert(e + base, attributes);
+        }
+        for mut f in other.function {
+            f.node = moved(&f.node);
+            f.code = shift(f.code);
+            self.function.push(f);
+        }
+        self.import.extend(other.import);
+        if !self.function.is_empty() {
+            self.node = Node::new(self.node.start, moved(&other.node).end);
+        }
+    }
+
+    // Drop the expressions which no function refers to, e.g. the ones left
+    // behind by a rewrite, and renumber the others keeping their order.
+    // The locations stay indexed like the expressions. A reference out of
+    // the pool stays out of it.
+    pub fn compact(self) -> Program {
+        let len = self.expression.len();
+        let mut live = vec![false; len];
+        let mut work: Vec<ExprRef> = self.function.iter().map(|f| f.code).collect();
+        while let Some(e) = work.pop() {
+            match live.get_mut(e.0 as usize) {
+                Some(mark) if !*mark => {
+                    *mark = true;
+                    work.extend(self.expression.0[e.0 as usize].children());
+                }
+                _ => (),
+            }
+        }
+
+        let mut index = vec![u32::MAX; len];
+        let mut next = 0;
+        for (i, _) in live.iter().enumerate().filter(|(_, live)| **live) {
+            index[i] = next;
+            next += 1;
+        }
+        let remap = |e: ExprRef| ExprRef(index.get(e.0 as usize).copied().unwrap_or(u32::MAX));
+
+        let mut expression = ExprPool::with_capacity(next as usize);
+        let mut location = LocationPool::new();
+        for (i, expr) in self.expression.0.into_iter().enumerate().filter(|(i, _)| live[*i]) {
+            expression.push(expr.map_children(remap));
+            if !self.location.is_empty() {
+                location.add(self.location.0.get(i).cloned().unwrap_or(Node::new(0, 0)));
+            }
+        }
+        let function = self.function.into_iter().map(|f| Function { code: remap(f.code), ..f }).collect();
+        let attributes = self.attributes.into_iter()
+            .filter(|(e, _)| live.get(*e as usize).is_some_and(|live| *live))
+            .map(|(e, attributes)| (index[e as usize], attributes))
+            .collect();
+        Program { node: self.node, import: self.import, function, expression, location, attributes }
+    }
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -90,6 +225,24 @@ pub struct Function {
     pub parameter: ParameterList,
     pub return_type: Option<Type>,
     pub code: ExprRef,
+    pub doc: Option<String>, // `///` lines in front of the function
+    pub is_const: bool, // `const fn`, see `crate::consteval`
+    pub attributes: Vec<Attribute>,
+}
+
+impl Function {
+    pub fn attribute(&self, name: &str) -> Option<&Attribute> {
+        self.attributes.iter().find(|a| a.name == name)
+    }
+}
+
+// `#[name]` or `#[name(arg, ...)]` in front of a function or a statement,
+// see `crate::attribute`
+#[derive(Debug, PartialEq, Clone)]
+pub struct Attribute {
+    pub node: Node,
+    pub name: String,
+    pub args: Vec<String>, // identifiers or integers, as written
 }
 
 pub type Parameter = (String, Type);
@@ -103,10 +256,53 @@ pub enum Expr {
     Int64(i64),
     UInt64(u64),
     Int(String),
-    Val(String, Option<Type>, Option<ExprRef>),
+    Val(String, Option<Type>, Option<ExprRef>), // immutable binding
+    Var(String, Option<Type>, Option<ExprRef>), // mutable binding
     Identifier(String),
     Null,
-    Call(String, ExprRef) // apply, function call, etc
+    Call(String, ExprRef), // apply, function call, etc
+    While(ExprRef, ExprRef), // condition, body
+    For(String, ExprRef, ExprRef, ExprRef), // induction variable, start, end (exclusive), body
+    Spawn(ExprRef), // the call run on its own thread
+    Function(Box<Function>), // nested definition, visible in the rest of the block
+}
+
+impl Expr {
+    // Expressions directly under this one
+    pub fn children(&self) -> Vec<ExprRef> {
+        match self {
+            Expr::IfElse(cond, then_block, else_block) => vec![*cond, *then_block, *else_block],
+            Expr::Binary(_, lhs, rhs) => vec![*lhs, *rhs],
+            Expr::Block(expressions) => expressions.clone(),
+            Expr::Val(_, _, rhs) | Expr::Var(_, _, rhs) => rhs.iter().copied().collect(),
+            Expr::Call(_, args) => vec![*args],
+            Expr::While(cond, body) => vec![*cond, *body],
+            Expr::For(_, start, end, body) => vec![*start, *end, *body],
+            Expr::Spawn(call) => vec![*call],
+            Expr::Function(f) => vec![f.code],
+            _ => vec![],
+        }
+    }
+
+    // The same expression with each child `e` replaced by `f(e)`
+    pub fn map_children(self, f: impl Fn(ExprRef) -> ExprRef) -> Expr {
+        match self {
+            Expr::IfElse(cond, then_block, else_block) => Expr::IfElse(f(cond), f(then_block), f(else_block)),
+            Expr::Binary(op, lhs, rhs) => Expr::Binary(op, f(lhs), f(rhs)),
+            Expr::Block(expressions) => Expr::Block(expressions.into_iter().map(f).collect()),
+            Expr::Val(name, ty, rhs) => Expr::Val(name, ty, rhs.map(f)),
+            Expr::Var(name, ty, rhs) => Expr::Var(name, ty, rhs.map(f)),
+            Expr::Call(name, args) => Expr::Call(name, f(args)),
+            Expr::While(cond, body) => Expr::While(f(cond), f(body)),
+            Expr::For(name, start, end, body) => Expr::For(name, f(start), f(end), f(body)),
+            Expr::Spawn(call) => Expr::Spawn(f(call)),
+            Expr::Function(mut function) => {
+                function.code = f(function.code);
+                Expr::Function(function)
+            }
+            leaf => leaf,
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -144,4 +340,117 @@ pub enum Type {
     Identifier(String),
     Unit,
     Bool,
-}
\ No newline at end of file
+    Task(Box<Type>), // handle of a spawned call and its result type
+    Channel(Box<Type>), // `channel<T>`, shared by tasks to pass values of T
+}
+
+impl Type {
+    // Type of the values carried by a task or a channel
+    pub fn element(&self) -> Option<&Type> {
+        match self {
+            Type::Task(ty) | Type::Channel(ty) => Some(ty),
+            _ => None,
+        }
+    }
+}
+
+// Indented tree of the expression `e` for debugging (REPL `:ast`).
+// A child expression is on its own line, one level deeper than its parent.
+pub fn dump(pool: &ExprPool, e: ExprRef) -> String {
+    let mut text = String::new();
+    dump_to(pool, e, 0, &mut text);
+    text
+}
+
+fn dump_to(pool: &ExprPool, e: ExprRef, depth: usize, text: &mut String) {
+    let indent = "  ".repeat(depth);
+    let expr = match pool.get(e.0 as usize) {
+        Some(expr) => expr,
+        None => {
+            *text += &format!("{}<invalid {:?}>\n", indent, e);
+            return;
+        }
+    };
+    let label = match expr {
+        Expr::IfElse(..) => "IfElse".to_string(),
+        Expr::Binary(op, _, _) => format!("Binary({:?})", op),
+        Expr::Block(_) => "Block".to_string(),
+        Expr::Val(name, ty, _) => format!("Val({:?}, {:?})", name, ty),
+        Expr::Var(name, ty, _) => format!("Var({:?}, {:?})", name, ty),
+        Expr::Call(name, _) => format!("Call({:?})", name),
+        Expr::While(..) => "While".to_string(),
+        Expr::For(name, ..) => format!("For({:?})", name),
+        Expr::Spawn(_) => "Spawn".to_string(),
+        Expr::Function(f) => format!("Function({:?})", f.name),
+        leaf => format!("{:?}", leaf),
+    };
+    *text += &format!("{}{}\n", indent, label);
+    for child in expr.children() {
+        dump_to(pool, child, depth + 1, text);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Parser;
+
+    #[test]
+    fn dump_expression() {
+        let (e, pool) = Parser::new("if a < 1u64 { val b = a + 2u64\nb } else { f(a) }").parse_expression().unwrap();
+        assert_eq!(concat!(
+            "IfElse\n",
+            "  Binary(LT)\n",
+            "    Identifier(\"a\")\n",
+            "    UInt64(1)\n",
+            "  Block\n",
+            "    Val(\"b\", Some(Unknown))\n",
+            "      Binary(IAdd)\n",
+            "        Identifier(\"a\")\n",
+            "        UInt64(2)\n",
+            "    Identifier(\"b\")\n",
+            "  Block\n",
+            "    Call(\"f\")\n",
+            "      Block\n",
+            "        Identifier(\"a\")\n",
+        ), dump(&pool, e));
+    }
+
+    #[test]
+    fn append_program() {
+        let mut a = Parser::new("fn f() -> u64 {\n1u64\n}").parse_program().unwrap();
+        let b = Parser::new("fn g() -> u64 {\nf() + 2u64\n}").parse_program().unwrap();
+        let (len, b_location) = (a.len(), b.location.clone());
+        a.append(b, 100);
+        assert_eq!(vec!["f", "g"], a.function.iter().map(|f| f.name.as_str()).collect::<Vec<_>>());
+        assert_eq!(a.len(), a.location.len());
+        assert_eq!(Node::new(100, 128), a.function[1].node);
+        let g = &a.function[1];
+        assert_eq!("Block\n  Binary(IAdd)\n    Call(\"f\")\n      Block\n    UInt64(2)\n", dump(&a.expression, g.code));
+        assert_eq!(b_location.get(ExprRef(0)).unwrap().start() + 100, a.location.get(ExprRef(len as u32)).unwrap().start());
+    }
+
+    #[test]
+    fn compact_program() {
+        let mut program = Parser::new("fn f(a: u64) -> u64 {\nfn g() -> u64 { a }\ng() + 1u64\n}").parse_program().unwrap();
+        // orphans in front of and between the live expressions
+        let code = program.function[0].code;
+        program.expression.0.insert(0, Expr::Null);
+        program.location.0.insert(0, Node::new(0, 0));
+        for e in program.expression.0.iter_mut() {
+            *e = e.clone().map_children(|e| ExprRef(e.0 + 1));
+        }
+        program.function[0].code = ExprRef(code.0 + 1);
+        program.expression.add(Expr::UInt64(7));
+        program.location.add(Node::new(0, 1));
+
+        let before = dump(&program.expression, program.function[0].code);
+        let start = program.location.get(program.function[0].code).unwrap().start();
+        let program = program.compact();
+        assert_eq!(program.len(), program.location.len());
+        assert_eq!(code.0 as usize + 1, program.len());
+        assert!(!program.expression.0.contains(&Expr::Null) && !program.expression.0.contains(&Expr::UInt64(7)));
+        assert_eq!(before, dump(&program.expression, program.function[0].code));
+        assert_eq!(start, program.location.get(program.function[0].code).unwrap().start());
+    }
+}
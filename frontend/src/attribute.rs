@@ -0,0 +1,62 @@
+use std::collections::HashMap;
+use crate::ast::Attribute;
+
+// Attributes which the passes look up: `#[name]` or `#[name(arg, ...)]`
+// in front of a function or a statement. An attribute is only a name in
+// the tree, it means something to the pass which looks for it, so a
+// feature of a pass needs no keyword of its own. The standard ones are
+//   #[inline]   function: inlined at its calls (`optimizer::Inlining`)
+//   #[test]     function: run by the test runner whatever its name
+//   #[memoize]  function: the interpreter keeps its results by the arguments
+//   #[cfg(..)]  both: removed unless the features are enabled (`crate::cfg`)
+// The type checker rejects an attribute which is not registered for the
+// place where it is written (`TypeCheckErrorKind::InvalidAttribute`).
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum AttributeTarget {
+    Function,
+    Statement,
+    Any,
+}
+
+#[derive(Debug, Clone)]
+pub struct AttributeRegistry {
+    known: HashMap<String, AttributeTarget>,
+}
+
+impl AttributeRegistry {
+    // The standard attributes
+    pub fn new() -> Self {
+        let mut registry = Self::empty();
+        registry.register("inline", AttributeTarget::Function);
+        registry.register("test", AttributeTarget::Function);
+        registry.register("memoize", AttributeTarget::Function);
+        registry.register("cfg", AttributeTarget::Any);
+        registry
+    }
+
+    pub fn empty() -> Self {
+        AttributeRegistry { known: HashMap::new() }
+    }
+
+    // Accept `#[name]` at `target`, e.g. for a pass of the embedder
+    pub fn register(&mut self, name: &str, target: AttributeTarget) {
+        self.known.insert(name.to_string(), target);
+    }
+
+    // The attribute can be written in front of a function, or of a statement
+    pub fn allows(&self, attribute: &Attribute, function: bool) -> bool {
+        match self.known.get(&attribute.name) {
+            Some(AttributeTarget::Any) => true,
+            Some(AttributeTarget::Function) => function,
+            Some(AttributeTarget::Statement) => !function,
+            None => false,
+        }
+    }
+}
+
+impl Default for AttributeRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
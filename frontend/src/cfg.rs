@@ -0,0 +1,79 @@
+use std::collections::HashSet;
+use crate::ast::{Attribute, Expr, Program};
+
+// Conditional compilation: a function or a statement marked
+// `#[cfg(feature, ...)]` is kept only when all the features are enabled,
+// and `!feature` when it is not. The features are given by the embedder
+// (`TypeCheckContext::set_feature`) or by `--cfg=feature` on the command
+// line, and the program is configured once it is parsed, before it is
+// checked, so a function may be defined for each configuration.
+
+// The attributes don't exclude the item
+pub fn enabled(attributes: &[Attribute], features: &HashSet<String>) -> bool {
+    attributes.iter().filter(|a| a.name == "cfg").flat_map(|a| &a.args).all(|arg| match arg.strip_prefix('!') {
+        Some(feature) => !features.contains(feature),
+        None => features.contains(arg),
+    })
+}
+
+// Remove the excluded functions and statements. The result is the
+// number of removed ones, the expressions are left in the pool (see
+// `Program::compact`).
+pub fn configure(program: &mut Program, features: &HashSet<String>) -> usize {
+    let functions = program.function.len();
+    program.function.retain(|f| enabled(&f.attributes, features));
+    let mut removed = functions - program.function.len();
+
+    let mut excluded: HashSet<u32> = program.attributes.iter()
+        .filter(|(_, attributes)| !enabled(attributes, features))
+        .map(|(e, _)| *e)
+        .collect();
+    for (i, e) in program.expression.0.iter().enumerate() {
+        if let Expr::Function(f) = e {
+            if !enabled(&f.attributes, features) {
+                excluded.insert(i as u32);
+            }
+        }
+    }
+    if excluded.is_empty() {
+        return removed;
+    }
+    for e in &mut program.expression.0 {
+        if let Expr::Block(statements) = e {
+            let len = statements.len();
+            statements.retain(|s| !excluded.contains(&s.0));
+            removed += len - statements.len();
+        }
+    }
+    removed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::dump;
+    use crate::Parser;
+
+    #[test]
+    fn configure_program() {
+        let source = "#[cfg(debug)]\nfn level() -> u64 {\n2u64\n}\n#[cfg(!debug)]\nfn level() -> u64 {\n0u64\n}\n\
+            fn main() -> u64 {\nvar n = level()\n#[cfg(debug, trace)]\nn = n + 1u64\n#[cfg(!trace)]\nfn g() -> u64 {\n1u64\n}\nn\n}";
+        let configured = |features: &[&str]| {
+            let mut program = Parser::new(source).parse_program().unwrap();
+            let features: HashSet<String> = features.iter().map(|f| f.to_string()).collect();
+            let removed = configure(&mut program, &features);
+            let main = program.function.iter().find(|f| f.name == "main").unwrap();
+            let level = program.function.iter().find(|f| f.name == "level").unwrap();
+            let statements = match program.get(main.code.0) {
+                Some(Expr::Block(statements)) => statements.len(),
+                _ => 0,
+            };
+            (removed, dump(&program.expression, level.code), statements)
+        };
+        assert_eq!((2, "Block\n  UInt64(0)\n".to_string(), 3), configured(&[]));
+        assert_eq!((2, "Block\n  UInt64(2)\n".to_string(), 3), configured(&["debug"]));
+        // `n = n + 1u64` is kept and `g` is removed
+        assert_eq!((2, "Block\n  UInt64(2)\n".to_string(), 3), configured(&["debug", "trace"]));
+        assert_eq!(3, configured(&["trace"]).0);
+    }
+}
@@ -0,0 +1,46 @@
+use crate::ast::{Expr, Program};
+
+// Calls of `const fn`s evaluated while the program is type checked (see
+// `TypeCheckContext::evaluate_constants`), e.g. a `val` initialized by
+// `f(10u64)`. A `const fn` only calls other `const fn`s and doesn't
+// spawn (`TypeCheckErrorKind::NotConst`), so the result of a call depends
+// on nothing but its arguments. The evaluator is given by the embedder:
+// the interpreter has one which runs the functions without capabilities
+// and with limited steps, so a call which fails or doesn't end is left
+// to the runtime.
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ConstValue {
+    Bool(bool),
+    Int64(i64),
+    UInt64(u64),
+}
+
+impl ConstValue {
+    // The value of a literal
+    pub fn of(e: &Expr) -> Option<ConstValue> {
+        match e {
+            Expr::Int64(v) => Some(ConstValue::Int64(*v)),
+            Expr::UInt64(v) => Some(ConstValue::UInt64(*v)),
+            _ => None,
+        }
+    }
+
+    // The literal of the value. There is no literal of bool, it is
+    // written as a comparison.
+    pub fn to_expr(self) -> Option<Expr> {
+        match self {
+            ConstValue::Int64(v) => Some(Expr::Int64(v)),
+            ConstValue::UInt64(v) => Some(Expr::UInt64(v)),
+            ConstValue::Bool(_) => None,
+        }
+    }
+}
+
+pub trait ConstEvaluator {
+    // Called with the checked program before its calls are evaluated
+    fn load(&mut self, program: &Program);
+
+    // Result of the `const fn` `name`, None when it cannot be evaluated
+    fn call(&mut self, name: &str, args: &[ConstValue]) -> Option<ConstValue>;
+}
@@ -0,0 +1,141 @@
+use crate::ast::Node;
+use crate::line::LineIndex;
+use crate::type_checker::TypeCheckError;
+
+// Terminal rendering of errors with the source line they point to:
+//
+//   error[E0001]: type mismatch: expected UInt64 but Int64
+//    --> a.toy:2:5
+//     |
+//   2 |     1i64
+//     |     ^^^^
+//     = note: for more information, run with `--explain E0001`
+//
+// A range over several lines is underlined to the end of its first line.
+// Colors are ANSI escapes, off by default.
+
+const RED: &str = "\x1b[1;31m";
+const BLUE: &str = "\x1b[1;34m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub code: Option<&'static str>,
+    pub message: String,
+    pub location: Option<Node>,
+    pub note: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn new(code: Option<&'static str>, message: impl Into<String>, location: Option<Node>) -> Self {
+        let note = code.map(|code| format!("for more information, run with `--explain {}`", code));
+        Diagnostic { code, message: message.into(), location, note }
+    }
+}
+
+impl From<&TypeCheckError> for Diagnostic {
+    fn from(e: &TypeCheckError) -> Self {
+        Diagnostic::new(Some(e.kind.code()), e.kind.to_string(), e.location.clone())
+    }
+}
+
+pub struct ErrorFormatter<'a> {
+    source: &'a str,
+    lines: LineIndex,
+    file: Option<String>,
+    color: bool,
+}
+
+impl<'a> ErrorFormatter<'a> {
+    pub fn new(source: &'a str) -> Self {
+        ErrorFormatter { source, lines: LineIndex::new(source), file: None, color: false }
+    }
+
+    // Name of the source shown in front of the line number
+    pub fn set_file(&mut self, file: &str) {
+        self.file = Some(file.to_string());
+    }
+
+    pub fn set_color(&mut self, color: bool) {
+        self.color = color;
+    }
+
+    fn paint(&self, color: &str, text: &str) -> String {
+        if self.color {
+            format!("{}{}{}", color, text, RESET)
+        } else {
+            text.to_string()
+        }
+    }
+
+    pub fn format(&self, diagnostic: &Diagnostic) -> String {
+        let label = match diagnostic.code {
+            Some(code) => format!("error[{}]", code),
+            None => "error".to_string(),
+        };
+        let mut out = format!("{}{}\n", self.paint(RED, &label), self.paint(BOLD, &format!(": {}", diagnostic.message)));
+
+        let location = diagnostic.location.as_ref().filter(|node| node.start() <= self.source.len());
+        let line = location.map(|node| self.lines.line_col(node.start()));
+        let width = line.map_or(1, |(line, _)| line.to_string().len());
+        let gutter = " ".repeat(width);
+        match (&self.file, line) {
+            (Some(file), Some((line, column))) => out += &format!("{}{} {}:{}:{}\n", gutter, self.paint(BLUE, "-->"), file, line, column),
+            (None, Some((line, column))) => out += &format!("{}{} {}:{}\n", gutter, self.paint(BLUE, "-->"), line, column),
+            (Some(file), None) => out += &format!("{}{} {}\n", gutter, self.paint(BLUE, "-->"), file),
+            (None, None) => (),
+        }
+
+        if let (Some(node), Some((line, column))) = (location, line) {
+            let start = node.start() - (column - 1);
+            let text = self.source[start..].lines().next().unwrap_or("");
+            let bar = self.paint(BLUE, "|");
+            let prefix: String = text[..column - 1].chars().map(|c| if c == '\t' { '\t' } else { ' ' }).collect();
+            let end = node.end().clamp(node.start(), start + text.len());
+            let carets = self.source[node.start()..end].chars().count().max(1);
+            out += &format!("{} {}\n", gutter, bar);
+            out += &format!("{} {} {}\n", self.paint(BLUE, &line.to_string()), bar, text);
+            out += &format!("{} {} {}{}\n", gutter, bar, prefix, self.paint(RED, &"^".repeat(carets)));
+        }
+        if let Some(note) = &diagnostic.note {
+            out += &format!("{} {} note: {}\n", gutter, self.paint(BLUE, "="), note);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::type_checker::TypeCheckContext;
+
+    #[test]
+    fn format_diagnostic() {
+        let source = "fn main() -> u64 {\n    val a = 1u64\n    a + 1i64\n}\n";
+        let program = crate::Parser::new(source).parse_program().unwrap();
+        let errors = TypeCheckContext::new().check_program(&program).unwrap_err();
+        let mut formatter = ErrorFormatter::new(source);
+        formatter.set_file("a.toy");
+        assert_eq!(concat!(
+            "error[E0001]: type mismatch: expected UInt64 but Int64\n",
+            " --> a.toy:3:5\n",
+            "  |\n",
+            "3 |     a + 1i64\n",
+            "  |     ^^^^^^^^\n",
+            "  = note: for more information, run with `--explain E0001`\n",
+        ), formatter.format(&Diagnostic::from(&errors[0])));
+
+        // no location, colored
+        let mut formatter = ErrorFormatter::new(source);
+        formatter.set_color(true);
+        let mut diagnostic = Diagnostic::new(None, "failed", None);
+        diagnostic.note = Some("a note".to_string());
+        assert_eq!("\x1b[1;31merror\x1b[0m\x1b[1m: failed\x1b[0m\n  \x1b[1;34m=\x1b[0m note: a note\n", formatter.format(&diagnostic));
+
+        // a range over lines is underlined to the end of the first line
+        let formatter = ErrorFormatter::new(source);
+        let text = formatter.format(&Diagnostic::new(None, "block", Some(Node::new(17, 40))));
+        assert!(text.ends_with("1 | fn main() -> u64 {\n  |                  ^\n"), "{}", text);
+    }
+}
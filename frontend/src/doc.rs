@@ -0,0 +1,106 @@
+use crate::ast::{Program, Type};
+use crate::type_checker::TypeCheckContext;
+
+// Documentation of a program: the signature of each function with its
+// `///` comment. A return type left unknown by the parser is shown as
+// inferred by the type checker. A blank `///` line separates paragraphs.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DocFormat {
+    Markdown,
+    Html,
+}
+
+// The builtins the program calls must be declared in `ctx`
+pub fn render(title: &str, program: &Program, ctx: &mut TypeCheckContext, format: DocFormat) -> String {
+    // registers the signatures of all the functions, errors are left to `check`
+    let _ = ctx.check_program(program);
+
+    let mut out = match format {
+        DocFormat::Markdown => format!("# {}\n", title),
+        DocFormat::Html => format!(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{0}</title>\n</head>\n<body>\n<h1>{0}</h1>\n",
+            escape(title)
+        ),
+    };
+    for f in &program.function {
+        let declared = f.return_type.clone().unwrap_or(Type::Unit);
+        let return_type = match declared {
+            Type::Unknown => ctx.check_function(f, &program.expression, &program.location).unwrap_or(Type::Unknown),
+            ty => ty,
+        };
+        let parameter: Vec<String> = f.parameter.iter().map(|(name, ty)| format!("{}: {}", name, type_name(ty))).collect();
+        let signature = format!("{}fn {}({}) -> {}", if f.is_const { "const " } else { "" }, f.name, parameter.join(", "), type_name(&return_type));
+        let paragraphs: Vec<&str> = f.doc.as_deref().map_or(vec![], |doc| doc.split("\n\n").collect());
+        match format {
+            DocFormat::Markdown => {
+                out += &format!("\n## {}\n\n```\n{}\n```\n", f.name, signature);
+                for p in paragraphs {
+                    out += &format!("\n{}\n", p);
+                }
+            }
+            DocFormat::Html => {
+                out += &format!("<h2 id=\"{0}\">{0}</h2>\n<pre><code>{1}</code></pre>\n", escape(&f.name), escape(&signature));
+                for p in paragraphs {
+                    out += &format!("<p>{}</p>\n", escape(p));
+                }
+            }
+        }
+    }
+    if format == DocFormat::Html {
+        out += "</body>\n</html>\n";
+    }
+    out
+}
+
+fn type_name(ty: &Type) -> String {
+    match ty {
+        Type::Int64 => "i64".to_string(),
+        Type::UInt64 => "u64".to_string(),
+        Type::Bool => "bool".to_string(),
+        Type::Unit => "()".to_string(),
+        Type::Identifier(name) => name.clone(),
+        Type::Unknown => "_".to_string(),
+        Type::Task(ty) => format!("task<{}>", type_name(ty)),
+        Type::Channel(ty) => format!("channel<{}>", type_name(ty)),
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CODE: &str = r#"
+/// Fibonacci number of `n`.
+///
+/// Slow for n > 30.
+fn fib(n: u64) -> u64 {
+    if n < 2u64 { n } else { fib(n - 1u64) + fib(n - 2u64) }
+}
+// not a doc comment
+fn main() -> bool {
+    fib(10u64) < 100u64
+}
+"#;
+
+    #[test]
+    fn render_documentation() {
+        let mut program = crate::Parser::new(CODE).parse_program().unwrap();
+        assert_eq!(Some("Fibonacci number of `n`.\n\nSlow for n > 30."), program.function[0].doc.as_deref());
+        assert_eq!(None, program.function[1].doc);
+        program.function[1].return_type = Some(Type::Unknown);
+
+        let markdown = render("fib", &program, &mut TypeCheckContext::new(), DocFormat::Markdown);
+        assert_eq!(
+            "# fib\n\n## fib\n\n```\nfn fib(n: u64) -> u64\n```\n\nFibonacci number of `n`.\n\nSlow for n > 30.\n\n## main\n\n```\nfn main() -> bool\n```\n",
+            markdown
+        );
+        let html = render("fib", &program, &mut TypeCheckContext::new(), DocFormat::Html);
+        assert!(html.contains("<h2 id=\"fib\">fib</h2>\n<pre><code>fn fib(n: u64) -&gt; u64</code></pre>\n<p>Fibonacci number of `n`.</p>\n<p>Slow for n &gt; 30.</p>\n"));
+        assert!(html.ends_with("<pre><code>fn main() -&gt; bool</code></pre>\n</body>\n</html>\n"));
+    }
+}
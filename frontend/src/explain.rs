@@ -0,0 +1,188 @@
+// Longer descriptions of the error codes, for `--explain`.
+//   E0001..  type errors (`TypeCheckErrorKind::code`)
+//   E0100..  syntax errors (`ParseError::code`)
+// A code is never reused for another error once it is released.
+
+const EXPLANATIONS: &[(&str, &str)] = &[
+    ("E0001", r#"A value has a different type than the one expected.
+
+There is no implicit conversion between i64, u64 and bool. Both operands
+of an arithmetic or comparison operator, the branches of `if` and the
+value returned by a function must have the same type.
+
+    fn f() -> u64 {
+        1i64              // error: expected UInt64 but Int64
+    }
+
+Write the literal with the expected suffix (`1u64`) or leave the suffix
+out so that the type is inferred.
+"#),
+    ("E0002", r#"A variable is used but not defined in the scope.
+
+    fn f() -> u64 {
+        x + 1u64          // error: `x` is not defined
+    }
+
+Define it with `val` or `var` before its use. A variable defined in a
+block is not visible after the block.
+"#),
+    ("E0003", r#"A function is called but neither the program nor the builtins
+define it.
+
+    fn main() -> u64 {
+        fib(10u64)        // error: `fib` is not defined
+    }
+
+Check the spelling, or define the function in the program (or in
+another module of the project).
+"#),
+    ("E0004", r#"A type name is not known.
+
+    val x: u32 = 1        // error: there is no type `u32`
+
+The types are i64, u64 and bool.
+"#),
+    ("E0005", r#"A function is called with a wrong number of arguments.
+
+    fn add(a: u64, b: u64) -> u64 { a + b }
+    add(1u64)             // error: takes 2 argument(s) but 1 given
+"#),
+    ("E0006", r#"A variable defined by `val` is assigned again.
+
+    val x = 1u64
+    x = 2u64              // error
+
+Define it with `var` to change it later.
+"#),
+    ("E0007", r#"The left hand side of `=` is not a variable.
+
+    f() = 1u64            // error
+
+Only a variable defined by `var` can be assigned.
+"#),
+    ("E0008", r#"The type of a variable cannot be known.
+
+    var x                 // error: neither a type nor a value
+    val ch = channel()    // error: the element type is not known
+
+Give it a type (`var x: u64`, `val ch: channel<u64> = channel()`) or
+an initial value (`var x = 0u64`).
+"#),
+    ("E0009", r#"An integer literal does not fit in its type or cannot have it.
+
+    val x: u64 = -1       // error: u64 cannot be negative
+
+Use a type which holds the value, e.g. i64 for a negative number.
+"#),
+    ("E0010", r#"The tree of the program refers to an expression which doesn't
+exist. The program was not built by the parser; report it as a bug of
+the tool which built it.
+"#),
+    ("E0011", r#"A value which cannot be moved to another thread is passed to a
+spawned function.
+
+    val t = spawn f(1u64)
+    spawn g(t)            // error: a task handle stays in its thread
+
+The arguments of `spawn` are copied to the new thread, so only i64,
+u64, bool and () values can be passed. Join the task first and pass its
+result instead.
+"#),
+    ("E0012", r#"A function is defined again with other parameter or return
+types, e.g. in the REPL.
+
+    fn fib(n: u64) -> u64 { n }
+    fn fib(n: i64) -> i64 { n }    // error: the callers expect u64
+
+The callers are checked with the first definition and are not checked
+again, so only the body of a function can be replaced.
+"#),
+    ("E0013", r#"A `const fn` calls a function which is not a `const fn`, a
+builtin or `dbg`, or spawns a function.
+
+    fn now() -> u64 { 1u64 }
+    const fn size() -> u64 {
+        now() * 2u64      // error: `now` is not a const fn
+    }
+
+A call of a `const fn` whose arguments are constants may be evaluated
+while the program is checked, so it must not depend on anything but its
+arguments. Mark the callee `const` as well, or remove `const`.
+"#),
+    ("E0014", r#"An attribute is not known, or is written where it has no
+meaning.
+
+    #[tets]               // error: unknown attribute `tets`
+    fn check() -> u64 { 1u64 }
+
+The standard attributes `inline`, `test` and `memoize` are written in
+front of a function, and `cfg` in front of a function or a statement. An embedder registers its own attributes with
+`TypeCheckContext::attributes_mut`.
+"#),
+    ("E0100", r#"The parser found a token (or the end of the input) where it
+cannot be.
+
+    fn main() u64 {       // error: `->` expected before the return type
+        1u64
+    }
+
+Look at the source just before the location: an unclosed `(` or `{`
+is often the cause.
+"#),
+    ("E0101", r#"The syntax is not implemented yet. At the top level of a
+program only functions can be defined:
+
+    val x = 1u64          // error: not in a function
+    fn main() -> u64 { 1u64 }
+"#),
+    ("E0102", r#"An integer literal is out of range for its declared type.
+
+    val x: u64 = 300000000000000000000   // error: larger than u64::MAX
+"#),
+];
+
+pub fn explain(code: &str) -> Option<&'static str> {
+    EXPLANATIONS.iter().find(|(c, _)| c.eq_ignore_ascii_case(code)).map(|(_, text)| *text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::Type;
+    use crate::type_checker::TypeCheckErrorKind;
+    use crate::ParseError;
+
+    #[test]
+    fn explain_codes() {
+        let kinds = [
+            TypeCheckErrorKind::TypeMismatch { expected: Type::UInt64, actual: Type::Int64 },
+            TypeCheckErrorKind::UndefinedVariable("x".to_string()),
+            TypeCheckErrorKind::UndefinedFunction("f".to_string()),
+            TypeCheckErrorKind::UnknownType("t".to_string()),
+            TypeCheckErrorKind::ArgumentCount { name: "f".to_string(), expected: 1, actual: 2 },
+            TypeCheckErrorKind::AssignToImmutable("x".to_string()),
+            TypeCheckErrorKind::InvalidAssignTarget,
+            TypeCheckErrorKind::Uninitialized("x".to_string()),
+            TypeCheckErrorKind::InvalidLiteral(String::new()),
+            TypeCheckErrorKind::InvalidExprRef(crate::ast::ExprRef(0)),
+            TypeCheckErrorKind::NotSendable(Type::Unit),
+            TypeCheckErrorKind::Redefinition("f".to_string()),
+            TypeCheckErrorKind::NotConst("f".to_string()),
+            TypeCheckErrorKind::InvalidAttribute("a".to_string()),
+        ];
+        for (i, kind) in kinds.iter().enumerate() {
+            assert_eq!(format!("E{:04}", i + 1), kind.code());
+            assert!(explain(kind.code()).is_some());
+        }
+        assert!(explain("e0001").unwrap().starts_with("A value has a different type"));
+        assert_eq!(None, explain("E9999"));
+
+        let code = |source| crate::Parser::new(source).parse_program().err().and_then(|e| ParseError::code_of(&e));
+        assert_eq!(Some("E0100"), code("fn main() u64 {\n1u64\n}"));
+        assert_eq!(Some("E0101"), code("val x = 1u64"));
+        assert_eq!(Some("E0102"), code("fn main() -> u64 {\nval x: u64 = 300000000000000000000\nx\n}"));
+        for code in ["E0100", "E0101", "E0102"] {
+            assert!(explain(code).is_some());
+        }
+    }
+}
@@ -0,0 +1,202 @@
+use anyhow::{anyhow, Result};
+use crate::ast::{Attribute, Expr, Function, Node, Program};
+use crate::token::{Kind, Token};
+use crate::Parser;
+
+// Source code formatter.
+//
+// The source is reprinted from its tokens, including the comments, with
+//   * 4 spaces of indentation for each level of braces
+//   * one space around operators and after `,` and `:`
+//   * `{` at the end of the line, `}` on its own line (`} else {`)
+//   * at most one blank line, none after `{` or before `}`
+// The text of each token is kept as it is written (e.g. literals).
+// The formatted source must parse to the same program, otherwise it is
+// an error rather than a change of the meaning.
+
+const INDENT: &str = "    ";
+
+pub fn format(source: &str) -> Result<String> {
+    let program = Parser::new(source).parse_program()?;
+    let tokens = match crate::lex_with_trivia(source) {
+        (tokens, None) => tokens,
+        (_, Some(position)) => return Err(anyhow!("unexpected character at {}", position)),
+    };
+    let formatted = Printer::default().print(source, &tokens);
+    match Parser::new(&formatted).parse_program() {
+        Ok(result) if same_program(&program, &result) => Ok(formatted),
+        _ => Err(anyhow!("formatting changes the program")),
+    }
+}
+
+// The same functions and expressions, the locations may differ
+fn same_program(a: &Program, b: &Program) -> bool {
+    let unlocated = |attributes: &[Attribute]| -> Vec<Attribute> {
+        attributes.iter().map(|a| Attribute { node: Node::new(0, 0), ..a.clone() }).collect()
+    };
+    let signature = |p: &Program| -> Vec<_> {
+        p.function.iter().map(|f| (f.name.clone(), f.parameter.clone(), f.return_type.clone(), f.code, unlocated(&f.attributes))).collect()
+    };
+    // nested functions keep their location in the tree
+    let expression = |p: &Program| -> Vec<Expr> {
+        p.expression.0.iter().map(|e| match e {
+            Expr::Function(f) => Expr::Function(Box::new(Function {
+                node: Node::new(0, 0),
+                attributes: unlocated(&f.attributes),
+                ..f.as_ref().clone()
+            })),
+            e => e.clone(),
+        }).collect()
+    };
+    let statements = |p: &Program| -> Vec<_> {
+        let mut statements: Vec<_> = p.attributes.iter().map(|(e, attributes)| (*e, unlocated(attributes))).collect();
+        statements.sort_by_key(|(e, _)| *e);
+        statements
+    };
+    expression(a) == expression(b) && signature(a) == signature(b) && statements(a) == statements(b)
+}
+
+#[derive(Default)]
+struct Printer {
+    lines: Vec<String>,
+    line: String,
+    depth: usize,
+    newlines: usize, // in the source since the last token
+    prev: Option<Kind>, // the last token of the line
+}
+
+impl Printer {
+    fn print(mut self, source: &str, tokens: &[Token]) -> String {
+        let mut i = 0;
+        while i < tokens.len() {
+            let t = &tokens[i];
+            let text = &source[t.position.clone()];
+            match &t.kind {
+                Kind::Whitespace(_) => (),
+                Kind::NewLine => {
+                    self.end_line();
+                    self.newlines += 1;
+                }
+                Kind::Comment(_) | Kind::DocComment(_) => {
+                    self.push(&t.kind, text, true);
+                    self.prev = None;
+                }
+                Kind::BraceOpen => {
+                    // `{}` stays empty
+                    let next = tokens[i + 1..].iter().position(|t| !matches!(t.kind, Kind::Whitespace(_) | Kind::NewLine));
+                    if let Some(n) = next.filter(|n| tokens[i + 1 + n].kind == Kind::BraceClose) {
+                        self.push(&t.kind, "{}", true);
+                        i += n + 2;
+                        continue;
+                    }
+                    self.push(&t.kind, text, true);
+                    self.end_line();
+                    self.depth += 1;
+                }
+                Kind::BraceClose => {
+                    self.end_line();
+                    self.depth = self.depth.saturating_sub(1);
+                    self.push(&t.kind, text, false);
+                }
+                kind => {
+                    let space = self.prev.as_ref().is_some_and(|prev| space_between(prev, kind));
+                    self.push(kind, text, space);
+                }
+            }
+            i += 1;
+        }
+        self.end_line();
+        let mut text = self.lines.join("\n");
+        text.push('\n');
+        text
+    }
+
+    fn push(&mut self, kind: &Kind, text: &str, space: bool) {
+        if self.line.is_empty() {
+            // a blank line of the source is kept, but not at the edge of a block
+            let edge = self.lines.last().is_none_or(|last| last.ends_with('{')) || *kind == Kind::BraceClose;
+            if self.newlines >= 2 && !edge {
+                self.lines.push(String::new());
+            }
+            self.line = INDENT.repeat(self.depth);
+        } else if space {
+            self.line.push(' ');
+        }
+        self.line += text;
+        self.newlines = 0;
+        self.prev = Some(kind.clone());
+    }
+
+    fn end_line(&mut self) {
+        if !self.line.is_empty() {
+            self.lines.push(std::mem::take(&mut self.line));
+        }
+        self.prev = None;
+    }
+}
+
+fn space_between(prev: &Kind, next: &Kind) -> bool {
+    match (prev, next) {
+        (Kind::ParenOpen | Kind::BracketOpen | Kind::Exclamation | Kind::Hash | Kind::Dot | Kind::DotDot | Kind::DoubleColon, _) => false,
+        (_, Kind::ParenClose | Kind::BracketClose | Kind::Comma | Kind::Colon | Kind::Dot | Kind::DotDot | Kind::DoubleColon) => false,
+        // call
+        (Kind::Identifier(_), Kind::ParenOpen | Kind::BracketOpen) => false,
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_source() {
+        let source = r#"// fibonacci
+fn fib(n:u64)->u64{
+  if n<2u64 {n} else {fib(n - 1u64)+fib( n - 2u64 )}   // recursion
+}
+
+
+
+fn main( ) -> u64 {
+
+        var sum=0u64
+  for i in 0u64 .. 10u64 {
+     // add
+     sum = sum+fib(i)
+  }
+  while sum > 100u64 {}
+    sum
+
+}
+"#;
+        let expected = r#"// fibonacci
+fn fib(n: u64) -> u64 {
+    if n < 2u64 {
+        n
+    } else {
+        fib(n - 1u64) + fib(n - 2u64)
+    } // recursion
+}
+
+fn main() -> u64 {
+    var sum = 0u64
+    for i in 0u64..10u64 {
+        // add
+        sum = sum + fib(i)
+    }
+    while sum > 100u64 {}
+    sum
+}
+"#;
+        assert_eq!(expected, format(source).unwrap());
+        // formatted source is not changed
+        assert_eq!(expected, format(expected).unwrap());
+        assert!(format("fn main() -> u64 {\n1u64 +\n}").is_err());
+        // a nested function moves with the indentation
+        assert_eq!("fn main() -> u64 {\n    fn one() -> u64 {\n        1u64\n    }\n    one()\n}\n",
+                   format("fn main() -> u64 {\nfn one() -> u64 {\n1u64\n}\none()\n}\n").unwrap());
+        assert_eq!("#[cfg(a, b)]\nfn main() -> u64 {\n    #[trace]\n    1u64\n}\n",
+                   format("#[ cfg( a,b ) ]\nfn main() -> u64 {\n#[trace]\n1u64\n}\n").unwrap());
+    }
+}
@@ -0,0 +1,105 @@
+use std::collections::HashSet;
+use std::ops::Range;
+use crate::token::Kind;
+
+// Classification of the tokens of a source for syntax highlighting.
+// It works on incomplete input too (e.g. a REPL line being typed): the
+// tokens are classified by the lexer and their neighbours, not by the
+// parser.
+
+pub type Span = Range<usize>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TokenClass {
+    Keyword,
+    Type,
+    Literal, // integers and null
+    Operator,
+    Punctuation,
+    Comment,
+    Function,   // a called or defined function
+    Variable,   // a name defined by val, var, for or a parameter in the source
+    Identifier, // any other name, e.g. a variable defined by earlier input
+    Error,      // the rest of the source after a character which is not a token
+}
+
+// Byte range and class of each token, whitespaces are not included
+pub fn highlight(source: &str) -> Vec<(Span, TokenClass)> {
+    let (tokens, error) = crate::lex_with_trivia(source);
+    let tokens: Vec<_> = tokens.into_iter().filter(|t| !matches!(t.kind, Kind::Whitespace(_) | Kind::NewLine)).collect();
+    let kind = |i: usize| tokens.get(i).map(|t| &t.kind);
+
+    let mut variables = HashSet::new();
+    for (i, t) in tokens.iter().enumerate() {
+        if let Kind::Identifier(name) = &t.kind {
+            let defined = matches!(i.checked_sub(1).and_then(kind), Some(Kind::Val | Kind::Var | Kind::For))
+                || kind(i + 1) == Some(&Kind::Colon); // parameter
+            if defined {
+                variables.insert(name.as_str());
+            }
+        }
+    }
+
+    let mut classes = vec![];
+    for (i, t) in tokens.iter().enumerate() {
+        let class = match &t.kind {
+            Kind::Identifier(name) => {
+                let function = kind(i + 1) == Some(&Kind::ParenOpen)
+                    || i.checked_sub(1).and_then(kind) == Some(&Kind::Function);
+                if function {
+                    TokenClass::Function
+                } else if variables.contains(name.as_str()) {
+                    TokenClass::Variable
+                } else {
+                    TokenClass::Identifier
+                }
+            }
+            kind => class(kind),
+        };
+        classes.push((t.position.clone(), class));
+    }
+    if let Some(start) = error {
+        classes.push((start..source.len(), TokenClass::Error));
+    }
+    classes
+}
+
+fn class(kind: &Kind) -> TokenClass {
+    match kind {
+        Kind::If | Kind::Else | Kind::For | Kind::While | Kind::In | Kind::Break | Kind::Continue | Kind::Class
+        | Kind::Struct | Kind::Function | Kind::Return | Kind::Extern | Kind::Public | Kind::Val | Kind::Var | Kind::Spawn | Kind::Const | Kind::Include =>
+            TokenClass::Keyword,
+        Kind::U64 | Kind::I64 | Kind::Bool | Kind::USize | Kind::Ptr => TokenClass::Type,
+        Kind::Int64(_) | Kind::UInt64(_) | Kind::Integer(_) | Kind::Str(_) | Kind::Null => TokenClass::Literal,
+        Kind::Comment(_) | Kind::DocComment(_) => TokenClass::Comment,
+        Kind::ParenOpen | Kind::ParenClose | Kind::BraceOpen | Kind::BraceClose | Kind::BracketOpen
+        | Kind::BracketClose | Kind::Comma | Kind::Dot | Kind::Colon | Kind::DoubleColon | Kind::Hash => TokenClass::Punctuation,
+        _ => TokenClass::Operator,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify_tokens() {
+        let source = "fn f(a: u64) -> u64 { // one\n  val b = g(a) + c\n}";
+        let classes: Vec<(&str, TokenClass)> = highlight(source).into_iter().map(|(span, c)| (&source[span], c)).collect();
+        assert_eq!(vec![
+            ("fn", TokenClass::Keyword), ("f", TokenClass::Function), ("(", TokenClass::Punctuation),
+            ("a", TokenClass::Variable), (":", TokenClass::Punctuation), ("u64", TokenClass::Type),
+            (")", TokenClass::Punctuation), ("->", TokenClass::Operator), ("u64", TokenClass::Type),
+            ("{", TokenClass::Punctuation), ("// one", TokenClass::Comment), ("val", TokenClass::Keyword),
+            ("b", TokenClass::Variable), ("=", TokenClass::Operator), ("g", TokenClass::Function),
+            ("(", TokenClass::Punctuation), ("a", TokenClass::Variable), (")", TokenClass::Punctuation),
+            ("+", TokenClass::Operator), ("c", TokenClass::Identifier), ("}", TokenClass::Punctuation),
+        ], classes);
+
+        // incomplete input
+        let classes = highlight("if 1u64 < $x");
+        assert_eq!((0..2, TokenClass::Keyword), classes[0]);
+        assert_eq!((3..7, TokenClass::Literal), classes[1]);
+        assert_eq!(Some(&(10..12, TokenClass::Error)), classes.last());
+    }
+}
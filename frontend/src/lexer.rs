@@ -0,0 +1,204 @@
+use std::ops::Range;
+use crate::token::{Token, Kind};
+
+// Tokens of a source, with the byte range of each. The longest token
+// wins, and a keyword wins over an identifier of the same text:
+//
+//   -12i64  Int64         12u64  UInt64         -12  Integer("-12")
+//   a-1     Identifier, Integer("-1")
+//   "a.toy" Str("a.toy"), which doesn't span lines and has no escapes
+//
+// Whitespaces and `//` comments are returned only with `trivia`; `///`
+// doc comments and newlines always are. Like `\n`, each of the other
+// line terminators (`\r`, U+2028, ...) is a NewLine. An error doesn't stop the lexer:
+// the next call continues after the bad character or literal.
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    Eof,
+    Unmatch(Range<usize>),        // a character which starts no token
+    InvalidLiteral(Range<usize>), // i64/u64 literal out of its range
+}
+
+pub struct Lexer<'a> {
+    input: &'a str,
+    position: usize,
+    line: usize,
+    trivia: bool,
+}
+
+const KEYWORDS: &[(&str, Kind)] = &[
+    ("if", Kind::If),
+    ("else", Kind::Else),
+    ("for", Kind::For),
+    ("while", Kind::While),
+    ("in", Kind::In),
+    ("break", Kind::Break),
+    ("continue", Kind::Continue),
+    ("class", Kind::Class),
+    ("struct", Kind::Struct),
+    ("fn", Kind::Function),
+    ("return", Kind::Return),
+    ("extern", Kind::Extern),
+    ("pub", Kind::Public),
+    ("val", Kind::Val),
+    ("var", Kind::Var),
+    ("spawn", Kind::Spawn),
+    ("const", Kind::Const),
+    ("include", Kind::Include),
+    ("u64", Kind::U64),
+    ("i64", Kind::I64),
+    ("bool", Kind::Bool),
+    ("ptr", Kind::Ptr),
+    ("usize", Kind::USize),
+    ("null", Kind::Null),
+];
+
+// longest first where one is a prefix of another
+const PUNCTUATIONS: &[(&str, Kind)] = &[
+    ("(", Kind::ParenOpen),
+    (")", Kind::ParenClose),
+    ("{", Kind::BraceOpen),
+    ("}", Kind::BraceClose),
+    ("[", Kind::BracketOpen),
+    ("]", Kind::BracketClose),
+    (",", Kind::Comma),
+    ("..", Kind::DotDot),
+    (".", Kind::Dot),
+    ("::", Kind::DoubleColon),
+    (":", Kind::Colon),
+    ("->", Kind::Arrow),
+    ("!=", Kind::NotEqual),
+    ("!", Kind::Exclamation),
+    ("#", Kind::Hash),
+    ("==", Kind::DoubleEqual),
+    ("=", Kind::Equal),
+    ("<=", Kind::LE),
+    ("<", Kind::LT),
+    (">=", Kind::GE),
+    (">", Kind::GT),
+    ("&&", Kind::DoubleAnd),
+    ("||", Kind::DoubleOr),
+    ("+", Kind::IAdd),
+    ("-", Kind::ISub),
+    ("*", Kind::IMul),
+    ("/", Kind::IDiv),
+];
+
+fn is_line_terminator(c: char) -> bool {
+    matches!(c, '\n' | '\r' | '\u{b}' | '\u{c}' | '\u{85}' | '\u{2028}' | '\u{2029}')
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(input: &'a str, trivia: bool) -> Self {
+        Lexer { input, position: 0, line: 1, trivia }
+    }
+
+    pub fn trivia(&self) -> bool {
+        self.trivia
+    }
+
+    pub fn input(&self) -> &'a str {
+        self.input
+    }
+
+    // Line of the next token, from 1
+    #[allow(dead_code)]
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    pub fn next_token(&mut self) -> Result<Token, Error> {
+        loop {
+            let (input, start) = (self.input, self.position);
+            let rest = &input[start..];
+            let c = match rest.chars().next() {
+                Some(c) => c,
+                None => return Err(Error::Eof),
+            };
+            let bytes = rest.as_bytes();
+            let digits = |from: usize| bytes[from..].iter().take_while(|b| b.is_ascii_digit()).count();
+            let kind = match c {
+                c if is_line_terminator(c) => {
+                    self.line += 1;
+                    self.take(c.len_utf8(), Kind::NewLine)
+                }
+                ' ' | '\t' => {
+                    let len = bytes.iter().take_while(|b| matches!(b, b' ' | b'\t')).count();
+                    let text = self.text(len);
+                    if !self.trivia {
+                        continue;
+                    }
+                    Kind::Whitespace(text)
+                }
+                '/' if rest.starts_with("//") => {
+                    let text = self.text(rest.find(is_line_terminator).unwrap_or(rest.len()));
+                    if text.starts_with("///") {
+                        Kind::DocComment(text)
+                    } else if self.trivia {
+                        Kind::Comment(text)
+                    } else {
+                        continue;
+                    }
+                }
+                '"' => match rest[1..].find(|c: char| c == '"' || is_line_terminator(c)) {
+                    Some(len) if rest[1 + len..].starts_with('"') => {
+                        let text = self.text(len + 2);
+                        Kind::Str(text[1..text.len() - 1].to_string())
+                    }
+                    _ => {
+                        self.position += 1;
+                        return Err(Error::Unmatch(start..self.position));
+                    }
+                },
+                '0'..='9' => self.number(digits(0), false)?,
+                '-' if digits(1) > 0 => self.number(1 + digits(1), true)?,
+                'A'..='Z' | 'a'..='z' | '_' => {
+                    let len = bytes.iter().take_while(|b| b.is_ascii_alphanumeric() || **b == b'_').count();
+                    let text = self.text(len);
+                    match KEYWORDS.iter().find(|(keyword, _)| *keyword == text) {
+                        Some((_, kind)) => kind.clone(),
+                        None => Kind::Identifier(text),
+                    }
+                }
+                _ => match PUNCTUATIONS.iter().find(|(text, _)| rest.starts_with(text)) {
+                    Some((text, kind)) => self.take(text.len(), kind.clone()),
+                    None => {
+                        self.position += c.len_utf8();
+                        return Err(Error::Unmatch(start..self.position));
+                    }
+                },
+            };
+            return Ok(Token { kind, position: start..self.position });
+        }
+    }
+
+    fn take(&mut self, len: usize, kind: Kind) -> Kind {
+        self.position += len;
+        kind
+    }
+
+    fn text(&mut self, len: usize) -> String {
+        let text = self.input[self.position..self.position + len].to_string();
+        self.position += len;
+        text
+    }
+
+    // `len` bytes of digits (and the sign), maybe followed by a suffix
+    fn number(&mut self, len: usize, negative: bool) -> Result<Kind, Error> {
+        let start = self.position;
+        let text = self.text(len);
+        let input = self.input;
+        let rest = &input[self.position..];
+        let value = if rest.starts_with("i64") {
+            self.position += 3;
+            text.parse().map(Kind::Int64).ok()
+        } else if rest.starts_with("u64") && !negative {
+            self.position += 3;
+            text.parse().map(Kind::UInt64).ok()
+        } else {
+            return Ok(Kind::Integer(text));
+        };
+        value.ok_or(Error::InvalidLiteral(start..self.position))
+    }
+}
@@ -1,41 +1,205 @@
 pub mod ast;
+pub mod attribute;
+pub mod cfg;
+pub mod consteval;
+pub mod diagnostic;
+pub mod doc;
+pub mod explain;
+pub mod formatter;
+pub mod highlight;
+mod lexer;
+pub mod line;
+pub mod literal;
+pub mod node_id;
+pub mod optimizer;
+pub mod prelude;
 pub mod token;
+pub mod transform;
+pub mod type_checker;
 use crate::ast::*;
 use crate::token::{Token, Kind};
+use std::collections::HashMap;
 
-use anyhow::{anyhow, Result};
+use anyhow::Result;
 
-mod lexer {
-    include!(concat!(env!("OUT_DIR"), "/lexer.rs"));
+
+pub use highlight::highlight;
+
+// Class of a syntax error, with a stable code (see `explain`). The parser
+// returns it in the `anyhow::Error`, where `ParseError::code_of` finds it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ParseErrorKind {
+    UnexpectedToken, // including the end of the input
+    Unsupported,     // syntax which is not implemented yet
+    InvalidLiteral,  // e.g. out of range for its type
+}
+
+#[derive(Debug)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    pub message: String,
+}
+
+impl ParseError {
+    pub fn code(&self) -> &'static str {
+        match self.kind {
+            ParseErrorKind::UnexpectedToken => "E0100",
+            ParseErrorKind::Unsupported => "E0101",
+            ParseErrorKind::InvalidLiteral => "E0102",
+        }
+    }
+
+    pub fn code_of(error: &anyhow::Error) -> Option<&'static str> {
+        error.downcast_ref::<ParseError>().map(ParseError::code)
+    }
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+macro_rules! syntax_error {
+    ($kind:ident, $($arg:tt)*) => {
+        anyhow::Error::new($crate::ParseError { kind: $crate::ParseErrorKind::$kind, message: format!($($arg)*) })
+    };
+}
+pub(crate) use syntax_error;
+
+// All the tokens of the source including trivia, and the position of an
+// unexpected character if the lexer stopped there
+pub(crate) fn lex_with_trivia(source: &str) -> (Vec<Token>, Option<usize>) {
+    let mut lexer = lexer::Lexer::new(source, true);
+    let mut tokens = vec![];
+    loop {
+        match lexer.next_token() {
+            Ok(t) => tokens.push(t),
+            Err(lexer::Error::Eof) => return (tokens, None),
+            Err(lexer::Error::Unmatch(position) | lexer::Error::InvalidLiteral(position)) => return (tokens, Some(position.start)),
+        }
+    }
 }
 
 pub struct Parser<'a> {
     lexer: lexer::Lexer<'a>,
     ahead: Vec<Token>,
     ast:   ExprPool,
+    location: LocationPool,
+    last: std::ops::Range<usize>, // position of the last consumed token
+    trivia: HashMap<usize, Vec<Token>>, // start position of token -> leading trivia
+    pending_trivia: Vec<Token>,
+    doc: HashMap<usize, Vec<String>>, // start position of token -> doc comment lines in front of it
+    pending_doc: Vec<String>,
+    attributes: HashMap<u32, Vec<Attribute>>, // of the statements, by ExprRef
+    lex_error: Option<lexer::Error>, // no token is read after it
 }
 
 impl<'a> Parser<'a> {
     pub fn new(input: &'a str) -> Self {
-        let lexer = lexer::Lexer::new(&input, 1u64);
+        Self::new_parser(input, false)
+    }
+
+    // keep whitespaces and comments for formatter or linter
+    pub fn with_trivia(input: &'a str) -> Self {
+        Self::new_parser(input, true)
+    }
+
+    fn new_parser(input: &'a str, trivia: bool) -> Self {
+        let lexer = lexer::Lexer::new(input, trivia);
         Parser {
             lexer,
             ahead: Vec::new(),
             ast: ExprPool::with_capacity(1024),
+            location: LocationPool::new(),
+            last: 0..0,
+            trivia: HashMap::new(),
+            pending_trivia: vec![],
+            doc: HashMap::new(),
+            pending_doc: vec![],
+            attributes: HashMap::new(),
+            lex_error: None,
+        }
+    }
+
+    // Locations of the expressions parsed by the last `parse_expression`
+    pub fn location(&self) -> &LocationPool {
+        &self.location
+    }
+
+    // Trivia (whitespaces and comments) in front of the node.
+    // It is collected only when the parser is created by `with_trivia`.
+    pub fn trivia(&self, node: &Node) -> &[Token] {
+        match self.trivia.get(&node.start()) {
+            Some(t) => t.as_slice(),
+            None => &[],
+        }
+    }
+
+    // Trivia after the last token
+    pub fn trailing_trivia(&self) -> &[Token] {
+        self.pending_trivia.as_slice()
+    }
+
+    // NewLine is a token of the grammar, but it is also kept in trivia
+    // so that comment lines in front of a node belong to that node.
+    fn lex(&mut self) -> Result<Token, lexer::Error> {
+        if let Some(e) = &self.lex_error {
+            return Err(e.clone());
+        }
+        loop {
+            let t = match self.lexer.next_token() {
+                Ok(t) => t,
+                Err(e) => {
+                    if e != lexer::Error::Eof {
+                        self.lex_error = Some(e.clone());
+                    }
+                    return Err(e);
+                }
+            };
+            match t.kind {
+                Kind::Whitespace(_) | Kind::Comment(_) => self.pending_trivia.push(t),
+                Kind::DocComment(ref text) => {
+                    let line = text.trim_start_matches('/');
+                    self.pending_doc.push(line.strip_prefix(' ').unwrap_or(line).to_string());
+                    if self.lexer.trivia() {
+                        self.pending_trivia.push(t);
+                    }
+                }
+                Kind::NewLine => {
+                    if self.lexer.trivia() {
+                        self.pending_trivia.push(t.clone());
+                    }
+                    return Ok(t);
+                }
+                _ => {
+                    if !self.pending_trivia.is_empty() {
+                        let trivia = std::mem::take(&mut self.pending_trivia);
+                        self.trivia.insert(t.position.start, trivia);
+                    }
+                    if !self.pending_doc.is_empty() {
+                        let doc = std::mem::take(&mut self.pending_doc);
+                        self.doc.insert(t.position.start, doc);
+                    }
+                    return Ok(t);
+                }
+            }
         }
     }
 
     fn peek(&mut self) -> Option<&Kind> {
         if self.ahead.is_empty() {
-            match self.lexer.yylex() {
+            match self.lex() {
                 Ok(t) => {
                     self.ahead.push(t);
-                    Some(&self.ahead.get(0).unwrap().kind)
+                    Some(&self.ahead.first().unwrap().kind)
                 }
                 _ => None,
             }
         } else {
-            match self.ahead.get(0) {
+            match self.ahead.first() {
                 Some(t) => Some(&t.kind),
                 None => None,
             }
@@ -46,7 +210,7 @@ impl<'a> Parser<'a> {
     #[allow(dead_code)]
     fn peek_n(&mut self, pos: usize) -> Option<&Kind> {
         while self.ahead.len() < pos + 1 {
-            match self.lexer.yylex() {
+            match self.lex() {
                 Ok(t) => self.ahead.push(t),
                 _ => return None,
             }
@@ -60,7 +224,7 @@ impl<'a> Parser<'a> {
     #[allow(dead_code)]
     fn peek_position_n(&mut self, pos: usize) -> Option<&std::ops::Range<usize>> {
         while self.ahead.len() < pos + 1 {
-            match self.lexer.yylex() {
+            match self.lex() {
                 Ok(t) => self.ahead.push(t),
                 _ => return None,
             }
@@ -73,11 +237,33 @@ impl<'a> Parser<'a> {
 
     #[allow(dead_code)]
     fn consume(&mut self, count: usize) -> usize {
+        if let Some(t) = self.ahead.get(count.wrapping_sub(1)) {
+            self.last = t.position.clone();
+        }
         self.ahead.drain(0..count).count()
     }
 
     fn next(&mut self) {
-        self.ahead.remove(0);
+        let t = self.ahead.remove(0);
+        self.last = t.position;
+    }
+
+    // start position of the next token
+    fn next_start(&mut self) -> usize {
+        match self.peek_position_n(0) {
+            Some(pos) => pos.start,
+            None => self.last.end,
+        }
+    }
+
+    fn add(&mut self, expr: Expr, start: usize) -> ExprRef {
+        self.location.add(Node::new(start, self.last.end));
+        self.ast.add(expr)
+    }
+
+    fn add_binary(&mut self, op: Operator, lhs: ExprRef, rhs: ExprRef) -> ExprRef {
+        let start = self.location.get(lhs).map_or(self.last.start, |n| n.start());
+        self.add(Self::new_binary(op, lhs, rhs), start)
     }
 
     pub fn expect(&mut self, accept: &Kind) -> bool {
@@ -96,7 +282,7 @@ impl<'a> Parser<'a> {
 
     pub fn expect_err(&mut self, accept: &Kind) -> Result<()> {
         if !self.expect(accept) {
-            return Err(anyhow!("{:?} expected but {:?}", accept, self.ahead.get(0)));
+            return Err(syntax_error!(UnexpectedToken, "{:?} expected but {:?}", accept, self.ahead.first()));
         }
         Ok(())
     }
@@ -106,40 +292,76 @@ impl<'a> Parser<'a> {
         self.ast.len() as u32
     }
 
-    // code := (import | fn)*
+    // code := (include | fn)*
+    // include := "include" string
     // fn := "fn" identifier "(" param_def_list* ") "->" def_ty block
     // param_def_list := e | param_def | param_def "," param_def_list
     // param_def := identifier ":" def_ty |
     // prog := expr NewLine expr | expr | e
-    // expr := assign | if_expr
+    // expr := assign | if_expr | while_expr | for_expr | fn
     // block := "{" prog* "}"
     // if_expr := "if" expr block else_expr?
-    // else_expr := "else" block
-    // assign := val_def | identifier "=" logical_expr | logical_expr
+    // else_expr := "else" block | "else" if_expr
+    // while_expr := "while" logical_expr block
+    // for_expr := "for" identifier "in" logical_expr ".." logical_expr block
+    // assign := val_def | var_def | identifier "=" logical_expr | logical_expr
     // val_def := "val" identifier (":" def_ty)? ("=" logical_expr)
-    // def_ty := Int64 | UInt64 | identifier | Unknown
-    // logical_expr := equality ("&&" relational | "||" relational)*
+    // var_def := "var" identifier (":" def_ty)? ("=" logical_expr)
+    // def_ty := Int64 | UInt64 | Bool | "channel" "<" def_ty ">" | identifier | Unknown
+    // logical_expr := equality ("&&" equality | "||" equality)*
     // equality := relational ("==" relational | "!=" relational)*
     // relational := add ("<" add | "<=" add | ">" add | ">=" add")*
     // add := mul ("+" mul | "-" mul)*
     // mul := primary ("*" mul | "/" mul)*
     // primary := "(" expr ")" | identifier "(" expr_list ")" |
-    //            identifier |
+    //            "spawn" identifier "(" expr_list ")" | identifier |
     //            UInt64 | Int64 | Integer | Null
     // expr_list = "" | expr | expr "," expr_list
 
-    // this function is for test
-    pub fn parse_stmt_line(&mut self) -> Result<(ExprRef, ExprPool)> {
-        let e = self.parse_expr();
-        if e.is_err() {
-            return Err(anyhow!(e.err().unwrap()));
+    // Entry point for a single expression (REPL input, tests).
+    // It shares the grammar and the pooled AST with `parse_program`.
+    pub fn parse_expression(&mut self) -> Result<(ExprRef, ExprPool)> {
+        let result = self.parse_input_expression();
+        self.lex_error_or(result)
+    }
+
+    fn parse_input_expression(&mut self) -> Result<(ExprRef, ExprPool)> {
+        self.location = LocationPool::new();
+        let e = self.parse_expr()?;
+        while let Some(Kind::NewLine) = self.peek() {
+            self.next();
+        }
+        match self.peek() {
+            None | Some(Kind::EOF) => (),
+            x => return Err(syntax_error!(UnexpectedToken, "parse_expression: unexpected token {:?}", x)),
         }
         let mut expr: ExprPool = ExprPool(vec![]);
         std::mem::swap(&mut expr, &mut self.ast);
-        Ok((e?, expr))
+        // Literals without a hint are left as Expr::Int here because the
+        // variables defined by previous input are not known to the parser.
+        // They are resolved by TypeCheckContext::check_expression.
+        literal::LiteralResolver::new(&mut expr).resolve(e, None)?;
+        Ok((e, expr))
     }
 
     pub fn parse_program(&mut self) -> Result<Program> {
+        let result = self.parse_functions();
+        self.lex_error_or(result)
+    }
+
+    // The tokens end at an error of the lexer, so it is the cause of the
+    // result of the parser
+    fn lex_error_or<T>(&self, result: Result<T>) -> Result<T> {
+        match &self.lex_error {
+            Some(lexer::Error::Unmatch(position)) => Err(syntax_error!(UnexpectedToken,
+                "unexpected character {:?} at {}", self.lexer.input()[position.clone()].to_string(), position.start)),
+            Some(lexer::Error::InvalidLiteral(position)) => Err(syntax_error!(InvalidLiteral,
+                "integer literal {} is out of range at {}", &self.lexer.input()[position.clone()], position.start)),
+            _ => result,
+        }
+    }
+
+    fn parse_functions(&mut self) -> Result<Program> {
         let mut start_pos: Option<usize> = None;
         let mut end_pos: Option<usize> = None;
         let mut update_start_pos = |start: usize| {
@@ -151,37 +373,24 @@ impl<'a> Parser<'a> {
             end_pos = Some(end);
         };
         let mut def_func = vec![];
+        let mut import = vec![];
         loop {
             match self.peek() {
-                // Function definition
-                Some(Kind::Function) => {
-                    let fn_start_pos = self.peek_position_n(0).unwrap().start;
-                    update_start_pos(fn_start_pos);
+                // `include "file.toy"`, the file is read by the embedder
+                Some(Kind::Include) => {
                     self.next();
                     match self.peek() {
-                        Some(Kind::Identifier(s)) => {
-                            let fn_name = s.to_string();
-                            self.next();
-
-                            self.expect_err(&Kind::ParenOpen)?;
-                            let params = self.parse_param_def_list(vec![])?;
-                            self.expect_err(&Kind::ParenClose)?;
-                            self.expect_err(&Kind::Arrow)?;
-                            let ret_ty = self.parse_def_ty()?;
-                            let block = self.parse_block()?;
-                            let fn_end_pos = self.peek_position_n(0).unwrap().end;
-                            update_end_pos(fn_end_pos);
-                            
-                            def_func.push(Function{
-                                node: Node::new(fn_start_pos, fn_end_pos),
-                                name: fn_name,
-                                parameter: params,
-                                return_type: Some(ret_ty),
-                                code: block,
-                            });
-                        }
-                        _ => return Err(anyhow!("expected function")),
+                        Some(Kind::Str(path)) => import.push(path.clone()),
+                        x => return Err(syntax_error!(UnexpectedToken, "expected file name after include but {:?}", x)),
                     }
+                    self.next();
+                }
+                // Function definition
+                Some(Kind::Function | Kind::Const | Kind::Hash) => {
+                    let f = self.parse_function()?;
+                    update_start_pos(f.node.start());
+                    update_end_pos(f.node.end());
+                    def_func.push(f);
                 }
                 Some(Kind::NewLine) => {
                     // skip
@@ -189,21 +398,105 @@ impl<'a> Parser<'a> {
                 }
                 None | Some(Kind::EOF) => break,
                 // import, etc...
-                x => return Err(anyhow!("not implemented!!: {:?}", x)),
+                x => return Err(syntax_error!(Unsupported, "not implemented!!: {:?}", x)),
             }
         }
         // TODO: update end_position each element
         // TODO: handle Err
         let mut expr = ExprPool::new();
         std::mem::swap(&mut expr, &mut self.ast);
-        Ok(Program{
+        let mut location = LocationPool::new();
+        std::mem::swap(&mut location, &mut self.location);
+        let mut program = Program{
             node: Node::new(start_pos.unwrap_or(0usize), end_pos.unwrap_or(0usize)),
-            import: vec![],
+            import,
             function: def_func,
             expression: expr,
+            location,
+            attributes: std::mem::take(&mut self.attributes),
+        };
+        literal::resolve_program(&mut program)?;
+        Ok(program)
+    }
+
+    // Definition of a function in a program or in a block, with its
+    // attributes. Only a function of the program can be a `const fn`.
+    fn parse_function(&mut self) -> Result<Function> {
+        let fn_start_pos = self.next_start();
+        let attributes = self.parse_attributes()?;
+        self.parse_function_at(fn_start_pos, attributes)
+    }
+
+    fn parse_function_at(&mut self, fn_start_pos: usize, attributes: Vec<Attribute>) -> Result<Function> {
+        let is_const = self.peek() == Some(&Kind::Const);
+        if is_const {
+            self.next();
+        }
+        self.expect_err(&Kind::Function)?;
+        let fn_name = match self.peek() {
+            Some(Kind::Identifier(s)) => s.to_string(),
+            _ => return Err(syntax_error!(UnexpectedToken, "expected function")),
+        };
+        self.next();
+
+        self.expect_err(&Kind::ParenOpen)?;
+        let params = self.parse_param_def_list(vec![])?;
+        self.expect_err(&Kind::ParenClose)?;
+        self.expect_err(&Kind::Arrow)?;
+        let ret_ty = self.parse_def_ty()?;
+        let block = self.parse_block()?;
+        let last_end = self.last.end;
+        let fn_end_pos = self.peek_position_n(0).map_or(last_end, |pos| pos.end);
+        Ok(Function {
+            node: Node::new(fn_start_pos, fn_end_pos),
+            name: fn_name,
+            parameter: params,
+            return_type: Some(ret_ty),
+            code: block,
+            doc: self.doc.remove(&fn_start_pos).map(|lines| lines.join("\n")),
+            is_const,
+            attributes,
         })
     }
 
+    // `#[name]` or `#[name(arg, ...)]`, each followed by any number of
+    // new lines. An argument is an identifier (maybe after `!`) or an
+    // integer.
+    fn parse_attributes(&mut self) -> Result<Vec<Attribute>> {
+        let mut attributes = vec![];
+        while let Some(Kind::Hash) = self.peek() {
+            let start = self.next_start();
+            self.next();
+            self.expect_err(&Kind::BracketOpen)?;
+            let name = match self.peek() {
+                Some(Kind::Identifier(s)) => s.to_string(),
+                x => return Err(syntax_error!(UnexpectedToken, "parse_attributes: expected attribute name but {:?}", x)),
+            };
+            self.next();
+            let mut args = vec![];
+            if self.expect(&Kind::ParenOpen) {
+                while !self.expect(&Kind::ParenClose) {
+                    if !args.is_empty() {
+                        self.expect_err(&Kind::Comma)?;
+                    }
+                    let not = if self.expect(&Kind::Exclamation) { "!" } else { "" };
+                    match self.peek() {
+                        Some(Kind::Identifier(s)) => args.push(format!("{}{}", not, s)),
+                        Some(Kind::Integer(s)) if not.is_empty() => args.push(s.to_string()),
+                        x => return Err(syntax_error!(UnexpectedToken, "parse_attributes: expected argument but {:?}", x)),
+                    }
+                    self.next();
+                }
+            }
+            self.expect_err(&Kind::BracketClose)?;
+            attributes.push(Attribute { node: Node::new(start, self.last.end), name, args });
+            while let Some(Kind::NewLine) = self.peek() {
+                self.next();
+            }
+        }
+        Ok(attributes)
+    }
+
     pub fn parse_param_def(&mut self) -> Result<Parameter> {
         match self.peek() {
             Some(Kind::Identifier(s)) => {
@@ -213,14 +506,13 @@ impl<'a> Parser<'a> {
                 let typ = self.parse_def_ty()?;
                 Ok((name, typ))
             }
-            x => Err(anyhow!("expect type parameter of function but: {:?}", x)),
+            x => Err(syntax_error!(UnexpectedToken, "expect type parameter of function but: {:?}", x)),
         }
     }
 
     fn parse_param_def_list(&mut self, mut args: Vec<Parameter>) -> Result<Vec<Parameter>> {
-        match self.peek() {
-            Some(Kind::ParenClose) => return Ok(args),
-            _ => (),
+        if let Some(Kind::ParenClose) = self.peek() {
+            return Ok(args);
         }
 
         let def = self.parse_param_def();
@@ -251,13 +543,8 @@ impl<'a> Parser<'a> {
         }
 
         // remove unused NewLine
-        loop {
-            match self.peek() {
-                Some(Kind::NewLine) =>
-                    self.next(),
-                Some(_) | None =>
-                    break,
-            }
+        while let Some(Kind::NewLine) = self.peek() {
+            self.next();
         }
 
         // check end of expressions (twice)
@@ -267,11 +554,22 @@ impl<'a> Parser<'a> {
             _ => (),
         }
 
+        let start = self.next_start();
+        let attributes = self.parse_attributes()?;
+        if !attributes.is_empty() && self.peek() == Some(&Kind::Function) {
+            let f = self.parse_function_at(start, attributes)?;
+            expressions.push(self.add(Expr::Function(Box::new(f)), start));
+            return self.parse_expression_block(expressions);
+        }
         let lhs = self.parse_expr();
         if lhs.is_err() {
-            return Err(anyhow!("parse_expression_block: expected expression: {:?}", lhs.err()));
+            return Err(syntax_error!(UnexpectedToken, "parse_expression_block: expected expression: {:?}", lhs.err()));
+        }
+        let lhs = lhs?;
+        if !attributes.is_empty() {
+            self.attributes.insert(lhs.0, attributes);
         }
-        expressions.push(lhs?);
+        expressions.push(lhs);
 
         self.parse_expression_block(expressions)
     }
@@ -287,15 +585,32 @@ impl<'a> Parser<'a> {
                 self.next();
                 self.parse_if()
             }
+            Some(Kind::While) => {
+                self.next();
+                self.parse_while()
+            }
+            Some(Kind::For) => {
+                self.next();
+                self.parse_for()
+            }
             Some(Kind::Val) => {
                 self.next();
                 self.parse_val_def()
             }
+            Some(Kind::Var) => {
+                self.next();
+                self.parse_var_def()
+            }
+            Some(Kind::Function) => {
+                let start = self.next_start();
+                let f = self.parse_function()?;
+                Ok(self.add(Expr::Function(Box::new(f)), start))
+            }
             Some(x) => {
-                Err(anyhow!("parse_expr: expected expression but Kind ({:?})", x))
+                Err(syntax_error!(UnexpectedToken, "parse_expr: expected expression but Kind ({:?})", x))
             }
             None => {
-                Err(anyhow!("parse_expr: expected expression but None"))
+                Err(syntax_error!(UnexpectedToken, "parse_expr: expected expression but None"))
             }
         }
     }
@@ -306,17 +621,17 @@ impl<'a> Parser<'a> {
                 self.next();
                 self.parse_val_def()
             }
+            Some(Kind::Var) => {
+                self.next();
+                self.parse_var_def()
+            }
             _ => {
                 let lhs = self.parse_logical_expr()?;
                 match self.peek() {
                     Some(Kind::Equal) => {
                         self.next();
                         let rhs = self.parse_logical_expr()?;
-                        Ok(self.ast.add(Self::new_binary(
-                            Operator::Assign,
-                            lhs,
-                            rhs),
-                        ))
+                        Ok(self.add_binary(Operator::Assign, lhs, rhs))
                     }
                     _ => Ok(lhs),
                 }
@@ -325,43 +640,88 @@ impl<'a> Parser<'a> {
     }
 
     pub fn parse_if(&mut self) -> Result<ExprRef> {
+        let start = self.last.start; // "if"
         let cond = self.parse_logical_expr()?;
         let if_block = self.parse_block()?;
 
         let else_block: ExprRef = match self.peek() {
             Some(Kind::Else) => {
                 self.next();
-                self.parse_block()?
+                match self.peek() {
+                    Some(Kind::If) => {
+                        self.next();
+                        self.parse_if()?
+                    }
+                    _ => self.parse_block()?,
+                }
+            }
+            _ => {
+                let end = self.last.end;
+                self.add(Expr::Block(vec![]), end) // through
+            }
+        };
+        Ok(self.add(Expr::IfElse(cond, if_block, else_block), start))
+    }
+
+    pub fn parse_while(&mut self) -> Result<ExprRef> {
+        let start = self.last.start; // "while"
+        let cond = self.parse_logical_expr()?;
+        let body = self.parse_block()?;
+        Ok(self.add(Expr::While(cond, body), start))
+    }
+
+    pub fn parse_for(&mut self) -> Result<ExprRef> {
+        let start = self.last.start; // "for"
+        let ident = match self.peek() {
+            Some(Kind::Identifier(s)) => {
+                let s = s.to_string();
+                self.next();
+                s
             }
-            _ => self.ast.add(Expr::Block(vec![])), // through
+            x => return Err(syntax_error!(UnexpectedToken, "parse_for: expected identifier but {:?}", x)),
         };
-        Ok(self.ast.add(Expr::IfElse(cond, if_block, else_block)))
+        self.expect_err(&Kind::In)?;
+        let range_start = self.parse_logical_expr()?;
+        self.expect_err(&Kind::DotDot)?;
+        let range_end = self.parse_logical_expr()?;
+        let body = self.parse_block()?;
+        Ok(self.add(Expr::For(ident, range_start, range_end, body), start))
     }
 
     pub fn parse_block(&mut self) -> Result<ExprRef> {
+        let start = self.next_start();
         self.expect_err(&Kind::BraceOpen)?;
         match self.peek() {
             Some(Kind::BraceClose) => {
                 // empty block
                 self.next();
-                Ok(self.ast.add(Expr::Block(vec![])))
+                Ok(self.add(Expr::Block(vec![]), start))
             }
             _ => {
                 let block = self.parse_expression_block(vec![])?;
                 self.expect_err(&Kind::BraceClose)?;
-                Ok(self.ast.add(Expr::Block(block)))
+                Ok(self.add(Expr::Block(block), start))
             }
         }
     }
 
     pub fn parse_val_def(&mut self) -> Result<ExprRef> {
+        self.parse_binding(false)
+    }
+
+    pub fn parse_var_def(&mut self) -> Result<ExprRef> {
+        self.parse_binding(true)
+    }
+
+    fn parse_binding(&mut self, mutable: bool) -> Result<ExprRef> {
+        let start = self.last.start; // "val" or "var"
         let ident: String = match self.peek() {
             Some(Kind::Identifier(s)) => {
                 let s = s.to_string();
                 self.next();
                 s
             }
-            x => return Err(anyhow!("parse_val_def: expected identifier but {:?}", x)),
+            x => return Err(syntax_error!(UnexpectedToken, "parse_binding: expected identifier but {:?}", x)),
         };
 
         let ty: Type = match self.peek() {
@@ -380,13 +740,26 @@ impl<'a> Parser<'a> {
             }
             _ => None,
         };
-        Ok(self.ast.add(Expr::Val(ident, Some(ty), rhs)))
+        let binding = if mutable {
+            Expr::Var(ident, Some(ty), rhs)
+        } else {
+            Expr::Val(ident, Some(ty), rhs)
+        };
+        Ok(self.add(binding, start))
     }
 
     fn parse_def_ty(&mut self) -> Result<Type> {
         let ty: Type = match self.peek() {
             Some(Kind::U64) => Type::UInt64,
             Some(Kind::I64) => Type::Int64,
+            Some(Kind::Bool) => Type::Bool,
+            Some(Kind::Identifier(s)) if s == "channel" => {
+                self.next();
+                self.expect_err(&Kind::LT)?;
+                let element = self.parse_def_ty()?;
+                self.expect_err(&Kind::GT)?;
+                return Ok(Type::Channel(Box::new(element)));
+            }
             Some(Kind::Identifier(s)) => {
                 let ident = s.to_string();
                 Type::Identifier(ident)
@@ -404,13 +777,13 @@ impl<'a> Parser<'a> {
             match self.peek() {
                 Some(Kind::DoubleAnd) => {
                     self.next();
-                    let rhs = self.parse_relational()?;
-                    lhs = self.ast.add(Self::new_binary(Operator::LogicalAnd, lhs, rhs));
+                    let rhs = self.parse_equality()?;
+                    lhs = self.add_binary(Operator::LogicalAnd, lhs, rhs);
                 }
                 Some(Kind::DoubleOr) => {
                     self.next();
-                    let rhs = self.parse_relational()?;
-                    lhs = self.ast.add(Self::new_binary(Operator::LogicalOr, lhs, rhs));
+                    let rhs = self.parse_equality()?;
+                    lhs = self.add_binary(Operator::LogicalOr, lhs, rhs);
                 }
                 _ => return Ok(lhs),
             }
@@ -425,12 +798,12 @@ impl<'a> Parser<'a> {
                 Some(Kind::DoubleEqual) => {
                     self.next();
                     let rhs = self.parse_relational()?;
-                    lhs = self.ast.add(Self::new_binary(Operator::EQ, lhs, rhs));
+                    lhs = self.add_binary(Operator::EQ, lhs, rhs);
                 }
                 Some(Kind::NotEqual) => {
                     self.next();
                     let rhs = self.parse_relational()?;
-                    lhs = self.ast.add(Self::new_binary(Operator::NE, lhs, rhs));
+                    lhs = self.add_binary(Operator::NE, lhs, rhs);
                 }
                 _ => return Ok(lhs),
             }
@@ -445,22 +818,22 @@ impl<'a> Parser<'a> {
                 Some(Kind::LT) => {
                     self.next();
                     let rhs = self.parse_add()?;
-                    lhs = self.ast.add(Self::new_binary(Operator::LT, lhs, rhs));
+                    lhs = self.add_binary(Operator::LT, lhs, rhs);
                 }
                 Some(Kind::LE) => {
                     self.next();
                     let rhs = self.parse_add()?;
-                    lhs = self.ast.add(Self::new_binary(Operator::LE, lhs, rhs));
+                    lhs = self.add_binary(Operator::LE, lhs, rhs);
                 }
                 Some(Kind::GT) => {
                     self.next();
                     let rhs = self.parse_add()?;
-                    lhs = self.ast.add(Self::new_binary(Operator::GT, lhs, rhs));
+                    lhs = self.add_binary(Operator::GT, lhs, rhs);
                 }
                 Some(Kind::GE) => {
                     self.next();
                     let rhs = self.parse_add()?;
-                    lhs = self.ast.add(Self::new_binary(Operator::GE, lhs, rhs))
+                    lhs = self.add_binary(Operator::GE, lhs, rhs)
                 }
                 _ => return Ok(lhs),
             }
@@ -475,12 +848,12 @@ impl<'a> Parser<'a> {
                 Some(Kind::IAdd) => {
                     self.next();
                     let rhs = self.parse_mul()?;
-                    lhs = self.ast.add(Self::new_binary(Operator::IAdd, lhs, rhs));
+                    lhs = self.add_binary(Operator::IAdd, lhs, rhs);
                 }
                 Some(Kind::ISub) => {
                     self.next();
                     let rhs = self.parse_mul()?;
-                    lhs = self.ast.add(Self::new_binary(Operator::ISub, lhs, rhs));
+                    lhs = self.add_binary(Operator::ISub, lhs, rhs);
                 }
                 _ => return Ok(lhs),
             }
@@ -495,12 +868,12 @@ impl<'a> Parser<'a> {
                 Some(Kind::IMul) => {
                     self.next();
                     let rhs = self.parse_mul()?;
-                    lhs = self.ast.add(Self::new_binary(Operator::IMul, lhs, rhs));
+                    lhs = self.add_binary(Operator::IMul, lhs, rhs);
                 }
                 Some(Kind::IDiv) => {
                     self.next();
                     let rhs = self.parse_mul()?;
-                    lhs = self.ast.add(Self::new_binary(Operator::IDiv, lhs, rhs));
+                    lhs = self.add_binary(Operator::IDiv, lhs, rhs);
                 }
                 _ => return Ok(lhs),
             }
@@ -508,6 +881,7 @@ impl<'a> Parser<'a> {
     }
 
     fn parse_primary(&mut self) -> Result<ExprRef> {
+        let start = self.next_start();
         match self.peek() {
             Some(Kind::ParenOpen) => {
                 self.next();
@@ -515,45 +889,50 @@ impl<'a> Parser<'a> {
                 self.expect_err(&Kind::ParenClose)?;
                 Ok(node)
             }
+            Some(Kind::Spawn) => {
+                self.next();
+                let call = self.parse_primary()?;
+                match self.ast.get(call.0 as usize) {
+                    Some(Expr::Call(_, _)) => Ok(self.add(Expr::Spawn(call), start)),
+                    _ => Err(syntax_error!(UnexpectedToken, "parse_primary: spawn needs a function call")),
+                }
+            }
             Some(Kind::Identifier(s)) => {
                 let s = s.to_string();
                 self.next();
                 match self.peek() {
                     Some(Kind::ParenOpen) => {
                         // function call
+                        let args_start = self.next_start();
                         self.next();
                         let args = self.parse_expr_list(vec![])?;
                         self.expect_err(&Kind::ParenClose)?;
-                        let args = self.ast.add(Expr::Block(args));
-                        Ok(self.ast.add(Expr::Call(s, args)))
+                        let args = self.add(Expr::Block(args), args_start);
+                        Ok(self.add(Expr::Call(s, args), start))
                     }
                     _ => {
                         // identifier
-                        Ok(self.ast.add(Expr::Identifier(s)))
+                        Ok(self.add(Expr::Identifier(s), start))
                     }
                 }
             }
             x => {
                 let e = match x {
-                    Some(&Kind::UInt64(num)) => Ok(self.ast.add(Expr::UInt64(num))),
-                    Some(&Kind::Int64(num)) => Ok(self.ast.add(Expr::Int64(num))),
-                    Some(Kind::Integer(num)) => {
-                        let integer = Expr::Int(num.clone());
-                        Ok(self.ast.add(integer))
-                    }
-                    Some(&Kind::Null) => Ok(self.ast.add(Expr::Null)),
-                    x => return Err(anyhow!("parse_primary: unexpected token {:?}", x)),
+                    Some(&Kind::UInt64(num)) => Expr::UInt64(num),
+                    Some(&Kind::Int64(num)) => Expr::Int64(num),
+                    Some(Kind::Integer(num)) => Expr::Int(num.clone()),
+                    Some(&Kind::Null) => Expr::Null,
+                    x => return Err(syntax_error!(UnexpectedToken, "parse_primary: unexpected token {:?}", x)),
                 };
                 self.next();
-                e
+                Ok(self.add(e, start))
             }
         }
     }
 
     fn parse_expr_list(&mut self, mut args: Vec<ExprRef>) -> Result<Vec<ExprRef>> {
-        match self.peek() {
-            Some(Kind::ParenClose) => return Ok(args),
-            _ => (),
+        if let Some(Kind::ParenClose) = self.peek() {
+            return Ok(args);
         }
 
         let expr = self.parse_expr();
@@ -569,7 +948,7 @@ impl<'a> Parser<'a> {
                 self.parse_expr_list(args)
             }
             Some(Kind::ParenClose) => Ok(args),
-            x => Err(anyhow!("parse_expr_list: unexpected token {:?}", x)),
+            x => Err(syntax_error!(UnexpectedToken, "parse_expr_list: unexpected token {:?}", x)),
         }
     }
 }
@@ -581,78 +960,78 @@ mod tests {
     #[test]
     fn lexer_simple_keyword() {
         let s = " if else while break continue for class fn val var";
-        let mut l = lexer::Lexer::new(&s, 1u64);
-        assert_eq!(l.yylex().unwrap().kind, Kind::If);
-        assert_eq!(l.yylex().unwrap().kind, Kind::Else);
-        assert_eq!(l.yylex().unwrap().kind, Kind::While);
-        assert_eq!(l.yylex().unwrap().kind, Kind::Break);
-        assert_eq!(l.yylex().unwrap().kind, Kind::Continue);
-        assert_eq!(l.yylex().unwrap().kind, Kind::For);
-        assert_eq!(l.yylex().unwrap().kind, Kind::Class);
-        assert_eq!(l.yylex().unwrap().kind, Kind::Function);
-        assert_eq!(l.yylex().unwrap().kind, Kind::Val);
-        assert_eq!(l.yylex().unwrap().kind, Kind::Var);
+        let mut l = lexer::Lexer::new(s, false);
+        assert_eq!(l.next_token().unwrap().kind, Kind::If);
+        assert_eq!(l.next_token().unwrap().kind, Kind::Else);
+        assert_eq!(l.next_token().unwrap().kind, Kind::While);
+        assert_eq!(l.next_token().unwrap().kind, Kind::Break);
+        assert_eq!(l.next_token().unwrap().kind, Kind::Continue);
+        assert_eq!(l.next_token().unwrap().kind, Kind::For);
+        assert_eq!(l.next_token().unwrap().kind, Kind::Class);
+        assert_eq!(l.next_token().unwrap().kind, Kind::Function);
+        assert_eq!(l.next_token().unwrap().kind, Kind::Val);
+        assert_eq!(l.next_token().unwrap().kind, Kind::Var);
     }
 
     #[test]
     fn lexer_simple_integer() {
         let s = " -1i64 1i64 2u64 123 -456";
-        let mut l = lexer::Lexer::new(&s, 1u64);
-        assert_eq!(l.yylex().unwrap().kind, Kind::Int64(-1));
-        assert_eq!(l.yylex().unwrap().kind, Kind::Int64(1));
-        assert_eq!(l.yylex().unwrap().kind, Kind::UInt64(2u64));
-        assert_eq!(l.yylex().unwrap().kind, Kind::Integer("123".to_string()));
-        assert_eq!(l.yylex().unwrap().kind, Kind::Integer("-456".to_string()));
+        let mut l = lexer::Lexer::new(s, false);
+        assert_eq!(l.next_token().unwrap().kind, Kind::Int64(-1));
+        assert_eq!(l.next_token().unwrap().kind, Kind::Int64(1));
+        assert_eq!(l.next_token().unwrap().kind, Kind::UInt64(2u64));
+        assert_eq!(l.next_token().unwrap().kind, Kind::Integer("123".to_string()));
+        assert_eq!(l.next_token().unwrap().kind, Kind::Integer("-456".to_string()));
     }
 
     #[test]
     fn lexer_simple_symbol1() {
         let s = " ( ) { } [ ] , . :: : = !";
-        let mut l = lexer::Lexer::new(&s, 1u64);
-        assert_eq!(l.yylex().unwrap().kind, Kind::ParenOpen);
-        assert_eq!(l.yylex().unwrap().kind, Kind::ParenClose);
-        assert_eq!(l.yylex().unwrap().kind, Kind::BraceOpen);
-        assert_eq!(l.yylex().unwrap().kind, Kind::BraceClose);
-        assert_eq!(l.yylex().unwrap().kind, Kind::BracketOpen);
-        assert_eq!(l.yylex().unwrap().kind, Kind::BracketClose);
-        assert_eq!(l.yylex().unwrap().kind, Kind::Comma);
-        assert_eq!(l.yylex().unwrap().kind, Kind::Dot);
-        assert_eq!(l.yylex().unwrap().kind, Kind::DoubleColon);
-        assert_eq!(l.yylex().unwrap().kind, Kind::Colon);
-        assert_eq!(l.yylex().unwrap().kind, Kind::Equal);
-        assert_eq!(l.yylex().unwrap().kind, Kind::Exclamation);
+        let mut l = lexer::Lexer::new(s, false);
+        assert_eq!(l.next_token().unwrap().kind, Kind::ParenOpen);
+        assert_eq!(l.next_token().unwrap().kind, Kind::ParenClose);
+        assert_eq!(l.next_token().unwrap().kind, Kind::BraceOpen);
+        assert_eq!(l.next_token().unwrap().kind, Kind::BraceClose);
+        assert_eq!(l.next_token().unwrap().kind, Kind::BracketOpen);
+        assert_eq!(l.next_token().unwrap().kind, Kind::BracketClose);
+        assert_eq!(l.next_token().unwrap().kind, Kind::Comma);
+        assert_eq!(l.next_token().unwrap().kind, Kind::Dot);
+        assert_eq!(l.next_token().unwrap().kind, Kind::DoubleColon);
+        assert_eq!(l.next_token().unwrap().kind, Kind::Colon);
+        assert_eq!(l.next_token().unwrap().kind, Kind::Equal);
+        assert_eq!(l.next_token().unwrap().kind, Kind::Exclamation);
     }
 
     #[test]
     fn lexer_simple_symbol2() {
         let s = "== != <= < >= >";
-        let mut l = lexer::Lexer::new(&s, 1u64);
-        assert_eq!(l.yylex().unwrap().kind, Kind::DoubleEqual);
-        assert_eq!(l.yylex().unwrap().kind, Kind::NotEqual);
-        assert_eq!(l.yylex().unwrap().kind, Kind::LE);
-        assert_eq!(l.yylex().unwrap().kind, Kind::LT);
-        assert_eq!(l.yylex().unwrap().kind, Kind::GE);
-        assert_eq!(l.yylex().unwrap().kind, Kind::GT);
+        let mut l = lexer::Lexer::new(s, false);
+        assert_eq!(l.next_token().unwrap().kind, Kind::DoubleEqual);
+        assert_eq!(l.next_token().unwrap().kind, Kind::NotEqual);
+        assert_eq!(l.next_token().unwrap().kind, Kind::LE);
+        assert_eq!(l.next_token().unwrap().kind, Kind::LT);
+        assert_eq!(l.next_token().unwrap().kind, Kind::GE);
+        assert_eq!(l.next_token().unwrap().kind, Kind::GT);
     }
 
     #[test]
     fn lexer_arithmetic_operator_symbol() {
         let s = " + - * / +. -. *. /.";
-        let mut l = lexer::Lexer::new(&s, 1u64);
-        assert_eq!(l.yylex().unwrap().kind, Kind::IAdd);
-        assert_eq!(l.yylex().unwrap().kind, Kind::ISub);
-        assert_eq!(l.yylex().unwrap().kind, Kind::IMul);
-        assert_eq!(l.yylex().unwrap().kind, Kind::IDiv);
+        let mut l = lexer::Lexer::new(s, false);
+        assert_eq!(l.next_token().unwrap().kind, Kind::IAdd);
+        assert_eq!(l.next_token().unwrap().kind, Kind::ISub);
+        assert_eq!(l.next_token().unwrap().kind, Kind::IMul);
+        assert_eq!(l.next_token().unwrap().kind, Kind::IDiv);
     }
 
     #[test]
     fn lexer_simple_identifier() {
         let s = " A _name Identifier ";
-        let mut l = lexer::Lexer::new(&s, 1u64);
-        assert_eq!(l.yylex().unwrap().kind, Kind::Identifier("A".to_string()));
-        assert_eq!(l.yylex().unwrap().kind, Kind::Identifier("_name".to_string()));
+        let mut l = lexer::Lexer::new(s, false);
+        assert_eq!(l.next_token().unwrap().kind, Kind::Identifier("A".to_string()));
+        assert_eq!(l.next_token().unwrap().kind, Kind::Identifier("_name".to_string()));
         assert_eq!(
-            l.yylex().unwrap().kind,
+            l.next_token().unwrap().kind,
             Kind::Identifier("Identifier".to_string())
         );
     }
@@ -660,11 +1039,87 @@ mod tests {
     #[test]
     fn lexer_multiple_lines() {
         let s = " A \n B ";
-        let mut l = lexer::Lexer::new(&s, 1u64);
-        assert_eq!(l.yylex().unwrap().kind, Kind::Identifier("A".to_string()));
-        assert_eq!(l.yylex().unwrap().kind, Kind::NewLine);
-        assert_eq!(l.yylex().unwrap().kind, Kind::Identifier("B".to_string()));
-        assert_eq!(*l.get_line_count(), 2);
+        let mut l = lexer::Lexer::new(s, false);
+        assert_eq!(l.next_token().unwrap().kind, Kind::Identifier("A".to_string()));
+        assert_eq!(l.next_token().unwrap().kind, Kind::NewLine);
+        assert_eq!(l.next_token().unwrap().kind, Kind::Identifier("B".to_string()));
+        assert_eq!(l.line(), 2);
+    }
+
+    #[test]
+    fn lexer_errors() {
+        // the lexer goes on after an error, each line terminator is a newline
+        let mut l = lexer::Lexer::new("a $ 99999999999999999999u64 \r b", false);
+        assert_eq!(l.next_token().unwrap().kind, Kind::Identifier("a".to_string()));
+        assert_eq!(l.next_token(), Err(lexer::Error::Unmatch(2..3)));
+        assert_eq!(l.next_token(), Err(lexer::Error::InvalidLiteral(4..27)));
+        assert_eq!(l.next_token().unwrap(), Token { kind: Kind::NewLine, position: 28..29 });
+        assert_eq!(l.next_token().unwrap().kind, Kind::Identifier("b".to_string()));
+        assert_eq!(l.next_token(), Err(lexer::Error::Eof));
+
+        // the parser stops at the error
+        let error = Parser::new("fn main() -> u64 {\n1u64\n}\n$").parse_program().err().unwrap();
+        assert_eq!(("E0100", "unexpected character \"$\" at 26"), (ParseError::code_of(&error).unwrap(), error.to_string().as_str()));
+        let error = Parser::new("1u64 + 99999999999999999999u64").parse_expression().unwrap_err();
+        assert_eq!(Some("E0102"), ParseError::code_of(&error));
+    }
+
+    #[test]
+    fn lexer_trivia() {
+        let s = " a // comment\n";
+        let mut l = lexer::Lexer::new(s, true);
+        assert_eq!(l.next_token().unwrap().kind, Kind::Whitespace(" ".to_string()));
+        assert_eq!(l.next_token().unwrap().kind, Kind::Identifier("a".to_string()));
+        assert_eq!(l.next_token().unwrap().kind, Kind::Whitespace(" ".to_string()));
+        assert_eq!(l.next_token().unwrap().kind, Kind::Comment("// comment".to_string()));
+        assert_eq!(l.next_token().unwrap().kind, Kind::NewLine);
+    }
+
+    #[test]
+    fn lexer_skip_comment() {
+        let s = "a // comment\n\tb";
+        let mut l = lexer::Lexer::new(s, false);
+        assert_eq!(l.next_token().unwrap().kind, Kind::Identifier("a".to_string()));
+        assert_eq!(l.next_token().unwrap().kind, Kind::NewLine);
+        assert_eq!(l.next_token().unwrap().kind, Kind::Identifier("b".to_string()));
+    }
+
+    #[test]
+    fn parser_trivia() {
+        let code = "// add one\nfn f(a: u64) -> u64 {\n  a + 1u64 // result\n}\n";
+        let mut p = Parser::with_trivia(code);
+        let prog = p.parse_program().unwrap();
+
+        let kinds = |t: &[Token]| t.iter().map(|t| t.kind.clone()).collect::<Vec<Kind>>();
+        assert_eq!(
+            vec![Kind::Comment("// add one".to_string()), Kind::NewLine],
+            kinds(p.trivia(&prog.function[0].node))
+        );
+
+        let body = match prog.expression.get(prog.function[0].code.0 as usize) {
+            Some(Expr::Block(b)) => b[0],
+            x => panic!("unexpected {:?}", x),
+        };
+        let node = prog.location.get(body).unwrap();
+        assert_eq!("a + 1u64", &code[node.start()..node.end()]);
+        assert_eq!(vec![Kind::NewLine, Kind::Whitespace("  ".to_string())], kinds(p.trivia(node)));
+
+        // trivia is not collected by default
+        let mut p = Parser::new(code);
+        let prog = p.parse_program().unwrap();
+        assert!(p.trivia(&prog.function[0].node).is_empty());
+    }
+
+    #[test]
+    fn parser_location() {
+        let code = "val a = b(1u64, c) * 2u64";
+        let mut p = Parser::new(code);
+        p.parse_expr().unwrap();
+        let text: Vec<&str> = p.location.0.iter().map(|n| &code[n.start()..n.end()]).collect();
+        assert_eq!(
+            vec!["1u64", "c", "(1u64, c)", "b(1u64, c)", "2u64", "b(1u64, c) * 2u64", "val a = b(1u64, c) * 2u64"],
+            text
+        );
     }
 
     #[test]
@@ -680,11 +1135,45 @@ mod tests {
         assert_eq!(Kind::UInt64(2), *t2);
     }
 
+    #[test]
+    fn parser_loop() {
+        let (e, pool) = Parser::new("while a < 10u64 { a = a + 1u64 }").parse_expression().unwrap();
+        match pool.get(e.0 as usize) {
+            Some(Expr::While(cond, body)) => {
+                assert!(matches!(pool.get(cond.0 as usize), Some(Expr::Binary(Operator::LT, _, _))));
+                assert!(matches!(pool.get(body.0 as usize), Some(Expr::Block(b)) if b.len() == 1));
+            }
+            x => panic!("unexpected {:?}", x),
+        }
+
+        let (e, pool) = Parser::new("for i in 0u64..n { print(i) }").parse_expression().unwrap();
+        match pool.get(e.0 as usize) {
+            Some(Expr::For(name, start, end, _)) => {
+                assert_eq!("i", name);
+                assert_eq!(Some(&Expr::UInt64(0)), pool.get(start.0 as usize));
+                assert_eq!(Some(&Expr::Identifier("n".to_string())), pool.get(end.0 as usize));
+            }
+            x => panic!("unexpected {:?}", x),
+        }
+        assert!(Parser::new("for 1 in 0..2 { }").parse_expression().is_err());
+        assert!(Parser::new("for i 0..2 { }").parse_expression().is_err());
+    }
+
+    #[test]
+    fn parser_else_if() {
+        let (e, pool) = Parser::new("if a { 1 } else if b { 2 } else { 3 }").parse_expression().unwrap();
+        let else_block = match pool.get(e.0 as usize) {
+            Some(Expr::IfElse(_, _, else_block)) => *else_block,
+            x => panic!("unexpected {:?}", x),
+        };
+        assert!(matches!(pool.get(else_block.0 as usize), Some(Expr::IfElse(..))));
+    }
+
     /*
     #[test]
     fn parser_simple_expr_test1() {
         let mut p = Parser::new("1u64 + 2u64 ");
-        let _ = p.parse_stmt_line().unwrap();
+        let _ = p.parse_expression().unwrap();
         assert_eq!(3, p.len(), "ExprPool.len must be 3");
         let a = p.get(0).unwrap();
         assert_eq!(Expr::UInt64(1), *a);
@@ -706,7 +1195,7 @@ mod tests {
     #[test]
     fn parser_simple_expr_mul() {
         let mut p = Parser::new("(1u64) + 2u64 * 3u64");
-        let e = p.parse_stmt_line();
+        let e = p.parse_expression();
         assert!(e.is_ok());
         let (_, p) = e.unwrap();
 
@@ -727,7 +1216,7 @@ mod tests {
     #[test]
     fn parser_simple_relational_expr() {
         let mut p = Parser::new("0u64 < 2u64 + 4u64");
-        let e = p.parse_stmt_line();
+        let e = p.parse_expression();
         assert!(e.is_ok());
         let (_, p) = e.unwrap();
 
@@ -748,7 +1237,7 @@ mod tests {
     #[test]
     fn parser_simple_logical_expr() {
         let mut p = Parser::new("1u64 && 2u64 < 3u64");
-        let e = p.parse_stmt_line();
+        let e = p.parse_expression();
         assert!(e.is_ok());
         let (_, p) = e.unwrap();
 
@@ -772,7 +1261,7 @@ mod tests {
             "variable", "a + b", "a + 1u64", "a() + 1u64", "a(b,c) + 1u64"];
         for input in expr_str {
             let mut p = Parser::new(input);
-            let e = p.parse_stmt_line();
+            let e = p.parse_expression();
             assert!(e.is_ok());
         }
     }
@@ -780,7 +1269,7 @@ mod tests {
     #[test]
     fn parser_simple_ident_expr() {
         let mut p = Parser::new("abc + 1u64");
-        let e = p.parse_stmt_line();
+        let e = p.parse_expression();
         assert!(e.is_ok());
         let (_, p) = e.unwrap();
 
@@ -797,7 +1286,7 @@ mod tests {
     #[test]
     fn parser_simple_apply_empty() {
         let mut p = Parser::new("abc()");
-        let e = p.parse_stmt_line();
+        let e = p.parse_expression();
         assert!(e.is_ok());
         let (_, p) = e.unwrap();
 
@@ -811,7 +1300,7 @@ mod tests {
     #[test]
     fn parser_simple_apply_expr() {
         let mut p = Parser::new("abc(1u64, 2u64)");
-        let e = p.parse_stmt_line();
+        let e = p.parse_expression();
         assert!(e.is_ok());
         let (_, p) = e.unwrap();
 
@@ -827,6 +1316,14 @@ mod tests {
         assert_eq!(Expr::Call("abc".to_string(), ExprRef(2)), *d);
     }
 
+    #[test]
+    fn parser_expression_trailing_token() {
+        assert!(Parser::new("1u64 + 2u64\n\n").parse_expression().is_ok());
+        let result = Parser::new("1u64 2u64").parse_expression();
+        assert!(result.is_err());
+        assert_eq!(result.err().unwrap().to_string(), "parse_expression: unexpected token Some(UInt64(2))");
+    }
+
     #[test]
     fn parser_param_def() {
         let param = Parser::new("test: u64").parse_param_def();
@@ -860,7 +1357,7 @@ mod tests {
 
     #[test]
     fn parser_simple_error() {
-        let result = Parser::new("++").parse_stmt_line();
+        let result = Parser::new("++").parse_expression();
         assert!(result.is_err());
         assert_eq!(result.err().unwrap().to_string() , "parse_expr: expected expression but Kind (IAdd)");
     }
@@ -888,7 +1385,7 @@ c
         assert_eq!(3, prog.function.len());
 
         assert_eq!(Function{node: Node::new(1, 27), name: "hello".to_string(),
-            parameter: vec![], return_type: Some(Type::UInt64), code: ExprRef(2)}, prog.function[0]);
+            parameter: vec![], return_type: Some(Type::UInt64), code: ExprRef(2), doc: None, is_const: false, attributes: vec![]}, prog.function[0]);
 
         // hello, hello2, hello3 blocks
 
@@ -898,7 +1395,7 @@ c
             println!("Func {}", func.name);
         }
 
-        let block0 = blocks.get(0).unwrap();
+        let block0 = blocks.first().unwrap();
         assert_eq!("hello".to_string(), prog.function[0].name);
         assert_eq!(0, prog.function[0].parameter.len());
         assert_eq!(
@@ -925,16 +1422,48 @@ c
         );
     }
 
+    #[test]
+    fn parser_attributes() {
+        let code = "/// doc\n#[inline]\n#[cfg(feature, 2)]\nfn f() -> u64 {\n#[memoize] fn g() -> u64 {\n1u64\n}\n#[trace]\ng()\n}\n";
+        let prog = Parser::new(code).parse_program().unwrap();
+        let f = &prog.function[0];
+        assert_eq!(8, f.node.start());
+        assert_eq!(Some("doc"), f.doc.as_deref());
+        assert_eq!(Some(&Attribute { node: Node::new(18, 36), name: "cfg".to_string(), args: vec!["feature".to_string(), "2".to_string()] }),
+                   f.attribute("cfg"));
+        assert!(f.attribute("inline").is_some());
+        let block = match prog.get(f.code.0) {
+            Some(Expr::Block(b)) => b.clone(),
+            x => panic!("{:?}", x),
+        };
+        assert!(matches!(prog.get(block[0].0), Some(Expr::Function(g)) if g.attribute("memoize").is_some()));
+        assert_eq!("trace", prog.attribute(block[1], "trace").unwrap().name);
+
+        // the name is an identifier
+        assert!(Parser::new("#[1]\nfn f() -> u64 {\n1u64\n}").parse_program().is_err());
+        assert!(Parser::new("#[a(b c)]\nfn f() -> u64 {\n1u64\n}").parse_program().is_err());
+    }
+
+    #[test]
+    fn parser_include() {
+        let prog = Parser::new("include \"lib/a.toy\"\nfn f() -> u64 {\n1u64\n}\ninclude \"b.toy\"\n").parse_program().unwrap();
+        assert_eq!(vec!["lib/a.toy".to_string(), "b.toy".to_string()], prog.import);
+        assert_eq!(1, prog.function.len());
+
+        assert!(Parser::new("include a\n").parse_program().is_err());
+        assert!(Parser::new("include \"a.toy\n").parse_program().is_err());
+    }
+
     /*
     #[test]
     fn parser_simple_expr_null_value() {
-        let res = Parser::new("null").parse_stmt_line().unwrap();
+        let res = Parser::new("null").parse_expression().unwrap();
         assert_eq!(Expr::Null, res);
     }
 
     #[test]
     fn parser_simple_assign() {
-        let res = Parser::new("a = 1u64").parse_stmt_line().unwrap();
+        let res = Parser::new("a = 1u64").parse_expression().unwrap();
         assert_eq!(
             Expr::Binary(Box::new(BinaryExpr {
                 op: Operator::Assign,
@@ -947,19 +1476,19 @@ c
 
     #[test]
     fn parser_err_primary() {
-        let res = Parser::new(".").parse_stmt_line();
+        let res = Parser::new(".").parse_expression();
         assert!(res.is_err());
     }
 
     #[test]
     fn parser_err_call_expr_list() {
-        let res = Parser::new("foo(a,,)").parse_stmt_line();
+        let res = Parser::new("foo(a,,)").parse_expression();
         assert!(res.is_err());
     }
 
     #[test]
     fn parser_val_simple_expr() {
-        let res = Parser::new("val foo = 10u64").parse_stmt_line().unwrap();
+        let res = Parser::new("val foo = 10u64").parse_expression().unwrap();
         assert_eq!(
             Expr::Val(
                 "foo".to_string(),
@@ -973,7 +1502,7 @@ c
     #[test]
     fn parser_val_simple_expr_with_type() {
         let res = Parser::new("val foo: u64 = 30u64")
-            .parse_stmt_line()
+            .parse_expression()
             .unwrap();
         assert_eq!(
             Expr::Val(
@@ -986,7 +1515,7 @@ c
     }
     #[test]
     fn parser_val_simple_expr_without_type1() {
-        let res = Parser::new("val foo = 20u64").parse_stmt_line().unwrap();
+        let res = Parser::new("val foo = 20u64").parse_expression().unwrap();
         assert_eq!(
             Expr::Val(
                 "foo".to_string(),
@@ -1000,7 +1529,7 @@ c
     #[test]
     fn parser_val_simple_expr_without_type2() {
         let res = Parser::new("val foo: ty = 20u64")
-            .parse_stmt_line()
+            .parse_expression()
             .unwrap();
         assert_eq!(
             Expr::Val(
@@ -1014,7 +1543,7 @@ c
 
     #[test]
     fn parser_if_expr() {
-        let res = Parser::new("if condition { }").parse_stmt_line().unwrap();
+        let res = Parser::new("if condition { }").parse_expression().unwrap();
         assert_eq!(
             Expr::IfElse(
                 Box::new(Expr::Identifier("condition".to_string())),
@@ -1028,7 +1557,7 @@ c
     #[test]
     fn parser_if_else_expr() {
         let res = Parser::new("if condition { a } else { b }")
-            .parse_stmt_line()
+            .parse_expression()
             .unwrap();
         assert_eq!(
             Expr::IfElse(
@@ -1041,3 +1570,4 @@ c
     }
      */
 }
+
@@ -0,0 +1,47 @@
+// Maps byte offsets in the source (as in `Node`) to line and column
+#[derive(Debug, PartialEq, Clone)]
+pub struct LineIndex {
+    line_starts: Vec<usize>,
+}
+
+impl LineIndex {
+    pub fn new(source: &str) -> Self {
+        let mut line_starts = vec![0];
+        line_starts.extend(source.match_indices('\n').map(|(i, _)| i + 1));
+        LineIndex { line_starts }
+    }
+
+    // 1-origin
+    pub fn line(&self, offset: usize) -> usize {
+        match self.line_starts.binary_search(&offset) {
+            Ok(i) => i + 1,
+            Err(i) => i,
+        }
+    }
+
+    // 1-origin line and column (column counts bytes)
+    pub fn line_col(&self, offset: usize) -> (usize, usize) {
+        let line = self.line(offset);
+        (line, offset - self.line_starts[line - 1] + 1)
+    }
+
+    pub fn line_count(&self) -> usize {
+        self.line_starts.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn line_of_offset() {
+        let index = LineIndex::new("ab\ncd\n\nx");
+        assert_eq!((1, 1), index.line_col(0));
+        assert_eq!((1, 3), index.line_col(2));
+        assert_eq!((2, 1), index.line_col(3));
+        assert_eq!((3, 1), index.line_col(6));
+        assert_eq!((4, 1), index.line_col(7));
+        assert_eq!(4, index.line_count());
+    }
+}
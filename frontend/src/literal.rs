@@ -0,0 +1,315 @@
+use std::collections::HashMap;
+use anyhow::{anyhow, Result};
+use crate::ast::*;
+use crate::type_checker::FunctionSignature;
+
+// Integer literals without suffix (`123`) are parsed as `Expr::Int(String)`.
+// The width of them is decided here by the context they appear in:
+//   * the other operand of a binary expression (`a + 1`, `1 < 2u64`)
+//   * the other end of a range (`for i in 0..10u64`)
+//   * the type annotation of `val` (`val a: u64 = 1`)
+//   * the type of a known variable or a function parameter
+//   * the return type of a called function
+//   * the declared return type of the function
+// A literal without any hint is treated as i64.
+pub struct LiteralResolver<'a> {
+    pool: &'a mut ExprPool,
+    scope: HashMap<String, Type>,
+    function: HashMap<String, FunctionSignature>,
+}
+
+pub fn is_integer_type(ty: &Type) -> bool {
+    matches!(ty, Type::Int64 | Type::UInt64)
+}
+
+// Convert the text of an integer literal to a concrete literal of `ty`
+pub fn resolve_integer(text: &str, ty: &Type) -> Result<Expr> {
+    match ty {
+        Type::UInt64 => match text.parse::<u64>() {
+            Ok(u) => Ok(Expr::UInt64(u)),
+            Err(_) => Err(crate::syntax_error!(InvalidLiteral, "integer literal `{}` is out of range for u64", text)),
+        },
+        Type::Int64 => match text.parse::<i64>() {
+            Ok(i) => Ok(Expr::Int64(i)),
+            Err(_) => Err(crate::syntax_error!(InvalidLiteral, "integer literal `{}` is out of range for i64", text)),
+        },
+        x => Err(crate::syntax_error!(InvalidLiteral, "integer literal `{}` cannot be {:?}", text, x)),
+    }
+}
+
+pub fn resolve_expr(pool: &mut ExprPool, e: ExprRef) -> Result<()> {
+    resolve_expr_in_scope(pool, e, HashMap::new(), HashMap::new())
+}
+
+// `scope` is types of the variables defined outside of `e` (e.g. previous REPL input)
+// and `function` is signatures of the known functions
+pub fn resolve_expr_in_scope(pool: &mut ExprPool, e: ExprRef, scope: HashMap<String, Type>,
+                             function: HashMap<String, FunctionSignature>) -> Result<()> {
+    let mut resolver = LiteralResolver::new(pool);
+    resolver.scope = scope;
+    resolver.function = function;
+    resolver.resolve(e, None)?;
+    resolver.resolve_rest()
+}
+
+pub fn resolve_program(program: &mut Program) -> Result<()> {
+    let mut resolver = LiteralResolver::new(&mut program.expression);
+    for f in &program.function {
+        resolver.function.insert(f.name.clone(), FunctionSignature::of(f));
+    }
+    for f in &program.function {
+        resolver.scope = f.parameter.iter().cloned().collect();
+        resolver.resolve(f.code, f.return_type.as_ref())?;
+    }
+    resolver.resolve_rest()
+}
+
+impl<'a> LiteralResolver<'a> {
+    pub fn new(pool: &'a mut ExprPool) -> Self {
+        LiteralResolver {
+            pool,
+            scope: HashMap::new(),
+            function: HashMap::new(),
+        }
+    }
+
+    fn get(&self, e: ExprRef) -> Result<Expr> {
+        match self.pool.get(e.0 as usize) {
+            Some(expr) => Ok(expr.clone()),
+            None => Err(anyhow!("resolve: invalid expression reference {:?}", e)),
+        }
+    }
+
+    // returns the type of `e` if it is decided
+    pub fn resolve(&mut self, e: ExprRef, expected: Option<&Type>) -> Result<Option<Type>> {
+        match self.get(e)? {
+            Expr::Int(text) => match expected {
+                Some(ty) if is_integer_type(ty) => {
+                    self.pool.0[e.0 as usize] = resolve_integer(&text, ty)?;
+                    Ok(Some(ty.clone()))
+                }
+                _ => Ok(None),
+            },
+            Expr::Int64(_) => Ok(Some(Type::Int64)),
+            Expr::UInt64(_) => Ok(Some(Type::UInt64)),
+            Expr::Binary(op, lhs, rhs) => match op {
+                Operator::IAdd | Operator::ISub | Operator::IMul | Operator::IDiv => {
+                    self.resolve_operand(lhs, rhs, expected)
+                }
+                Operator::EQ | Operator::NE | Operator::LT | Operator::LE |
+                Operator::GT | Operator::GE => {
+                    self.resolve_operand(lhs, rhs, None)?;
+                    Ok(Some(Type::Bool))
+                }
+                Operator::LogicalAnd | Operator::LogicalOr => {
+                    self.resolve(lhs, None)?;
+                    self.resolve(rhs, None)?;
+                    Ok(Some(Type::Bool))
+                }
+                Operator::Assign => {
+                    let lhs_ty = self.resolve(lhs, None)?;
+                    self.resolve(rhs, lhs_ty.as_ref())?;
+                    Ok(Some(Type::Unit))
+                }
+            },
+            Expr::Block(expressions) => {
+                let saved = self.scope.clone();
+                let saved_function = self.function.clone();
+                let mut ty = Some(Type::Unit);
+                let last = expressions.len().saturating_sub(1);
+                for (i, e) in expressions.into_iter().enumerate() {
+                    ty = self.resolve(e, if i == last { expected } else { None })?;
+                }
+                self.scope = saved;
+                self.function = saved_function;
+                Ok(ty)
+            }
+            Expr::IfElse(cond, then_block, else_block) => {
+                self.resolve(cond, None)?;
+                let then_ty = self.resolve(then_block, expected)?;
+                let else_ty = self.resolve(else_block, expected.or(then_ty.as_ref()))?;
+                if then_ty.is_none() && else_ty.is_some() {
+                    return self.resolve(then_block, else_ty.as_ref());
+                }
+                Ok(then_ty.or(else_ty))
+            }
+            Expr::Val(name, ty, rhs) | Expr::Var(name, ty, rhs) => {
+                let declared = ty.filter(|t| *t != Type::Unknown);
+                let rhs_ty = match rhs {
+                    Some(rhs) => self.resolve(rhs, declared.as_ref())?,
+                    None => None,
+                };
+                if let Some(ty) = declared.or(rhs_ty) {
+                    self.scope.insert(name, ty);
+                }
+                Ok(Some(Type::Unit))
+            }
+            Expr::Identifier(name) => Ok(self.scope.get(&name).cloned()),
+            Expr::Call(name, args) if name == "dbg" && !self.function.contains_key(&name) => match self.get(args)? {
+                Expr::Block(args) if args.len() == 1 => self.resolve(args[0], expected),
+                _ => Ok(None),
+            },
+            Expr::Call(name, args) => {
+                let signature = self.function.get(&name).cloned();
+                if let (Some(s), Expr::Block(args)) = (&signature, self.get(args)?) {
+                    if s.is_generic() {
+                        return self.resolve_generic(&args, expected);
+                    }
+                }
+                let param = signature.as_ref().map(|s| s.parameter.clone()).unwrap_or_default();
+                // `Unknown` of a builtin over a task or a channel (e.g. `join`,
+                // `send`) is the element type of it
+                let mut element: Option<Type> = None;
+                if let Expr::Block(args) = self.get(args)? {
+                    for (i, arg) in args.into_iter().enumerate() {
+                        let expected = match param.get(i) {
+                            Some(Type::Unknown) => element.as_ref(),
+                            ty => ty,
+                        };
+                        let ty = self.resolve(arg, expected)?;
+                        if element.is_none() {
+                            element = ty.as_ref().and_then(Type::element).cloned();
+                        }
+                    }
+                }
+                match signature.map(|s| s.return_type) {
+                    Some(Type::Unknown) if element.is_some() => Ok(element),
+                    result => Ok(result),
+                }
+            }
+            Expr::Function(f) => {
+                self.function.insert(f.name.clone(), FunctionSignature::of(&f));
+                // the body sees its parameters only
+                let saved = std::mem::replace(&mut self.scope, f.parameter.iter().cloned().collect());
+                let result = self.resolve(f.code, f.return_type.as_ref());
+                self.scope = saved;
+                result?;
+                Ok(Some(Type::Unit))
+            }
+            Expr::Spawn(call) => Ok(self.resolve(call, None)?.map(|ty| Type::Task(Box::new(ty)))),
+            Expr::Null => Ok(None),
+            Expr::While(cond, body) => {
+                self.resolve(cond, None)?;
+                self.resolve(body, None)?;
+                Ok(Some(Type::Unit))
+            }
+            Expr::For(name, start, end, body) => {
+                let ty = self.resolve_operand(start, end, None)?;
+                let saved = self.scope.clone();
+                self.scope.insert(name, ty.unwrap_or(Type::Int64));
+                self.resolve(body, None)?;
+                self.scope = saved;
+                Ok(Some(Type::Unit))
+            }
+        }
+    }
+
+    fn resolve_operand(&mut self, lhs: ExprRef, rhs: ExprRef, expected: Option<&Type>) -> Result<Option<Type>> {
+        let lhs_ty = self.resolve(lhs, expected)?;
+        let rhs_ty = self.resolve(rhs, lhs_ty.as_ref().or(expected))?;
+        if lhs_ty.is_none() && rhs_ty.is_some() {
+            return self.resolve(lhs, rhs_ty.as_ref());
+        }
+        Ok(lhs_ty.or(rhs_ty))
+    }
+
+    // Arguments of a generic builtin share one type, like binary operands
+    fn resolve_generic(&mut self, args: &[ExprRef], expected: Option<&Type>) -> Result<Option<Type>> {
+        let mut ty = expected.cloned();
+        for arg in args {
+            if let Some(t) = self.resolve(*arg, ty.as_ref())? {
+                ty.get_or_insert(t);
+            }
+        }
+        if ty.is_some() {
+            for arg in args {
+                self.resolve(*arg, ty.as_ref())?;
+            }
+        }
+        Ok(ty)
+    }
+
+    // literals which have no hint from the context
+    pub fn resolve_rest(&mut self) -> Result<()> {
+        for expr in self.pool.0.iter_mut() {
+            if let Expr::Int(text) = expr {
+                *expr = resolve_integer(text, &Type::Int64)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Parser;
+
+    fn parse(input: &str) -> Result<(ExprRef, ExprPool)> {
+        let (e, mut pool) = Parser::new(input).parse_expression()?;
+        resolve_expr(&mut pool, e)?;
+        Ok((e, pool))
+    }
+
+    #[test]
+    fn resolve_integer_by_operand() {
+        let (_, pool) = parse("1 + 2u64").unwrap();
+        assert_eq!(Expr::UInt64(1), *pool.get(0).unwrap());
+
+        let (_, pool) = parse("1i64 < 2").unwrap();
+        assert_eq!(Expr::Int64(2), *pool.get(1).unwrap());
+
+        let (_, pool) = parse("(1 + 2) * 3u64").unwrap();
+        assert_eq!(Expr::UInt64(1), *pool.get(0).unwrap());
+        assert_eq!(Expr::UInt64(2), *pool.get(1).unwrap());
+    }
+
+    #[test]
+    fn resolve_integer_by_val_type() {
+        let (_, pool) = parse("val a: u64 = 10").unwrap();
+        assert_eq!(Expr::UInt64(10), *pool.get(0).unwrap());
+    }
+
+    #[test]
+    fn resolve_integer_default_i64() {
+        let (_, pool) = parse("-5").unwrap();
+        assert_eq!(Expr::Int64(-5), *pool.get(0).unwrap());
+    }
+
+    #[test]
+    fn resolve_integer_in_scope() {
+        let (e, mut pool) = Parser::new("a * 2").parse_expression().unwrap();
+        assert_eq!(Expr::Int("2".to_string()), *pool.get(1).unwrap());
+        let scope = vec![("a".to_string(), Type::UInt64)].into_iter().collect();
+        resolve_expr_in_scope(&mut pool, e, scope, HashMap::new()).unwrap();
+        assert_eq!(Expr::UInt64(2), *pool.get(1).unwrap());
+    }
+
+    #[test]
+    fn resolve_integer_in_loop() {
+        let (_, pool) = parse("for i in 0..10u64 { i * 2 }").unwrap();
+        assert_eq!(Expr::UInt64(0), *pool.get(0).unwrap());
+        assert_eq!(Expr::UInt64(2), *pool.get(3).unwrap());
+    }
+
+    #[test]
+    fn resolve_integer_out_of_range() {
+        assert!(parse("-1 + 2u64").is_err());
+        assert!(parse("99999999999999999999").is_err());
+    }
+
+    #[test]
+    fn resolve_integer_in_program() {
+        let code = r#"
+fn add(a: u64, b: u64) -> u64 {
+val c = a + 1
+add(c, 2)
+}
+        "#;
+        let prog = Parser::new(code).parse_program().unwrap();
+        let literals: Vec<&Expr> = prog.expression.0.iter()
+            .filter(|e| matches!(e, Expr::UInt64(_) | Expr::Int64(_) | Expr::Int(_)))
+            .collect();
+        assert_eq!(vec![&Expr::UInt64(1), &Expr::UInt64(2)], literals);
+    }
+}
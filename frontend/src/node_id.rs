@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+use crate::ast::{Expr, ExprRef, Program};
+
+// Identity of an expression which survives a re-parse of the edited
+// source, unlike its ExprRef which shifts with each expression added in
+// front of it. It is a hash of the function and of the path from its body
+// to the expression, where each step is the kind of the child with its
+// name or value (`val x`, `call f`, `1`) and
+//   - for a statement of a block (or an argument), the number of the
+//     statements before it in the block with the same kind and name
+//   - for any other child, its position in the parent
+// So an edit changes the IDs of the expressions whose kind, name or value
+// it changes, of the expressions under them and of the statements of the
+// same kind after them in their block. The other expressions keep theirs,
+// e.g. coverage data can be moved over to the new program.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(pub u64);
+
+#[derive(Debug, Default)]
+pub struct NodeIds {
+    ids: Vec<Option<NodeId>>, // indexed by ExprRef, None if no function reaches it
+    refs: HashMap<NodeId, ExprRef>,
+}
+
+// FNV-1a, so that the IDs don't depend on the Rust version
+const OFFSET: u64 = 0xcbf29ce484222325;
+const PRIME: u64 = 0x100000001b3;
+
+fn hash(state: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(state, |h, b| (h ^ *b as u64).wrapping_mul(PRIME))
+}
+
+fn step(parent: NodeId, key: &str, index: usize) -> NodeId {
+    NodeId(hash(hash(parent.0, key.as_bytes()), &(index as u64).to_le_bytes()))
+}
+
+// Kind of the expression with its name or value, without its children
+fn key(expr: &Expr) -> String {
+    match expr {
+        Expr::IfElse(..) => "if".to_string(),
+        Expr::Binary(op, _, _) => format!("{:?}", op),
+        Expr::Block(_) => "block".to_string(),
+        Expr::Int64(i) => format!("{}i64", i),
+        Expr::UInt64(u) => format!("{}u64", u),
+        Expr::Int(text) => text.clone(),
+        Expr::Val(name, _, _) => format!("val {}", name),
+        Expr::Var(name, _, _) => format!("var {}", name),
+        Expr::Identifier(name) => format!("id {}", name),
+        Expr::Null => "null".to_string(),
+        Expr::Call(name, _) => format!("call {}", name),
+        Expr::While(..) => "while".to_string(),
+        Expr::For(name, ..) => format!("for {}", name),
+        Expr::Spawn(_) => "spawn".to_string(),
+        Expr::Function(f) => format!("fn {}", f.name),
+    }
+}
+
+impl NodeIds {
+    pub fn new(program: &Program) -> Self {
+        let pool = &program.expression;
+        let mut ids = NodeIds { ids: vec![None; pool.len()], refs: HashMap::new() };
+        let mut work: Vec<(ExprRef, NodeId)> = program.function.iter()
+            .map(|f| (f.code, NodeId(hash(OFFSET, format!("fn {}", f.name).as_bytes()))))
+            .collect();
+        while let Some((e, id)) = work.pop() {
+            let expr = match (pool.get(e.0 as usize), ids.ids.get(e.0 as usize)) {
+                (Some(expr), Some(None)) => expr,
+                _ => continue, // out of the pool, or reached twice
+            };
+            ids.ids[e.0 as usize] = Some(id);
+            ids.refs.insert(id, e);
+            let mut seen: HashMap<String, usize> = HashMap::new();
+            for (position, child) in expr.children().into_iter().enumerate() {
+                let key = pool.get(child.0 as usize).map_or(String::new(), key);
+                let index = match expr {
+                    Expr::Block(_) => {
+                        let count = seen.entry(key.clone()).or_insert(0);
+                        *count += 1;
+                        *count - 1
+                    }
+                    _ => position,
+                };
+                work.push((child, step(id, &key, index)));
+            }
+        }
+        ids
+    }
+
+    pub fn id(&self, e: ExprRef) -> Option<NodeId> {
+        self.ids.get(e.0 as usize).copied().flatten()
+    }
+
+    pub fn expr(&self, id: NodeId) -> Option<ExprRef> {
+        self.refs.get(&id).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Parser;
+
+    fn ids_of(source: &str) -> (Program, NodeIds) {
+        let program = Parser::new(source).parse_program().unwrap();
+        let ids = NodeIds::new(&program);
+        (program, ids)
+    }
+
+    #[test]
+    fn stable_ids() {
+        let before = "fn f() -> u64 {\n1u64\n}\nfn main() -> u64 {\nval a = 1u64\nval b = a + 2u64\nb\n}";
+        let after = "fn g() -> u64 {\n0u64\n}\nfn f() -> u64 {\n1u64\n}\nfn main() -> u64 {\nval z = 0u64\nval a = 1u64\nval b = a + 3u64\nb\n}";
+        let (old, old_ids) = ids_of(before);
+        let (new, new_ids) = ids_of(after);
+        let find = |program: &Program, ids: &NodeIds, text: &str, source: &str| {
+            let start = source.rfind(text).unwrap();
+            let e = (0..program.len() as u32).map(ExprRef)
+                .find(|e| program.location.get(*e).is_some_and(|node| node.start() == start && node.end() == start + text.len()))
+                .unwrap();
+            ids.id(e).unwrap()
+        };
+        // moved in the pool by the new function and statement
+        for text in ["1u64", "val a = 1u64", "b"] {
+            let id = find(&old, &old_ids, text, before);
+            assert_eq!(id, find(&new, &new_ids, text, after), "{}", text);
+            assert!(new_ids.expr(id).is_some());
+        }
+        // the same binding with another value
+        assert_eq!(find(&old, &old_ids, "val b = a + 2u64", before), find(&new, &new_ids, "val b = a + 3u64", after));
+        assert_ne!(find(&old, &old_ids, "2u64", before), find(&new, &new_ids, "3u64", after));
+        assert_eq!(old.len(), (0..old.len() as u32).filter_map(|e| old_ids.id(ExprRef(e))).count());
+    }
+}
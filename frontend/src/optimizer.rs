@@ -0,0 +1,408 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+use crate::ast::{Expr, ExprPool, ExprRef, Function, Operator, Program};
+use crate::consteval::ConstValue;
+use crate::transform::{transform_program, AstTransformer};
+
+// Pipeline of rewriting passes over a type checked program, run before it
+// is interpreted or compiled. The passes run in the order they are added,
+// each over the whole program, and the number of expressions each one
+// rewrote is kept with the time it took. A pass keeps the result of the
+// program, including its runtime errors: e.g. `1u64 / 0u64` and an
+// overflowing `+` are not folded.
+
+pub trait Pass {
+    fn name(&self) -> &'static str;
+
+    // Rewrite the program, the result is the number of rewritten expressions
+    fn run(&mut self, program: &mut Program) -> usize;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PassStatistics {
+    pub name: &'static str,
+    pub rewrites: usize,
+    pub time: Duration,
+}
+
+#[derive(Default)]
+pub struct PassManager {
+    passes: Vec<Box<dyn Pass>>,
+    statistics: Vec<PassStatistics>,
+}
+
+impl PassManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Inlining, constant folding, then algebraic simplification and dead
+    // branch removal
+    pub fn standard() -> Self {
+        let mut manager = Self::new();
+        manager.add(Box::new(Inlining::default()));
+        manager.add(Box::new(ConstantFolding::default()));
+        manager.add(Box::new(AlgebraicSimplification::default()));
+        manager.add(Box::new(DeadBranchRemoval::default()));
+        manager
+    }
+
+    pub fn add(&mut self, pass: Box<dyn Pass>) {
+        self.passes.push(pass);
+    }
+
+    // The result is the number of rewritten expressions
+    pub fn run(&mut self, program: &mut Program) -> usize {
+        let mut total = 0;
+        for pass in &mut self.passes {
+            let start = Instant::now();
+            let rewrites = pass.run(program);
+            let time = start.elapsed();
+            match self.statistics.iter_mut().find(|s| s.name == pass.name()) {
+                Some(s) => {
+                    s.rewrites += rewrites;
+                    s.time += time;
+                }
+                None => self.statistics.push(PassStatistics { name: pass.name(), rewrites, time }),
+            }
+            total += rewrites;
+        }
+        total
+    }
+
+    // Totals of the runs, in the order of the passes
+    pub fn statistics(&self) -> &[PassStatistics] {
+        &self.statistics
+    }
+}
+
+impl fmt::Display for PassManager {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{:<24} {:>10} {:>14}", "pass", "rewrites", "time (us)")?;
+        for s in &self.statistics {
+            writeln!(f, "{:<24} {:>10} {:>14}", s.name, s.rewrites, s.time.as_micros())?;
+        }
+        Ok(())
+    }
+}
+
+// Binary operator of two literals of the same type, None when the result
+// is not a literal or is a runtime error
+fn fold(op: &Operator, lhs: &Expr, rhs: &Expr) -> Option<Expr> {
+    match (lhs, rhs) {
+        (Expr::Int64(a), Expr::Int64(b)) => match op {
+            Operator::IAdd => a.checked_add(*b),
+            Operator::ISub => a.checked_sub(*b),
+            Operator::IMul => a.checked_mul(*b),
+            Operator::IDiv => a.checked_div(*b),
+            _ => None,
+        }.map(Expr::Int64),
+        (Expr::UInt64(a), Expr::UInt64(b)) => match op {
+            Operator::IAdd => a.checked_add(*b),
+            Operator::ISub => a.checked_sub(*b),
+            Operator::IMul => a.checked_mul(*b),
+            Operator::IDiv => a.checked_div(*b),
+            _ => None,
+        }.map(Expr::UInt64),
+        _ => None,
+    }
+}
+
+// Value of a condition made of comparisons of literals
+fn condition(pool: &ExprPool, e: ExprRef) -> Option<bool> {
+    let (op, lhs, rhs) = match pool.get(e.0 as usize)? {
+        Expr::Binary(op, lhs, rhs) => (op, *lhs, *rhs),
+        _ => return None,
+    };
+    let compare = |ordering: std::cmp::Ordering| match op {
+        Operator::EQ => Some(ordering.is_eq()),
+        Operator::NE => Some(ordering.is_ne()),
+        Operator::LT => Some(ordering.is_lt()),
+        Operator::LE => Some(ordering.is_le()),
+        Operator::GT => Some(ordering.is_gt()),
+        Operator::GE => Some(ordering.is_ge()),
+        _ => None,
+    };
+    match (op, pool.get(lhs.0 as usize)?, pool.get(rhs.0 as usize)?) {
+        (Operator::LogicalAnd, _, _) => match condition(pool, lhs)? {
+            false => Some(false),
+            true => condition(pool, rhs),
+        },
+        (Operator::LogicalOr, _, _) => match condition(pool, lhs)? {
+            true => Some(true),
+            false => condition(pool, rhs),
+        },
+        (_, Expr::Int64(a), Expr::Int64(b)) => compare(a.cmp(b)),
+        (_, Expr::UInt64(a), Expr::UInt64(b)) => compare(a.cmp(b)),
+        _ => None,
+    }
+}
+
+// `2u64 * 3u64` -> `6u64`
+#[derive(Default)]
+pub struct ConstantFolding {
+    rewrites: usize,
+}
+
+impl AstTransformer for ConstantFolding {
+    fn rewrite(&mut self, pool: &mut ExprPool, e: ExprRef) -> Option<Expr> {
+        let folded = match pool.get(e.0 as usize)? {
+            Expr::Binary(op, lhs, rhs) => fold(op, pool.get(lhs.0 as usize)?, pool.get(rhs.0 as usize)?)?,
+            _ => return None,
+        };
+        self.rewrites += 1;
+        Some(folded)
+    }
+}
+
+// `x + 0`, `0 + x`, `x - 0`, `x * 1`, `1 * x` and `x / 1` -> `x`
+#[derive(Default)]
+pub struct AlgebraicSimplification {
+    rewrites: usize,
+}
+
+impl AstTransformer for AlgebraicSimplification {
+    fn rewrite(&mut self, pool: &mut ExprPool, e: ExprRef) -> Option<Expr> {
+        let (op, lhs, rhs) = match pool.get(e.0 as usize)? {
+            Expr::Binary(op, lhs, rhs) => (op, *lhs, *rhs),
+            _ => return None,
+        };
+        let is = |e: ExprRef, n: u64| matches!(pool.get(e.0 as usize), Some(Expr::UInt64(v)) if *v == n)
+            || matches!(pool.get(e.0 as usize), Some(Expr::Int64(v)) if *v as u64 == n && *v >= 0);
+        let operand = match op {
+            Operator::IAdd if is(rhs, 0) => lhs,
+            Operator::IAdd if is(lhs, 0) => rhs,
+            Operator::ISub if is(rhs, 0) => lhs,
+            Operator::IMul if is(rhs, 1) => lhs,
+            Operator::IMul if is(lhs, 1) => rhs,
+            Operator::IDiv if is(rhs, 1) => lhs,
+            _ => return None,
+        };
+        self.rewrites += 1;
+        pool.get(operand.0 as usize).cloned()
+    }
+}
+
+// `if 1u64 < 2u64 { a } else { b }` -> `{ a }`, and a `while` whose
+// condition is false -> `{}`
+#[derive(Default)]
+pub struct DeadBranchRemoval {
+    rewrites: usize,
+}
+
+impl AstTransformer for DeadBranchRemoval {
+    fn rewrite(&mut self, pool: &mut ExprPool, e: ExprRef) -> Option<Expr> {
+        let live = match pool.get(e.0 as usize)? {
+            Expr::IfElse(cond, then_block, else_block) => match condition(pool, *cond)? {
+                true => pool.get(then_block.0 as usize)?.clone(),
+                false => pool.get(else_block.0 as usize)?.clone(),
+            },
+            Expr::While(cond, _) if condition(pool, *cond) == Some(false) => Expr::Block(vec![]),
+            _ => return None,
+        };
+        self.rewrites += 1;
+        Some(live)
+    }
+}
+
+// A call of an `#[inline]` function -> a block binding the parameters to
+// the arguments, followed by a copy of the body:
+// `f(x + 1u64)` -> `{ val n'1 = x + 1u64 { ..n'1.. } }`. The parameters
+// and the variables of the body are renamed in each copy, so that they
+// neither hide the variables used by the other arguments nor are defined
+// twice in the caller (which the bytecode compiler doesn't allow). Not inlined: a recursive function, a function
+// with a function in its body or which calls a name of a function of a
+// block (which could hide the callee at the call), and a spawned call.
+#[derive(Default)]
+pub struct Inlining {
+    functions: HashMap<String, Function>,
+    spawned: Vec<u32>, // calls under `spawn`, by ExprRef
+    copies: usize, // for the names of the parameters
+    rewrites: usize,
+}
+
+impl Inlining {
+    // Functions of the program which can be inlined
+    fn inlinable(program: &Program) -> HashMap<String, Function> {
+        let pool = &program.expression;
+        let nested: Vec<&str> = pool.0.iter().filter_map(|e| match e {
+            Expr::Function(f) => Some(f.name.as_str()),
+            _ => None,
+        }).collect();
+        program.function.iter()
+            .filter(|f| f.attribute("inline").is_some())
+            .filter(|f| {
+                let mut work = vec![f.code];
+                while let Some(e) = work.pop() {
+                    match pool.get(e.0 as usize) {
+                        Some(Expr::Function(_)) | None => return false,
+                        Some(Expr::Call(name, _)) if *name == f.name || nested.contains(&name.as_str()) => return false,
+                        Some(e) => work.extend(e.children()),
+                    }
+                }
+                true
+            })
+            .map(|f| (f.name.clone(), f.clone()))
+            .collect()
+    }
+
+    // Copy of the expression `e` with the variables renamed
+    fn copy(pool: &mut ExprPool, e: ExprRef, renamed: &HashMap<String, String>) -> ExprRef {
+        let expr = pool.0[e.0 as usize].clone();
+        let copied: HashMap<u32, ExprRef> = expr.children().into_iter().map(|c| (c.0, Self::copy(pool, c, renamed))).collect();
+        let rename = |name: String| renamed.get(&name).cloned().unwrap_or(name);
+        let expr = match expr.map_children(|c| copied[&c.0]) {
+            Expr::Identifier(name) => Expr::Identifier(rename(name)),
+            Expr::Val(name, ty, rhs) => Expr::Val(rename(name), ty, rhs),
+            Expr::Var(name, ty, rhs) => Expr::Var(rename(name), ty, rhs),
+            Expr::For(name, start, end, body) => Expr::For(rename(name), start, end, body),
+            expr => expr,
+        };
+        pool.add(expr)
+    }
+}
+
+impl AstTransformer for Inlining {
+    fn rewrite(&mut self, pool: &mut ExprPool, e: ExprRef) -> Option<Expr> {
+        let (f, args) = match pool.get(e.0 as usize)? {
+            Expr::Call(name, args) if !self.spawned.contains(&e.0) => (self.functions.get(name)?, *args),
+            _ => return None,
+        };
+        let args = match pool.get(args.0 as usize)? {
+            Expr::Block(args) if args.len() == f.parameter.len() => args.clone(),
+            _ => return None,
+        };
+        self.copies += 1;
+        let mut names: Vec<String> = f.parameter.iter().map(|(name, _)| name.clone()).collect();
+        let mut work = vec![f.code];
+        while let Some(e) = work.pop() {
+            let expr = pool.get(e.0 as usize)?;
+            if let Expr::Val(name, ..) | Expr::Var(name, ..) | Expr::For(name, ..) = expr {
+                names.push(name.clone());
+            }
+            work.extend(expr.children());
+        }
+        let renamed: HashMap<String, String> = names.into_iter()
+            .map(|name| (name.clone(), format!("{}'{}", name, self.copies)))
+            .collect();
+        let mut block = vec![];
+        for ((name, ty), arg) in f.parameter.iter().zip(args) {
+            block.push(pool.add(Expr::Val(renamed[name].clone(), Some(ty.clone()), Some(arg))));
+        }
+        block.push(Self::copy(pool, f.code, &renamed));
+        self.rewrites += 1;
+        Some(Expr::Block(block))
+    }
+}
+
+impl Pass for Inlining {
+    fn name(&self) -> &'static str {
+        "inlining"
+    }
+
+    fn run(&mut self, program: &mut Program) -> usize {
+        self.functions = Self::inlinable(program);
+        self.spawned = program.expression.0.iter().filter_map(|e| match e {
+            Expr::Spawn(call) => Some(call.0),
+            _ => None,
+        }).collect();
+        self.rewrites = 0;
+        if !self.functions.is_empty() {
+            transform_program(program, self);
+        }
+        self.rewrites
+    }
+}
+
+// `f(3u64)` of a `const fn f` -> its value, by ExprRef of the calls
+// evaluated by `TypeCheckContext::evaluate_constants`. The program must
+// not be changed since it was checked.
+pub struct ConstantCalls {
+    constants: HashMap<u32, ConstValue>,
+    rewrites: usize,
+}
+
+impl ConstantCalls {
+    pub fn new(constants: HashMap<u32, ConstValue>) -> Self {
+        ConstantCalls { constants, rewrites: 0 }
+    }
+}
+
+impl AstTransformer for ConstantCalls {
+    fn rewrite(&mut self, pool: &mut ExprPool, e: ExprRef) -> Option<Expr> {
+        if !matches!(pool.get(e.0 as usize)?, Expr::Call(..)) {
+            return None;
+        }
+        let value = self.constants.get(&e.0)?.to_expr()?;
+        self.rewrites += 1;
+        Some(value)
+    }
+}
+
+macro_rules! transformer_pass {
+    ($pass:ident, $name:literal) => {
+        impl Pass for $pass {
+            fn name(&self) -> &'static str {
+                $name
+            }
+
+            fn run(&mut self, program: &mut Program) -> usize {
+                self.rewrites = 0;
+                transform_program(program, self);
+                self.rewrites
+            }
+        }
+    };
+}
+
+transformer_pass!(ConstantFolding, "constant-folding");
+transformer_pass!(AlgebraicSimplification, "algebraic-simplification");
+transformer_pass!(DeadBranchRemoval, "dead-branch-removal");
+transformer_pass!(ConstantCalls, "constant-calls");
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::dump;
+    use crate::Parser;
+
+    fn optimize(source: &str) -> (String, PassManager) {
+        let mut program = Parser::new(source).parse_program().unwrap();
+        let mut manager = PassManager::standard();
+        manager.run(&mut program);
+        (dump(&program.expression, program.function[0].code), manager)
+    }
+
+    #[test]
+    fn standard_passes() {
+        let (tree, manager) = optimize("fn f(x: u64) -> u64 {\nif 2u64 * 3u64 > 5u64 { (x + 0u64) * 1u64 } else { x / 0u64 }\n}");
+        assert_eq!("Block\n  Block\n    Identifier(\"x\")\n", tree);
+        let rewrites: Vec<_> = manager.statistics().iter().map(|s| (s.name, s.rewrites)).collect();
+        assert_eq!(vec![("inlining", 0), ("constant-folding", 1), ("algebraic-simplification", 2), ("dead-branch-removal", 1)], rewrites);
+        assert!(manager.to_string().starts_with("pass "));
+
+        // runtime errors and the operands which may have effects are kept
+        let (tree, _) = optimize("fn f() -> i64 {\n9223372036854775807i64 + 1i64\n}");
+        assert!(tree.contains("Binary(IAdd)"), "{}", tree);
+        let (tree, _) = optimize("fn f(x: u64) -> u64 {\nwhile 1u64 > 2u64 && x < 1u64 { x }\nwhile 2u64 > 1u64 || x > 1u64 { x }\n0u64\n}");
+        assert_eq!("Block\n  Block\n  While\n", tree.lines().take(3).map(|l| format!("{}\n", l)).collect::<String>());
+        let (tree, _) = optimize("fn f(x: u64) -> u64 {\nwhile x < 1u64 && 1u64 > 2u64 { x }\n0u64\n}");
+        assert!(tree.starts_with("Block\n  While\n"), "{}", tree);
+    }
+
+    #[test]
+    fn inline_functions() {
+        let source = "#[inline]\nfn add(a: u64, b: u64) -> u64 {\nval c = a\nc + b\n}\n\
+            #[inline]\nfn fact(n: u64) -> u64 {\nif n < 1u64 { 1u64 } else { n * fact(n - 1u64) }\n}\n\
+            fn f(a: u64) -> u64 {\nadd(1u64, a) + fact(a)\n}\n";
+        let mut program = Parser::new(source).parse_program().unwrap();
+        assert_eq!(1, Inlining::default().run(&mut program));
+        let tree = dump(&program.expression, program.function[2].code);
+        // the argument `a` is not hidden by the parameter `a`, and the
+        // recursive function is called
+        let inlined = "Block\n  Binary(IAdd)\n    Block\n      Val(\"a'1\", Some(UInt64))\n        UInt64(1)\n      Val(\"b'1\", Some(UInt64))\n        Identifier(\"a\")\n      \
+            Block\n        Val(\"c'1\", Some(Unknown))\n          Identifier(\"a'1\")\n        Binary(IAdd)\n          Identifier(\"c'1\")\n          Identifier(\"b'1\")\n    Call(\"fact\")\n";
+        assert!(tree.starts_with(inlined), "{}", tree);
+    }
+}
@@ -0,0 +1,72 @@
+use crate::ast::Type;
+use crate::type_checker::FunctionSignature;
+
+// Builtins which every program can call without declaring them. A
+// `TypeCheckContext` declares the prelude when it is made; the runtime
+// implements them (the interpreter registers them as native functions).
+// The standard ones are
+//   print(x)                 prints the value on a line of its own
+//   dbg(x) -> x              prints the value with its location to stderr
+//                            and gives it back, of any type
+//   assert(cond)             fails the run when `cond` is false
+//   len(ch) -> u64           number of values waiting in a channel
+//   to_i64(x), to_u64(x)     conversion of an integer or a bool, which
+//                            fails when the value is out of range
+// The bytecode backend implements only `print` and declares the prelude
+// with the others removed.
+// An embedder replaces the prelude with `TypeCheckContext::set_prelude`,
+// with `Prelude::empty()` to disable it or with more functions registered.
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Prelude {
+    functions: Vec<(String, FunctionSignature)>, // in the order of registration
+}
+
+impl Prelude {
+    // The standard prelude
+    pub fn new() -> Self {
+        let mut prelude = Self::empty();
+        prelude.register("print", FunctionSignature { parameter: vec![Type::Unknown], return_type: Type::Unit });
+        // the type checker gives `dbg` the type of its argument
+        prelude.register("dbg", FunctionSignature::generic(1));
+        prelude.register("assert", FunctionSignature { parameter: vec![Type::Bool], return_type: Type::Unit });
+        prelude.register("len", FunctionSignature { parameter: vec![Type::Channel(Box::new(Type::Unknown))], return_type: Type::UInt64 });
+        prelude.register("to_i64", FunctionSignature { parameter: vec![Type::Unknown], return_type: Type::Int64 });
+        prelude.register("to_u64", FunctionSignature { parameter: vec![Type::Unknown], return_type: Type::UInt64 });
+        prelude
+    }
+
+    pub fn empty() -> Self {
+        Prelude { functions: vec![] }
+    }
+
+    // Declare `name` to every program, replacing a function of the same name
+    pub fn register(&mut self, name: &str, signature: FunctionSignature) {
+        self.functions.retain(|(n, _)| n != name);
+        self.functions.push((name.to_string(), signature));
+    }
+
+    // Keep the functions whose name satisfies `f`, e.g. those which a
+    // runtime implements
+    pub fn retain(&mut self, f: impl Fn(&str) -> bool) {
+        self.functions.retain(|(name, _)| f(name));
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&FunctionSignature> {
+        self.functions.iter().find(|(n, _)| n == name).map(|(_, signature)| signature)
+    }
+
+    pub fn functions(&self) -> impl Iterator<Item = (&str, &FunctionSignature)> {
+        self.functions.iter().map(|(name, signature)| (name.as_str(), signature))
+    }
+}
+
+impl Default for Prelude {
+    fn default() -> Self {
+        Self::new()
+    }
+}
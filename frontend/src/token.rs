@@ -10,6 +10,7 @@ pub enum Kind {
     Else,
     For,
     While,
+    In,
     Break,
     Continue,
     Class,
@@ -20,9 +21,13 @@ pub enum Kind {
     Public,
     Val,
     Var,
+    Spawn,
+    Const,
+    Include,
 
     U64,
     I64,
+    Bool,
     USize,
     Ptr,
     Null,
@@ -35,10 +40,12 @@ pub enum Kind {
     BracketClose,
     Comma,
     Dot,
+    DotDot,      // ..
     DoubleColon,
     Colon,
     Arrow,       // ->
     Exclamation, // !
+    Hash,        // #
 
     Equal,
 
@@ -64,9 +71,16 @@ pub enum Kind {
     Int64(i64),
     UInt64(u64),
     Integer(String),
+    Str(String), // "text" without the quotes
 
     Identifier(String),
 
     NewLine,
     EOF,
+
+    // trivia: returned only when the lexer is created with `trivia`
+    Whitespace(String),
+    Comment(String),
+    // `///`, always returned; the parser attaches it to the next function
+    DocComment(String),
 }
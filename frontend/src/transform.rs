@@ -0,0 +1,116 @@
+use crate::ast::{Expr, ExprPool, ExprRef, Function, Program};
+
+// Rewriting of the expressions of a pool, for desugaring and optimization
+// passes. `rewrite` is called once for each expression reachable from the
+// root, children first, so it sees them already rewritten. The expression
+// it returns replaces the old one at the same ExprRef, so the parents and
+// the locations stay valid; the expressions it refers to can be added to
+// the pool. An expression which becomes unreachable is left in the pool
+// (see `Program::compact`).
+pub trait AstTransformer {
+    fn rewrite(&mut self, pool: &mut ExprPool, e: ExprRef) -> Option<Expr>;
+
+    // Called before the body of each function of the program
+    fn enter_function(&mut self, _f: &Function) {}
+}
+
+// Rewrite the functions of the program. An expression added by `rewrite`
+// gets the location of the expression it replaces.
+pub fn transform_program(program: &mut Program, transformer: &mut impl AstTransformer) {
+    let mut done = vec![false; program.expression.len()];
+    for i in 0..program.function.len() {
+        transformer.enter_function(&program.function[i]);
+        let code = program.function[i].code;
+        walk(&mut program.expression, code, &mut done, transformer, &mut |pool, e| {
+            let location = &mut program.location.0;
+            if !location.is_empty() && location.len() < pool.len() {
+                let node = location.get(e.0 as usize).cloned().unwrap_or(crate::ast::Node::new(0, 0));
+                location.resize(pool.len(), node);
+            }
+        });
+    }
+}
+
+// Rewrite the expression `e` and the ones under it, e.g. REPL input
+pub fn transform_expression(pool: &mut ExprPool, e: ExprRef, transformer: &mut impl AstTransformer) {
+    let mut done = vec![false; pool.len()];
+    walk(pool, e, &mut done, transformer, &mut |_, _| ());
+}
+
+// Post-order without recursion, so that a deep tree doesn't use up the
+// stack. `added` is called after each rewrite.
+fn walk(pool: &mut ExprPool, root: ExprRef, done: &mut Vec<bool>, transformer: &mut impl AstTransformer,
+        added: &mut impl FnMut(&ExprPool, ExprRef)) {
+    let mut work = vec![(root, false)];
+    while let Some((e, children_done)) = work.pop() {
+        let i = e.0 as usize;
+        if done.get(i).is_none_or(|done| *done) {
+            continue; // out of the pool, or reached twice
+        }
+        if !children_done {
+            work.push((e, true));
+            work.extend(pool.0[i].children().into_iter().rev().map(|child| (child, false)));
+            continue;
+        }
+        done[i] = true;
+        if let Some(expr) = transformer.rewrite(pool, e) {
+            pool.0[i] = expr;
+        }
+        done.resize(pool.len(), true); // added by the rewrite, already final
+        added(pool, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::{dump, Operator};
+    use crate::Parser;
+
+    // `x * 2u64` -> `x + x`, and a count of the functions
+    #[derive(Default)]
+    struct Double {
+        functions: usize,
+    }
+
+    impl AstTransformer for Double {
+        fn rewrite(&mut self, pool: &mut ExprPool, e: ExprRef) -> Option<Expr> {
+            match pool.get(e.0 as usize) {
+                Some(Expr::Binary(Operator::IMul, lhs, rhs)) if pool.get(rhs.0 as usize) == Some(&Expr::UInt64(2)) => {
+                    let lhs = *lhs;
+                    let copy = pool.add(pool.get(lhs.0 as usize)?.clone());
+                    Some(Expr::Binary(Operator::IAdd, lhs, copy))
+                }
+                _ => None,
+            }
+        }
+
+        fn enter_function(&mut self, _f: &Function) {
+            self.functions += 1;
+        }
+    }
+
+    #[test]
+    fn transform() {
+        let mut program = Parser::new("fn f(x: u64) -> u64 {\n(x * 2u64) * 2u64\n}\nfn main() -> u64 {\nf(1u64)\n}").parse_program().unwrap();
+        let mut double = Double::default();
+        transform_program(&mut program, &mut double);
+        assert_eq!(2, double.functions);
+        // the inner product is rewritten first, then the outer one copies it
+        assert_eq!(concat!(
+            "Block\n",
+            "  Binary(IAdd)\n",
+            "    Binary(IAdd)\n",
+            "      Identifier(\"x\")\n",
+            "      Identifier(\"x\")\n",
+            "    Binary(IAdd)\n",
+            "      Identifier(\"x\")\n",
+            "      Identifier(\"x\")\n",
+        ), dump(&program.expression, program.function[0].code));
+        assert_eq!(program.len(), program.location.len());
+
+        let (e, mut pool) = Parser::new("1u64 + 3u64 * 2u64").parse_expression().unwrap();
+        transform_expression(&mut pool, e, &mut double);
+        assert_eq!("Binary(IAdd)\n  UInt64(1)\n  Binary(IAdd)\n    UInt64(3)\n    UInt64(3)\n", dump(&pool, e));
+    }
+}
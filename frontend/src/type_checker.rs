@@ -0,0 +1,1028 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use crate::ast::*;
+use crate::attribute::AttributeRegistry;
+use crate::consteval::{ConstEvaluator, ConstValue};
+use crate::literal;
+use crate::prelude::Prelude;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct VarState {
+    pub ty: Type,
+    pub mutable: bool,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct FunctionSignature {
+    pub parameter: Vec<Type>,
+    pub return_type: Type,
+}
+
+impl FunctionSignature {
+    // Builtin over integers (e.g. `max`): all arguments and the result
+    // share one integer type, written as `Type::Unknown`
+    pub fn generic(arity: usize) -> Self {
+        FunctionSignature {
+            parameter: vec![Type::Unknown; arity],
+            return_type: Type::Unknown,
+        }
+    }
+
+    pub fn of(f: &Function) -> Self {
+        FunctionSignature {
+            parameter: f.parameter.iter().map(|(_, ty)| ty.clone()).collect(),
+            return_type: f.return_type.clone().unwrap_or(Type::Unit),
+        }
+    }
+
+    pub fn is_generic(&self) -> bool {
+        self.return_type == Type::Unknown && !self.parameter.is_empty()
+            && self.parameter.iter().all(|ty| *ty == Type::Unknown)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum TypeCheckErrorKind {
+    TypeMismatch { expected: Type, actual: Type },
+    UndefinedVariable(String),
+    UndefinedFunction(String),
+    UnknownType(String),
+    ArgumentCount { name: String, expected: usize, actual: usize },
+    AssignToImmutable(String),
+    InvalidAssignTarget,
+    Uninitialized(String),
+    InvalidLiteral(String),
+    InvalidExprRef(ExprRef),
+    NotSendable(Type),
+    Redefinition(String),
+    NotConst(String),
+    InvalidAttribute(String),
+}
+
+impl TypeCheckErrorKind {
+    // Stable code of the error, see `crate::explain`
+    pub fn code(&self) -> &'static str {
+        match self {
+            TypeCheckErrorKind::TypeMismatch { .. } => "E0001",
+            TypeCheckErrorKind::UndefinedVariable(_) => "E0002",
+            TypeCheckErrorKind::UndefinedFunction(_) => "E0003",
+            TypeCheckErrorKind::UnknownType(_) => "E0004",
+            TypeCheckErrorKind::ArgumentCount { .. } => "E0005",
+            TypeCheckErrorKind::AssignToImmutable(_) => "E0006",
+            TypeCheckErrorKind::InvalidAssignTarget => "E0007",
+            TypeCheckErrorKind::Uninitialized(_) => "E0008",
+            TypeCheckErrorKind::InvalidLiteral(_) => "E0009",
+            TypeCheckErrorKind::InvalidExprRef(_) => "E0010",
+            TypeCheckErrorKind::NotSendable(_) => "E0011",
+            TypeCheckErrorKind::Redefinition(_) => "E0012",
+            TypeCheckErrorKind::NotConst(_) => "E0013",
+            TypeCheckErrorKind::InvalidAttribute(_) => "E0014",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct TypeCheckError {
+    pub kind: TypeCheckErrorKind,
+    pub location: Option<Node>,
+}
+
+impl fmt::Display for TypeCheckErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TypeCheckErrorKind::TypeMismatch { expected, actual } =>
+                write!(f, "type mismatch: expected {:?} but {:?}", expected, actual),
+            TypeCheckErrorKind::UndefinedVariable(name) =>
+                write!(f, "undefined variable `{}`", name),
+            TypeCheckErrorKind::UndefinedFunction(name) =>
+                write!(f, "undefined function `{}`", name),
+            TypeCheckErrorKind::UnknownType(name) =>
+                write!(f, "unknown type `{}`", name),
+            TypeCheckErrorKind::ArgumentCount { name, expected, actual } =>
+                write!(f, "function `{}` takes {} argument(s) but {} given", name, expected, actual),
+            TypeCheckErrorKind::AssignToImmutable(name) =>
+                write!(f, "cannot assign twice to immutable variable `{}`", name),
+            TypeCheckErrorKind::InvalidAssignTarget =>
+                write!(f, "left hand side of assignment must be a variable"),
+            TypeCheckErrorKind::Uninitialized(name) =>
+                write!(f, "`{}` needs an initializer or a type", name),
+            TypeCheckErrorKind::InvalidLiteral(message) =>
+                write!(f, "{}", message),
+            TypeCheckErrorKind::InvalidExprRef(e) =>
+                write!(f, "invalid expression reference {:?}", e),
+            TypeCheckErrorKind::NotSendable(ty) =>
+                write!(f, "{:?} cannot be passed to a spawned function", ty),
+            TypeCheckErrorKind::Redefinition(name) =>
+                write!(f, "function `{}` cannot be redefined with another signature", name),
+            TypeCheckErrorKind::NotConst(name) =>
+                write!(f, "`{}` cannot be called from a const fn", name),
+            TypeCheckErrorKind::InvalidAttribute(name) =>
+                write!(f, "unknown attribute `{}` here", name),
+        }
+    }
+}
+
+impl fmt::Display for TypeCheckError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.location {
+            Some(node) => write!(f, "{}..{}: {}", node.start(), node.end(), self.kind),
+            None => write!(f, "{}", self.kind),
+        }
+    }
+}
+
+impl std::error::Error for TypeCheckError {}
+
+#[derive(Debug, Clone)]
+pub struct TypeCheckContext {
+    vars: Vec<HashMap<String, VarState>>, // scope stack, innermost last
+    functions: Vec<HashMap<String, FunctionSignature>>, // scope stack like `vars`
+    types: HashMap<u32, Type>, // of the checked expressions, by ExprRef
+    returning: HashSet<u32>, // `if`s giving the value of a function, by ExprRef
+    const_fns: HashSet<String>, // `const fn`s of the program
+    in_const: bool, // checking the body of a `const fn`
+    const_calls: Vec<ExprRef>, // calls of the `const fn`s, see `evaluate_constants`
+    constants: HashMap<u32, ConstValue>, // values of the evaluated calls, by ExprRef
+    attributes: AttributeRegistry,
+    features: HashSet<String>, // enabled for `#[cfg]`, see `crate::cfg`
+    prelude: Prelude, // declared in the outermost scope
+}
+
+impl TypeCheckContext {
+    // The functions of the standard prelude are declared (see `crate::prelude`)
+    pub fn new() -> Self {
+        let mut ctx = TypeCheckContext {
+            vars: vec![HashMap::new()],
+            functions: vec![HashMap::new()],
+            types: HashMap::new(),
+            returning: HashSet::new(),
+            const_fns: HashSet::new(),
+            in_const: false,
+            const_calls: vec![],
+            constants: HashMap::new(),
+            attributes: AttributeRegistry::new(),
+            features: HashSet::new(),
+            prelude: Prelude::empty(),
+        };
+        ctx.set_prelude(Prelude::new());
+        ctx
+    }
+
+    // Replace the functions of the prelude, e.g. with `Prelude::empty()`
+    // when the runtime has none of them
+    pub fn set_prelude(&mut self, prelude: Prelude) {
+        let global = self.functions.first_mut().unwrap();
+        for (name, _) in self.prelude.functions() {
+            global.remove(name);
+        }
+        for (name, signature) in prelude.functions() {
+            global.insert(name.to_string(), signature.clone());
+        }
+        self.prelude = prelude;
+    }
+
+    pub fn prelude(&self) -> &Prelude {
+        &self.prelude
+    }
+
+    // `dbg` of the prelude, not replaced by a function of the program
+    fn is_dbg(&self, name: &str) -> bool {
+        name == "dbg" && self.get_fn(name).is_some() && self.get_fn(name) == self.prelude.get(name)
+    }
+
+    pub fn push_scope(&mut self) {
+        self.vars.push(HashMap::new());
+        self.functions.push(HashMap::new());
+    }
+
+    pub fn pop_scope(&mut self) {
+        self.vars.pop();
+        self.functions.pop();
+    }
+
+    pub fn set_var(&mut self, name: &str, ty: Type, mutable: bool) {
+        self.vars.last_mut().unwrap().insert(name.to_string(), VarState { ty, mutable });
+    }
+
+    pub fn get_var(&self, name: &str) -> Option<&VarState> {
+        self.vars.iter().rev().find_map(|scope| scope.get(name))
+    }
+
+    pub fn set_fn(&mut self, name: &str, signature: FunctionSignature) {
+        self.functions.last_mut().unwrap().insert(name.to_string(), signature);
+    }
+
+    pub fn get_fn(&self, name: &str) -> Option<&FunctionSignature> {
+        self.functions.iter().rev().find_map(|scope| scope.get(name))
+    }
+
+    // Check all functions in the program. Errors of every function are collected.
+    pub fn check_program(&mut self, program: &Program) -> Result<(), Vec<TypeCheckError>> {
+        for f in &program.function {
+            self.set_fn(&f.name, FunctionSignature::of(f));
+        }
+        self.const_fns = program.function.iter().filter(|f| f.is_const).map(|f| f.name.clone()).collect();
+
+        self.types.clear();
+        self.returning.clear();
+        self.const_calls.clear();
+        self.constants.clear();
+        let mut errors = self.check_attributes(program);
+        for f in &program.function {
+            self.in_const = f.is_const;
+            if let Err(e) = self.check_function_returns(f, &program.expression, &program.location) {
+                errors.extend(e);
+            }
+        }
+        self.in_const = false;
+        if errors.is_empty() { Ok(()) } else { Err(errors) }
+    }
+
+    // The attributes which can be written in front of a function or a
+    // statement, see `crate::attribute`
+    pub fn attributes_mut(&mut self) -> &mut AttributeRegistry {
+        &mut self.attributes
+    }
+
+    // Enable `feature` for the programs loaded with this context, which
+    // configure them with `crate::cfg::configure` before checking
+    pub fn set_feature(&mut self, feature: &str) {
+        self.features.insert(feature.to_string());
+    }
+
+    pub fn features(&self) -> &HashSet<String> {
+        &self.features
+    }
+
+    fn check_attributes(&self, program: &Program) -> Vec<TypeCheckError> {
+        let nested = program.expression.0.iter().filter_map(|e| match e {
+            Expr::Function(f) => Some(f.as_ref()),
+            _ => None,
+        });
+        let functions = program.function.iter().chain(nested).flat_map(|f| f.attributes.iter().map(|a| (a, true)));
+        let statements = program.attributes.values().flatten().map(|a| (a, false));
+        let mut errors: Vec<TypeCheckError> = functions.chain(statements)
+            .filter(|(a, function)| !self.attributes.allows(a, *function))
+            .map(|(a, _)| TypeCheckError {
+                kind: TypeCheckErrorKind::InvalidAttribute(a.name.clone()),
+                location: Some(a.node.clone()),
+            })
+            .collect();
+        errors.sort_by_key(|e| e.location.as_ref().map(Node::start));
+        errors
+    }
+
+    // Evaluate the calls of `const fn`s in the checked program whose
+    // arguments are literals or evaluated calls. A call which the
+    // evaluator gives up on is left to the runtime. The result is the
+    // number of evaluated calls, see `constant` for their values.
+    pub fn evaluate_constants(&mut self, program: &Program, evaluator: &mut dyn ConstEvaluator) -> usize {
+        self.constants.clear();
+        if self.const_calls.is_empty() {
+            return 0;
+        }
+        evaluator.load(program);
+        let pool = &program.expression;
+        // the arguments of a call are added to the pool before it
+        let mut calls = self.const_calls.clone();
+        calls.sort_by_key(|e| e.0);
+        for call in calls {
+            let (name, args) = match pool.get(call.0 as usize) {
+                Some(Expr::Call(name, args)) => (name, *args),
+                _ => continue,
+            };
+            let args: Option<Vec<ConstValue>> = match pool.get(args.0 as usize) {
+                Some(Expr::Block(args)) => args.iter()
+                    .map(|arg| self.constant(*arg).or_else(|| pool.get(arg.0 as usize).and_then(ConstValue::of)))
+                    .collect(),
+                _ => None,
+            };
+            if let Some(value) = args.and_then(|args| evaluator.call(name, &args)) {
+                self.constants.insert(call.0, value);
+            }
+        }
+        self.constants.len()
+    }
+
+    // Value of the call `e` given by `evaluate_constants`
+    pub fn constant(&self, e: ExprRef) -> Option<ConstValue> {
+        self.constants.get(&e.0).copied()
+    }
+
+    pub fn constants(&self) -> &HashMap<u32, ConstValue> {
+        &self.constants
+    }
+
+    // `name` is a `const fn` of the program, not hidden by a function of a block
+    fn is_const_fn(&self, name: &str) -> bool {
+        self.const_fns.contains(name) && self.functions[1..].iter().all(|scope| !scope.contains_key(name))
+    }
+
+    // The first error of the function, see `check_program` for all of them
+    pub fn check_function(&mut self, f: &Function, pool: &ExprPool, location: &LocationPool) -> Result<Type, TypeCheckError> {
+        self.check_function_returns(f, pool, location).map_err(|mut errors| errors.remove(0))
+    }
+
+    // A body which doesn't have the declared return type is an error at
+    // each place its value comes from (see `return_sites`), or at the
+    // function when none of them can be blamed.
+    fn check_function_returns(&mut self, f: &Function, pool: &ExprPool, location: &LocationPool) -> Result<Type, Vec<TypeCheckError>> {
+        let expected = f.return_type.clone().unwrap_or(Type::Unit);
+        let mut ifs = vec![];
+        let sites = Self::return_sites(pool, f.code, &mut ifs);
+        if expected != Type::Unit {
+            self.returning.extend(ifs.iter().map(|e| e.0));
+        }
+        self.push_scope();
+        for (name, ty) in &f.parameter {
+            self.set_var(name, ty.clone(), false);
+        }
+        let ty = self.check_expr(pool, location, f.code);
+        self.pop_scope();
+
+        let ty = ty.map_err(|e| vec![e])?;
+        if expected == Type::Unit {
+            return Ok(ty);
+        }
+        let mut errors = vec![];
+        for site in sites {
+            match self.types.get(&site.0) {
+                Some(actual) if !Self::compatible(&expected, actual) => errors.push(TypeCheckError {
+                    kind: TypeCheckErrorKind::TypeMismatch { expected: expected.clone(), actual: actual.clone() },
+                    location: location.get(site).cloned(),
+                }),
+                _ => (),
+            }
+        }
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        if !Self::compatible(&expected, &ty) {
+            return Err(vec![TypeCheckError {
+                kind: TypeCheckErrorKind::TypeMismatch { expected, actual: ty },
+                location: Some(f.node.clone()),
+            }]);
+        }
+        Ok(ty)
+    }
+
+    // Expressions whose value is the value of `e`: the last expression of
+    // a block and both branches of `if`, through nested blocks. The `if`s
+    // on the way are added to `ifs`.
+    fn return_sites(pool: &ExprPool, e: ExprRef, ifs: &mut Vec<ExprRef>) -> Vec<ExprRef> {
+        match pool.get(e.0 as usize) {
+            Some(Expr::Block(expressions)) => match expressions.last() {
+                Some(last) => Self::return_sites(pool, *last, ifs),
+                None => vec![e],
+            },
+            Some(Expr::IfElse(_, then_block, else_block)) => match pool.get(else_block.0 as usize) {
+                // if without else is a statement
+                Some(Expr::Block(b)) if b.is_empty() => vec![e],
+                _ => {
+                    ifs.push(e);
+                    [Self::return_sites(pool, *then_block, ifs), Self::return_sites(pool, *else_block, ifs)].concat()
+                }
+            },
+            _ => vec![e],
+        }
+    }
+
+    // Entry point for an expression which is not a part of a program
+    // (e.g. REPL input). Integer literals left without width are resolved
+    // here with the types of known variables.
+    pub fn check_expression(&mut self, pool: &mut ExprPool, location: &LocationPool, e: ExprRef) -> Result<Type, TypeCheckError> {
+        self.types.clear();
+        let mut scope = HashMap::new();
+        for vars in &self.vars {
+            for (name, var) in vars {
+                scope.insert(name.clone(), var.ty.clone());
+            }
+        }
+        let functions = self.functions.iter().flatten().map(|(name, f)| (name.clone(), f.clone())).collect();
+        if let Err(err) = literal::resolve_expr_in_scope(pool, e, scope, functions) {
+            return Err(TypeCheckError {
+                kind: TypeCheckErrorKind::InvalidLiteral(err.to_string()),
+                location: location.get(e).cloned(),
+            });
+        }
+        self.check_expr(pool, location, e)
+    }
+
+    // Define the function `e` (an `Expr::Function`, e.g. REPL input) in the
+    // scope, replacing the one of the same name. Only the new function is
+    // checked, so it must keep the signature its callers were checked with.
+    // The previous definition is kept if it is rejected.
+    pub fn redefine_function(&mut self, pool: &mut ExprPool, location: &LocationPool, e: ExprRef) -> Result<FunctionSignature, TypeCheckError> {
+        let error = |kind| TypeCheckError { kind, location: location.get(e).cloned() };
+        let (name, signature) = match pool.get(e.0 as usize) {
+            Some(Expr::Function(f)) => (f.name.clone(), FunctionSignature::of(f)),
+            _ => return Err(error(TypeCheckErrorKind::InvalidExprRef(e))),
+        };
+        let previous = self.get_fn(&name).cloned();
+        if previous.as_ref().is_some_and(|previous| *previous != signature) {
+            return Err(error(TypeCheckErrorKind::Redefinition(name)));
+        }
+        if let Err(err) = self.check_expression(pool, location, e) {
+            match previous {
+                Some(previous) => self.set_fn(&name, previous),
+                None => {
+                    self.functions.last_mut().unwrap().remove(&name);
+                }
+            }
+            return Err(err);
+        }
+        Ok(signature)
+    }
+
+    fn compatible(lhs: &Type, rhs: &Type) -> bool {
+        // null has unknown type
+        match (lhs, rhs) {
+            (Type::Task(lhs), Type::Task(rhs)) | (Type::Channel(lhs), Type::Channel(rhs)) => Self::compatible(lhs, rhs),
+            _ => lhs == rhs || *lhs == Type::Unknown || *rhs == Type::Unknown,
+        }
+    }
+
+    // A spawned function runs on another thread, so its arguments are
+    // copied to it. Only plain values and channels can be: a task handle
+    // belongs to the thread which spawned it and nothing else mutable is
+    // shared by the threads.
+    fn sendable(ty: &Type) -> bool {
+        matches!(ty, Type::Int64 | Type::UInt64 | Type::Bool | Type::Unit | Type::Unknown | Type::Channel(_))
+    }
+
+    pub fn check_expr(&mut self, pool: &ExprPool, location: &LocationPool, e: ExprRef) -> Result<Type, TypeCheckError> {
+        let ty = self.check_expr_type(pool, location, e)?;
+        self.types.insert(e.0, ty.clone());
+        Ok(ty)
+    }
+
+    fn check_expr_type(&mut self, pool: &ExprPool, location: &LocationPool, e: ExprRef) -> Result<Type, TypeCheckError> {
+        let error = |kind: TypeCheckErrorKind| TypeCheckError { kind, location: location.get(e).cloned() };
+        let mismatch = |expected: &Type, actual: &Type| error(TypeCheckErrorKind::TypeMismatch {
+            expected: expected.clone(),
+            actual: actual.clone(),
+        });
+
+        let expr = match pool.get(e.0 as usize) {
+            Some(expr) => expr,
+            None => return Err(error(TypeCheckErrorKind::InvalidExprRef(e))),
+        };
+        match expr {
+            Expr::Int64(_) => Ok(Type::Int64),
+            Expr::UInt64(_) => Ok(Type::UInt64),
+            Expr::Int(_) => Ok(Type::Int64),
+            Expr::Null => Ok(Type::Unknown),
+            Expr::Identifier(name) => match self.get_var(name) {
+                Some(var) => Ok(var.ty.clone()),
+                None => Err(error(TypeCheckErrorKind::UndefinedVariable(name.to_string()))),
+            },
+            Expr::Binary(Operator::Assign, lhs, rhs) => {
+                let name = match pool.get(lhs.0 as usize) {
+                    Some(Expr::Identifier(name)) => name,
+                    _ => return Err(error(TypeCheckErrorKind::InvalidAssignTarget)),
+                };
+                let var = match self.get_var(name) {
+                    Some(var) => var.clone(),
+                    None => return Err(error(TypeCheckErrorKind::UndefinedVariable(name.to_string()))),
+                };
+                if !var.mutable {
+                    return Err(error(TypeCheckErrorKind::AssignToImmutable(name.to_string())));
+                }
+                let rhs_ty = self.check_expr(pool, location, *rhs)?;
+                if !Self::compatible(&var.ty, &rhs_ty) {
+                    return Err(mismatch(&var.ty, &rhs_ty));
+                }
+                Ok(Type::Unit)
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                let lhs_ty = self.check_expr(pool, location, *lhs)?;
+                let rhs_ty = self.check_expr(pool, location, *rhs)?;
+                match op {
+                    Operator::IAdd | Operator::ISub | Operator::IMul | Operator::IDiv => {
+                        if lhs_ty != Type::Int64 && lhs_ty != Type::UInt64 {
+                            return Err(mismatch(&Type::UInt64, &lhs_ty));
+                        }
+                        if lhs_ty != rhs_ty {
+                            return Err(mismatch(&lhs_ty, &rhs_ty));
+                        }
+                        Ok(lhs_ty)
+                    }
+                    Operator::EQ | Operator::NE | Operator::LT | Operator::LE |
+                    Operator::GT | Operator::GE => {
+                        if !Self::compatible(&lhs_ty, &rhs_ty) {
+                            return Err(mismatch(&lhs_ty, &rhs_ty));
+                        }
+                        Ok(Type::Bool)
+                    }
+                    Operator::LogicalAnd | Operator::LogicalOr => {
+                        if lhs_ty != Type::Bool {
+                            return Err(mismatch(&Type::Bool, &lhs_ty));
+                        }
+                        if rhs_ty != Type::Bool {
+                            return Err(mismatch(&Type::Bool, &rhs_ty));
+                        }
+                        Ok(Type::Bool)
+                    }
+                    Operator::Assign => unreachable!(),
+                }
+            }
+            Expr::Block(expressions) => {
+                self.push_scope();
+                let mut ty = Ok(Type::Unit);
+                for e in expressions {
+                    ty = self.check_expr(pool, location, *e);
+                    if ty.is_err() {
+                        break;
+                    }
+                }
+                self.pop_scope();
+                ty
+            }
+            Expr::IfElse(cond, then_block, else_block) => {
+                let cond_ty = self.check_expr(pool, location, *cond)?;
+                if cond_ty != Type::Bool {
+                    return Err(mismatch(&Type::Bool, &cond_ty));
+                }
+                let then_ty = self.check_expr(pool, location, *then_block)?;
+                let else_ty = self.check_expr(pool, location, *else_block)?;
+                match pool.get(else_block.0 as usize) {
+                    // if without else is a statement
+                    Some(Expr::Block(b)) if b.is_empty() => Ok(Type::Unit),
+                    _ if Self::compatible(&then_ty, &else_ty) => Ok(then_ty),
+                    // checked against the return type at each branch instead
+                    _ if self.returning.contains(&e.0) => Ok(then_ty),
+                    _ => Err(mismatch(&then_ty, &else_ty)),
+                }
+            }
+            Expr::While(cond, body) => {
+                let cond_ty = self.check_expr(pool, location, *cond)?;
+                if cond_ty != Type::Bool {
+                    return Err(mismatch(&Type::Bool, &cond_ty));
+                }
+                self.check_expr(pool, location, *body)?;
+                Ok(Type::Unit)
+            }
+            Expr::For(name, start, end, body) => {
+                let start_ty = self.check_expr(pool, location, *start)?;
+                let end_ty = self.check_expr(pool, location, *end)?;
+                if !literal::is_integer_type(&start_ty) {
+                    return Err(mismatch(&Type::Int64, &start_ty));
+                }
+                if start_ty != end_ty {
+                    return Err(mismatch(&start_ty, &end_ty));
+                }
+                // the induction variable cannot be assigned in the body
+                self.push_scope();
+                self.set_var(name, start_ty, false);
+                let ty = self.check_expr(pool, location, *body);
+                self.pop_scope();
+                ty?;
+                Ok(Type::Unit)
+            }
+            Expr::Spawn(_) if self.in_const => Err(error(TypeCheckErrorKind::NotConst("spawn".to_string()))),
+            Expr::Spawn(call) => {
+                let args = match pool.get(call.0 as usize) {
+                    Some(Expr::Call(_, args)) => *args,
+                    _ => return Err(error(TypeCheckErrorKind::InvalidExprRef(*call))),
+                };
+                let ty = self.check_expr(pool, location, *call)?;
+                if let Some(Expr::Block(args)) = pool.get(args.0 as usize) {
+                    for arg in args {
+                        let arg_ty = self.check_expr(pool, location, *arg)?;
+                        if !Self::sendable(&arg_ty) {
+                            return Err(TypeCheckError {
+                                kind: TypeCheckErrorKind::NotSendable(arg_ty),
+                                location: location.get(*arg).cloned(),
+                            });
+                        }
+                    }
+                }
+                Ok(Type::Task(Box::new(ty)))
+            }
+            Expr::Function(f) => {
+                // defined before its body is checked, so that it can recurse
+                self.set_fn(&f.name, FunctionSignature::of(f));
+                // no capture: the body sees the functions but not the variables around it
+                let vars = std::mem::replace(&mut self.vars, vec![HashMap::new()]);
+                let in_const = std::mem::replace(&mut self.in_const, false);
+                let ty = self.check_function(f, pool, location);
+                self.vars = vars;
+                self.in_const = in_const;
+                ty?;
+                Ok(Type::Unit)
+            }
+            Expr::Val(name, ty, rhs) => self.check_binding(pool, location, e, name, ty, rhs, false),
+            Expr::Var(name, ty, rhs) => self.check_binding(pool, location, e, name, ty, rhs, true),
+            // `dbg(x)` gives `x` back after printing it, so it has any type
+            Expr::Call(name, _) if self.is_dbg(name) && self.in_const =>
+                Err(error(TypeCheckErrorKind::NotConst(name.to_string()))),
+            Expr::Call(name, args) if self.is_dbg(name) => {
+                match pool.get(args.0 as usize) {
+                    Some(Expr::Block(args)) if args.len() == 1 => self.check_expr(pool, location, args[0]),
+                    Some(Expr::Block(args)) => Err(error(TypeCheckErrorKind::ArgumentCount {
+                        name: name.to_string(),
+                        expected: 1,
+                        actual: args.len(),
+                    })),
+                    _ => Err(error(TypeCheckErrorKind::InvalidExprRef(*args))),
+                }
+            }
+            Expr::Call(name, args) => {
+                let signature = match self.get_fn(name) {
+                    Some(s) => s.clone(),
+                    None => return Err(error(TypeCheckErrorKind::UndefinedFunction(name.to_string()))),
+                };
+                if self.is_const_fn(name) {
+                    self.const_calls.push(e);
+                } else if self.in_const {
+                    return Err(error(TypeCheckErrorKind::NotConst(name.to_string())));
+                }
+                let args = match pool.get(args.0 as usize) {
+                    Some(Expr::Block(args)) => args,
+                    _ => return Err(error(TypeCheckErrorKind::InvalidExprRef(*args))),
+                };
+                if args.len() != signature.parameter.len() {
+                    return Err(error(TypeCheckErrorKind::ArgumentCount {
+                        name: name.to_string(),
+                        expected: signature.parameter.len(),
+                        actual: args.len(),
+                    }));
+                }
+                if signature.is_generic() {
+                    let mut generic = None;
+                    for arg in args {
+                        let ty = self.check_expr(pool, location, *arg)?;
+                        let mismatch = match &generic {
+                            None => !literal::is_integer_type(&ty),
+                            Some(expected) => *expected != ty,
+                        };
+                        if mismatch {
+                            return Err(TypeCheckError {
+                                kind: TypeCheckErrorKind::TypeMismatch {
+                                    expected: generic.unwrap_or(Type::Int64),
+                                    actual: ty,
+                                },
+                                location: location.get(*arg).cloned(),
+                            });
+                        }
+                        generic = Some(ty);
+                    }
+                    return Ok(generic.unwrap_or(Type::Unit));
+                }
+                // `Unknown` of a builtin over a task or a channel (e.g. `join`,
+                // `send`) is the element type of it
+                let mut element: Option<Type> = None;
+                for (arg, expected) in args.iter().zip(signature.parameter.iter()) {
+                    let ty = self.check_expr(pool, location, *arg)?;
+                    let expected = match (expected, &element) {
+                        (Type::Unknown, Some(element)) => element,
+                        (expected, _) => expected,
+                    };
+                    if !Self::compatible(expected, &ty) {
+                        return Err(TypeCheckError {
+                            kind: TypeCheckErrorKind::TypeMismatch { expected: expected.clone(), actual: ty },
+                            location: location.get(*arg).cloned(),
+                        });
+                    }
+                    if element.is_none() && expected.element().is_some() {
+                        element = ty.element().cloned();
+                    }
+                }
+                match (signature.return_type, element) {
+                    (Type::Unknown, Some(element)) => Ok(element),
+                    (result, _) => Ok(result),
+                }
+            }
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn check_binding(&mut self, pool: &ExprPool, location: &LocationPool, e: ExprRef,
+                     name: &str, ty: &Option<Type>, rhs: &Option<ExprRef>, mutable: bool) -> Result<Type, TypeCheckError> {
+        let error = |kind: TypeCheckErrorKind| TypeCheckError { kind, location: location.get(e).cloned() };
+        let declared = match ty {
+            Some(Type::Unknown) | None => None,
+            Some(Type::Identifier(name)) => return Err(error(TypeCheckErrorKind::UnknownType(name.to_string()))),
+            Some(ty) => Some(ty.clone()),
+        };
+        let rhs_ty = match rhs {
+            Some(rhs) => Some(self.check_expr(pool, location, *rhs)?),
+            None => None,
+        };
+        let ty = match (declared, rhs_ty) {
+            (Some(declared), Some(rhs_ty)) => {
+                if !Self::compatible(&declared, &rhs_ty) {
+                    return Err(error(TypeCheckErrorKind::TypeMismatch { expected: declared, actual: rhs_ty }));
+                }
+                declared
+            }
+            // `var` can be initialized later by assignment
+            (Some(declared), None) if mutable => declared,
+            // the element type of `channel()` is not inferred from its uses
+            (None, Some(Type::Channel(element))) if *element == Type::Unknown =>
+                return Err(error(TypeCheckErrorKind::Uninitialized(name.to_string()))),
+            (None, Some(rhs_ty)) => rhs_ty,
+            _ => return Err(error(TypeCheckErrorKind::Uninitialized(name.to_string()))),
+        };
+        self.set_var(name, ty, mutable);
+        Ok(Type::Unit)
+    }
+}
+
+impl Default for TypeCheckContext {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Parser;
+
+    fn check(ctx: &mut TypeCheckContext, input: &str) -> Result<Type, TypeCheckError> {
+        let mut parser = Parser::new(input);
+        let (e, mut pool) = parser.parse_expression().unwrap();
+        ctx.check_expression(&mut pool, parser.location(), e)
+    }
+
+    #[test]
+    fn check_arithmetic() {
+        let mut ctx = TypeCheckContext::new();
+        assert_eq!(Ok(Type::UInt64), check(&mut ctx, "1u64 + 2u64 * 3"));
+        assert_eq!(Ok(Type::Bool), check(&mut ctx, "1i64 < 2i64 && 3u64 == 4u64"));
+        let err = check(&mut ctx, "1u64 + 2i64").unwrap_err();
+        assert_eq!(TypeCheckErrorKind::TypeMismatch { expected: Type::UInt64, actual: Type::Int64 }, err.kind);
+        assert_eq!(Some(Node::new(0, 11)), err.location);
+    }
+
+    #[test]
+    fn check_assign_to_val() {
+        let mut ctx = TypeCheckContext::new();
+        assert_eq!(Ok(Type::Unit), check(&mut ctx, "val a = 1u64"));
+        let err = check(&mut ctx, "a = 2u64").unwrap_err();
+        assert_eq!(TypeCheckErrorKind::AssignToImmutable("a".to_string()), err.kind);
+        assert_eq!("0..8: cannot assign twice to immutable variable `a`", err.to_string());
+    }
+
+    #[test]
+    fn check_assign_to_var() {
+        let mut ctx = TypeCheckContext::new();
+        assert_eq!(Ok(Type::Unit), check(&mut ctx, "var a = 1u64"));
+        assert_eq!(Ok(Type::Unit), check(&mut ctx, "a = a + 2"));
+        assert_eq!(Ok(Type::Unit), check(&mut ctx, "var b: i64"));
+        assert_eq!(Ok(Type::Unit), check(&mut ctx, "b = -1"));
+        let err = check(&mut ctx, "a = -1i64").unwrap_err();
+        assert_eq!(TypeCheckErrorKind::TypeMismatch { expected: Type::UInt64, actual: Type::Int64 }, err.kind);
+        let err = check(&mut ctx, "val c").unwrap_err();
+        assert_eq!(TypeCheckErrorKind::Uninitialized("c".to_string()), err.kind);
+    }
+
+    #[test]
+    fn check_program_parameter_is_immutable() {
+        let code = r#"
+fn f(a: u64) -> u64 {
+var b = a
+b = b + 1
+a = b
+}
+
+fn g() -> u64 {
+f(1u64, 2u64)
+}
+        "#;
+        let prog = Parser::new(code).parse_program().unwrap();
+        let errors = TypeCheckContext::new().check_program(&prog).unwrap_err();
+        assert_eq!(2, errors.len());
+        assert_eq!(TypeCheckErrorKind::AssignToImmutable("a".to_string()), errors[0].kind);
+        assert_eq!("a = b", &code[errors[0].location.as_ref().unwrap().start()..errors[0].location.as_ref().unwrap().end()]);
+        assert_eq!(
+            TypeCheckErrorKind::ArgumentCount { name: "f".to_string(), expected: 1, actual: 2 },
+            errors[1].kind
+        );
+    }
+
+    #[test]
+    fn check_spawn() {
+        let mut ctx = TypeCheckContext::new();
+        ctx.set_fn("f", FunctionSignature { parameter: vec![Type::UInt64], return_type: Type::UInt64 });
+        ctx.set_fn("join", FunctionSignature { parameter: vec![Type::Task(Box::new(Type::Unknown))], return_type: Type::Unknown });
+        assert_eq!(Ok(Type::Unit), check(&mut ctx, "val t = spawn f(1)"));
+        assert_eq!(Ok(Type::Task(Box::new(Type::UInt64))), check(&mut ctx, "t"));
+        assert_eq!(Ok(Type::UInt64), check(&mut ctx, "join(t) + 1"));
+        ctx.set_fn("g", FunctionSignature { parameter: vec![Type::Task(Box::new(Type::UInt64))], return_type: Type::Unit });
+        assert_eq!(Ok(Type::Unit), check(&mut ctx, "g(t)"));
+        let err = check(&mut ctx, "spawn g(t)").unwrap_err();
+        assert_eq!(TypeCheckErrorKind::NotSendable(Type::Task(Box::new(Type::UInt64))), err.kind);
+        assert_eq!(Some(Node::new(8, 9)), err.location);
+    }
+
+    #[test]
+    fn check_channel() {
+        let mut ctx = TypeCheckContext::new();
+        let channel = |ty: Type| Type::Channel(Box::new(ty));
+        ctx.set_fn("channel", FunctionSignature { parameter: vec![], return_type: channel(Type::Unknown) });
+        ctx.set_fn("send", FunctionSignature { parameter: vec![channel(Type::Unknown), Type::Unknown], return_type: Type::Unit });
+        ctx.set_fn("recv", FunctionSignature { parameter: vec![channel(Type::Unknown)], return_type: Type::Unknown });
+        assert_eq!(Ok(Type::Unit), check(&mut ctx, "val ch: channel<u64> = channel()"));
+        assert_eq!(Ok(Type::Unit), check(&mut ctx, "send(ch, 1)"));
+        assert_eq!(Ok(Type::UInt64), check(&mut ctx, "recv(ch) + 1"));
+        let err = check(&mut ctx, "send(ch, 1i64)").unwrap_err();
+        assert_eq!(TypeCheckErrorKind::TypeMismatch { expected: Type::UInt64, actual: Type::Int64 }, err.kind);
+        let err = check(&mut ctx, "val c = channel()").unwrap_err();
+        assert_eq!(TypeCheckErrorKind::Uninitialized("c".to_string()), err.kind);
+        // a channel can be passed to a spawned function
+        ctx.set_fn("produce", FunctionSignature { parameter: vec![channel(Type::UInt64)], return_type: Type::Unit });
+        assert_eq!(Ok(Type::Task(Box::new(Type::Unit))), check(&mut ctx, "spawn produce(ch)"));
+    }
+
+    #[test]
+    fn check_prelude() {
+        let mut ctx = TypeCheckContext::new();
+        assert_eq!(Ok(Type::Unit), check(&mut ctx, "print(1u64)"));
+        assert_eq!(Ok(Type::Int64), check(&mut ctx, "to_i64(2u64) + 1"));
+        let err = check(&mut ctx, "to_u64(1u64) + 1i64").unwrap_err();
+        assert_eq!(TypeCheckErrorKind::TypeMismatch { expected: Type::UInt64, actual: Type::Int64 }, err.kind);
+        let err = check(&mut ctx, "len(1u64)").unwrap_err();
+        assert_eq!(TypeCheckErrorKind::TypeMismatch { expected: Type::Channel(Box::new(Type::Unknown)), actual: Type::UInt64 }, err.kind);
+
+        // the embedder replaces the prelude
+        let mut prelude = Prelude::empty();
+        prelude.register("hash", FunctionSignature { parameter: vec![Type::UInt64], return_type: Type::UInt64 });
+        ctx.set_prelude(prelude);
+        let err = check(&mut ctx, "print(1u64)").unwrap_err();
+        assert_eq!(TypeCheckErrorKind::UndefinedFunction("print".to_string()), err.kind);
+        assert_eq!(Ok(Type::UInt64), check(&mut ctx, "hash(1u64)"));
+    }
+
+    #[test]
+    fn check_nested_function() {
+        let code = r#"
+fn main() -> u64 {
+fn fact(n: u64) -> u64 {
+if n == 0 { 1 } else { n * fact(n - 1u64) }
+}
+fact(5)
+}
+
+fn f() -> u64 {
+val a = 1u64
+fn g() -> u64 {
+a
+}
+g()
+}
+
+fn h() -> u64 {
+for i in 0..1u64 {
+fn inner() -> u64 {
+1
+}
+}
+inner()
+}
+        "#;
+        let prog = Parser::new(code).parse_program().unwrap();
+        let errors = TypeCheckContext::new().check_program(&prog).unwrap_err();
+        assert_eq!(2, errors.len());
+        // no capture of the variables around
+        assert_eq!(TypeCheckErrorKind::UndefinedVariable("a".to_string()), errors[0].kind);
+        // visible in its block only
+        assert_eq!(TypeCheckErrorKind::UndefinedFunction("inner".to_string()), errors[1].kind);
+    }
+
+    #[test]
+    fn check_redefine_function() {
+        let mut ctx = TypeCheckContext::new();
+        let mut redefine = |input: &str| {
+            let mut parser = Parser::new(input);
+            let (e, mut pool) = parser.parse_expression().unwrap();
+            ctx.redefine_function(&mut pool, parser.location(), e).map_err(|e| e.kind)
+        };
+        let signature = FunctionSignature { parameter: vec![Type::UInt64], return_type: Type::UInt64 };
+        assert_eq!(Ok(signature.clone()), redefine("fn fib(n: u64) -> u64 { if n < 2 { n } else { fib(n - 1) + fib(n - 2) } }"));
+        assert_eq!(Ok(signature), redefine("fn fib(n: u64) -> u64 { n }"));
+        assert_eq!(Err(TypeCheckErrorKind::Redefinition("fib".to_string())), redefine("fn fib(n: i64) -> i64 { n }"));
+        // a rejected function is not defined
+        assert!(matches!(redefine("fn f() -> u64 { 1i64 }"), Err(TypeCheckErrorKind::TypeMismatch { .. })));
+        assert_eq!(Err(TypeCheckErrorKind::UndefinedFunction("f".to_string())), check(&mut ctx, "f()").map_err(|e| e.kind));
+        assert_eq!(Ok(Type::UInt64), check(&mut ctx, "fib(3)"));
+    }
+
+    #[test]
+    fn check_return_sites() {
+        let code = r#"
+fn f(a: i64) -> u64 {
+if a < 0 {
+1u64
+} else if a == 0 {
+2i64
+} else {
+val b = 3i64
+b
+}
+}
+        "#;
+        let prog = Parser::new(code).parse_program().unwrap();
+        let errors = TypeCheckContext::new().check_program(&prog).unwrap_err();
+        // each branch whose value is not the return type, not the function
+        let text = |e: &TypeCheckError| &code[e.location.as_ref().unwrap().start()..e.location.as_ref().unwrap().end()];
+        assert_eq!(vec!["2i64", "b"], errors.iter().map(text).collect::<Vec<_>>());
+        assert_eq!(TypeCheckErrorKind::TypeMismatch { expected: Type::UInt64, actual: Type::Int64 }, errors[0].kind);
+    }
+
+    #[test]
+    fn check_dbg() {
+        let mut ctx = TypeCheckContext::new();
+        assert_eq!(Ok(Type::UInt64), check(&mut ctx, "dbg(1u64) + 2"));
+        assert_eq!(Ok(Type::Bool), check(&mut ctx, "dbg(1 < 2)"));
+        let err = check(&mut ctx, "dbg(1, 2)").unwrap_err();
+        assert_eq!(TypeCheckErrorKind::ArgumentCount { name: "dbg".to_string(), expected: 1, actual: 2 }, err.kind);
+        // a function of the program named `dbg` is called instead
+        ctx.set_fn("dbg", FunctionSignature { parameter: vec![], return_type: Type::Unit });
+        assert_eq!(Ok(Type::Unit), check(&mut ctx, "dbg()"));
+        // not without the prelude
+        let mut ctx = TypeCheckContext::new();
+        ctx.set_prelude(Prelude::empty());
+        let err = check(&mut ctx, "dbg(1u64)").unwrap_err();
+        assert_eq!(TypeCheckErrorKind::UndefinedFunction("dbg".to_string()), err.kind);
+    }
+
+    #[test]
+    fn check_loop() {
+        let mut ctx = TypeCheckContext::new();
+        assert_eq!(Ok(Type::Unit), check(&mut ctx, "var n = 0u64"));
+        assert_eq!(Ok(Type::Unit), check(&mut ctx, "while n < 10 { n = n + 1 }"));
+        assert_eq!(Ok(Type::Unit), check(&mut ctx, "for i in 0..n { n = n + i }"));
+        let err = check(&mut ctx, "while n { }").unwrap_err();
+        assert_eq!(TypeCheckErrorKind::TypeMismatch { expected: Type::Bool, actual: Type::UInt64 }, err.kind);
+        let err = check(&mut ctx, "for i in 0i64..n { }").unwrap_err();
+        assert_eq!(TypeCheckErrorKind::TypeMismatch { expected: Type::Int64, actual: Type::UInt64 }, err.kind);
+        let err = check(&mut ctx, "for i in 0..n { i = 1 }").unwrap_err();
+        assert_eq!(TypeCheckErrorKind::AssignToImmutable("i".to_string()), err.kind);
+        // the induction variable is not visible after the loop
+        let err = check(&mut ctx, "i").unwrap_err();
+        assert_eq!(TypeCheckErrorKind::UndefinedVariable("i".to_string()), err.kind);
+    }
+
+    #[test]
+    fn check_attributes() {
+        let source = "#[test]\nfn f() -> u64 {\n#[inline]\nval x = 1u64\n#[tets]\nfn g() -> u64 {\nx\n}\nx\n}";
+        let program = Parser::new(source).parse_program().unwrap();
+        let mut ctx = TypeCheckContext::new();
+        let errors: Vec<_> = ctx.check_program(&program).unwrap_err().into_iter().map(|e| e.kind).collect();
+        assert_eq!(vec![
+            TypeCheckErrorKind::InvalidAttribute("inline".to_string()),
+            TypeCheckErrorKind::InvalidAttribute("tets".to_string()),
+            TypeCheckErrorKind::UndefinedVariable("x".to_string()),
+        ], errors);
+
+        // attributes of the embedder
+        let source = "#[test]\nfn f() -> u64 {\n#[inline]\nval x = 1u64\n#[tets]\nfn g() -> u64 {\n1u64\n}\nx\n}";
+        let program = Parser::new(source).parse_program().unwrap();
+        ctx.attributes_mut().register("inline", crate::attribute::AttributeTarget::Any);
+        ctx.attributes_mut().register("tets", crate::attribute::AttributeTarget::Function);
+        assert_eq!(Ok(()), ctx.check_program(&program));
+    }
+
+    // Sum of the arguments, `None` for a negative one
+    struct Sum;
+
+    impl ConstEvaluator for Sum {
+        fn load(&mut self, _program: &Program) {}
+
+        fn call(&mut self, _name: &str, args: &[ConstValue]) -> Option<ConstValue> {
+            args.iter().try_fold(ConstValue::Int64(0), |sum, arg| match (sum, arg) {
+                (ConstValue::Int64(sum), ConstValue::Int64(v)) if *v >= 0 => Some(ConstValue::Int64(sum + v)),
+                _ => None,
+            })
+        }
+    }
+
+    #[test]
+    fn check_const_fn() {
+        let errors = |source: &str| {
+            let program = Parser::new(source).parse_program().unwrap();
+            TypeCheckContext::new().check_program(&program).err().map(|e| e[0].kind.clone())
+        };
+        let not_const = |name: &str| Some(TypeCheckErrorKind::NotConst(name.to_string()));
+        assert_eq!(not_const("g"), errors("fn g() -> i64 {\n1i64\n}\nconst fn f() -> i64 {\ng()\n}"));
+        assert_eq!(not_const("dbg"), errors("const fn f() -> i64 {\ndbg(1i64)\n}"));
+        assert_eq!(not_const("spawn"), errors("const fn f() -> i64 {\nspawn f()\n0i64\n}"));
+        // a function of a block hides the `const fn`, and its body is not const
+        assert_eq!(not_const("f"), errors("const fn f() -> i64 {\nfn f() -> i64 {\n1i64\n}\nf()\n}"));
+        assert_eq!(None, errors("fn g() -> i64 {\n1i64\n}\nconst fn f() -> i64 {\nfn h() -> i64 {\ng()\n}\n1i64\n}"));
+
+        let source = "const fn add(a: i64, b: i64) -> i64 {\na + b\n}\n\
+            fn main() -> i64 {\nval x = add(add(1i64, 2i64), 3i64)\nadd(x, 1i64) + add(-1i64, 1i64)\n}";
+        let program = Parser::new(source).parse_program().unwrap();
+        let mut ctx = TypeCheckContext::new();
+        ctx.check_program(&program).unwrap();
+        // `a + b` of the body and `x` are not constants, -1 is refused by the evaluator
+        assert_eq!(2, ctx.evaluate_constants(&program, &mut Sum));
+        let calls: Vec<ExprRef> = (0..program.expression.len() as u32).map(ExprRef)
+            .filter(|e| matches!(program.expression.get(e.0 as usize), Some(Expr::Call(..)))).collect();
+        let values: Vec<_> = calls.iter().map(|e| ctx.constant(*e)).collect();
+        assert_eq!(vec![Some(ConstValue::Int64(3)), Some(ConstValue::Int64(6)), None, None], values);
+    }
+}
@@ -0,0 +1,305 @@
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use frontend::ast::Type;
+use frontend::prelude::Prelude;
+use frontend::type_checker::FunctionSignature;
+use crate::cancel::CancellationToken;
+use crate::channel::{self, Channels};
+use crate::clock::Clock;
+use crate::object::Object;
+use crate::policy::Capability;
+use crate::processor::Processor;
+use crate::task::Tasks;
+
+// Math builtins. They are generic over the integer types: all arguments
+// and the result have the same type (i64 or u64).
+//   abs(x), min(a, b), max(a, b), pow(base, exp), sqrt(x), clamp(x, lo, hi)
+// sqrt is the integer square root rounded down.
+pub fn register_math(p: &mut Processor) {
+    p.register_native("abs", FunctionSignature::generic(1), |args| match args {
+        [Object::Int64(i)] => i.checked_abs().map(Object::Int64).ok_or_else(overflow),
+        [Object::UInt64(u)] => Ok(Object::UInt64(*u)),
+        _ => Err(invalid(args)),
+    });
+    p.register_native("min", FunctionSignature::generic(2), |args| match args {
+        [Object::Int64(a), Object::Int64(b)] => Ok(Object::Int64(*a.min(b))),
+        [Object::UInt64(a), Object::UInt64(b)] => Ok(Object::UInt64(*a.min(b))),
+        _ => Err(invalid(args)),
+    });
+    p.register_native("max", FunctionSignature::generic(2), |args| match args {
+        [Object::Int64(a), Object::Int64(b)] => Ok(Object::Int64(*a.max(b))),
+        [Object::UInt64(a), Object::UInt64(b)] => Ok(Object::UInt64(*a.max(b))),
+        _ => Err(invalid(args)),
+    });
+    p.register_native("pow", FunctionSignature::generic(2), |args| match args {
+        [Object::Int64(base), Object::Int64(exp)] => {
+            let exp = u32::try_from(*exp).map_err(|_| format!("invalid exponent {}", exp))?;
+            base.checked_pow(exp).map(Object::Int64).ok_or_else(overflow)
+        }
+        [Object::UInt64(base), Object::UInt64(exp)] => {
+            let exp = u32::try_from(*exp).map_err(|_| format!("invalid exponent {}", exp))?;
+            base.checked_pow(exp).map(Object::UInt64).ok_or_else(overflow)
+        }
+        _ => Err(invalid(args)),
+    });
+    p.register_native("sqrt", FunctionSignature::generic(1), |args| match args {
+        [Object::Int64(i)] if *i < 0 => Err(format!("square root of negative number {}", i)),
+        [Object::Int64(i)] => Ok(Object::Int64(i.isqrt())),
+        [Object::UInt64(u)] => Ok(Object::UInt64(u.isqrt())),
+        _ => Err(invalid(args)),
+    });
+    p.register_native("clamp", FunctionSignature::generic(3), |args| match args {
+        [Object::Int64(x), Object::Int64(lo), Object::Int64(hi)] if lo <= hi => Ok(Object::Int64(*x.clamp(lo, hi))),
+        [Object::UInt64(x), Object::UInt64(lo), Object::UInt64(hi)] if lo <= hi => Ok(Object::UInt64(*x.clamp(lo, hi))),
+        [_, lo, hi] if lo.ty() == hi.ty() => Err(format!("lower bound {} is greater than upper bound {}", lo, hi)),
+        _ => Err(invalid(args)),
+    });
+}
+
+// Random numbers from a SplitMix64 generator whose state is shared with
+// the processor (see `Processor::set_random_seed`).
+//   random_u64() -> u64, random_range(lo, hi) with lo <= x < hi
+pub fn register_random(p: &mut Processor, state: Rc<Cell<u64>>) {
+    let s = state.clone();
+    p.register_native("random_u64", FunctionSignature { parameter: vec![], return_type: Type::UInt64 }, move |_| {
+        Ok(Object::UInt64(next_random(&s)))
+    });
+    p.register_native("random_range", FunctionSignature::generic(2), move |args| {
+        let (lo, hi) = match args {
+            [Object::Int64(lo), Object::Int64(hi)] => (*lo as i128, *hi as i128),
+            [Object::UInt64(lo), Object::UInt64(hi)] => (*lo as i128, *hi as i128),
+            _ => return Err(invalid(args)),
+        };
+        if lo >= hi {
+            return Err(format!("empty range {}..{}", lo, hi));
+        }
+        let span = (hi - lo) as u128;
+        let x = lo + ((next_random(&state) as u128 * span) >> 64) as i128;
+        Ok(match args[0] {
+            Object::Int64(_) => Object::Int64(x as i64),
+            _ => Object::UInt64(x as u64),
+        })
+    });
+}
+
+// Time builtins, allowed only when the policy allows time access.
+//   now_millis() -> u64       milliseconds since the Unix epoch
+//   monotonic_nanos() -> u64  for measuring elapsed time
+pub fn register_time(p: &mut Processor, clock: Rc<RefCell<Box<dyn Clock>>>) {
+    let signature = FunctionSignature { parameter: vec![], return_type: Type::UInt64 };
+    let c = clock.clone();
+    p.register_native_requiring("now_millis", Capability::Time, signature.clone(), move |_| {
+        Ok(Object::UInt64(c.borrow().now_millis()))
+    });
+    p.register_native_requiring("monotonic_nanos", Capability::Time, signature, move |_| {
+        Ok(Object::UInt64(clock.borrow().monotonic_nanos()))
+    });
+}
+
+// `assert(cond)` fails the run when `cond` is false. The processor
+// reports it as `InterpreterError::AssertionFailed` with the location.
+pub fn register_assert(p: &mut Processor) {
+    p.register_native("assert", FunctionSignature { parameter: vec![Type::Bool], return_type: Type::Unit }, |args| match args {
+        [Object::Bool(true)] => Ok(Object::Unit),
+        [Object::Bool(false)] => Err("assertion failed".to_string()),
+        _ => Err(invalid(args)),
+    });
+}
+
+// The functions of the prelude (see `frontend::prelude`) but `len`,
+// which is registered with the channels, and `dbg`, which the processor
+// evaluates itself. `print` writes to stdout, so it requires `Capability::Io`.
+//   print(x), assert(cond), to_i64(x), to_u64(x)
+pub fn register_prelude(p: &mut Processor) {
+    p.register_native_requiring("print", Capability::Io, prelude("print"), |args| match args {
+        [value] => {
+            println!("{}", value);
+            Ok(Object::Unit)
+        }
+        _ => Err(invalid(args)),
+    });
+    register_assert(p);
+    p.register_native("to_i64", prelude("to_i64"), |args| match args {
+        [Object::Int64(i)] => Ok(Object::Int64(*i)),
+        [Object::UInt64(u)] => i64::try_from(*u).map(Object::Int64).map_err(|_| format!("{} is out of the range of i64", u)),
+        [Object::Bool(b)] => Ok(Object::Int64(*b as i64)),
+        _ => Err(invalid(args)),
+    });
+    p.register_native("to_u64", prelude("to_u64"), |args| match args {
+        [Object::Int64(i)] => u64::try_from(*i).map(Object::UInt64).map_err(|_| format!("{} is out of the range of u64", i)),
+        [Object::UInt64(u)] => Ok(Object::UInt64(*u)),
+        [Object::Bool(b)] => Ok(Object::UInt64(*b as u64)),
+        _ => Err(invalid(args)),
+    });
+}
+
+// `join(task)` waits for a task started by `spawn` and returns its result.
+// The type checker gives it the result type of the task.
+pub fn register_tasks(p: &mut Processor, tasks: Rc<RefCell<Tasks>>) {
+    let signature = FunctionSignature { parameter: vec![Type::Task(Box::new(Type::Unknown))], return_type: Type::Unknown };
+    p.register_native("join", signature, move |args| match args {
+        [Object::Task(id)] => tasks.borrow_mut().join(*id),
+        _ => Err(invalid(args)),
+    });
+}
+
+// Channels between tasks. The element type is given by the type of the
+// variable, e.g. `val ch: channel<u64> = channel()`.
+//   channel(), send(ch, value), recv(ch) -> value, close(ch), len(ch) -> u64
+// `recv` stops waiting when the token in `cancellation` is cancelled.
+pub fn register_channels(p: &mut Processor, channels: Arc<Mutex<Channels>>, cancellation: Rc<RefCell<Option<CancellationToken>>>) {
+    let channel = || Type::Channel(Box::new(Type::Unknown));
+    let c = channels.clone();
+    p.register_native("channel", FunctionSignature { parameter: vec![], return_type: channel() }, move |_| {
+        Ok(channel::lock(&c)?.open())
+    });
+    let c = channels.clone();
+    p.register_native("send", FunctionSignature { parameter: vec![channel(), Type::Unknown], return_type: Type::Unit }, move |args| match args {
+        [Object::Channel(id), value] => channel::lock(&c)?.send(*id, *value).map(|_| Object::Unit),
+        _ => Err(invalid(args)),
+    });
+    let c = channels.clone();
+    p.register_native("recv", FunctionSignature { parameter: vec![channel()], return_type: Type::Unknown }, move |args| match args {
+        [Object::Channel(id)] => channel::recv(&c, *id, || cancellation.borrow().as_ref().is_some_and(CancellationToken::is_cancelled)),
+        _ => Err(invalid(args)),
+    });
+    let c = channels.clone();
+    p.register_native("close", FunctionSignature { parameter: vec![channel()], return_type: Type::Unit }, move |args| match args {
+        [Object::Channel(id)] => channel::lock(&c)?.close(*id).map(|_| Object::Unit),
+        _ => Err(invalid(args)),
+    });
+    p.register_native("len", prelude("len"), move |args| match args {
+        [Object::Channel(id)] => channel::lock(&channels)?.len(*id).map(Object::UInt64),
+        _ => Err(invalid(args)),
+    });
+}
+
+pub(crate) fn next_random(state: &Cell<u64>) -> u64 {
+    let s = state.get().wrapping_add(0x9e37_79b9_7f4a_7c15);
+    state.set(s);
+    let mut z = s;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+fn prelude(name: &str) -> FunctionSignature {
+    Prelude::new().get(name).cloned().unwrap()
+}
+
+fn overflow() -> String {
+    "integer overflow".to_string()
+}
+
+fn invalid(args: &[Object]) -> String {
+    let args: Vec<String> = args.iter().map(|a| format!("{:?}", a)).collect();
+    format!("invalid arguments ({})", args.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use frontend::type_checker::{TypeCheckContext, TypeCheckErrorKind};
+    use crate::error::InterpreterError;
+
+    fn evaluate(input: &str) -> Result<Object, InterpreterError> {
+        let mut p = Processor::new();
+        let mut ctx = TypeCheckContext::new();
+        p.declare_native(&mut ctx);
+        let mut parser = frontend::Parser::new(input);
+        let (e, mut pool) = parser.parse_expression().unwrap();
+        ctx.check_expression(&mut pool, parser.location(), e).unwrap();
+        p.evaluate(&pool, e)
+    }
+
+    #[test]
+    fn math_builtins() {
+        assert_eq!(Ok(Object::Int64(5)), evaluate("abs(-5)"));
+        assert_eq!(Ok(Object::UInt64(2)), evaluate("min(2u64, 3)"));
+        assert_eq!(Ok(Object::Int64(3)), evaluate("max(-2, 3)"));
+        assert_eq!(Ok(Object::UInt64(1024)), evaluate("pow(2, 10u64)"));
+        assert_eq!(Ok(Object::UInt64(3)), evaluate("sqrt(15u64)"));
+        assert_eq!(Ok(Object::Int64(10)), evaluate("clamp(20, 0, 10)"));
+        assert_eq!(Ok(Object::UInt64(7)), evaluate("max(1, 2) + 5u64"));
+    }
+
+    #[test]
+    fn math_builtins_error() {
+        assert!(matches!(evaluate("pow(2, 64)"), Err(InterpreterError::Native { .. })));
+        assert!(matches!(evaluate("sqrt(-1)"), Err(InterpreterError::Native { .. })));
+        assert!(matches!(evaluate("clamp(1, 10, 0)"), Err(InterpreterError::Native { .. })));
+
+        let mut ctx = TypeCheckContext::new();
+        Processor::new().declare_native(&mut ctx);
+        let mut parser = frontend::Parser::new("max(1u64, 2i64)");
+        let (e, mut pool) = parser.parse_expression().unwrap();
+        let err = ctx.check_expression(&mut pool, parser.location(), e).unwrap_err();
+        assert_eq!(TypeCheckErrorKind::TypeMismatch { expected: Type::UInt64, actual: Type::Int64 }, err.kind);
+    }
+
+    #[test]
+    fn prelude_builtins() {
+        assert_eq!(Ok(Object::Int64(3)), evaluate("to_i64(2u64) + 1"));
+        assert_eq!(Ok(Object::UInt64(1)), evaluate("to_u64(1 < 2)"));
+        assert!(matches!(evaluate("to_u64(-1)"), Err(InterpreterError::Native { .. })));
+        assert!(matches!(evaluate("to_i64(18446744073709551615u64)"), Err(InterpreterError::Native { .. })));
+        assert_eq!(Ok(Object::Unit), evaluate("print(1u64)"));
+
+        let (e, pool) = frontend::Parser::new("print(1u64)").parse_expression().unwrap();
+        let mut p = Processor::new();
+        p.set_policy(crate::policy::ExecutionPolicy::sandboxed());
+        assert_eq!(Err(InterpreterError::PermissionDenied(Capability::Io)), p.evaluate(&pool, e));
+
+        let code = "fn main() -> u64 {\nval ch: channel<u64> = channel()\nsend(ch, 1u64)\nsend(ch, 2u64)\nval first = recv(ch)\nlen(ch) * 10u64 + first\n}";
+        let program = frontend::Parser::new(code).parse_program().unwrap();
+        let mut p = Processor::new();
+        assert_eq!(Ok(Object::UInt64(11)), p.execute_program(&program));
+
+        // the embedder disables the prelude, the other builtins stay
+        p.set_prelude(&Prelude::empty());
+        let mut ctx = TypeCheckContext::new();
+        ctx.set_prelude(Prelude::empty());
+        p.declare_native(&mut ctx);
+        assert_eq!(None, ctx.get_fn("len"));
+        assert!(ctx.get_fn("abs").is_some());
+        assert!(p.execute_program(&program).is_err());
+    }
+
+    #[test]
+    fn random_with_seed() {
+        let (e, pool) = frontend::Parser::new("random_range(-3i64, 3i64)").parse_expression().unwrap();
+        let run = |seed: u64| -> Vec<Object> {
+            let mut p = Processor::new();
+            p.set_random_seed(seed);
+            (0..100).map(|_| p.evaluate(&pool, e).unwrap()).collect()
+        };
+        let values = run(42);
+        assert_eq!(values, run(42));
+        assert_ne!(values, run(43));
+        assert!(values.iter().all(|v| matches!(v, Object::Int64(-3..=2))));
+        assert!(values.contains(&Object::Int64(-3)) && values.contains(&Object::Int64(2)));
+
+        assert!(matches!(evaluate("random_range(5u64, 5u64)"), Err(InterpreterError::Native { .. })));
+        assert!(matches!(evaluate("random_u64()"), Ok(Object::UInt64(_))));
+    }
+
+    #[test]
+    fn time_with_clock_and_policy() {
+        use crate::policy::ExecutionPolicy;
+
+        struct FixedClock;
+        impl Clock for FixedClock {
+            fn now_millis(&self) -> u64 { 1_000 }
+            fn monotonic_nanos(&self) -> u64 { 42 }
+        }
+
+        let (e, pool) = frontend::Parser::new("now_millis() + monotonic_nanos()").parse_expression().unwrap();
+        let mut p = Processor::new();
+        p.set_clock(Box::new(FixedClock));
+        assert_eq!(Ok(Object::UInt64(1_042)), p.evaluate(&pool, e));
+
+        p.set_policy(ExecutionPolicy::sandboxed());
+        assert_eq!(Err(InterpreterError::PermissionDenied(Capability::Time)), p.evaluate(&pool, e));
+    }
+}
@@ -0,0 +1,92 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use crate::object::Object;
+
+// Channels made by `channel()`, shared by a processor and the tasks it
+// spawns. A channel is referred to by `Object::Channel(id)`; any task can
+// send to it and receive from it. `recv` blocks until a value arrives, or
+// fails once the channel is closed and empty.
+#[derive(Debug, Default)]
+pub struct Channels {
+    next: u64,
+    open: HashMap<u64, Channel>,
+}
+
+#[derive(Debug)]
+struct Channel {
+    sender: Option<Sender<Object>>, // None after `close`
+    receiver: Arc<Mutex<Receiver<Object>>>,
+    pending: Arc<AtomicU64>, // values sent and not received yet
+}
+
+impl Channels {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn open(&mut self) -> Object {
+        let (sender, receiver) = mpsc::channel();
+        let id = self.next;
+        self.next += 1;
+        self.open.insert(id, Channel { sender: Some(sender), receiver: Arc::new(Mutex::new(receiver)), pending: Arc::new(AtomicU64::new(0)) });
+        Object::Channel(id)
+    }
+
+    pub fn send(&self, id: u64, value: Object) -> Result<(), String> {
+        let channel = self.get(id)?;
+        match channel.sender.as_ref() {
+            Some(sender) => sender.send(value).map_err(|_| format!("channel {} is closed", id))?,
+            None => return Err(format!("channel {} is closed", id)),
+        }
+        channel.pending.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    // Number of the values waiting to be received
+    pub fn len(&self, id: u64) -> Result<u64, String> {
+        Ok(self.get(id)?.pending.load(Ordering::SeqCst))
+    }
+
+    // Values already sent can still be received
+    pub fn close(&mut self, id: u64) -> Result<(), String> {
+        self.open.get_mut(&id).ok_or_else(|| format!("channel {} does not exist", id))?.sender = None;
+        Ok(())
+    }
+
+    fn get(&self, id: u64) -> Result<&Channel, String> {
+        self.open.get(&id).ok_or_else(|| format!("channel {} does not exist", id))
+    }
+}
+
+pub fn lock(channels: &Mutex<Channels>) -> Result<MutexGuard<'_, Channels>, String> {
+    channels.lock().map_err(|_| "channels are poisoned".to_string())
+}
+
+// How long `recv` waits before it checks whether it is cancelled
+const RECV_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// Wait for a value of the channel until `cancelled` returns true. The table
+// is not locked while waiting, so that other tasks can send.
+pub fn recv(channels: &Mutex<Channels>, id: u64, cancelled: impl Fn() -> bool) -> Result<Object, String> {
+    let (receiver, pending) = {
+        let channels = lock(channels)?;
+        let channel = channels.get(id)?;
+        (channel.receiver.clone(), channel.pending.clone())
+    };
+    loop {
+        // unlocked between the waits, so that another task can receive too
+        let result = receiver.lock().map_err(|_| format!("channel {} is poisoned", id))?.recv_timeout(RECV_POLL_INTERVAL);
+        match result {
+            Ok(value) => {
+                pending.fetch_sub(1, Ordering::SeqCst);
+                return Ok(value);
+            }
+            Err(RecvTimeoutError::Timeout) if !cancelled() => (),
+            Err(RecvTimeoutError::Timeout) => return Err(format!("receiving from channel {} is cancelled", id)),
+            Err(RecvTimeoutError::Disconnected) => return Err(format!("channel {} is closed", id)),
+        }
+    }
+}
@@ -0,0 +1,293 @@
+use std::fmt;
+use std::io::{IsTerminal, Read};
+use frontend::ast::Program;
+use frontend::doc::DocFormat;
+use frontend::diagnostic::{Diagnostic, ErrorFormatter};
+use frontend::ParseError;
+use frontend::type_checker::TypeCheckContext;
+use crate::consteval::ConstProcessor;
+use crate::project::{BuildError, Project};
+use crate::timings;
+
+// Command line shared by the `interpreter` and `bytecodeinterpreter` binaries:
+//   <binary> [repl]                  start REPL
+//   <binary> run [options] file      run `main` of the file
+//   <binary> check file              parse and type check only
+//   <binary> ast file                print the tree of each function
+//   <binary> fmt [--check] file      print the formatted source
+//   <binary> disasm [options] file   print the bytecode of each function
+//   <binary> doc [--html] file       print the documentation (markdown by default)
+//   <binary> test file               run the `test_*` and `#[test]` functions of the file
+//   <binary> --explain code          describe an error code (e.g. E0001)
+// `--cfg=feature` enables a feature for `#[cfg]` (see `frontend::cfg`)
+// with any command.
+// `file` is `-` to read the source from stdin, or a directory with a
+// `toy.toml` manifest (or the manifest) to build the project as one program. `run --check-only` is the
+// same as `check`. A file without a command is run, as before the commands.
+// The options are left to each binary.
+//
+// A failure exits with the code of the phase where it happened (see
+// `Phase`), a successful run exits with the value of `main`.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Command {
+    Repl,
+    Run,
+    Check,
+    Ast,
+    Fmt,
+    Disasm,
+    Doc,
+    Test,
+    Explain,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Phase {
+    Usage,   // 2: invalid arguments
+    Read,    // 3: the file cannot be read
+    Parse,   // 4: syntax error
+    Check,   // 5: type error
+    Compile, // 6: the program cannot be compiled by the backend
+    Run,     // 7: runtime error
+}
+
+impl Phase {
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Phase::Usage => 2,
+            Phase::Read => 3,
+            Phase::Parse => 4,
+            Phase::Check => 5,
+            Phase::Compile => 6,
+            Phase::Run => 7,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Failure {
+    pub phase: Phase,
+    pub message: String,
+}
+
+impl Failure {
+    pub fn new(phase: Phase, message: impl Into<String>) -> Self {
+        Failure { phase, message: message.into() }
+    }
+
+    pub fn exit(&self) -> ! {
+        eprintln!("{}", self);
+        std::process::exit(self.phase.exit_code());
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Args {
+    pub command: Command,
+    pub file: Option<String>,
+    pub options: Vec<String>, // arguments starting with `-` (but `-` itself)
+    pub features: Vec<String>, // of `--cfg=feature`
+}
+
+pub fn parse_args(args: &[String]) -> Result<Args, Failure> {
+    let (command, rest) = match args.first().map(String::as_str) {
+        None => (Command::Repl, args),
+        Some("repl") => (Command::Repl, &args[1..]),
+        Some("run") => (Command::Run, &args[1..]),
+        Some("check") => (Command::Check, &args[1..]),
+        Some("ast") => (Command::Ast, &args[1..]),
+        Some("fmt") => (Command::Fmt, &args[1..]),
+        Some("disasm") => (Command::Disasm, &args[1..]),
+        Some("doc") => (Command::Doc, &args[1..]),
+        Some("test") => (Command::Test, &args[1..]),
+        Some("--explain") => (Command::Explain, &args[1..]),
+        Some(_) => (Command::Run, args),
+    };
+    let mut parsed = Args { command, file: None, options: vec![], features: vec![] };
+    for arg in rest {
+        if arg == "--check-only" && command == Command::Run {
+            parsed.command = Command::Check;
+        } else if let Some(feature) = arg.strip_prefix("--cfg=") {
+            parsed.features.push(feature.to_string());
+        } else if arg.starts_with('-') && arg != "-" {
+            parsed.options.push(arg.clone());
+        } else if parsed.file.is_some() {
+            return Err(Failure::new(Phase::Usage, format!("unexpected argument {}", arg)));
+        } else {
+            parsed.file = Some(arg.clone());
+        }
+    }
+    match (parsed.command, &parsed.file) {
+        (Command::Repl, Some(file)) => Err(Failure::new(Phase::Usage, format!("unexpected argument {}", file))),
+        (Command::Repl, None) => Ok(parsed),
+        (Command::Explain, None) => Err(Failure::new(Phase::Usage, "no error code")),
+        (_, None) => Err(Failure::new(Phase::Usage, "no input file")),
+        _ => Ok(parsed),
+    }
+}
+
+// The contents of `file`, or stdin for `-`
+pub fn read_source(file: &str) -> Result<String, Failure> {
+    if file == "-" {
+        let mut source = String::new();
+        return match std::io::stdin().read_to_string(&mut source) {
+            Ok(_) => Ok(source),
+            Err(e) => Err(Failure::new(Phase::Read, format!("cannot read stdin: {}", e))),
+        };
+    }
+    std::fs::read_to_string(file).map_err(|e| Failure::new(Phase::Read, format!("cannot read {}: {}", file, e)))
+}
+
+// Diagnostics are rendered with the source line by `ErrorFormatter`,
+// colored when stderr is a terminal and NO_COLOR is not set
+fn formatter<'a>(file: &str, source: &'a str) -> ErrorFormatter<'a> {
+    let mut formatter = ErrorFormatter::new(source);
+    formatter.set_file(file);
+    formatter.set_color(std::io::stderr().is_terminal() && std::env::var_os("NO_COLOR").is_none());
+    formatter
+}
+
+pub fn parse(file: &str, source: &str) -> Result<Program, Failure> {
+    let _span = tracing::info_span!(timings::PARSE).entered();
+    frontend::Parser::new(source).parse_program().map_err(|e| {
+        let diagnostic = Diagnostic::new(ParseError::code_of(&e), format!("parse_program failed {}", e), None);
+        Failure::new(Phase::Parse, formatter(file, source).format(&diagnostic).trim_end())
+    })
+}
+
+// Parse, configure with the features of `ctx` and type check the source.
+// The builtins of the binary must be declared in `ctx`.
+pub fn check(file: &str, source: &str, ctx: &mut TypeCheckContext) -> Result<Program, Failure> {
+    let program = parse(file, source)?;
+    check_parsed(file, source, program, ctx)
+}
+
+fn check_parsed(file: &str, source: &str, mut program: Program, ctx: &mut TypeCheckContext) -> Result<Program, Failure> {
+    frontend::cfg::configure(&mut program, ctx.features());
+    let _span = tracing::info_span!(timings::CHECK).entered();
+    if let Err(errors) = ctx.check_program(&program) {
+        let formatter = formatter(file, source);
+        let errors: String = errors.iter().map(|e| formatter.format(&Diagnostic::from(e))).collect();
+        return Err(Failure::new(Phase::Check, errors.trim_end()));
+    }
+    Ok(program)
+}
+
+pub fn is_project(file: &str) -> bool {
+    file.ends_with(crate::project::MANIFEST) || std::path::Path::new(file).is_dir()
+}
+
+// Source and type checked program of a file or a project (see
+// `crate::project`). A file which includes others is built like a
+// project. The builtins of the binary must be declared in `ctx`.
+// The calls of `const fn`s are evaluated into `ctx` (see `crate::consteval`).
+pub fn load(file: &str, ctx: &mut TypeCheckContext) -> Result<(String, Program), Failure> {
+    let project = if is_project(file) {
+        Project::load(std::path::Path::new(file))
+    } else {
+        let source = read_source(file)?;
+        let program = parse(file, &source)?;
+        if program.import.is_empty() {
+            let program = check_parsed(file, &source, program, ctx)?;
+            ctx.evaluate_constants(&program, &mut ConstProcessor::new());
+            return Ok((source, program));
+        }
+        Project::include(std::path::Path::new(file), source)
+    };
+    let project = project.map_err(|e| Failure::new(Phase::Read, e))?;
+    let program = project.build(ctx).map_err(|e| match e {
+        BuildError::Module(errors) => Failure::new(Phase::Parse, errors.join("\n")),
+        BuildError::Check(errors) => Failure::new(Phase::Check, errors.concat().trim_end()),
+    })?;
+    ctx.evaluate_constants(&program, &mut ConstProcessor::new());
+    Ok((project.source(), program))
+}
+
+// `fmt`: print the formatted source, or with `check` only tell whether
+// the file is formatted. The result is whether it was formatted.
+pub fn fmt(file: &str, check: bool) -> Result<bool, Failure> {
+    let source = read_source(file)?;
+    let formatted = frontend::formatter::format(&source)
+        .map_err(|e| Failure::new(Phase::Parse, format!("fmt failed {}", e)))?;
+    if !check {
+        print!("{}", formatted);
+    } else if formatted != source {
+        eprintln!("{} is not formatted", file);
+    }
+    Ok(formatted == source)
+}
+
+// `doc`: documentation of the file, titled by its name. The builtins of
+// the binary must be declared in `ctx`.
+pub fn doc(file: &str, format: DocFormat, ctx: &mut TypeCheckContext) -> Result<String, Failure> {
+    let program = parse(file, &read_source(file)?)?;
+    let title = std::path::Path::new(file).file_stem().map_or(file.to_string(), |s| s.to_string_lossy().to_string());
+    Ok(frontend::doc::render(&title, &program, ctx, format))
+}
+
+// `--explain`: description of an error code
+pub fn explain(code: &str) -> Result<&'static str, Failure> {
+    frontend::explain::explain(code).ok_or_else(|| Failure::new(Phase::Usage, format!("unknown error code {}", code)))
+}
+
+// Tree of each function, for `ast`
+pub fn ast(program: &Program) -> String {
+    let mut text = String::new();
+    for f in &program.function {
+        text += &format!("fn {}\n", f.name);
+        text += &frontend::ast::dump(&program.expression, f.code);
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Result<Args, Failure> {
+        parse_args(&args.iter().map(|a| a.to_string()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(Ok(Args { command: Command::Repl, file: None, options: vec![], features: vec![] }), args(&[]));
+        assert_eq!(
+            Ok(Args { command: Command::Run, file: Some("a.toy".to_string()), options: vec!["-O2".to_string()], features: vec![] }),
+            args(&["run", "-O2", "a.toy"])
+        );
+        assert_eq!(vec!["debug".to_string()], args(&["check", "--cfg=debug", "a.toy"]).unwrap().features);
+        // a file without a command is run
+        assert_eq!(Command::Run, args(&["--jit", "a.toy"]).unwrap().command);
+        assert_eq!(Command::Check, args(&["run", "--check-only", "-"]).unwrap().command);
+        assert_eq!(Some("-".to_string()), args(&["ast", "-"]).unwrap().file);
+        assert_eq!(Command::Test, args(&["test", "a.toy"]).unwrap().command);
+        assert_eq!(Some("E0001".to_string()), args(&["--explain", "E0001"]).unwrap().file);
+
+        assert_eq!(Err(Failure::new(Phase::Usage, "no input file")), args(&["check"]));
+        assert_eq!(Phase::Usage, args(&["fmt", "a.toy", "b.toy"]).unwrap_err().phase);
+        assert_eq!(Phase::Usage, args(&["repl", "a.toy"]).unwrap_err().phase);
+    }
+
+    #[test]
+    fn failure_phases() {
+        assert_eq!(Phase::Read, read_source("/nonexistent/a.toy").unwrap_err().phase);
+        let phase = |source| check("a.toy", source, &mut TypeCheckContext::new()).err().map(|e| e.phase);
+        assert_eq!(Some(Phase::Parse), phase("fn main() u64 {\n1u64\n}"));
+        let failure = check("a.toy", "fn main() -> u64 {\n1i64\n}", &mut TypeCheckContext::new()).err().unwrap();
+        assert_eq!(Phase::Check, failure.phase);
+        assert!(failure.message.starts_with("error[E0001]: type mismatch"));
+        assert!(failure.message.contains(" --> a.toy:2:1\n  |\n2 | 1i64\n  | ^^^^\n"), "{}", failure.message);
+        assert!(explain(&failure.message[6..11]).unwrap().starts_with("A value has a different type"));
+        assert_eq!(5, failure.phase.exit_code());
+
+        let program = check("a.toy", "fn main() -> u64 {\n1u64\n}", &mut TypeCheckContext::new()).unwrap();
+        assert!(ast(&program).starts_with("fn main\nBlock\n"));
+    }
+}
@@ -0,0 +1,36 @@
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+// Source of time for the time builtins. Tests replace it with a fixed
+// clock through `Processor::set_clock` to get deterministic results.
+pub trait Clock {
+    // wall-clock time since the Unix epoch
+    fn now_millis(&self) -> u64;
+    // monotonic time from an arbitrary starting point
+    fn monotonic_nanos(&self) -> u64;
+}
+
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> Self {
+        SystemClock { start: Instant::now() }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn now_millis(&self) -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64)
+    }
+
+    fn monotonic_nanos(&self) -> u64 {
+        self.start.elapsed().as_nanos() as u64
+    }
+}
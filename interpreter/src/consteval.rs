@@ -0,0 +1,88 @@
+use std::sync::Arc;
+use frontend::ast::Program;
+use frontend::consteval::{ConstEvaluator, ConstValue};
+use crate::object::Object;
+use crate::policy::ExecutionPolicy;
+use crate::processor::Processor;
+
+// Evaluator of the `const fn`s for the type checker (see
+// `frontend::consteval`), so that a call gives the same value while the
+// program is checked as when it runs. The functions run in a processor
+// without capabilities, and a call gives up after `fuel` steps or
+// MAX_DEPTH nested expressions.
+
+pub const DEFAULT_FUEL: u64 = 1_000_000;
+const MAX_DEPTH: usize = 10_000;
+
+pub struct ConstProcessor {
+    processor: Processor,
+    program: Option<Arc<Program>>,
+}
+
+impl ConstProcessor {
+    pub fn new() -> Self {
+        let mut processor = Processor::new();
+        processor.set_policy(ExecutionPolicy { fuel: Some(DEFAULT_FUEL), ..ExecutionPolicy::sandboxed() });
+        processor.set_max_depth(MAX_DEPTH);
+        ConstProcessor { processor, program: None }
+    }
+
+    // Evaluation steps of each call
+    pub fn set_fuel(&mut self, fuel: u64) {
+        self.processor.set_policy(ExecutionPolicy { fuel: Some(fuel), ..ExecutionPolicy::sandboxed() });
+    }
+}
+
+impl Default for ConstProcessor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ConstEvaluator for ConstProcessor {
+    fn load(&mut self, program: &Program) {
+        let program = Arc::new(program.clone());
+        self.processor.load_shared(program.clone());
+        self.program = Some(program);
+    }
+
+    fn call(&mut self, name: &str, args: &[ConstValue]) -> Option<ConstValue> {
+        let program = self.program.clone()?;
+        let args: Vec<Object> = args.iter().map(|arg| match *arg {
+            ConstValue::Bool(v) => Object::Bool(v),
+            ConstValue::Int64(v) => Object::Int64(v),
+            ConstValue::UInt64(v) => Object::UInt64(v),
+        }).collect();
+        self.processor.refuel();
+        match self.processor.evaluate_function(&program.expression, name, &args).ok()? {
+            Object::Bool(v) => Some(ConstValue::Bool(v)),
+            Object::Int64(v) => Some(ConstValue::Int64(v)),
+            Object::UInt64(v) => Some(ConstValue::UInt64(v)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use frontend::type_checker::TypeCheckContext;
+
+    #[test]
+    fn evaluate_const_calls() {
+        let source = "const fn square(x: u64) -> u64 {\nx * x\n}\n\
+            const fn forever(x: u64) -> u64 {\nforever(x)\n}\n\
+            const fn half(x: i64) -> i64 {\nx / 0i64\n}\n\
+            fn main() -> u64 {\nval a = square(square(3u64))\nval b = half(4i64)\nforever(1u64) + a\n}\n";
+        let program = frontend::Parser::new(source).parse_program().unwrap();
+        let mut ctx = TypeCheckContext::new();
+        ctx.check_program(&program).unwrap();
+        let mut evaluator = ConstProcessor::new();
+        evaluator.set_fuel(10_000);
+        // the recursion and the division by zero are left to the runtime
+        assert_eq!(2, ctx.evaluate_constants(&program, &mut evaluator));
+        let mut values: Vec<_> = ctx.constants().values().copied().collect();
+        values.sort_by_key(|v| format!("{:?}", v));
+        assert_eq!(vec![ConstValue::UInt64(81), ConstValue::UInt64(9)], values);
+    }
+}
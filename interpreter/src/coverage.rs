@@ -0,0 +1,105 @@
+use std::collections::BTreeSet;
+use std::fmt;
+use frontend::ast::{Expr, ExprRef, Program};
+use frontend::line::LineIndex;
+use frontend::node_id::NodeIds;
+
+// Statements executed while coverage is enabled on the processor.
+// A statement is an expression directly inside a block.
+#[derive(Debug, Default)]
+pub struct Coverage {
+    executed: Vec<bool>, // indexed by ExprRef
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct CoverageReport {
+    pub covered: Vec<usize>,   // lines, 1-origin
+    pub uncovered: Vec<usize>,
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn mark(&mut self, e: ExprRef) {
+        let i = e.0 as usize;
+        if self.executed.len() <= i {
+            self.executed.resize(i + 1, false);
+        }
+        self.executed[i] = true;
+    }
+
+    pub fn is_executed(&self, e: ExprRef) -> bool {
+        self.executed.get(e.0 as usize).copied().unwrap_or(false)
+    }
+
+    // Coverage of the program re-parsed after an edit, `from` and `to` are
+    // the IDs of the old and new programs. Statements which the edit
+    // changed are not executed.
+    pub fn remap(&self, from: &NodeIds, to: &NodeIds) -> Coverage {
+        let mut coverage = Coverage::new();
+        let executed = self.executed.iter().enumerate().filter(|(_, executed)| **executed);
+        for e in executed.filter_map(|(i, _)| from.id(ExprRef(i as u32)).and_then(|id| to.expr(id))) {
+            coverage.mark(e);
+        }
+        coverage
+    }
+
+    // Map statements of the program to lines of its source.
+    // A line is covered when any statement starting on it was executed.
+    pub fn report(&self, program: &Program, source: &str) -> CoverageReport {
+        let lines = LineIndex::new(source);
+        // argument lists of calls are blocks too, but not statements
+        let arguments: BTreeSet<u32> = program.expression.0.iter()
+            .filter_map(|expr| match expr {
+                Expr::Call(_, args) => Some(args.0),
+                _ => None,
+            })
+            .collect();
+
+        let mut covered = BTreeSet::new();
+        let mut all = BTreeSet::new();
+        for (i, expr) in program.expression.0.iter().enumerate() {
+            let statements = match expr {
+                Expr::Block(statements) if !arguments.contains(&(i as u32)) => statements,
+                _ => continue,
+            };
+            for e in statements {
+                if let Some(node) = program.location.get(*e) {
+                    let line = lines.line(node.start());
+                    all.insert(line);
+                    if self.is_executed(*e) {
+                        covered.insert(line);
+                    }
+                }
+            }
+        }
+        CoverageReport {
+            uncovered: all.difference(&covered).copied().collect(),
+            covered: covered.into_iter().collect(),
+        }
+    }
+}
+
+impl CoverageReport {
+    pub fn percent(&self) -> f64 {
+        let total = self.covered.len() + self.uncovered.len();
+        if total == 0 {
+            return 100.0;
+        }
+        self.covered.len() as f64 * 100.0 / total as f64
+    }
+}
+
+impl fmt::Display for CoverageReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "coverage: {}/{} lines ({:.1}%)",
+                 self.covered.len(), self.covered.len() + self.uncovered.len(), self.percent())?;
+        if !self.uncovered.is_empty() {
+            let lines: Vec<String> = self.uncovered.iter().map(|l| l.to_string()).collect();
+            writeln!(f, "not executed: {}", lines.join(", "))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,73 @@
+use std::collections::HashSet;
+use frontend::ast::{ExprRef, LocationPool, Node};
+use frontend::line::LineIndex;
+
+// Hook called by the processor before a statement is evaluated.
+// Statements are the expressions directly in a block.
+pub trait Debugger {
+    fn on_statement(&mut self, event: &StatementEvent) -> DebugAction;
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct StatementEvent {
+    pub expr: ExprRef,
+    pub location: Option<Node>,
+    pub line: Option<usize>, // 1-origin
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum DebugAction {
+    // run until the next breakpoint
+    Continue,
+    // stop again at the next statement
+    Step,
+    // stop the execution with `InterpreterError::Cancelled`
+    Abort,
+}
+
+pub(crate) struct DebugSession {
+    debugger: Box<dyn Debugger>,
+    breakpoints: HashSet<usize>,
+    lines: LineIndex,
+    stepping: bool,
+}
+
+impl DebugSession {
+    pub(crate) fn new(debugger: Box<dyn Debugger>, source: &str) -> Self {
+        DebugSession {
+            debugger,
+            breakpoints: HashSet::new(),
+            lines: LineIndex::new(source),
+            stepping: false,
+        }
+    }
+
+    pub(crate) fn add_breakpoint(&mut self, line: usize) {
+        self.breakpoints.insert(line);
+    }
+
+    pub(crate) fn remove_breakpoint(&mut self, line: usize) {
+        self.breakpoints.remove(&line);
+    }
+
+    pub(crate) fn step(&mut self) {
+        self.stepping = true;
+    }
+
+    // Returns false if the execution should be aborted
+    pub(crate) fn before_statement(&mut self, e: ExprRef, location: &LocationPool) -> bool {
+        let location = location.get(e).cloned();
+        let line = location.as_ref().map(|node| self.lines.line(node.start()));
+        let hit = line.is_some_and(|line| self.breakpoints.contains(&line));
+        if !self.stepping && !hit {
+            return true;
+        }
+        let event = StatementEvent { expr: e, location, line };
+        match self.debugger.on_statement(&event) {
+            DebugAction::Continue => self.stepping = false,
+            DebugAction::Step => self.stepping = true,
+            DebugAction::Abort => return false,
+        }
+        true
+    }
+}
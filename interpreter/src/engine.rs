@@ -0,0 +1,262 @@
+use std::fmt;
+use std::sync::Arc;
+use frontend::ast::{ExprPool, Program};
+use frontend::type_checker::{FunctionSignature, TypeCheckContext, TypeCheckError};
+use crate::cancel::CancellationToken;
+use crate::error::InterpreterError;
+use crate::object::Object;
+use crate::policy::ExecutionPolicy;
+use crate::processor::Processor;
+
+// Entry point for Rust applications embedding toylang.
+// It wraps parse -> type check -> execute of a program, so embedders
+// don't need to handle the AST pools or the processor directly.
+//
+//   let mut engine = Engine::new();
+//   let n: u64 = engine.eval("fn main() -> u64 { 1 + 2 }")?;
+//
+// Functions of the loaded program can be called with arguments:
+//
+//   engine.load(source)?;
+//   let n: u64 = engine.call("fib", &[10u64.into()])?;
+pub struct Engine {
+    processor: Processor,
+    program: Option<Arc<Program>>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum EngineError {
+    Parse(String),
+    TypeCheck(Vec<TypeCheckError>),
+    Runtime(InterpreterError),
+    Conversion { expected: &'static str, actual: Object },
+}
+
+impl fmt::Display for EngineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EngineError::Parse(message) => write!(f, "parse error: {}", message),
+            EngineError::TypeCheck(errors) => {
+                let errors: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+                write!(f, "type check error: {}", errors.join(", "))
+            }
+            EngineError::Runtime(e) => write!(f, "runtime error: {}", e),
+            EngineError::Conversion { expected, actual } =>
+                write!(f, "cannot convert {:?} to {}", actual, expected),
+        }
+    }
+}
+
+impl std::error::Error for EngineError {}
+
+impl From<InterpreterError> for EngineError {
+    fn from(e: InterpreterError) -> Self {
+        EngineError::Runtime(e)
+    }
+}
+
+// Conversion of a result of toylang to a Rust value
+pub trait FromObject: Sized {
+    fn from_object(object: Object) -> Result<Self, EngineError>;
+}
+
+impl FromObject for u64 {
+    fn from_object(object: Object) -> Result<Self, EngineError> {
+        match object {
+            Object::UInt64(u) => Ok(u),
+            actual => Err(EngineError::Conversion { expected: "u64", actual }),
+        }
+    }
+}
+
+impl FromObject for i64 {
+    fn from_object(object: Object) -> Result<Self, EngineError> {
+        match object {
+            Object::Int64(i) => Ok(i),
+            actual => Err(EngineError::Conversion { expected: "i64", actual }),
+        }
+    }
+}
+
+impl FromObject for bool {
+    fn from_object(object: Object) -> Result<Self, EngineError> {
+        match object {
+            Object::Bool(b) => Ok(b),
+            actual => Err(EngineError::Conversion { expected: "bool", actual }),
+        }
+    }
+}
+
+impl FromObject for () {
+    fn from_object(object: Object) -> Result<Self, EngineError> {
+        match object {
+            Object::Unit => Ok(()),
+            actual => Err(EngineError::Conversion { expected: "()", actual }),
+        }
+    }
+}
+
+impl FromObject for Object {
+    fn from_object(object: Object) -> Result<Self, EngineError> {
+        Ok(object)
+    }
+}
+
+impl Engine {
+    pub fn new() -> Self {
+        Engine {
+            processor: Processor::new(),
+            program: None,
+        }
+    }
+
+    pub fn register_native<F>(&mut self, name: &str, signature: FunctionSignature, function: F)
+    where
+        F: Fn(&[Object]) -> Result<Object, String> + 'static,
+    {
+        self.processor.register_native(name, signature, function);
+    }
+
+    // See `Processor::set_policy`
+    pub fn set_policy(&mut self, policy: ExecutionPolicy) {
+        self.processor.set_policy(policy);
+    }
+
+    // See `Processor::set_fuel`
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.processor.set_fuel(fuel);
+    }
+
+    // See `Processor::set_cancellation`
+    pub fn set_cancellation(&mut self, token: Option<CancellationToken>) {
+        self.processor.set_cancellation(token);
+    }
+
+    // Parse and type check the program, and replace the loaded one
+    pub fn load(&mut self, source: &str) -> Result<(), EngineError> {
+        let program = match frontend::Parser::new(source).parse_program() {
+            Ok(program) => program,
+            Err(e) => return Err(EngineError::Parse(e.to_string())),
+        };
+        let mut ctx = TypeCheckContext::new();
+        self.processor.declare_native(&mut ctx);
+        ctx.check_program(&program).map_err(EngineError::TypeCheck)?;
+        let program = Arc::new(program);
+        self.processor.load_shared(program.clone());
+        self.program = Some(program);
+        Ok(())
+    }
+
+    // Run `main` of the program in `source` and convert its result
+    pub fn eval<T: FromObject>(&mut self, source: &str) -> Result<T, EngineError> {
+        self.load(source)?;
+        self.call("main", &[])
+    }
+
+    // Call a function of the loaded program (or a native function).
+    // Arguments are checked against the parameter types before the call.
+    pub fn call<T: FromObject>(&mut self, name: &str, args: &[Object]) -> Result<T, EngineError> {
+        let empty = ExprPool::new();
+        let pool = match &self.program {
+            Some(program) => {
+                if let Some(f) = program.function.iter().find(|f| f.name == name) {
+                    let parameter: Vec<_> = f.parameter.iter().map(|(_, ty)| ty.clone()).collect();
+                    let actual: Vec<_> = args.iter().map(|a| a.ty()).collect();
+                    if parameter != actual {
+                        return Err(EngineError::Runtime(InterpreterError::TypeMismatch(format!(
+                            "function `{}` takes {:?} but {:?} given", name, parameter, actual))));
+                    }
+                }
+                &program.expression
+            }
+            None => &empty,
+        };
+        self.processor.refuel();
+        let result = self.processor.evaluate_function(pool, name, args)?;
+        T::from_object(result)
+    }
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use frontend::ast::{Node, Type};
+
+    #[test]
+    fn eval_to_rust_value() {
+        let mut engine = Engine::new();
+        let code = r#"
+fn add(a: u64, b: u64) -> u64 {
+a + b
+}
+
+fn main() -> u64 {
+val c = add(1, 2)
+c * 10
+}
+        "#;
+        assert_eq!(Ok(30u64), engine.eval(code));
+        assert_eq!(Ok(true), engine.eval("fn main() -> bool { 1i64 < 2 }"));
+        assert_eq!(
+            Err(EngineError::Conversion { expected: "i64", actual: Object::UInt64(30) }),
+            engine.eval::<i64>(code)
+        );
+    }
+
+    #[test]
+    fn eval_with_native_function() {
+        let mut engine = Engine::new();
+        let signature = FunctionSignature { parameter: vec![Type::Int64], return_type: Type::Int64 };
+        engine.register_native("negate", signature, |args| match args {
+            [Object::Int64(i)] => Ok(Object::Int64(-i)),
+            _ => Err("expects i64".to_string()),
+        });
+        assert_eq!(Ok(-5i64), engine.eval("fn main() -> i64 { negate(2) - 3 }"));
+    }
+
+    #[test]
+    fn eval_reports_errors() {
+        let mut engine = Engine::new();
+        assert!(matches!(engine.eval::<u64>("fn main() -> u64 { 1u64 + }"), Err(EngineError::Parse(_))));
+        assert!(matches!(engine.eval::<u64>("fn main() -> u64 { 1i64 }"), Err(EngineError::TypeCheck(_))));
+        assert_eq!(
+            Err(EngineError::Runtime(InterpreterError::DivisionByZero(Some(Node::new(19, 30))))),
+            engine.eval::<u64>("fn main() -> u64 { 1u64 / 0u64 }")
+        );
+        assert_eq!(
+            Err(EngineError::Runtime(InterpreterError::UndefinedFunction("main".to_string()))),
+            engine.eval::<u64>("fn f() -> u64 { 1u64 }")
+        );
+    }
+
+    #[test]
+    fn call_function_with_arguments() {
+        let mut engine = Engine::new();
+        let code = r#"
+fn fib(n: u64) -> u64 {
+if n <= 1 { n } else { fib(n - 1) + fib(n - 2) }
+}
+
+fn sub(a: i64, b: i64) -> i64 {
+a - b
+}
+        "#;
+        engine.load(code).unwrap();
+        assert_eq!(Ok(55u64), engine.call("fib", &[10u64.into()]));
+        assert_eq!(Ok(-1i64), engine.call("sub", &[Object::Int64(2), 3i64.into()]));
+        assert!(matches!(
+            engine.call::<u64>("fib", &[10i64.into()]),
+            Err(EngineError::Runtime(InterpreterError::TypeMismatch(_)))
+        ));
+        assert_eq!(
+            Err(EngineError::Runtime(InterpreterError::UndefinedFunction("main".to_string()))),
+            engine.call::<u64>("main", &[])
+        );
+    }
+}
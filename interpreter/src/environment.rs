@@ -0,0 +1,124 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use crate::object::Object;
+
+// Chain of scopes, innermost first. `Environment` is a handle: clones
+// share the same scopes, so a captured environment sees (and makes)
+// later changes to the variables. Use `snapshot` to capture by value.
+#[derive(Debug, Clone, Default)]
+pub struct Environment {
+    scope: Rc<RefCell<Scope>>,
+}
+
+#[derive(Debug, Default)]
+struct Scope {
+    vars: HashMap<String, Object>,
+    parent: Option<Environment>,
+}
+
+impl Environment {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // New innermost scope, e.g. for a block. The parent outlives the
+    // defining frame as long as the child is alive.
+    pub fn child(&self) -> Environment {
+        Environment {
+            scope: Rc::new(RefCell::new(Scope {
+                vars: HashMap::new(),
+                parent: Some(self.clone()),
+            })),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<Object> {
+        let scope = self.scope.borrow();
+        match scope.vars.get(name) {
+            Some(value) => Some(*value),
+            None => scope.parent.as_ref().and_then(|parent| parent.get(name)),
+        }
+    }
+
+    // Bind `name` in the innermost scope, shadowing outer bindings
+    pub fn define(&self, name: &str, value: Object) {
+        self.scope.borrow_mut().vars.insert(name.to_string(), value);
+    }
+
+    // Update the nearest existing binding. Returns false if there is none.
+    pub fn set(&self, name: &str, value: Object) -> bool {
+        let mut scope = self.scope.borrow_mut();
+        if let Some(v) = scope.vars.get_mut(name) {
+            *v = value;
+            return true;
+        }
+        match &scope.parent {
+            Some(parent) => parent.set(name, value),
+            None => false,
+        }
+    }
+
+    // Copy of all visible bindings flattened into one scope,
+    // independent of later changes (capture by value)
+    pub fn snapshot(&self) -> Environment {
+        let env = Environment::new();
+        for (name, value) in self.bindings() {
+            env.define(&name, value);
+        }
+        env
+    }
+
+    // Visible bindings, outer shadowed ones excluded
+    pub fn bindings(&self) -> HashMap<String, Object> {
+        let scope = self.scope.borrow();
+        let mut bindings = match &scope.parent {
+            Some(parent) => parent.bindings(),
+            None => HashMap::new(),
+        };
+        for (name, value) in &scope.vars {
+            bindings.insert(name.clone(), *value);
+        }
+        bindings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nested_scope() {
+        let global = Environment::new();
+        global.define("a", Object::UInt64(1));
+        let block = global.child();
+        block.define("a", Object::UInt64(2));
+        block.define("b", Object::UInt64(3));
+        assert_eq!(Some(Object::UInt64(2)), block.get("a"));
+        assert_eq!(Some(Object::UInt64(1)), global.get("a"));
+        assert_eq!(None, global.get("b"));
+
+        assert!(block.set("b", Object::UInt64(4)));
+        assert!(!block.set("c", Object::UInt64(4)));
+        assert_eq!(Some(Object::UInt64(4)), block.get("b"));
+    }
+
+    #[test]
+    fn capture_shared_and_by_value() {
+        let frame = Environment::new();
+        frame.define("count", Object::Int64(0));
+        let shared = frame.child();
+        let by_value = frame.snapshot();
+
+        // the defining frame is gone, captured scopes stay alive
+        let frame_view = frame.clone();
+        drop(frame);
+
+        assert!(shared.set("count", Object::Int64(1)));
+        assert_eq!(Some(Object::Int64(1)), frame_view.get("count"));
+        assert_eq!(Some(Object::Int64(0)), by_value.get("count"));
+
+        assert!(by_value.set("count", Object::Int64(5)));
+        assert_eq!(Some(Object::Int64(1)), shared.get("count"));
+    }
+}
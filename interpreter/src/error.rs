@@ -0,0 +1,79 @@
+use std::fmt;
+use frontend::ast::{ExprRef, Node};
+use crate::policy::Capability;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum InterpreterError {
+    UndefinedVariable(String),
+    UndefinedFunction(String),
+    TypeMismatch(String),
+    InvalidExprRef(ExprRef),
+    // location of the division, if the processor knows the locations
+    DivisionByZero(Option<Node>),
+    // integer overflow in `OverflowMode::Trap`, with the location of the operation
+    Overflow(Option<Node>),
+    // `assert` with false, with the location of the call
+    AssertionFailed(Option<Node>),
+    // the step limit set by `Processor::set_fuel` is used up
+    FuelExhausted,
+    // stopped by `CancellationToken`
+    Cancelled,
+    // evaluation nested deeper than `Processor::set_max_depth`, with the
+    // location of the expression which is not evaluated
+    RecursionLimit(Option<Node>),
+    // the execution policy does not allow the capability
+    PermissionDenied(Capability),
+    // error returned by a function registered by the host
+    Native { name: String, message: String },
+}
+
+impl fmt::Display for InterpreterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InterpreterError::UndefinedVariable(name) => write!(f, "undefined variable `{}`", name),
+            InterpreterError::UndefinedFunction(name) => write!(f, "undefined function `{}`", name),
+            InterpreterError::TypeMismatch(message) => write!(f, "type mismatch: {}", message),
+            InterpreterError::InvalidExprRef(e) => write!(f, "invalid expression reference {:?}", e),
+            InterpreterError::DivisionByZero(Some(node)) => write!(f, "{}..{}: division by zero", node.start(), node.end()),
+            InterpreterError::DivisionByZero(None) => write!(f, "division by zero"),
+            InterpreterError::Overflow(Some(node)) => write!(f, "{}..{}: integer overflow", node.start(), node.end()),
+            InterpreterError::Overflow(None) => write!(f, "integer overflow"),
+            InterpreterError::AssertionFailed(Some(node)) => write!(f, "{}..{}: assertion failed", node.start(), node.end()),
+            InterpreterError::AssertionFailed(None) => write!(f, "assertion failed"),
+            InterpreterError::FuelExhausted => write!(f, "execution step limit exceeded"),
+            InterpreterError::Cancelled => write!(f, "execution cancelled"),
+            InterpreterError::RecursionLimit(Some(node)) => write!(f, "{}..{}: recursion limit exceeded", node.start(), node.end()),
+            InterpreterError::RecursionLimit(None) => write!(f, "recursion limit exceeded"),
+            InterpreterError::PermissionDenied(capability) =>
+                write!(f, "{:?} is not allowed by the execution policy", capability),
+            InterpreterError::Native { name, message } => write!(f, "{}: {}", name, message),
+        }
+    }
+}
+
+impl InterpreterError {
+    // location of the expression which raised the error, if the error carries one
+    pub fn location(&self) -> Option<&Node> {
+        match self {
+            InterpreterError::DivisionByZero(node)
+            | InterpreterError::Overflow(node)
+            | InterpreterError::AssertionFailed(node)
+            | InterpreterError::RecursionLimit(node) => node.as_ref(),
+            _ => None,
+        }
+    }
+
+    // the same error without the location, for reports which print the
+    // location as a line and a column themselves
+    pub fn without_location(&self) -> InterpreterError {
+        match self {
+            InterpreterError::DivisionByZero(_) => InterpreterError::DivisionByZero(None),
+            InterpreterError::Overflow(_) => InterpreterError::Overflow(None),
+            InterpreterError::AssertionFailed(_) => InterpreterError::AssertionFailed(None),
+            InterpreterError::RecursionLimit(_) => InterpreterError::RecursionLimit(None),
+            e => e.clone(),
+        }
+    }
+}
+
+impl std::error::Error for InterpreterError {}
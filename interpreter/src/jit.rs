@@ -0,0 +1,678 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+use cranelift_codegen::ir::{self, condcodes::IntCC, types, AbiParam, InstBuilder, MemFlags};
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, FuncId, Linkage, Module};
+use frontend::ast::*;
+use crate::error::InterpreterError;
+use crate::object::Object;
+use crate::overflow::OverflowMode;
+use crate::processor::Processor;
+
+// Native code generation by Cranelift (feature `jit`).
+//
+// `load` lowers the functions of a program from the AST to Cranelift IR
+// and the processor calls the native code instead of evaluating them.
+// A function is compiled only if all of it is supported: integer and
+// bool values, val/var/assignment, arithmetic, comparison, logical
+// operators, if/while/for and calls of other compiled functions.
+// Anything else (builtins, null, ...) leaves the function to the
+// interpreter, which also runs the callers of such a function.
+//
+// Every value is an i64 in the native code (bool is 0 or 1). A compiled
+// function takes its arguments and a pointer to `Status`, where an error
+// is reported, and the caller checks it after each call.
+
+const STATUS_OK: u32 = 0;
+const STATUS_OVERFLOW: u32 = 1;
+const STATUS_DIVISION_BY_ZERO: u32 = 2;
+
+#[repr(C)]
+#[derive(Default)]
+struct Status {
+    code: u32,
+    expr: u32, // the failed expression, for the location of the error
+}
+
+// Functions compiled by `load` and the ones left to the interpreter with the reason
+#[derive(Debug, Default, PartialEq)]
+pub struct Report {
+    pub compiled: Vec<String>,
+    pub skipped: Vec<(String, String)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Int64,
+    UInt64,
+    Bool,
+    Unit,
+}
+
+impl Kind {
+    fn of(ty: &Type) -> Option<Kind> {
+        match ty {
+            Type::Int64 => Some(Kind::Int64),
+            Type::UInt64 => Some(Kind::UInt64),
+            Type::Bool => Some(Kind::Bool),
+            Type::Unit => Some(Kind::Unit),
+            _ => None,
+        }
+    }
+
+    fn object(self, value: i64) -> Object {
+        match self {
+            Kind::Int64 => Object::Int64(value),
+            Kind::UInt64 => Object::UInt64(value as u64),
+            Kind::Bool => Object::Bool(value != 0),
+            Kind::Unit => Object::Unit,
+        }
+    }
+
+    fn value(self, object: &Object) -> Option<i64> {
+        match (self, object) {
+            (Kind::Int64, Object::Int64(i)) => Some(*i),
+            (Kind::UInt64, Object::UInt64(u)) => Some(*u as i64),
+            (Kind::Bool, Object::Bool(b)) => Some(*b as i64),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Signature {
+    parameter: Vec<Kind>,
+    result: Kind,
+}
+
+type Unsupported = String;
+
+// Compile the supported functions of `program`, load the program into `p`
+// and let `p` call the native code.
+pub fn load(p: &mut Processor, program: &Program) -> Report {
+    p.load_program(program);
+    let mut report = Report::default();
+    let mut signatures = HashMap::new();
+    for f in &program.function {
+        match signature(f, p.overflow_mode()) {
+            Ok(s) => {
+                signatures.insert(f.name.clone(), s);
+            }
+            Err(reason) => report.skipped.push((f.name.clone(), reason)),
+        }
+    }
+
+    let mut module = match new_module() {
+        Ok(module) => module,
+        Err(reason) => {
+            report.skipped.extend(signatures.into_keys().map(|name| (name, reason.clone())));
+            return report;
+        }
+    };
+    let pointer = module.target_config().pointer_type();
+    let mut ids: HashMap<String, FuncId> = HashMap::new();
+    for (name, s) in &signatures {
+        let id = module.declare_function(name, Linkage::Local, &native_signature(&module, s.parameter.len(), pointer))
+            .expect("declare a function");
+        ids.insert(name.clone(), id);
+    }
+
+    // A function calling a skipped function is skipped too, so lower
+    // everything again until nothing more is skipped
+    loop {
+        let mut skipped = vec![];
+        for f in program.function.iter().filter(|f| signatures.contains_key(&f.name)) {
+            let mut ctx = module.make_context();
+            if let Err(reason) = lower(&mut module, &mut ctx, f, &program.expression, &signatures, &ids, p.overflow_mode()) {
+                skipped.push((f.name.clone(), reason));
+            }
+        }
+        if skipped.is_empty() {
+            break;
+        }
+        for (name, _) in &skipped {
+            signatures.remove(name);
+        }
+        report.skipped.extend(skipped);
+    }
+
+    let mut entries = vec![];
+    for f in program.function.iter().filter(|f| signatures.contains_key(&f.name)) {
+        let mut ctx = module.make_context();
+        lower(&mut module, &mut ctx, f, &program.expression, &signatures, &ids, p.overflow_mode())
+            .expect("lowered before");
+        module.define_function(ids[&f.name], &mut ctx).expect("define a function");
+        let entry = define_entry(&mut module, &f.name, ids[&f.name], signatures[&f.name].parameter.len(), pointer);
+        entries.push((f.name.clone(), entry));
+    }
+    module.finalize_definitions().expect("finalize the native code");
+
+    for (name, entry) in entries {
+        // the code stays valid after the module is dropped, JITModule does not free it
+        let entry: extern "C" fn(*const i64, *mut Status) -> i64 =
+            unsafe { std::mem::transmute(module.get_finalized_function(entry)) };
+        let s = signatures[&name].clone();
+        let location = program.location.clone();
+        let function_name = name.clone();
+        p.set_compiled(&name, Rc::new(move |args: &[Object]| {
+            if args.len() != s.parameter.len() {
+                return Err(InterpreterError::TypeMismatch(format!(
+                    "function `{}` takes {} argument(s) but {} given", function_name, s.parameter.len(), args.len())));
+            }
+            let mut values = vec![];
+            for (kind, arg) in s.parameter.iter().zip(args) {
+                match kind.value(arg) {
+                    Some(value) => values.push(value),
+                    None => return Err(InterpreterError::TypeMismatch(format!(
+                        "function `{}` expects {:?} but {:?}", function_name, kind, arg))),
+                }
+            }
+            let mut status = Status::default();
+            let result = entry(values.as_ptr(), &mut status);
+            let node = location.get(ExprRef(status.expr)).cloned();
+            match status.code {
+                STATUS_OK => Ok(s.result.object(result)),
+                STATUS_OVERFLOW => Err(InterpreterError::Overflow(node)),
+                _ => Err(InterpreterError::DivisionByZero(node)),
+            }
+        }));
+        report.compiled.push(name);
+    }
+    report
+}
+
+fn signature(f: &Function, overflow: OverflowMode) -> Result<Signature, Unsupported> {
+    if overflow == OverflowMode::Saturate {
+        return Err("saturating arithmetic is not supported".to_string());
+    }
+    let mut parameter = vec![];
+    for (name, ty) in &f.parameter {
+        match Kind::of(ty) {
+            Some(kind) if kind != Kind::Unit => parameter.push(kind),
+            _ => return Err(format!("parameter `{}` of type {:?}", name, ty)),
+        }
+    }
+    let result = match &f.return_type {
+        None => Kind::Unit,
+        Some(ty) => Kind::of(ty).ok_or(format!("return type {:?}", ty))?,
+    };
+    Ok(Signature { parameter, result })
+}
+
+fn new_module() -> Result<JITModule, Unsupported> {
+    let mut flags = settings::builder();
+    flags.set("opt_level", "speed").map_err(|e| e.to_string())?;
+    let isa = cranelift_native::builder().map_err(|e| e.to_string())?
+        .finish(settings::Flags::new(flags)).map_err(|e| e.to_string())?;
+    Ok(JITModule::new(JITBuilder::with_isa(isa, default_libcall_names())))
+}
+
+// (i64 arguments..., status pointer) -> i64
+fn native_signature(module: &JITModule, arity: usize, pointer: ir::Type) -> ir::Signature {
+    let mut sig = module.make_signature();
+    sig.params.extend((0..arity).map(|_| AbiParam::new(types::I64)));
+    sig.params.push(AbiParam::new(pointer));
+    sig.returns.push(AbiParam::new(types::I64));
+    sig
+}
+
+// `extern "C" fn(args: *const i64, status: *mut Status) -> i64` calling the function `id`,
+// so that the processor calls any function in the same way
+fn define_entry(module: &mut JITModule, name: &str, id: FuncId, arity: usize, pointer: ir::Type) -> FuncId {
+    let mut sig = module.make_signature();
+    sig.params.push(AbiParam::new(pointer));
+    sig.params.push(AbiParam::new(pointer));
+    sig.returns.push(AbiParam::new(types::I64));
+    let entry = module.declare_function(&format!("{}$entry", name), Linkage::Local, &sig).expect("declare an entry");
+
+    let mut ctx = module.make_context();
+    ctx.func.signature = sig;
+    let mut fctx = FunctionBuilderContext::new();
+    let mut b = FunctionBuilder::new(&mut ctx.func, &mut fctx);
+    let block = b.create_block();
+    b.append_block_params_for_function_params(block);
+    b.switch_to_block(block);
+    let (args, status) = (b.block_params(block)[0], b.block_params(block)[1]);
+    let mut values: Vec<ir::Value> = (0..arity)
+        .map(|i| b.ins().load(types::I64, MemFlags::trusted(), args, (i * 8) as i32))
+        .collect();
+    values.push(status);
+    let callee = module.declare_func_in_func(id, b.func);
+    let call = b.ins().call(callee, &values);
+    let result = b.inst_results(call)[0];
+    b.ins().return_(&[result]);
+    b.seal_all_blocks();
+    b.finalize();
+    module.define_function(entry, &mut ctx).expect("define an entry");
+    entry
+}
+
+fn lower(module: &mut JITModule, ctx: &mut cranelift_codegen::Context, f: &Function, pool: &ExprPool,
+         signatures: &HashMap<String, Signature>, ids: &HashMap<String, FuncId>,
+         overflow: OverflowMode) -> Result<(), Unsupported> {
+    let s = &signatures[&f.name];
+    let pointer = module.target_config().pointer_type();
+    ctx.func.signature = native_signature(module, s.parameter.len(), pointer);
+    let mut callees = HashMap::new();
+    for (name, id) in ids {
+        if signatures.contains_key(name) {
+            callees.insert(name.clone(), (module.declare_func_in_func(*id, &mut ctx.func), signatures[name].clone()));
+        }
+    }
+
+    let mut fctx = FunctionBuilderContext::new();
+    let mut b = FunctionBuilder::new(&mut ctx.func, &mut fctx);
+    let block = b.create_block();
+    b.append_block_params_for_function_params(block);
+    b.switch_to_block(block);
+    let params = b.block_params(block).to_vec();
+    let mut l = Lower {
+        b,
+        pool,
+        callees,
+        scopes: vec![HashMap::new()],
+        variables: 0,
+        status: params[s.parameter.len()],
+        overflow,
+    };
+    for (((name, _), kind), value) in f.parameter.iter().zip(&s.parameter).zip(params) {
+        l.define(name, *kind, value);
+    }
+
+    let (kind, value) = l.expr(f.code)?;
+    if kind != s.result {
+        return Err(format!("body is {:?} but the return type is {:?}", kind, s.result));
+    }
+    let value = match value {
+        Some(value) => value,
+        None => l.b.ins().iconst(types::I64, 0),
+    };
+    l.b.ins().return_(&[value]);
+    l.b.seal_all_blocks();
+    l.b.finalize();
+    Ok(())
+}
+
+struct Lower<'a> {
+    b: FunctionBuilder<'a>,
+    pool: &'a ExprPool,
+    callees: HashMap<String, (ir::FuncRef, Signature)>,
+    scopes: Vec<HashMap<String, (Variable, Kind)>>,
+    variables: u32,
+    status: ir::Value,
+    overflow: OverflowMode,
+}
+
+type Lowered = (Kind, Option<ir::Value>);
+
+impl Lower<'_> {
+    fn variable(&mut self) -> Variable {
+        let var = Variable::from_u32(self.variables);
+        self.variables += 1;
+        self.b.declare_var(var, types::I64);
+        var
+    }
+
+    fn define(&mut self, name: &str, kind: Kind, value: ir::Value) {
+        let var = self.variable();
+        self.b.def_var(var, value);
+        self.scopes.last_mut().unwrap().insert(name.to_string(), (var, kind));
+    }
+
+    fn lookup(&self, name: &str) -> Result<(Variable, Kind), Unsupported> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name).copied())
+            .ok_or(format!("variable `{}` outside of the function", name))
+    }
+
+    fn value(&mut self, e: ExprRef) -> Result<(Kind, ir::Value), Unsupported> {
+        match self.expr(e)? {
+            (kind, Some(value)) => Ok((kind, value)),
+            (kind, None) => Err(format!("{:?} is used as a value", kind)),
+        }
+    }
+
+    fn bool(&mut self, e: ExprRef) -> Result<ir::Value, Unsupported> {
+        match self.value(e)? {
+            (Kind::Bool, value) => Ok(value),
+            (kind, _) => Err(format!("condition of {:?}", kind)),
+        }
+    }
+
+    // Report `code` with the expression `e` and return from the function if `failed` is not 0
+    fn fail_if(&mut self, failed: ir::Value, code: u32, e: ExprRef) {
+        let error = self.b.create_block();
+        let next = self.b.create_block();
+        self.b.ins().brif(failed, error, &[], next, &[]);
+        self.b.switch_to_block(error);
+        let code = self.b.ins().iconst(types::I32, code as i64);
+        let expr = self.b.ins().iconst(types::I32, e.0 as i64);
+        self.b.ins().store(MemFlags::trusted(), code, self.status, 0);
+        self.b.ins().store(MemFlags::trusted(), expr, self.status, 4);
+        let zero = self.b.ins().iconst(types::I64, 0);
+        self.b.ins().return_(&[zero]);
+        self.b.switch_to_block(next);
+    }
+
+    fn expr(&mut self, e: ExprRef) -> Result<Lowered, Unsupported> {
+        let expr = match self.pool.get(e.0 as usize) {
+            Some(expr) => expr.clone(),
+            None => return Err(format!("invalid expression reference {:?}", e)),
+        };
+        match expr {
+            Expr::Int64(i) => Ok((Kind::Int64, Some(self.b.ins().iconst(types::I64, i)))),
+            Expr::UInt64(u) => Ok((Kind::UInt64, Some(self.b.ins().iconst(types::I64, u as i64)))),
+            Expr::Identifier(name) => {
+                let (var, kind) = self.lookup(&name)?;
+                Ok((kind, Some(self.b.use_var(var))))
+            }
+            Expr::Val(name, ty, Some(rhs)) | Expr::Var(name, ty, Some(rhs)) => {
+                let (kind, value) = self.value(rhs)?;
+                if let Some(declared) = ty.filter(|ty| *ty != Type::Unknown) {
+                    if Kind::of(&declared) != Some(kind) {
+                        return Err(format!("`{}` is declared {:?} but {:?}", name, declared, kind));
+                    }
+                }
+                self.define(&name, kind, value);
+                Ok((Kind::Unit, None))
+            }
+            Expr::Binary(op, lhs, rhs) => self.binary(e, op, lhs, rhs),
+            Expr::Block(expressions) => {
+                self.scopes.push(HashMap::new());
+                let mut last = Ok((Kind::Unit, None));
+                for e in expressions {
+                    last = self.expr(e);
+                    if last.is_err() {
+                        break;
+                    }
+                }
+                self.scopes.pop();
+                last
+            }
+            Expr::IfElse(cond, then_block, else_block) => {
+                let cond = self.bool(cond)?;
+                let (then, other, merge) = (self.b.create_block(), self.b.create_block(), self.b.create_block());
+                self.b.ins().brif(cond, then, &[], other, &[]);
+                self.b.switch_to_block(then);
+                let (then_kind, then_value) = self.expr(then_block)?;
+                self.b.ins().jump(merge, then_value.as_slice());
+                self.b.switch_to_block(other);
+                let (else_kind, else_value) = self.expr(else_block)?;
+                if then_kind != else_kind {
+                    return Err(format!("branches of if are {:?} and {:?}", then_kind, else_kind));
+                }
+                self.b.ins().jump(merge, else_value.as_slice());
+                self.b.switch_to_block(merge);
+                if then_kind == Kind::Unit {
+                    return Ok((Kind::Unit, None));
+                }
+                Ok((then_kind, Some(self.b.append_block_param(merge, types::I64))))
+            }
+            Expr::While(cond, body) => {
+                let (header, body_block, exit) = (self.b.create_block(), self.b.create_block(), self.b.create_block());
+                self.b.ins().jump(header, &[]);
+                self.b.switch_to_block(header);
+                let cond = self.bool(cond)?;
+                self.b.ins().brif(cond, body_block, &[], exit, &[]);
+                self.b.switch_to_block(body_block);
+                self.expr(body)?;
+                self.b.ins().jump(header, &[]);
+                self.b.switch_to_block(exit);
+                Ok((Kind::Unit, None))
+            }
+            Expr::For(name, start, end, body) => {
+                let (kind, start) = self.value(start)?;
+                let (end_kind, end) = self.value(end)?;
+                if kind != end_kind || !matches!(kind, Kind::Int64 | Kind::UInt64) {
+                    return Err(format!("range of for is {:?}..{:?}", kind, end_kind));
+                }
+                // the counter is hidden, the body sees a copy of it as the induction variable
+                let counter = self.variable();
+                self.b.def_var(counter, start);
+                let (header, body_block, exit) = (self.b.create_block(), self.b.create_block(), self.b.create_block());
+                self.b.ins().jump(header, &[]);
+                self.b.switch_to_block(header);
+                let current = self.b.use_var(counter);
+                let less = if kind == Kind::Int64 { IntCC::SignedLessThan } else { IntCC::UnsignedLessThan };
+                let cond = self.b.ins().icmp(less, current, end);
+                self.b.ins().brif(cond, body_block, &[], exit, &[]);
+                self.b.switch_to_block(body_block);
+                self.scopes.push(HashMap::new());
+                self.define(&name, kind, current);
+                let result = self.expr(body);
+                self.scopes.pop();
+                result?;
+                // no overflow, the counter is less than the end
+                let next = self.b.ins().iadd_imm(current, 1);
+                self.b.def_var(counter, next);
+                self.b.ins().jump(header, &[]);
+                self.b.switch_to_block(exit);
+                Ok((Kind::Unit, None))
+            }
+            Expr::Call(name, args) => {
+                let (callee, s) = match self.callees.get(&name) {
+                    Some(callee) => callee.clone(),
+                    None => return Err(format!("call of `{}`", name)),
+                };
+                let args = match self.pool.get(args.0 as usize) {
+                    Some(Expr::Block(args)) => args.clone(),
+                    _ => return Err(format!("arguments of `{}`", name)),
+                };
+                if args.len() != s.parameter.len() {
+                    return Err(format!("number of arguments of `{}`", name));
+                }
+                let mut values = vec![];
+                for (arg, expected) in args.iter().zip(&s.parameter) {
+                    let (kind, value) = self.value(*arg)?;
+                    if kind != *expected {
+                        return Err(format!("argument of `{}` is {:?} but {:?} is expected", name, kind, expected));
+                    }
+                    values.push(value);
+                }
+                values.push(self.status);
+                let call = self.b.ins().call(callee, &values);
+                let result = self.b.inst_results(call)[0];
+                // the error is already reported by the callee
+                let code = self.b.ins().load(types::I32, MemFlags::trusted(), self.status, 0);
+                let propagate = self.b.create_block();
+                let next = self.b.create_block();
+                self.b.ins().brif(code, propagate, &[], next, &[]);
+                self.b.switch_to_block(propagate);
+                let zero = self.b.ins().iconst(types::I64, 0);
+                self.b.ins().return_(&[zero]);
+                self.b.switch_to_block(next);
+                if s.result == Kind::Unit {
+                    return Ok((Kind::Unit, None));
+                }
+                Ok((s.result, Some(result)))
+            }
+            Expr::Int(text) => Err(format!("unresolved integer literal {}", text)),
+            Expr::Null => Err("null".to_string()),
+            Expr::Val(name, _, None) | Expr::Var(name, _, None) => Err(format!("`{}` without initial value", name)),
+            Expr::Spawn(_) => Err("spawn".to_string()),
+            Expr::Function(f) => Err(format!("nested function `{}`", f.name)),
+        }
+    }
+
+    fn binary(&mut self, e: ExprRef, op: Operator, lhs: ExprRef, rhs: ExprRef) -> Result<Lowered, Unsupported> {
+        match op {
+            Operator::Assign => {
+                let name = match self.pool.get(lhs.0 as usize) {
+                    Some(Expr::Identifier(name)) => name.clone(),
+                    x => return Err(format!("assignment to {:?}", x)),
+                };
+                let (var, kind) = self.lookup(&name)?;
+                let (rhs_kind, value) = self.value(rhs)?;
+                if kind != rhs_kind {
+                    return Err(format!("assignment of {:?} to `{}` of {:?}", rhs_kind, name, kind));
+                }
+                self.b.def_var(var, value);
+                return Ok((Kind::Unit, None));
+            }
+            Operator::LogicalAnd | Operator::LogicalOr => {
+                // short circuit: the result is lhs if it decides
+                let lhs = self.bool(lhs)?;
+                let (right, merge) = (self.b.create_block(), self.b.create_block());
+                let result = self.b.append_block_param(merge, types::I64);
+                if op == Operator::LogicalAnd {
+                    self.b.ins().brif(lhs, right, &[], merge, &[lhs]);
+                } else {
+                    self.b.ins().brif(lhs, merge, &[lhs], right, &[]);
+                }
+                self.b.switch_to_block(right);
+                let rhs = self.bool(rhs)?;
+                self.b.ins().jump(merge, &[rhs]);
+                self.b.switch_to_block(merge);
+                return Ok((Kind::Bool, Some(result)));
+            }
+            _ => (),
+        }
+
+        let (kind, l) = self.value(lhs)?;
+        let (rhs_kind, r) = self.value(rhs)?;
+        if kind != rhs_kind {
+            return Err(format!("operands of {:?} are {:?} and {:?}", op, kind, rhs_kind));
+        }
+        let signed = kind == Kind::Int64;
+        let cc = match op {
+            Operator::EQ => Some(IntCC::Equal),
+            Operator::NE => Some(IntCC::NotEqual),
+            Operator::LT => Some(if signed { IntCC::SignedLessThan } else { IntCC::UnsignedLessThan }),
+            Operator::LE => Some(if signed { IntCC::SignedLessThanOrEqual } else { IntCC::UnsignedLessThanOrEqual }),
+            Operator::GT => Some(if signed { IntCC::SignedGreaterThan } else { IntCC::UnsignedGreaterThan }),
+            Operator::GE => Some(if signed { IntCC::SignedGreaterThanOrEqual } else { IntCC::UnsignedGreaterThanOrEqual }),
+            _ => None,
+        };
+        if let Some(cc) = cc {
+            if kind == Kind::Bool && !matches!(op, Operator::EQ | Operator::NE) {
+                return Err(format!("operator {:?} for bool", op));
+            }
+            let cmp = self.b.ins().icmp(cc, l, r);
+            return Ok((Kind::Bool, Some(self.b.ins().uextend(types::I64, cmp))));
+        }
+        if kind == Kind::Bool {
+            return Err(format!("operator {:?} for bool", op));
+        }
+
+        let trap = self.overflow == OverflowMode::Trap;
+        let value = match op {
+            Operator::IDiv => {
+                let zero = self.b.ins().icmp_imm(IntCC::Equal, r, 0);
+                self.fail_if(zero, STATUS_DIVISION_BY_ZERO, e);
+                if signed {
+                    // i64::MIN / -1 overflows, and sdiv would trap on it
+                    let min = self.b.ins().icmp_imm(IntCC::Equal, l, i64::MIN);
+                    let minus_one = self.b.ins().icmp_imm(IntCC::Equal, r, -1);
+                    let overflow = self.b.ins().band(min, minus_one);
+                    if trap {
+                        self.fail_if(overflow, STATUS_OVERFLOW, e);
+                        self.b.ins().sdiv(l, r)
+                    } else {
+                        let one = self.b.ins().iconst(types::I64, 1);
+                        let divisor = self.b.ins().select(overflow, one, r);
+                        self.b.ins().sdiv(l, divisor) // MIN / 1 is the wrapped MIN / -1
+                    }
+                } else {
+                    self.b.ins().udiv(l, r)
+                }
+            }
+            _ if !trap => match op {
+                Operator::IAdd => self.b.ins().iadd(l, r),
+                Operator::ISub => self.b.ins().isub(l, r),
+                _ => self.b.ins().imul(l, r),
+            },
+            _ => {
+                let (value, overflow) = match (op, signed) {
+                    (Operator::IAdd, true) => self.b.ins().sadd_overflow(l, r),
+                    (Operator::IAdd, false) => self.b.ins().uadd_overflow(l, r),
+                    (Operator::ISub, true) => self.b.ins().ssub_overflow(l, r),
+                    (Operator::ISub, false) => self.b.ins().usub_overflow(l, r),
+                    (_, true) => self.b.ins().smul_overflow(l, r),
+                    (_, false) => self.b.ins().umul_overflow(l, r),
+                };
+                self.fail_if(overflow, STATUS_OVERFLOW, e);
+                value
+            }
+        };
+        Ok((kind, Some(value)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(code: &str, mode: OverflowMode) -> (Report, Result<Object, InterpreterError>, Result<Object, InterpreterError>) {
+        let program = frontend::Parser::new(code).parse_program().unwrap();
+        let mut p = Processor::new();
+        p.set_overflow_mode(mode);
+        let expected = p.execute_program(&program);
+        let report = load(&mut p, &program);
+        let actual = p.evaluate_function(&program.expression, "main", &[]);
+        (report, expected, actual)
+    }
+
+    #[test]
+    fn compile_supported_functions() {
+        let code = r#"
+fn fib(n: u64) -> u64 {
+    if n < 2u64 { n } else { fib(n - 1u64) + fib(n - 2u64) }
+}
+fn sum(n: i64) -> i64 {
+    var s = 0i64
+    for i in -3i64..n {
+        if i < 0i64 || i == 5i64 { s = s - i } else { s = s + i * 1000i64 / 3i64 }
+    }
+    var k = 0i64
+    while k < 4i64 && s > 0i64 { k = k + 1i64 }
+    s + k
+}
+fn rand() -> u64 {
+    random_u64()
+}
+fn main() -> u64 {
+    val a = fib(20u64)
+    if sum(10i64) > 0i64 && rand() >= 0u64 { a } else { 0u64 }
+}
+        "#;
+        let (report, expected, actual) = run(code, OverflowMode::Trap);
+        assert_eq!(Ok(Object::UInt64(6765)), expected);
+        assert_eq!(expected, actual);
+        assert_eq!(vec!["fib".to_string(), "sum".to_string()], {
+            let mut compiled = report.compiled.clone();
+            compiled.sort();
+            compiled
+        });
+        let mut skipped = report.skipped;
+        skipped.sort();
+        assert_eq!(vec![
+            ("main".to_string(), "call of `rand`".to_string()),
+            ("rand".to_string(), "call of `random_u64`".to_string()),
+        ], skipped);
+    }
+
+    #[test]
+    fn errors_of_native_code() {
+        let code = "fn f(a: i64, b: i64) -> i64 {\na / b\n}\nfn main() -> i64 {\nf(1i64, 0i64)\n}";
+        let (report, expected, actual) = run(code, OverflowMode::Trap);
+        assert_eq!(2, report.compiled.len());
+        assert!(matches!(expected, Err(InterpreterError::DivisionByZero(Some(_)))));
+        assert_eq!(expected, actual);
+
+        let code = "fn f(a: i64, b: i64) -> i64 {\na / b + a * b\n}\nfn main() -> i64 {\nf(-9223372036854775807i64 - 1i64, -1i64)\n}";
+        let (_, expected, actual) = run(code, OverflowMode::Trap);
+        assert!(matches!(expected, Err(InterpreterError::Overflow(Some(_)))));
+        assert_eq!(expected, actual);
+        // MIN / -1 + MIN * -1 wraps to MIN + MIN
+        let (_, expected, actual) = run(code, OverflowMode::Wrap);
+        assert_eq!(Ok(Object::Int64(0)), expected);
+        assert_eq!(expected, actual);
+
+        let code = "fn main() -> u64 {\nvar a = 18446744073709551615u64\na = a + 1u64\na\n}";
+        let (report, expected, actual) = run(code, OverflowMode::Saturate);
+        assert!(report.compiled.is_empty());
+        assert_eq!(Ok(Object::UInt64(u64::MAX)), expected);
+        assert_eq!(expected, actual);
+    }
+}
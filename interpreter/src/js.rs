@@ -0,0 +1,547 @@
+use std::collections::{HashMap, HashSet};
+use frontend::ast::*;
+
+// JavaScript backend, to run toylang functions in web pages.
+//
+// `transpile` emits a script with a function for each function of a type
+// checked program. Integers are BigInt (`1n`), bool is boolean, unit is
+// undefined and null is null. Each arithmetic result is range checked by
+// `$i64`/`$u64` of the runtime, so an overflow or a division by zero
+// throws a RangeError as the interpreter stops with an error (the trap
+// mode of `OverflowMode`). The runtime also implements the builtins.
+//
+// Statements of toylang are expressions; an `if` or a block used as a
+// value becomes a conditional operator if it is simple, or an arrow
+// function called in place otherwise.
+
+const RUNTIME: &str = r#"const $I64_MIN = -(1n << 63n), $I64_MAX = (1n << 63n) - 1n, $U64_MAX = (1n << 64n) - 1n;
+function $i64(x) {
+  if (x < $I64_MIN || x > $I64_MAX) throw new RangeError("integer overflow");
+  return x;
+}
+function $u64(x) {
+  if (x < 0n || x > $U64_MAX) throw new RangeError("integer overflow");
+  return x;
+}
+function $div(a, b) {
+  if (b === 0n) throw new RangeError("division by zero");
+  return a / b;
+}
+"#;
+
+// builtins of `crate::builtin` and of the prelude, the generic ones work on
+// both integer types
+const BUILTINS: &[(&str, &str)] = &[
+    ("abs", r#"function abs(x) {
+  return x < 0n ? $i64(-x) : x;
+}
+"#),
+    ("min", r#"function min(a, b) {
+  return a < b ? a : b;
+}
+"#),
+    ("max", r#"function max(a, b) {
+  return a > b ? a : b;
+}
+"#),
+    // the result is checked by the caller, which knows the type
+    ("pow", r#"function pow(base, exp) {
+  if (exp < 0n || exp > 0xffffffffn) throw new RangeError(`invalid exponent ${exp}`);
+  let result = 1n;
+  for (let i = 0n; i < exp; i++) {
+    result *= base;
+    if (result > $U64_MAX || result < $I64_MIN) throw new RangeError("integer overflow");
+  }
+  return result;
+}
+"#),
+    ("sqrt", r#"function sqrt(x) {
+  if (x < 0n) throw new RangeError(`square root of negative number ${x}`);
+  if (x < 2n) return x;
+  let r = x, next = (x + 1n) / 2n;
+  while (next < r) {
+    r = next;
+    next = (r + x / r) / 2n;
+  }
+  return r;
+}
+"#),
+    ("clamp", r#"function clamp(x, lo, hi) {
+  if (lo > hi) throw new RangeError(`lower bound ${lo} is greater than upper bound ${hi}`);
+  return x < lo ? lo : x > hi ? hi : x;
+}
+"#),
+    ("random_u64", r#"let $random = BigInt(Date.now());
+function random_u64() {
+  $random = BigInt.asUintN(64, $random + 0x9e3779b97f4a7c15n);
+  let z = $random;
+  z = BigInt.asUintN(64, (z ^ (z >> 30n)) * 0xbf58476d1ce4e5b9n);
+  z = BigInt.asUintN(64, (z ^ (z >> 27n)) * 0x94d049bb133111ebn);
+  return z ^ (z >> 31n);
+}
+"#),
+    ("random_range", r#"function random_range(lo, hi) {
+  if (lo >= hi) throw new RangeError(`empty range ${lo}..${hi}`);
+  return lo + ((random_u64() * (hi - lo)) >> 64n);
+}
+"#),
+    ("assert", r#"function assert(cond) {
+  if (!cond) throw new Error("assertion failed");
+}
+"#),
+    ("print", r#"function print(value) {
+  console.log(String(value));
+}
+"#),
+    ("to_i64", r#"function to_i64(x) {
+  if (typeof x === "boolean") return x ? 1n : 0n;
+  if (x > $I64_MAX) throw new RangeError(`${x} is out of the range of i64`);
+  return x;
+}
+"#),
+    ("to_u64", r#"function to_u64(x) {
+  if (typeof x === "boolean") return x ? 1n : 0n;
+  if (x < 0n) throw new RangeError(`${x} is out of the range of u64`);
+  return x;
+}
+"#),
+    ("dbg", r#"function dbg(value) {
+  console.error(`dbg: ${value}`);
+  return value;
+}
+"#),
+    ("now_millis", r#"function now_millis() {
+  return BigInt(Date.now());
+}
+"#),
+    ("monotonic_nanos", r#"function monotonic_nanos() {
+  return BigInt(Math.floor(performance.now() * 1e6));
+}
+"#),
+];
+
+// names which cannot be used as they are
+const RESERVED: &[&str] = &[
+    "arguments", "await", "break", "case", "catch", "class", "const", "continue", "debugger", "default",
+    "delete", "do", "else", "enum", "eval", "export", "extends", "false", "finally", "for", "function",
+    "if", "implements", "import", "in", "instanceof", "interface", "let", "new", "null", "package",
+    "private", "protected", "public", "return", "static", "super", "switch", "this", "throw", "true",
+    "try", "typeof", "undefined", "var", "void", "while", "with", "yield",
+];
+
+pub type Unsupported = String;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Int64,
+    UInt64,
+    Bool,
+    Unit,
+    Null,
+}
+
+impl Kind {
+    fn of(ty: &Option<Type>) -> Result<Kind, Unsupported> {
+        match ty {
+            Some(Type::Int64) => Ok(Kind::Int64),
+            Some(Type::UInt64) => Ok(Kind::UInt64),
+            Some(Type::Bool) => Ok(Kind::Bool),
+            Some(Type::Unit) | None => Ok(Kind::Unit),
+            Some(ty) => Err(format!("type {:?}", ty)),
+        }
+    }
+}
+
+// JavaScript source of the program: the runtime, then the functions.
+// The program must be type checked. Run it by calling e.g. `main()`.
+pub fn transpile(program: &Program) -> Result<String, Unsupported> {
+    let mut functions = HashMap::new();
+    for f in &program.function {
+        functions.insert(f.name.clone(), (mangle(&f.name), Kind::of(&f.return_type)?));
+    }
+
+    let mut out = String::from("\"use strict\";\n");
+    out += RUNTIME;
+    for (name, code) in BUILTINS {
+        // a function of the program replaces the builtin
+        if !functions.contains_key(*name) {
+            out += code;
+        }
+    }
+    for f in &program.function {
+        let mut e = Emitter::new(&program.expression, &functions);
+        let mut parameter = vec![];
+        for (name, ty) in &f.parameter {
+            parameter.push(e.declare(name, Kind::of(&Some(ty.clone()))?));
+        }
+        e.indent = 1;
+        let ret = Kind::of(&f.return_type)? != Kind::Unit;
+        e.body(f.code, ret).map_err(|reason| format!("`{}`: {}", f.name, reason))?;
+        out += &format!("\nfunction {}({}) {{\n{}}}\n", functions[&f.name].0, parameter.join(", "), e.out);
+    }
+    Ok(out)
+}
+
+fn mangle(name: &str) -> String {
+    if RESERVED.contains(&name) {
+        format!("{}$", name)
+    } else {
+        name.to_string()
+    }
+}
+
+struct Emitter<'a> {
+    pool: &'a ExprPool,
+    functions: &'a HashMap<String, (String, Kind)>,
+    scopes: Vec<HashMap<String, (String, Kind)>>,
+    names: HashSet<String>, // declared in the function, a redeclaration gets a new name
+    indent: usize,
+    out: String,
+}
+
+impl<'a> Emitter<'a> {
+    fn new(pool: &'a ExprPool, functions: &'a HashMap<String, (String, Kind)>) -> Self {
+        Emitter { pool, functions, scopes: vec![HashMap::new()], names: HashSet::new(), indent: 0, out: String::new() }
+    }
+
+    fn get(&self, e: ExprRef) -> Result<&'a Expr, Unsupported> {
+        self.pool.get(e.0 as usize).ok_or(format!("invalid expression reference {:?}", e))
+    }
+
+    fn line(&mut self, line: &str) {
+        self.out += &"  ".repeat(self.indent);
+        self.out += line;
+        self.out += "\n";
+    }
+
+    fn declare(&mut self, name: &str, kind: Kind) -> String {
+        let mut js = mangle(name);
+        let mut n = 0;
+        while !self.names.insert(js.clone()) {
+            n += 1;
+            js = format!("{}${}", name, n);
+        }
+        self.scopes.last_mut().unwrap().insert(name.to_string(), (js.clone(), kind));
+        js
+    }
+
+    fn lookup(&self, name: &str) -> Result<(String, Kind), Unsupported> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name).cloned())
+            .ok_or(format!("undefined variable `{}`", name))
+    }
+
+    // Contents of a block (or a single expression) as statements
+    fn body(&mut self, e: ExprRef, ret: bool) -> Result<Kind, Unsupported> {
+        let expressions = match self.get(e)? {
+            Expr::Block(expressions) => expressions,
+            _ => return self.statement(e, ret),
+        };
+        self.scopes.push(HashMap::new());
+        let mut kind = Ok(Kind::Unit);
+        for (i, e) in expressions.iter().enumerate() {
+            kind = self.statement(*e, ret && i + 1 == expressions.len());
+            if kind.is_err() {
+                break;
+            }
+        }
+        self.scopes.pop();
+        kind
+    }
+
+    // Emit `e` as a statement, which returns the value of `e` if `ret`
+    fn statement(&mut self, e: ExprRef, ret: bool) -> Result<Kind, Unsupported> {
+        match self.get(e)? {
+            Expr::Block(_) => {
+                self.line("{");
+                self.indent += 1;
+                let kind = self.body(e, ret);
+                self.indent -= 1;
+                self.line("}");
+                kind
+            }
+            Expr::Val(name, _, Some(rhs)) | Expr::Var(name, _, Some(rhs)) => {
+                let keyword = if matches!(self.get(e)?, Expr::Val(..)) { "const" } else { "let" };
+                let (kind, value) = self.expr(*rhs)?;
+                let name = self.declare(name, kind);
+                self.line(&format!("{} {} = {};", keyword, name, value));
+                Ok(Kind::Unit)
+            }
+            Expr::Binary(Operator::Assign, lhs, rhs) => {
+                let name = match self.get(*lhs)? {
+                    Expr::Identifier(name) => self.lookup(name)?.0,
+                    x => return Err(format!("assignment to {:?}", x)),
+                };
+                let value = self.expr(*rhs)?.1;
+                self.line(&format!("{} = {};", name, value));
+                Ok(Kind::Unit)
+            }
+            Expr::IfElse(cond, then_block, else_block) => {
+                let cond = self.expr(*cond)?.1;
+                self.line(&format!("if ({}) {{", cond));
+                self.if_else(*then_block, *else_block, ret)
+            }
+            Expr::While(cond, body) => {
+                let cond = self.expr(*cond)?.1;
+                self.line(&format!("while ({}) {{", cond));
+                self.indent += 1;
+                self.body(*body, false)?;
+                self.indent -= 1;
+                self.line("}");
+                Ok(Kind::Unit)
+            }
+            Expr::For(name, start, end, body) => {
+                let (kind, start) = self.expr(*start)?;
+                let literal = matches!(self.get(*end)?, Expr::Int64(_) | Expr::UInt64(_));
+                let end = self.expr(*end)?.1;
+                self.scopes.push(HashMap::new());
+                let name = self.declare(name, kind);
+                if literal {
+                    self.line(&format!("for (let {} = {}; {} < {}; {}++) {{", name, start, name, end, name));
+                } else {
+                    // the end is evaluated once
+                    self.line(&format!("for (let {} = {}, $end = {}; {} < $end; {}++) {{", name, start, end, name, name));
+                }
+                self.indent += 1;
+                let result = self.body(*body, false);
+                self.indent -= 1;
+                self.scopes.pop();
+                result?;
+                self.line("}");
+                Ok(Kind::Unit)
+            }
+            _ => {
+                let (kind, value) = self.expr(e)?;
+                if ret && kind != Kind::Unit {
+                    self.line(&format!("return {};", value));
+                } else {
+                    self.line(&format!("{};", value));
+                }
+                Ok(kind)
+            }
+        }
+    }
+
+    // Branches after `if (cond) {`, `else if` is kept as it is
+    fn if_else(&mut self, then_block: ExprRef, else_block: ExprRef, ret: bool) -> Result<Kind, Unsupported> {
+        self.indent += 1;
+        let kind = self.body(then_block, ret)?;
+        self.indent -= 1;
+        match self.get(else_block)? {
+            Expr::Block(expressions) if expressions.is_empty() => (),
+            Expr::IfElse(cond, then_block, else_block) => {
+                let cond = self.expr(*cond)?.1;
+                self.line(&format!("}} else if ({}) {{", cond));
+                return self.if_else(*then_block, *else_block, ret).map(|_| kind);
+            }
+            _ => {
+                self.line("} else {");
+                self.indent += 1;
+                self.body(else_block, ret)?;
+                self.indent -= 1;
+            }
+        }
+        self.line("}");
+        Ok(kind)
+    }
+
+    // A simple expression, which needs no statement: a block of one such
+    // expression is the expression itself
+    fn simple(&self, e: ExprRef) -> Result<Option<ExprRef>, Unsupported> {
+        match self.get(e)? {
+            Expr::Block(expressions) if expressions.len() == 1 => self.simple(expressions[0]),
+            Expr::Block(_) | Expr::Val(..) | Expr::Var(..) | Expr::While(..) | Expr::For(..)
+            | Expr::Binary(Operator::Assign, _, _) => Ok(None),
+            Expr::IfElse(cond, then_block, else_block) => {
+                let simple = self.simple(*cond)?.is_some() && self.simple(*then_block)?.is_some()
+                    && self.simple(*else_block)?.is_some();
+                Ok(if simple { Some(e) } else { None })
+            }
+            _ => Ok(Some(e)),
+        }
+    }
+
+    // `e` as an operand of an operator, in parentheses unless it is a primary expression
+    fn operand(&mut self, e: ExprRef) -> Result<(Kind, String), Unsupported> {
+        let (kind, value) = self.expr(e)?;
+        let e = self.simple(e)?.unwrap_or(e);
+        match self.get(e)? {
+            // arithmetic is a call of the runtime
+            Expr::Binary(Operator::IAdd | Operator::ISub | Operator::IMul | Operator::IDiv, _, _) => Ok((kind, value)),
+            Expr::IfElse(..) | Expr::Binary(..) => Ok((kind, format!("({})", value))),
+            Expr::Int64(i) if *i < 0 => Ok((kind, format!("({})", value))),
+            _ => Ok((kind, value)),
+        }
+    }
+
+    fn expr(&mut self, e: ExprRef) -> Result<(Kind, String), Unsupported> {
+        let e = match self.simple(e)? {
+            Some(e) => e,
+            None => return self.function(e),
+        };
+        match self.get(e)? {
+            Expr::Int64(i) => Ok((Kind::Int64, format!("{}n", i))),
+            Expr::UInt64(u) => Ok((Kind::UInt64, format!("{}n", u))),
+            Expr::Null => Ok((Kind::Null, "null".to_string())),
+            Expr::Identifier(name) => {
+                let (name, kind) = self.lookup(name)?;
+                Ok((kind, name))
+            }
+            Expr::IfElse(cond, then_block, else_block) => {
+                let cond = self.operand(*cond)?.1;
+                let (kind, then_value) = self.operand(*then_block)?;
+                let else_value = self.operand(*else_block)?.1;
+                Ok((kind, format!("{} ? {} : {}", cond, then_value, else_value)))
+            }
+            Expr::Binary(op, lhs, rhs) => self.binary(op, *lhs, *rhs),
+            Expr::Call(name, args) => {
+                let args = match self.get(*args)? {
+                    Expr::Block(args) => args,
+                    x => return Err(format!("arguments of `{}` are {:?}", name, x)),
+                };
+                let mut kinds = vec![];
+                let mut values = vec![];
+                for arg in args {
+                    let (kind, value) = self.expr(*arg)?;
+                    kinds.push(kind);
+                    values.push(value);
+                }
+                let call = |name: &str| format!("{}({})", name, values.join(", "));
+                if let Some((js, kind)) = self.functions.get(name) {
+                    return Ok((*kind, call(js)));
+                }
+                match name.as_str() {
+                    // the result has the type of the arguments
+                    "pow" => Ok((kinds[0], format!("{}({})", range_check(kinds[0]), call(name)))),
+                    "abs" | "min" | "max" | "sqrt" | "clamp" | "random_range" | "dbg" => Ok((kinds[0], call(name))),
+                    "random_u64" | "now_millis" | "monotonic_nanos" => Ok((Kind::UInt64, call(name))),
+                    "assert" | "print" => Ok((Kind::Unit, call(name))),
+                    "to_i64" => Ok((Kind::Int64, call(name))),
+                    "to_u64" => Ok((Kind::UInt64, call(name))),
+                    _ => Err(format!("call of `{}`", name)),
+                }
+            }
+            Expr::Int(text) => Err(format!("unresolved integer literal {}", text)),
+            Expr::Spawn(_) => Err("spawn".to_string()),
+            Expr::Function(f) => Err(format!("nested function `{}`", f.name)),
+            // `simple` leaves the statements to `function`
+            Expr::Block(_) | Expr::Val(..) | Expr::Var(..) | Expr::While(..) | Expr::For(..) => Err("statement as a value".to_string()),
+        }
+    }
+
+    // Statements as a value: an arrow function called in place
+    fn function(&mut self, e: ExprRef) -> Result<(Kind, String), Unsupported> {
+        let (indent, out) = (self.indent, std::mem::take(&mut self.out));
+        self.indent += 1;
+        self.scopes.push(HashMap::new());
+        let kind = self.body(e, true);
+        self.scopes.pop();
+        self.indent = indent;
+        let body = std::mem::replace(&mut self.out, out);
+        Ok((kind?, format!("(() => {{\n{}{}}})()", body, "  ".repeat(indent))))
+    }
+
+    fn binary(&mut self, op: &Operator, lhs: ExprRef, rhs: ExprRef) -> Result<(Kind, String), Unsupported> {
+        let (kind, l) = self.operand(lhs)?;
+        let r = self.operand(rhs)?.1;
+        let (kind, value) = match op {
+            Operator::Assign => return Err("assignment as a value".to_string()),
+            Operator::LogicalAnd => (Kind::Bool, format!("{} && {}", l, r)),
+            Operator::LogicalOr => (Kind::Bool, format!("{} || {}", l, r)),
+            Operator::EQ => (Kind::Bool, format!("{} === {}", l, r)),
+            Operator::NE => (Kind::Bool, format!("{} !== {}", l, r)),
+            Operator::LT => (Kind::Bool, format!("{} < {}", l, r)),
+            Operator::LE => (Kind::Bool, format!("{} <= {}", l, r)),
+            Operator::GT => (Kind::Bool, format!("{} > {}", l, r)),
+            Operator::GE => (Kind::Bool, format!("{} >= {}", l, r)),
+            // only i64::MIN / -1 overflows
+            Operator::IDiv if kind == Kind::UInt64 => (kind, format!("$div({}, {})", l, r)),
+            Operator::IDiv => (kind, format!("$i64($div({}, {}))", l, r)),
+            Operator::IAdd => (kind, format!("{}({} + {})", range_check(kind), l, r)),
+            Operator::ISub => (kind, format!("{}({} - {})", range_check(kind), l, r)),
+            Operator::IMul => (kind, format!("{}({} * {})", range_check(kind), l, r)),
+        };
+        Ok((kind, value))
+    }
+}
+
+fn range_check(kind: Kind) -> &'static str {
+    if kind == Kind::UInt64 { "$u64" } else { "$i64" }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn functions(code: &str) -> String {
+        let program = frontend::Parser::new(code).parse_program().unwrap();
+        let js = transpile(&program).unwrap();
+        js[js.find("\nfunction fib").unwrap()..].to_string()
+    }
+
+    #[test]
+    fn transpile_functions() {
+        let code = r#"
+fn fib(n: u64) -> u64 {
+    if n < 2u64 { n } else { fib(n - 1u64) + fib(n - 2u64) }
+}
+fn sum(n: i64) -> i64 {
+    var total = 0i64
+    for i in 0i64..n {
+        if i == 3i64 || i == 5i64 { total = total + i * 2i64 } else if i > 7i64 { total = total / 2i64 }
+    }
+    val delete = total
+    val x = (if total > 100i64 { val t = total - 100i64
+        t } else { total })
+    x
+}
+fn main() -> u64 {
+    fib(pow(2u64, 3u64))
+}
+"#;
+        assert_eq!(r#"
+function fib(n) {
+  if (n < 2n) {
+    return n;
+  } else {
+    return $u64(fib($u64(n - 1n)) + fib($u64(n - 2n)));
+  }
+}
+
+function sum(n) {
+  let total = 0n;
+  for (let i = 0n, $end = n; i < $end; i++) {
+    if ((i === 3n) || (i === 5n)) {
+      total = $i64(total + $i64(i * 2n));
+    } else if (i > 7n) {
+      total = $i64($div(total, 2n));
+    }
+  }
+  const delete$ = total;
+  const x = (() => {
+    if (total > 100n) {
+      const t = $i64(total - 100n);
+      return t;
+    } else {
+      return total;
+    }
+  })();
+  return x;
+}
+
+function main() {
+  return fib($u64(pow(2n, 3n)));
+}
+"#, functions(code));
+    }
+
+    #[test]
+    fn runtime_for_builtins() {
+        let program = frontend::Parser::new("fn abs(x: i64) -> i64 {\nx\n}\nfn main() -> bool {\nval a = 1\nval a = 2\na < 3 && a > 0\n}").parse_program();
+        let js = transpile(&program.unwrap()).unwrap();
+        assert!(js.starts_with("\"use strict\";\nconst $I64_MIN"));
+        assert!(js.contains("function sqrt(x)") && js.contains("function print(value)") && js.contains("function to_u64(x)"));
+        // replaced by the function of the program
+        assert_eq!(1, js.matches("function abs(").count());
+        assert!(js.contains("  const a = 1n;\n  const a$1 = 2n;\n  return (a$1 < 3n) && (a$1 > 0n);\n"));
+    }
+}
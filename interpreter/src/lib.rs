@@ -1 +1,26 @@
+pub mod builtin;
+pub mod cancel;
+pub mod channel;
+pub mod cli;
+pub mod consteval;
+pub mod clock;
+pub mod coverage;
+pub mod debugger;
+pub mod engine;
+pub mod environment;
+pub mod error;
+#[cfg(feature = "jit")]
+pub mod jit;
+pub mod js;
+pub mod object;
+pub mod observer;
+pub mod overflow;
+pub mod policy;
 pub mod processor;
+pub mod profiler;
+pub mod project;
+pub mod rust;
+pub mod task;
+pub mod test_runner;
+pub mod testing;
+pub mod timings;
@@ -1,27 +1,295 @@
-#![feature(box_patterns)]
-
-mod processor;
-
 use std::io;
-use frontend;
-use frontend::ast::*;
-use processor::*;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use frontend::type_checker::TypeCheckContext;
+use frontend::ast::{Expr, Program};
+use frontend::doc::DocFormat;
+use interpreter::cli::{self, Command, Failure, Phase};
+use interpreter::error::InterpreterError;
+use interpreter::object::Object;
+use interpreter::processor::*;
+use interpreter::timings::{self, Timings};
 
+// Usage (see `interpreter::cli` for the commands):
+//   interpreter [repl]
+//   interpreter run [--profile] [--coverage] [--watch] [--jit] [--timings] file   run `main` of the file
+//   interpreter run --emit-js file                        print the program as JavaScript
+//   interpreter run --emit-rust file                      print the program as Rust
+//   interpreter check [--timings] file
+//   interpreter ast file
+//   interpreter fmt [--check] file
+//   interpreter doc [--html] file
+//   interpreter test [--timings] file                     run the `test_*` and `#[test]` functions
+//   interpreter --explain code                            describe an error code
+// --jit runs the supported functions as native code (needs the `jit` feature)
+// --timings prints the time spent in each phase to stderr
+// --cfg=feature enables a feature for `#[cfg]`, with any command
 fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args = cli::parse_args(&args).unwrap_or_else(|e| usage(e));
+    let mut option = RunOption { features: args.features.clone(), ..RunOption::default() };
+    let mut watch = false;
+    let mut check = false;
+    let mut doc_format = DocFormat::Markdown;
+    let mut timings = None;
+    for arg in &args.options {
+        match (arg.as_str(), args.command) {
+            ("--timings", Command::Run | Command::Check | Command::Test) => timings = Some(Timings::new()),
+            ("--check", Command::Fmt) => check = true,
+            ("--html", Command::Doc) => doc_format = DocFormat::Html,
+            ("--profile", Command::Run) => option.profile = true,
+            ("--coverage", Command::Run) => option.coverage = true,
+            ("--watch", Command::Run) => watch = true,
+            ("--jit", Command::Run) => option.jit = true,
+            ("--emit-js", Command::Run) => option.emit_js = true,
+            ("--emit-rust", Command::Run) => option.emit_rust = true,
+            _ => usage(Failure::new(Phase::Usage, format!("unknown option {}", arg))),
+        }
+    }
+    if let Some(timings) = &timings {
+        timings.install().unwrap_or_else(|e| eprintln!("--timings is not available: {}", e));
+    }
+    let file = args.file.unwrap_or_default();
+    let result = match args.command {
+        Command::Repl => {
+            repl();
+            Ok(Object::Unit)
+        }
+        Command::Run if watch && file == "-" => usage(Failure::new(Phase::Usage, "--watch needs a file")),
+        Command::Run if watch => watch_file(&file, &option),
+        Command::Run => run_file(&file, &option),
+        Command::Check => load(&file, &option.features).map(|_| Object::Unit),
+        Command::Ast => load(&file, &option.features).map(|(_, program)| {
+            print!("{}", cli::ast(&program));
+            Object::Unit
+        }),
+        // only `--check` tells by the exit status whether the file was formatted
+        Command::Fmt => cli::fmt(&file, check).map(|formatted| if check { Object::Bool(formatted) } else { Object::Unit }),
+        Command::Explain => cli::explain(&file).map(|text| {
+            print!("{}", text);
+            Object::Unit
+        }),
+        Command::Doc => {
+            let mut ctx = TypeCheckContext::new();
+            Processor::new().declare_native(&mut ctx);
+            cli::doc(&file, doc_format, &mut ctx).map(|doc| {
+                print!("{}", doc);
+                Object::Unit
+            })
+        }
+        // exits with 1 if a test failed
+        Command::Test => test_file(&file, &option.features),
+        Command::Disasm => Err(Failure::new(Phase::Usage, "disasm is available in bytecodeinterpreter")),
+    };
+    if let Some(timings) = &timings {
+        eprint!("{}", timings);
+    }
+    match result {
+        // the result of `main` is the exit status of the process
+        Ok(result) => std::process::exit(result.to_exit_code()),
+        Err(e) => e.exit(),
+    }
+}
+
+fn usage(failure: Failure) -> ! {
+    eprintln!("usage: interpreter [repl | run [--profile] [--coverage] [--watch] [--jit] [--emit-js] [--emit-rust] [--timings] file | check [--timings] file | ast file | fmt [--check] file | doc [--html] file | test [--timings] file | --explain code]");
+    failure.exit()
+}
+
+#[derive(Default)]
+struct RunOption {
+    profile: bool,
+    coverage: bool,
+    jit: bool,
+    emit_js: bool,
+    emit_rust: bool,
+    features: Vec<String>, // of `--cfg`
+}
+
+// Source and program of the file or project, type checked with the builtins
+fn load(file: &str, features: &[String]) -> Result<(String, Program), Failure> {
+    let mut ctx = TypeCheckContext::new();
+    for feature in features {
+        ctx.set_feature(feature);
+    }
+    Processor::new().declare_native(&mut ctx);
+    cli::load(file, &mut ctx)
+}
+
+fn run_file(file: &str, option: &RunOption) -> Result<Object, Failure> {
+    let (source, program) = load(file, &option.features)?;
+    if option.emit_js {
+        let js = interpreter::js::transpile(&program).map_err(|e| Failure::new(Phase::Compile, format!("emit_js failed {}", e)))?;
+        print!("{}", js);
+        return Ok(Object::Unit);
+    }
+    if option.emit_rust {
+        let rust = interpreter::rust::transpile(&program).map_err(|e| Failure::new(Phase::Compile, format!("emit_rust failed {}", e)))?;
+        print!("{}", rust);
+        return Ok(Object::Unit);
+    }
+
+    let program = Arc::new(program);
     let mut p = Processor::new();
+    p.set_source(file, &source);
+    if option.profile {
+        p.enable_profiling();
+    }
+    if option.coverage {
+        p.enable_coverage();
+    }
+    let result = {
+        let _span = tracing::info_span!(timings::EXECUTE).entered();
+        if option.jit {
+            execute_jit(&mut p, &program)?
+        } else {
+            p.execute_shared(program.clone())
+        }
+    };
+    if let Some(profiler) = p.profiler() {
+        eprint!("{}", profiler);
+    }
+    if let Some(coverage) = p.coverage() {
+        eprint!("{}", coverage.report(&program, &source));
+    }
+    match result {
+        Ok(result) => {
+            println!("Result: {}", result);
+            Ok(result)
+        }
+        Err(e) => Err(Failure::new(Phase::Run, format!("execute_program failed {}", e))),
+    }
+}
+
+fn test_file(file: &str, features: &[String]) -> Result<Object, Failure> {
+    let (source, program) = load(file, features)?;
+    let mut p = Processor::new();
+    p.set_source(file, &source);
+    let report = tracing::info_span!(timings::EXECUTE).in_scope(|| interpreter::test_runner::run_tests(&mut p, &program, &source));
+    print!("{}", report);
+    Ok(Object::Bool(report.success()))
+}
+
+#[cfg(feature = "jit")]
+fn execute_jit(p: &mut Processor, program: &Program) -> Result<Result<Object, InterpreterError>, Failure> {
+    let report = interpreter::jit::load(p, program);
+    for (name, reason) in &report.skipped {
+        eprintln!("[jit] `{}` is interpreted: {}", name, reason);
+    }
+    p.refuel();
+    Ok(p.evaluate_function(&program.expression, "main", &[]))
+}
+
+#[cfg(not(feature = "jit"))]
+fn execute_jit(_p: &mut Processor, _program: &Program) -> Result<Result<Object, InterpreterError>, Failure> {
+    Err(Failure::new(Phase::Usage, "--jit needs the `jit` feature (cargo build --features jit)"))
+}
+
+fn modified(file: &str) -> Option<SystemTime> {
+    std::fs::metadata(file).and_then(|m| m.modified()).ok()
+}
+
+// Run the file, then run it again each time it is saved.
+// The modification time is polled, so no platform watcher is needed.
+fn watch_file(file: &str, option: &RunOption) -> ! {
+    let mut last = None;
+    loop {
+        let current = modified(file);
+        if current.is_some() && current != last {
+            last = current;
+            println!("[watch] running {}", file);
+            if let Err(e) = run_file(file, option) {
+                eprintln!("{}", e);
+            }
+            println!("[watch] waiting for changes");
+        }
+        std::thread::sleep(Duration::from_millis(300));
+    }
+}
+
+fn repl() {
+    let mut p = Processor::new();
+    let mut ctx = TypeCheckContext::new();
+    p.declare_native(&mut ctx);
     loop {
         println!("Input toylang expression:");
         let mut line = String::new();
         io::stdin().read_line(&mut line).expect("Failed to read line `read_line`");
+        if let Some(command) = line.trim().strip_prefix(':') {
+            meta_command(command, &p, &ctx);
+            continue;
+        }
 
         let mut parser = frontend::Parser::new(line.as_str());
-        let expr = parser.parse_expr();
-        if expr.is_err() {
-            println!("parser_expr failed {}", expr.unwrap_err());
-            return;
+        let (expr, mut pool) = match parser.parse_expression() {
+            Ok(expr) => expr,
+            Err(e) => {
+                println!("parse_expression failed {}", e);
+                return;
+            }
+        };
+        // `fn` replaces the function of the same name, the variables are kept
+        if let Some(Expr::Function(f)) = pool.get(expr.0 as usize).cloned() {
+            match ctx.redefine_function(&mut pool, parser.location(), expr) {
+                Ok(_) => {
+                    p.replace_function(&pool, &f);
+                    println!("defined fn {}", f.name);
+                }
+                Err(e) => println!("type check failed {}", e),
+            }
+            continue;
+        }
+        if let Err(e) = ctx.check_expression(&mut pool, parser.location(), expr) {
+            println!("type check failed {}", e);
+            continue;
+        }
+        println!("print AST: {:?}", pool.get(expr.0 as usize).unwrap());
+        p.set_location(parser.location().clone());
+        p.set_source("<repl>", &line);
+        match p.evaluate(&pool, expr) {
+            Ok(result) => println!("Evaluate expression: {}", result),
+            Err(e) => println!("evaluate failed {}", e),
+        }
+    }
+}
+
+// REPL commands starting with `:`. They inspect the state and don't evaluate.
+//   :type expr  type of the expression
+//   :ast expr   tree of the expression
+//   :env        variables defined so far
+fn meta_command(command: &str, p: &Processor, ctx: &TypeCheckContext) {
+    let (name, arg) = command.split_once(' ').unwrap_or((command, ""));
+    match name {
+        "type" | "ast" => {
+            let mut parser = frontend::Parser::new(arg);
+            let (expr, mut pool) = match parser.parse_expression() {
+                Ok(expr) => expr,
+                Err(e) => {
+                    println!("parse_expression failed {}", e);
+                    return;
+                }
+            };
+            if name == "ast" {
+                print!("{}", frontend::ast::dump(&pool, expr));
+                return;
+            }
+            // a `val` or `var` in the expression must not be defined by `:type`
+            match ctx.clone().check_expression(&mut pool, parser.location(), expr) {
+                Ok(ty) => println!("{:?}", ty),
+                Err(e) => println!("type check failed {}", e),
+            }
+        }
+        "env" => {
+            let mut bindings: Vec<(String, Object)> = p.bindings().into_iter().collect();
+            bindings.sort_by(|a, b| a.0.cmp(&b.0));
+            for (name, value) in bindings {
+                match ctx.get_var(&name) {
+                    Some(var) => println!("{} {}: {:?} = {}", if var.mutable { "var" } else { "val" }, name, var.ty, value),
+                    None => println!("{} = {}", name, value),
+                }
+            }
         }
-        println!("print AST: {:?}", expr.as_ref().unwrap());
-        let expr = expr.unwrap();
-        println!("Evaluate expression: {:?}", p.evaluate(&expr));
+        "disasm" => println!(":disasm is available in the bytecodeinterpreter REPL"),
+        _ => println!("unknown command :{} (available: :type, :ast, :env)", name),
     }
-}
\ No newline at end of file
+}
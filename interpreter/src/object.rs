@@ -0,0 +1,135 @@
+use std::fmt;
+use frontend::ast::Type;
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum Object {
+    Bool(bool),
+    Int64(i64),
+    UInt64(u64),
+    Null,
+    Unit,
+    Task(u64), // handle of a spawned function, see `crate::task`
+    Channel(u64), // see `crate::channel`
+}
+
+impl Object {
+    pub fn ty(&self) -> Type {
+        match self {
+            Object::Bool(_) => Type::Bool,
+            Object::Int64(_) => Type::Int64,
+            Object::UInt64(_) => Type::UInt64,
+            Object::Null => Type::Unknown,
+            Object::Unit => Type::Unit,
+            Object::Task(_) => Type::Task(Box::new(Type::Unknown)),
+            Object::Channel(_) => Type::Channel(Box::new(Type::Unknown)),
+        }
+    }
+
+    // Process exit code for the result of `main`:
+    // integers are taken modulo 256 as a shell does (-1 is 255),
+    // true and unit are success (0), false is failure (1)
+    pub fn to_exit_code(&self) -> i32 {
+        match self {
+            Object::Int64(i) => (*i & 0xff) as i32,
+            Object::UInt64(u) => (*u & 0xff) as i32,
+            Object::Bool(true) | Object::Unit | Object::Null | Object::Task(_) | Object::Channel(_) => 0,
+            Object::Bool(false) => 1,
+        }
+    }
+
+    // Unit, tasks and channels have no JSON counterpart and are written as null
+    pub fn to_json(&self) -> String {
+        match self {
+            Object::Bool(b) => b.to_string(),
+            Object::Int64(i) => i.to_string(),
+            Object::UInt64(u) => u.to_string(),
+            Object::Null | Object::Unit | Object::Task(_) | Object::Channel(_) => "null".to_string(),
+        }
+    }
+
+    // JSON numbers don't carry the width, so the expected type decides it
+    pub fn from_json(json: &str, ty: &Type) -> Result<Object, String> {
+        let json = json.trim();
+        if json == "null" {
+            return match ty {
+                Type::Unit => Ok(Object::Unit),
+                _ => Ok(Object::Null),
+            };
+        }
+        let object = match ty {
+            Type::Bool => json.parse::<bool>().ok().map(Object::Bool),
+            Type::Int64 => json.parse::<i64>().ok().map(Object::Int64),
+            Type::UInt64 => json.parse::<u64>().ok().map(Object::UInt64),
+            _ => None,
+        };
+        object.ok_or_else(|| format!("cannot read `{}` as {:?}", json, ty))
+    }
+}
+
+impl From<bool> for Object {
+    fn from(b: bool) -> Self {
+        Object::Bool(b)
+    }
+}
+
+impl From<i64> for Object {
+    fn from(i: i64) -> Self {
+        Object::Int64(i)
+    }
+}
+
+impl From<u64> for Object {
+    fn from(u: u64) -> Self {
+        Object::UInt64(u)
+    }
+}
+
+// User-facing form of the value, as written in toylang source
+// but without the type suffix of literals
+impl fmt::Display for Object {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Object::Bool(b) => write!(f, "{}", b),
+            Object::Int64(i) => write!(f, "{}", i),
+            Object::UInt64(u) => write!(f, "{}", u),
+            Object::Null => write!(f, "null"),
+            Object::Unit => write!(f, "()"),
+            Object::Task(id) => write!(f, "task#{}", id),
+            Object::Channel(id) => write!(f, "channel#{}", id),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display_object() {
+        assert_eq!("true", Object::Bool(true).to_string());
+        assert_eq!("-3", Object::Int64(-3).to_string());
+        assert_eq!("18446744073709551615", Object::UInt64(u64::MAX).to_string());
+        assert_eq!("null", Object::Null.to_string());
+        assert_eq!("()", Object::Unit.to_string());
+    }
+
+    #[test]
+    fn exit_code_object() {
+        assert_eq!(3, Object::UInt64(3).to_exit_code());
+        assert_eq!(0, Object::UInt64(256).to_exit_code());
+        assert_eq!(255, Object::Int64(-1).to_exit_code());
+        assert_eq!(0, Object::Unit.to_exit_code());
+        assert_eq!(1, Object::Bool(false).to_exit_code());
+    }
+
+    #[test]
+    fn json_object() {
+        assert_eq!("-3", Object::Int64(-3).to_json());
+        assert_eq!("null", Object::Unit.to_json());
+        assert_eq!(Ok(Object::UInt64(42)), Object::from_json(" 42\n", &Type::UInt64));
+        assert_eq!(Ok(Object::Bool(false)), Object::from_json("false", &Type::Bool));
+        assert_eq!(Ok(Object::Null), Object::from_json("null", &Type::Int64));
+        assert!(Object::from_json("-1", &Type::UInt64).is_err());
+        assert!(Object::from_json("1.5", &Type::Int64).is_err());
+    }
+}
@@ -0,0 +1,18 @@
+use crate::object::Object;
+
+// Evaluation events for loggers, visualizers and similar tools.
+// Every method has an empty default, so an observer implements only
+// the events it needs. Register with `Processor::add_observer`.
+pub trait EvalObserver {
+    // before the body of a toylang or native function is evaluated
+    fn on_call(&mut self, _name: &str, _args: &[Object]) {}
+
+    // after a function returned successfully
+    fn on_return(&mut self, _name: &str, _result: &Object) {}
+
+    // a variable got a value by `val`, `var` or assignment
+    fn on_assign(&mut self, _name: &str, _value: &Object) {}
+
+    // before each run of the body of `while` or `for`, counted from 0 per loop
+    fn on_loop_iteration(&mut self, _iteration: u64) {}
+}
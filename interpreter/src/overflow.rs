@@ -0,0 +1,94 @@
+// Behavior of `+ - * /` when the result does not fit in the integer type
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum OverflowMode {
+    // two's complement wrap around (i64::MAX + 1 == i64::MIN)
+    Wrap,
+    // clamp to the minimum or maximum of the type
+    Saturate,
+    // stop with an overflow error
+    #[default]
+    Trap,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ArithOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+pub trait Integer: Copy {
+    fn checked(op: ArithOp, l: Self, r: Self) -> Option<Self>;
+    fn wrapping(op: ArithOp, l: Self, r: Self) -> Self;
+    fn saturating(op: ArithOp, l: Self, r: Self) -> Self;
+}
+
+macro_rules! impl_integer {
+    ($t:ty) => {
+        impl Integer for $t {
+            fn checked(op: ArithOp, l: Self, r: Self) -> Option<Self> {
+                match op {
+                    ArithOp::Add => l.checked_add(r),
+                    ArithOp::Sub => l.checked_sub(r),
+                    ArithOp::Mul => l.checked_mul(r),
+                    ArithOp::Div => l.checked_div(r),
+                }
+            }
+
+            fn wrapping(op: ArithOp, l: Self, r: Self) -> Self {
+                match op {
+                    ArithOp::Add => l.wrapping_add(r),
+                    ArithOp::Sub => l.wrapping_sub(r),
+                    ArithOp::Mul => l.wrapping_mul(r),
+                    ArithOp::Div => l.wrapping_div(r),
+                }
+            }
+
+            fn saturating(op: ArithOp, l: Self, r: Self) -> Self {
+                match op {
+                    ArithOp::Add => l.saturating_add(r),
+                    ArithOp::Sub => l.saturating_sub(r),
+                    ArithOp::Mul => l.saturating_mul(r),
+                    ArithOp::Div => l.saturating_div(r),
+                }
+            }
+        }
+    };
+}
+
+impl_integer!(i64);
+impl_integer!(u64);
+
+// None means overflow in `Trap` mode. The divisor must not be zero.
+pub fn apply<T: Integer>(mode: OverflowMode, op: ArithOp, l: T, r: T) -> Option<T> {
+    match mode {
+        OverflowMode::Wrap => Some(T::wrapping(op, l, r)),
+        OverflowMode::Saturate => Some(T::saturating(op, l, r)),
+        OverflowMode::Trap => T::checked(op, l, r),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overflow_mode() {
+        assert_eq!(Some(i64::MIN), apply(OverflowMode::Wrap, ArithOp::Add, i64::MAX, 1));
+        assert_eq!(Some(i64::MAX), apply(OverflowMode::Saturate, ArithOp::Add, i64::MAX, 1));
+        assert_eq!(None, apply(OverflowMode::Trap, ArithOp::Add, i64::MAX, 1));
+
+        assert_eq!(Some(u64::MAX), apply(OverflowMode::Wrap, ArithOp::Sub, 0u64, 1));
+        assert_eq!(Some(0), apply(OverflowMode::Saturate, ArithOp::Sub, 0u64, 1));
+        assert_eq!(None, apply(OverflowMode::Trap, ArithOp::Sub, 0u64, 1));
+
+        assert_eq!(Some(0), apply(OverflowMode::Wrap, ArithOp::Mul, 1u64 << 32, 1 << 32));
+        assert_eq!(Some(u64::MAX), apply(OverflowMode::Saturate, ArithOp::Mul, 1u64 << 32, 1 << 32));
+
+        assert_eq!(Some(i64::MIN), apply(OverflowMode::Wrap, ArithOp::Div, i64::MIN, -1));
+        assert_eq!(Some(i64::MAX), apply(OverflowMode::Saturate, ArithOp::Div, i64::MIN, -1));
+        assert_eq!(None, apply(OverflowMode::Trap, ArithOp::Div, i64::MIN, -1));
+        assert_eq!(Some(-3), apply(OverflowMode::Trap, ArithOp::Div, -7i64, 2));
+    }
+}
@@ -0,0 +1,62 @@
+// Tasks which a sandboxed script can run at once, each on an OS thread
+pub const DEFAULT_MAX_TASKS: usize = 16;
+
+// What a script is allowed to do. Builtins touching the outside world
+// check their capability with `Processor::require` before running.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ExecutionPolicy {
+    pub allow_io: bool,
+    pub allow_env: bool,
+    pub allow_time: bool,
+    // evaluation steps per `execute_program`, unlimited if None. The
+    // spawned tasks use up the same fuel.
+    pub fuel: Option<u64>,
+    // tasks of `spawn` running at once, unlimited if None
+    pub max_tasks: Option<usize>,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Capability {
+    Io,
+    Env,
+    Time,
+}
+
+impl ExecutionPolicy {
+    // Everything is allowed (the default for the CLI)
+    pub fn permissive() -> Self {
+        ExecutionPolicy {
+            allow_io: true,
+            allow_env: true,
+            allow_time: true,
+            fuel: None,
+            max_tasks: None,
+        }
+    }
+
+    // Nothing outside of the script is reachable. Set `fuel` as well
+    // when the script is not trusted to terminate.
+    pub fn sandboxed() -> Self {
+        ExecutionPolicy {
+            allow_io: false,
+            allow_env: false,
+            allow_time: false,
+            fuel: None,
+            max_tasks: Some(DEFAULT_MAX_TASKS),
+        }
+    }
+
+    pub fn allows(&self, capability: Capability) -> bool {
+        match capability {
+            Capability::Io => self.allow_io,
+            Capability::Env => self.allow_env,
+            Capability::Time => self.allow_time,
+        }
+    }
+}
+
+impl Default for ExecutionPolicy {
+    fn default() -> Self {
+        Self::permissive()
+    }
+}
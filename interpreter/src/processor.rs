@@ -1,67 +1,1489 @@
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
-use frontend;
+use std::io::Write;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 use frontend::ast::*;
+use frontend::line::LineIndex;
+use frontend::prelude::Prelude;
+use frontend::type_checker::{FunctionSignature, TypeCheckContext};
+use crate::builtin;
+use crate::cancel::CancellationToken;
+use crate::channel::Channels;
+use crate::clock::{Clock, SystemClock};
+use crate::coverage::Coverage;
+use crate::debugger::{DebugSession, Debugger};
+use crate::environment::Environment;
+use crate::error::InterpreterError;
+use crate::object::Object;
+use crate::observer::EvalObserver;
+use crate::overflow::{self, ArithOp, Integer, OverflowMode};
+use crate::policy::{Capability, ExecutionPolicy};
+use crate::profiler::Profiler;
+use crate::task::Tasks;
 
-pub struct Processor {
-    environment: Environment,
+// The cancellation token is checked once per this number of steps
+const CANCELLATION_CHECK_INTERVAL: u64 = 1024;
+
+// Nesting of expressions being evaluated allowed by default, a call of a
+// small function is a few levels. The stack of the thread doesn't limit
+// it: when less than STACK_RED_ZONE is left, evaluation continues on a
+// new STACK_SEGMENT allocated on the heap.
+pub const DEFAULT_MAX_DEPTH: usize = 50_000;
+const STACK_RED_ZONE: usize = 64 * 1024;
+const STACK_SEGMENT: usize = 1024 * 1024;
+
+// Function implemented by the host application.
+// Arguments are already evaluated and checked against the signature.
+pub type NativeFunction = Box<dyn Fn(&[Object]) -> Result<Object, String>>;
+
+// Native code which runs instead of a function of the program (e.g. by the
+// JIT). Arguments are not checked, the function must do it.
+pub type CompiledFunction = Rc<dyn Fn(&[Object]) -> Result<Object, InterpreterError>>;
+
+pub struct Native {
+    pub signature: FunctionSignature,
+    pub function: NativeFunction,
+    // checked against the execution policy before each call
+    pub capability: Option<Capability>,
 }
 
-pub struct Environment {
-    pub context: HashMap<String, i64>,  // TODO: type of value
-    // TODO: nested scope
+// Source of the locations, for the messages of `dbg`
+struct Source {
+    name: String,
+    text: String,
+    lines: LineIndex,
 }
 
-impl Environment {
-    pub fn new() -> Self {
-        Environment {
-            context: HashMap::new(),
-        }
+// Function `index` of a program, which is the loaded program or one made
+// for a replaced function. Its body is evaluated in the pool of that
+// program whoever calls it.
+#[derive(Clone)]
+struct Defined {
+    program: Arc<Program>,
+    index: usize,
+}
+
+impl Defined {
+    fn function(&self) -> &Function {
+        &self.program.function[self.index]
     }
 }
+
+// Saved global bindings of a processor, see `Processor::snapshot`
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    environment: Environment,
+}
+
+pub struct Processor {
+    environment: Environment,
+    function: HashMap<String, Defined>,
+    local: Vec<Arc<Function>>, // defined in the blocks being evaluated, innermost last
+    native: HashMap<String, Native>,
+    compiled: HashMap<String, CompiledFunction>,
+    memo: HashMap<(String, Vec<Object>), Object>, // results of the `#[memoize]` functions
+    fuel: Option<Arc<AtomicU64>>, // remaining evaluation steps, unlimited if None, shared with the tasks
+    overflow: OverflowMode,
+    cancellation: Rc<RefCell<Option<CancellationToken>>>, // shared with `recv`, which waits for it
+    steps: u64,
+    depth: usize, // of the expressions being evaluated
+    max_depth: usize,
+    policy: ExecutionPolicy,
+    debug: Option<DebugSession>,
+    profiler: Option<Profiler>,
+    coverage: Option<Coverage>,
+    observers: Vec<Box<dyn EvalObserver>>,
+    located: Arc<Program>, // whose locations of the expressions are used for errors
+    random: Rc<Cell<u64>>, // state of the random builtins
+    clock: Rc<RefCell<Box<dyn Clock>>>,
+    tasks: Rc<RefCell<Tasks>>, // spawned and not joined yet
+    live_tasks: Arc<AtomicUsize>, // running tasks of the run, also those spawned by tasks
+    channels: Arc<Mutex<Channels>>, // shared with the spawned tasks
+    source: Option<Arc<Source>>,
+    dbg_output: Box<dyn Write>,
+    prelude: Prelude, // of the native functions, given to the spawned tasks
+}
+
 impl Processor {
+    // Builtins are registered as native functions.
+    // Functions of the program with the same name take precedence.
     pub fn new() -> Self {
-        Processor {
+        let mut p = Processor {
             environment: Environment::new(),
+            function: HashMap::new(),
+            local: vec![],
+            native: HashMap::new(),
+            compiled: HashMap::new(),
+            memo: HashMap::new(),
+            fuel: None,
+            overflow: OverflowMode::default(),
+            cancellation: Rc::new(RefCell::new(None)),
+            steps: 0,
+            depth: 0,
+            max_depth: DEFAULT_MAX_DEPTH,
+            policy: ExecutionPolicy::default(),
+            debug: None,
+            profiler: None,
+            coverage: None,
+            observers: vec![],
+            located: Arc::new(Self::locations(LocationPool::new())),
+            random: Rc::new(Cell::new(Self::initial_seed())),
+            clock: Rc::new(RefCell::new(Box::new(SystemClock::new()))),
+            tasks: Rc::new(RefCell::new(Tasks::new())),
+            live_tasks: Arc::new(AtomicUsize::new(0)),
+            channels: Arc::new(Mutex::new(Channels::new())),
+            source: None,
+            dbg_output: Box::new(std::io::stderr()),
+            prelude: Prelude::new(),
+        };
+        builtin::register_math(&mut p);
+        let random = p.random.clone();
+        builtin::register_random(&mut p, random);
+        let clock = p.clock.clone();
+        builtin::register_time(&mut p, clock);
+        builtin::register_prelude(&mut p);
+        let tasks = p.tasks.clone();
+        builtin::register_tasks(&mut p, tasks);
+        let (channels, cancellation) = (p.channels.clone(), p.cancellation.clone());
+        builtin::register_channels(&mut p, channels, cancellation);
+        p
+    }
+
+    // Expose a Rust function to toylang scripts as `name`.
+    // The signature is used by `declare_native` for the type checker.
+    // A spawned task has only the builtins (see `spawn`), so its call of a
+    // function of the embedder fails with `InterpreterError::UndefinedFunction`.
+    pub fn register_native<F>(&mut self, name: &str, signature: FunctionSignature, function: F)
+    where
+        F: Fn(&[Object]) -> Result<Object, String> + 'static,
+    {
+        self.native.insert(name.to_string(), Native { signature, function: Box::new(function), capability: None });
+    }
+
+    // Same as `register_native`, but the call fails with
+    // `InterpreterError::PermissionDenied` unless the policy allows `capability`
+    pub fn register_native_requiring<F>(&mut self, name: &str, capability: Capability, signature: FunctionSignature, function: F)
+    where
+        F: Fn(&[Object]) -> Result<Object, String> + 'static,
+    {
+        self.register_native(name, signature, function);
+        if let Some(native) = self.native.get_mut(name) {
+            native.capability = Some(capability);
+        }
+    }
+
+    // Unregister the native functions of the standard prelude which are not
+    // in `prelude`, e.g. all of them for `Prelude::empty()`. A function
+    // added to the prelude is implemented with `register_native`.
+    pub fn set_prelude(&mut self, prelude: &Prelude) {
+        for (name, _) in Prelude::new().functions() {
+            if !prelude.contains(name) {
+                self.native.remove(name);
+            }
+        }
+        self.prelude = prelude.clone();
+    }
+
+    // Declare the signatures of the registered native functions to the type checker
+    pub fn declare_native(&self, ctx: &mut TypeCheckContext) {
+        for (name, native) in &self.native {
+            ctx.set_fn(name, native.signature.clone());
+        }
+    }
+
+    // Limit the number of evaluation steps (one per expression) so that
+    // untrusted scripts cannot run forever. `None` removes the limit.
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel.map(|fuel| Arc::new(AtomicU64::new(fuel)));
+    }
+
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel.as_ref().map(|fuel| fuel.load(Ordering::Relaxed))
+    }
+
+    // Deeper evaluation fails with `InterpreterError::RecursionLimit`
+    // instead of using up the memory, e.g. by a recursion without end
+    pub fn set_max_depth(&mut self, depth: usize) {
+        self.max_depth = depth;
+    }
+
+    // How `+ - * /` behave when the result does not fit in i64/u64
+    pub fn set_overflow_mode(&mut self, mode: OverflowMode) {
+        self.overflow = mode;
+    }
+
+    pub fn overflow_mode(&self) -> OverflowMode {
+        self.overflow
+    }
+
+    // Evaluation stops with `InterpreterError::Cancelled` soon after
+    // the token is cancelled (or its deadline passes)
+    pub fn set_cancellation(&mut self, token: Option<CancellationToken>) {
+        *self.cancellation.borrow_mut() = token;
+    }
+
+    // The fuel of the policy is applied at each `execute_program`
+    pub fn set_policy(&mut self, policy: ExecutionPolicy) {
+        self.set_fuel(policy.fuel);
+        self.policy = policy;
+    }
+
+    pub fn policy(&self) -> &ExecutionPolicy {
+        &self.policy
+    }
+
+    // Reset the fuel to the amount of the policy for a new run
+    pub fn refuel(&mut self) {
+        if self.policy.fuel.is_some() {
+            self.set_fuel(self.policy.fuel);
+        }
+    }
+
+    // Called by builtins before they touch the outside world
+    pub fn require(&self, capability: Capability) -> Result<(), InterpreterError> {
+        if self.policy.allows(capability) {
+            Ok(())
+        } else {
+            Err(InterpreterError::PermissionDenied(capability))
+        }
+    }
+
+    // Attach a debugger for the program parsed from `source`.
+    // Locations are taken from the program at `load_program`.
+    pub fn set_debugger(&mut self, debugger: Box<dyn Debugger>, source: &str) {
+        self.debug = Some(DebugSession::new(debugger, source));
+    }
+
+    pub fn clear_debugger(&mut self) {
+        self.debug = None;
+    }
+
+    // Lines are 1-origin. Breakpoints need a debugger to be attached.
+    pub fn add_breakpoint(&mut self, line: usize) {
+        if let Some(debug) = &mut self.debug {
+            debug.add_breakpoint(line);
+        }
+    }
+
+    pub fn remove_breakpoint(&mut self, line: usize) {
+        if let Some(debug) = &mut self.debug {
+            debug.remove_breakpoint(line);
+        }
+    }
+
+    // Stop at the first statement of the next run
+    pub fn step(&mut self) {
+        if let Some(debug) = &mut self.debug {
+            debug.step();
+        }
+    }
+
+    // Record call counts and time of every function call from now on
+    pub fn enable_profiling(&mut self) {
+        self.profiler = Some(Profiler::new());
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    // Record executed statements of the loaded program.
+    // The record is cleared when another program is loaded.
+    pub fn enable_coverage(&mut self) {
+        self.coverage = Some(Coverage::new());
+    }
+
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+
+    // Observers are notified in the order of registration
+    pub fn add_observer(&mut self, observer: Box<dyn EvalObserver>) {
+        self.observers.push(observer);
+    }
+
+    // Global bindings visible to the next evaluation (REPL `:env`)
+    pub fn bindings(&self) -> HashMap<String, Object> {
+        self.environment.bindings()
+    }
+
+    // Copy of the current bindings. Later evaluation doesn't change it,
+    // so it can be restored any number of times (undo, forked sessions).
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot { environment: self.environment.snapshot() }
+    }
+
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        self.environment = snapshot.environment.snapshot();
+    }
+
+    // Fix the sequence of `random_u64` and `random_range` for reproducible runs
+    pub fn set_random_seed(&mut self, seed: u64) {
+        self.random.set(seed);
+    }
+
+    // Clock used by `now_millis` and `monotonic_nanos`
+    pub fn set_clock(&mut self, clock: Box<dyn Clock>) {
+        *self.clock.borrow_mut() = clock;
+    }
+
+    fn initial_seed() -> u64 {
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH);
+        now.map_or(0, |d| d.as_nanos() as u64)
+    }
+
+    // Locations of an expression evaluated by `evaluate` (e.g. REPL input).
+    // `load_program` sets the locations of the program.
+    pub fn set_location(&mut self, location: LocationPool) {
+        self.located = Arc::new(Self::locations(location));
+    }
+
+    // Program with the locations only
+    fn locations(location: LocationPool) -> Program {
+        Program { node: Node::new(0, 0), import: vec![], function: vec![], expression: ExprPool::new(), location, attributes: HashMap::new() }
+    }
+
+    // Source text of the locations and its name (e.g. the file), so that
+    // `dbg` prints `name:line: expression = value`
+    pub fn set_source(&mut self, name: &str, source: &str) {
+        self.source = Some(Arc::new(Source { name: name.to_string(), text: source.to_string(), lines: LineIndex::new(source) }));
+    }
+
+    // Where `dbg` writes, stderr by default. Spawned tasks write to stderr.
+    pub fn set_dbg_output(&mut self, output: Box<dyn Write>) {
+        self.dbg_output = output;
+    }
+
+    // Make the functions of the program callable by `evaluate_function`.
+    // Functions of the previously loaded program are discarded.
+    // The program is copied; `load_shared` keeps a reference instead.
+    pub fn load_program(&mut self, program: &Program) {
+        self.load_shared(Arc::new(program.clone()));
+    }
+
+    pub fn load_shared(&mut self, program: Arc<Program>) {
+        self.function.clear();
+        self.compiled.clear();
+        self.memo.clear();
+        for (index, f) in program.function.iter().enumerate() {
+            self.function.insert(f.name.clone(), Defined { program: program.clone(), index });
+        }
+        self.located = program;
+        if let Some(coverage) = &mut self.coverage {
+            *coverage = Coverage::new();
+        }
+    }
+
+    // Replace the function of the same name (or add it) while the bindings
+    // and the other functions are kept, e.g. to redefine a function in the
+    // REPL. `pool` holds the expressions of `f`. The caller checks it with
+    // `TypeCheckContext::redefine_function`. Compiled code of the previous
+    // function is dropped.
+    pub fn replace_function(&mut self, pool: &ExprPool, f: &Function) {
+        self.compiled.remove(&f.name);
+        self.memo.retain(|(name, _), _| *name != f.name);
+        let program = Program { node: f.node.clone(), function: vec![f.clone()], expression: pool.clone(), ..Self::locations(LocationPool::new()) };
+        self.function.insert(f.name.clone(), Defined { program: Arc::new(program), index: 0 });
+    }
+
+    // Call `function` instead of evaluating the loaded function `name`.
+    // Observers and the profiler see the call, but fuel, cancellation,
+    // coverage and the debugger don't work inside it.
+    // Cleared by `load_program` with the functions of the program.
+    pub fn set_compiled(&mut self, name: &str, function: CompiledFunction) {
+        self.compiled.insert(name.to_string(), function);
+    }
+
+    // Run `main` of the program. `main` takes no argument.
+    pub fn execute_program(&mut self, program: &Program) -> Result<Object, InterpreterError> {
+        self.execute_shared(Arc::new(program.clone()))
+    }
+
+    // `execute_program` without copying the program
+    pub fn execute_shared(&mut self, program: Arc<Program>) -> Result<Object, InterpreterError> {
+        self.load_shared(program.clone());
+        self.refuel();
+        self.evaluate_function(&program.expression, "main", &[])
+    }
+
+    // The function must be loaded by `load_program` or be a native function
+    pub fn evaluate_function(&mut self, pool: &ExprPool, name: &str, args: &[Object]) -> Result<Object, InterpreterError> {
+        let start = match &mut self.profiler {
+            Some(profiler) => profiler.enter(name).then(Instant::now),
+            None => None,
+        };
+        for observer in &mut self.observers {
+            observer.on_call(name, args);
+        }
+        let result = self.call_function(pool, name, args);
+        if let Some(profiler) = &mut self.profiler {
+            profiler.exit(name, start.map(|start| start.elapsed()));
+        }
+        if let Ok(value) = &result {
+            for observer in &mut self.observers {
+                observer.on_return(name, value);
+            }
+        }
+        result
+    }
+
+    fn call_function(&mut self, pool: &ExprPool, name: &str, args: &[Object]) -> Result<Object, InterpreterError> {
+        let local = self.local.iter().rposition(|f| f.name == name);
+        if local.is_none() {
+            if let Some(compiled) = self.compiled.get(name).cloned() {
+                return compiled(args);
+            }
+        }
+        let local_function;
+        let defined = if local.is_none() { self.function.get(name).cloned() } else { None };
+        let f = match local {
+            Some(i) => {
+                local_function = self.local[i].clone();
+                Some(local_function.as_ref())
+            }
+            None => defined.as_ref().map(Defined::function),
+        };
+        if let Some(f) = f {
+            if f.parameter.len() != args.len() {
+                return Err(InterpreterError::TypeMismatch(format!(
+                    "function `{}` takes {} argument(s) but {} given", name, f.parameter.len(), args.len())));
+            }
+            // a `#[memoize]` function of the program runs once for each arguments
+            let memoize = local.is_none() && f.attribute("memoize").is_some();
+            if memoize {
+                if let Some(result) = self.memo.get(&(name.to_string(), args.to_vec())) {
+                    return Ok(*result);
+                }
+            }
+            let environment = Environment::new();
+            for ((name, _ty), value) in f.parameter.iter().zip(args) {
+                environment.define(name, *value);
+            }
+            // the body sees the functions defined before it, not the ones of the caller
+            let visible = self.local[..local.map_or(0, |i| i + 1)].to_vec();
+            let saved_local = std::mem::replace(&mut self.local, visible);
+            let saved = std::mem::replace(&mut self.environment, environment);
+            // a local function is in the pool of the function which defines it
+            let result = self.evaluate(defined.as_ref().map_or(pool, |d| &d.program.expression), f.code);
+            self.environment = saved;
+            self.local = saved_local;
+            if let (true, Ok(value)) = (memoize, &result) {
+                self.memo.insert((name.to_string(), args.to_vec()), *value);
+            }
+            return result;
+        }
+        match self.native.get(name) {
+            Some(native) => {
+                if let Some(capability) = native.capability {
+                    self.require(capability)?;
+                }
+                // a builtin which waits (`recv`) gives up when the run is cancelled
+                (native.function)(args).map_err(|message| {
+                    if self.is_cancelled() {
+                        InterpreterError::Cancelled
+                    } else {
+                        InterpreterError::Native { name: name.to_string(), message }
+                    }
+                })
+            }
+            None => Err(InterpreterError::UndefinedFunction(name.to_string())),
+        }
+    }
+
+    fn get(pool: &ExprPool, e: ExprRef) -> Result<&Expr, InterpreterError> {
+        pool.get(e.0 as usize).ok_or(InterpreterError::InvalidExprRef(e))
+    }
+
+    pub fn evaluate(&mut self, pool: &ExprPool, e: ExprRef) -> Result<Object, InterpreterError> {
+        if self.depth >= self.max_depth {
+            return Err(InterpreterError::RecursionLimit(self.located.location.get(e).cloned()));
         }
+        self.depth += 1;
+        let result = stacker::maybe_grow(STACK_RED_ZONE, STACK_SEGMENT, || self.evaluate_expr(pool, e));
+        self.depth -= 1;
+        result
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancellation.borrow().as_ref().is_some_and(CancellationToken::is_cancelled)
     }
 
-    pub fn evaluate(&mut self, expr: &Expr) -> i64 {
+    fn evaluate_expr(&mut self, pool: &ExprPool, e: ExprRef) -> Result<Object, InterpreterError> {
+        if let Some(fuel) = &self.fuel {
+            if fuel.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |fuel| fuel.checked_sub(1)).is_err() {
+                return Err(InterpreterError::FuelExhausted);
+            }
+        }
+        self.steps += 1;
+        if self.steps.is_multiple_of(CANCELLATION_CHECK_INTERVAL) && self.is_cancelled() {
+            return Err(InterpreterError::Cancelled);
+        }
+        let expr = Self::get(pool, e)?;
         match expr {
-            Expr::IfElse(_, _, _) => (),
-            Expr::Binary(bop) => {
-                let lhs = self.evaluate(&bop.lhs);
-                let rhs = self.evaluate(&bop.rhs);
-                let res = match bop.op {
-                    Operator::IAdd => lhs + rhs,
-                    Operator::ISub => lhs - rhs,
-                    Operator::IMul => lhs * rhs,
-                    Operator::IDiv => lhs / rhs,
-                    _ => panic!("not implemented yet (Binary Operator)"),
-                };
-                return res;
+            Expr::IfElse(cond, then_block, else_block) => {
+                match self.evaluate(pool, *cond)? {
+                    Object::Bool(true) => self.evaluate(pool, *then_block),
+                    Object::Bool(false) => self.evaluate(pool, *else_block),
+                    x => Err(InterpreterError::TypeMismatch(format!("condition of if must be bool but {:?}", x))),
+                }
+            }
+            Expr::Binary(op, lhs, rhs) => self.evaluate_binary(pool, e, op, *lhs, *rhs),
+            Expr::Block(expressions) => {
+                let outer = self.environment.clone();
+                let depth = self.local.len();
+                self.environment = outer.child();
+                let result = self.evaluate_block(pool, expressions);
+                self.environment = outer;
+                self.local.truncate(depth);
+                result
+            }
+            Expr::Function(f) => {
+                self.local.push(Arc::new(f.as_ref().clone()));
+                Ok(Object::Unit)
+            }
+            Expr::Int64(i) => Ok(Object::Int64(*i)),
+            Expr::UInt64(u) => Ok(Object::UInt64(*u)),
+            Expr::Int(i_str) => {
+                // literals are resolved by the parser, so this is a fallback
+                match i_str.parse::<i64>() {
+                    Ok(i) => Ok(Object::Int64(i)),
+                    Err(_) => Err(InterpreterError::TypeMismatch(format!("invalid integer literal: {}", i_str))),
+                }
             }
-            Expr::Int64(i) => return *i,
-            Expr::UInt64(u) => return *u as i64,
-            Expr::Int(i_str) => return 0,
             Expr::Identifier(name) => {
-                match self.environment.context.get(name) {
-                    Some(v) => return *v,
-                    _ => return 0, // error
+                match self.environment.get(name) {
+                    Some(v) => Ok(v),
+                    _ => Err(InterpreterError::UndefinedVariable(name.to_string())),
+                }
+            }
+            Expr::Call(name, args) => {
+                if name == "dbg" && !self.is_function(name) {
+                    return self.evaluate_dbg(pool, *args);
+                }
+                let mut values = vec![];
+                if let Expr::Block(args) = Self::get(pool, *args)? {
+                    for arg in args {
+                        values.push(self.evaluate(pool, *arg)?);
+                    }
                 }
+                self.evaluate_function(pool, name, &values).map_err(|err| match err {
+                    InterpreterError::Native { name, .. } if name == "assert" =>
+                        InterpreterError::AssertionFailed(self.located.location.get(e).cloned()),
+                    err => err,
+                })
             }
-            Expr::Call(_, _) => (),
-            Expr::Null => (),
+            Expr::Spawn(call) => self.spawn(pool, *call),
+            Expr::Null => Ok(Object::Null),
             Expr::Val(name, _ty, expr) => {
                 match expr {
                     Some(expr) => {
-                        let eval = self.evaluate(expr);
-                        self.environment.context.insert(name.to_string(), eval);
-                        return 0;
+                        let eval = self.evaluate(pool, *expr)?;
+                        self.bind(name, eval);
+                        Ok(Object::Unit)
+                    }
+                    _ => Err(InterpreterError::UndefinedVariable(name.to_string())), // value is not set
+                }
+            }
+            Expr::Var(name, _ty, expr) => {
+                match expr {
+                    Some(expr) => {
+                        let eval = self.evaluate(pool, *expr)?;
+                        self.bind(name, eval);
                     }
-                    _ => panic!("value is not set: {}", name), // error
+                    // `var` without initializer is set by assignment later
+                    None => self.environment.define(name, Object::Null),
                 }
+                Ok(Object::Unit)
             }
+            Expr::While(cond, body) => self.evaluate_while(pool, *cond, *body),
+            Expr::For(name, start, end, body) => self.evaluate_for(pool, name, *start, *end, *body),
+        }
+    }
+
+    fn is_function(&self, name: &str) -> bool {
+        self.local.iter().any(|f| f.name == name) || self.function.contains_key(name) || self.native.contains_key(name)
+    }
+
+    // `dbg(x)`: print the source of `x` with its value, and give the value.
+    // It writes outside of the script, so it requires `Capability::Io`.
+    fn evaluate_dbg(&mut self, pool: &ExprPool, args: ExprRef) -> Result<Object, InterpreterError> {
+        self.require(Capability::Io)?;
+        let arg = match Self::get(pool, args)? {
+            Expr::Block(args) if args.len() == 1 => args[0],
+            x => return Err(InterpreterError::TypeMismatch(format!("dbg takes 1 argument but {:?}", x))),
+        };
+        let value = self.evaluate(pool, arg)?;
+        let at = self.source.as_ref().zip(self.located.location.get(arg)).filter(|(source, node)| node.end() <= source.text.len());
+        let message = match at {
+            Some((source, node)) => {
+                let line = source.lines.line(node.start());
+                format!("{}:{}: {} = {}", source.name, line, &source.text[node.start()..node.end()], value)
+            }
+            None => format!("dbg: {}", value),
+        };
+        // like `eprintln`, but a closed output doesn't stop the program
+        let _ = writeln!(self.dbg_output, "{}", message);
+        Ok(value)
+    }
+
+    // Run the call on a new thread with a processor of its own. Only the
+    // evaluated arguments, the functions and the settings are copied to it:
+    // the thread has its own random state and system clock, and the host
+    // functions are the builtins, not those of `register_native`. The fuel,
+    // the channels and the count of the tasks for `max_tasks` are shared.
+    fn spawn(&mut self, pool: &ExprPool, call: ExprRef) -> Result<Object, InterpreterError> {
+        let (name, args) = match Self::get(pool, call)? {
+            Expr::Call(name, args) => (name.clone(), *args),
+            x => return Err(InterpreterError::TypeMismatch(format!("spawn needs a function call but {:?}", x))),
+        };
+        let mut values = vec![];
+        if let Expr::Block(args) = Self::get(pool, args)? {
+            for arg in args {
+                values.push(self.evaluate(pool, *arg)?);
+            }
+        }
+        if let Some(value) = values.iter().find(|value| matches!(value, Object::Task(_))) {
+            return Err(InterpreterError::TypeMismatch(format!("{:?} cannot be passed to a spawned function", value)));
+        }
+        // only a local function is evaluated in the pool of the caller
+        let pool = if self.local.iter().any(|f| f.name == name) { pool.clone() } else { ExprPool::new() };
+        let function = self.function.clone();
+        let local = self.local.clone();
+        let located = self.located.clone();
+        let (overflow, max_depth, policy, cancellation) = (self.overflow, self.max_depth, self.policy.clone(), self.cancellation.borrow().clone());
+        let seed = builtin::next_random(&self.random);
+        let channels = self.channels.clone();
+        let source = self.source.clone();
+        let prelude = self.prelude.clone();
+        let (fuel, live_tasks) = (self.fuel.clone(), self.live_tasks.clone());
+        let max_tasks = self.policy.max_tasks.unwrap_or(usize::MAX);
+        if live_tasks.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| (n < max_tasks).then_some(n + 1)).is_err() {
+            return Err(InterpreterError::Native { name: "spawn".to_string(), message: format!("at most {} tasks can run at once", max_tasks) });
+        }
+        let live = live_tasks.clone();
+        let result = self.tasks.borrow_mut().spawn(move || {
+            let mut p = Processor::new();
+            let cancelled = p.cancellation.clone();
+            builtin::register_channels(&mut p, channels.clone(), cancelled);
+            p.set_prelude(&prelude);
+            p.channels = channels;
+            p.function = function;
+            p.local = local;
+            p.located = located;
+            p.source = source;
+            p.set_overflow_mode(overflow);
+            p.set_max_depth(max_depth);
+            p.set_policy(policy);
+            p.fuel = fuel;
+            p.live_tasks = live_tasks;
+            p.set_cancellation(cancellation);
+            p.set_random_seed(seed);
+            let result = p.evaluate_function(&pool, &name, &values);
+            p.live_tasks.fetch_sub(1, Ordering::SeqCst);
+            result
+        });
+        if result.is_err() {
+            live.fetch_sub(1, Ordering::SeqCst);
+        }
+        result.map_err(|message| InterpreterError::Native { name: "spawn".to_string(), message })
+    }
+
+    fn evaluate_while(&mut self, pool: &ExprPool, cond: ExprRef, body: ExprRef) -> Result<Object, InterpreterError> {
+        let mut iteration = 0;
+        loop {
+            match self.evaluate(pool, cond)? {
+                Object::Bool(true) => (),
+                Object::Bool(false) => return Ok(Object::Unit),
+                x => return Err(InterpreterError::TypeMismatch(format!("condition of while must be bool but {:?}", x))),
+            }
+            self.loop_iteration(iteration);
+            self.evaluate(pool, body)?;
+            iteration += 1;
+        }
+    }
+
+    fn evaluate_for(&mut self, pool: &ExprPool, name: &str, start: ExprRef, end: ExprRef, body: ExprRef) -> Result<Object, InterpreterError> {
+        let range: Box<dyn Iterator<Item = Object>> = match (self.evaluate(pool, start)?, self.evaluate(pool, end)?) {
+            (Object::Int64(s), Object::Int64(e)) => Box::new((s..e).map(Object::Int64)),
+            (Object::UInt64(s), Object::UInt64(e)) => Box::new((s..e).map(Object::UInt64)),
+            (s, e) => return Err(InterpreterError::TypeMismatch(format!("range of for must be integers but {:?}..{:?}", s, e))),
+        };
+        for (iteration, value) in range.enumerate() {
+            self.loop_iteration(iteration as u64);
+            // the induction variable is defined in its own scope for each iteration
+            let outer = self.environment.clone();
+            self.environment = outer.child();
+            self.bind(name, value);
+            let result = self.evaluate(pool, body);
+            self.environment = outer;
+            result?;
+        }
+        Ok(Object::Unit)
+    }
+
+    fn loop_iteration(&mut self, iteration: u64) {
+        for observer in &mut self.observers {
+            observer.on_loop_iteration(iteration);
         }
-        return 0i64;    // TODO
+    }
+
+    fn evaluate_block(&mut self, pool: &ExprPool, expressions: &[ExprRef]) -> Result<Object, InterpreterError> {
+        let mut last = Object::Unit;
+        for e in expressions {
+            if let Some(coverage) = &mut self.coverage {
+                coverage.mark(*e);
+            }
+            if let Some(debug) = &mut self.debug {
+                if !debug.before_statement(*e, &self.located.location) {
+                    return Err(InterpreterError::Cancelled);
+                }
+            }
+            last = self.evaluate(pool, *e)?;
+        }
+        Ok(last)
+    }
+
+    // `val` and `var` bind in the innermost scope
+    fn bind(&mut self, name: &str, value: Object) {
+        for observer in &mut self.observers {
+            observer.on_assign(name, &value);
+        }
+        self.environment.define(name, value);
+    }
+
+    // assignment updates the binding in the scope defining it
+    fn assign(&mut self, name: &str, value: Object) -> Result<(), InterpreterError> {
+        for observer in &mut self.observers {
+            observer.on_assign(name, &value);
+        }
+        if self.environment.set(name, value) {
+            Ok(())
+        } else {
+            Err(InterpreterError::UndefinedVariable(name.to_string()))
+        }
+    }
+
+    fn evaluate_binary(&mut self, pool: &ExprPool, e: ExprRef, op: &Operator, lhs: ExprRef, rhs: ExprRef) -> Result<Object, InterpreterError> {
+        match op {
+            Operator::Assign => {
+                let name = match Self::get(pool, lhs)? {
+                    Expr::Identifier(name) => name.to_string(),
+                    x => return Err(InterpreterError::TypeMismatch(format!("left hand side of assignment must be identifier but {:?}", x))),
+                };
+                let value = self.evaluate(pool, rhs)?;
+                self.assign(&name, value)?;
+                return Ok(Object::Unit);
+            }
+            Operator::LogicalAnd | Operator::LogicalOr => {
+                let lhs = match self.evaluate(pool, lhs)? {
+                    Object::Bool(b) => b,
+                    x => return Err(InterpreterError::TypeMismatch(format!("logical operator expects bool but {:?}", x))),
+                };
+                // short circuit
+                if (*op == Operator::LogicalAnd && !lhs) || (*op == Operator::LogicalOr && lhs) {
+                    return Ok(Object::Bool(lhs));
+                }
+                return match self.evaluate(pool, rhs)? {
+                    Object::Bool(b) => Ok(Object::Bool(b)),
+                    x => Err(InterpreterError::TypeMismatch(format!("logical operator expects bool but {:?}", x))),
+                };
+            }
+            _ => (),
+        }
+
+        let lhs = self.evaluate(pool, lhs)?;
+        let rhs = self.evaluate(pool, rhs)?;
+        match (lhs, rhs) {
+            (Object::Int64(_), Object::Int64(0)) | (Object::UInt64(_), Object::UInt64(0)) if *op == Operator::IDiv =>
+                Err(InterpreterError::DivisionByZero(self.located.location.get(e).cloned())),
+            (Object::Int64(l), Object::Int64(r)) => match Self::arith_op(op) {
+                Some(op) => self.arith(e, op, l, r).map(Object::Int64),
+                None => Self::compare(op, l, r),
+            },
+            (Object::UInt64(l), Object::UInt64(r)) => match Self::arith_op(op) {
+                Some(op) => self.arith(e, op, l, r).map(Object::UInt64),
+                None => Self::compare(op, l, r),
+            },
+            (Object::Bool(l), Object::Bool(r)) => match op {
+                Operator::EQ => Ok(Object::Bool(l == r)),
+                Operator::NE => Ok(Object::Bool(l != r)),
+                _ => Err(InterpreterError::TypeMismatch(format!("operator {:?} is not defined for bool", op))),
+            },
+            (l, r) => Err(InterpreterError::TypeMismatch(format!("binary operator {:?}: {:?} {:?}", op, l, r))),
+        }
+    }
+
+    fn arith_op(op: &Operator) -> Option<ArithOp> {
+        match op {
+            Operator::IAdd => Some(ArithOp::Add),
+            Operator::ISub => Some(ArithOp::Sub),
+            Operator::IMul => Some(ArithOp::Mul),
+            Operator::IDiv => Some(ArithOp::Div),
+            _ => None,
+        }
+    }
+
+    fn arith<T: Integer>(&self, e: ExprRef, op: ArithOp, l: T, r: T) -> Result<T, InterpreterError> {
+        overflow::apply(self.overflow, op, l, r)
+            .ok_or_else(|| InterpreterError::Overflow(self.located.location.get(e).cloned()))
+    }
+
+    fn compare<T: PartialOrd>(op: &Operator, l: T, r: T) -> Result<Object, InterpreterError> {
+        Ok(Object::Bool(match op {
+            Operator::EQ => l == r,
+            Operator::NE => l != r,
+            Operator::LT => l < r,
+            Operator::LE => l <= r,
+            Operator::GT => l > r,
+            Operator::GE => l >= r,
+            _ => return Err(InterpreterError::TypeMismatch(format!("operator {:?} is not a comparison", op))),
+        }))
+    }
+}
+
+impl Default for Processor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use frontend::node_id::NodeIds;
+
+    fn evaluate(p: &mut Processor, input: &str) -> Object {
+        let (e, pool) = frontend::Parser::new(input).parse_expression().unwrap();
+        p.evaluate(&pool, e).unwrap()
+    }
+
+    #[test]
+    fn evaluate_arithmetic() {
+        let mut p = Processor::new();
+        assert_eq!(Object::UInt64(7), evaluate(&mut p, "1u64 + 2u64 * 3u64"));
+        assert_eq!(Object::Int64(-3), evaluate(&mut p, "(1 - 2) * 3"));
+    }
+
+    #[test]
+    fn evaluate_var_assign() {
+        let mut p = Processor::new();
+        evaluate(&mut p, "var a = 1i64");
+        evaluate(&mut p, "a = a + 2i64");
+        assert_eq!(Object::Int64(3), evaluate(&mut p, "a"));
+    }
+
+    #[test]
+    fn evaluate_val_and_if() {
+        let mut p = Processor::new();
+        assert_eq!(Object::Unit, evaluate(&mut p, "val a = 10u64"));
+        assert_eq!(Object::UInt64(1), evaluate(&mut p, "if a < 20u64 && a != 0u64 { 1u64 } else { 2u64 }"));
+    }
+
+    #[test]
+    fn evaluate_native_function() {
+        let mut p = Processor::new();
+        let signature = FunctionSignature { parameter: vec![Type::UInt64, Type::UInt64], return_type: Type::UInt64 };
+        p.register_native("max", signature, |args| match args {
+            [Object::UInt64(a), Object::UInt64(b)] => Ok(Object::UInt64(*a.max(b))),
+            _ => Err("expects two u64".to_string()),
+        });
+        p.register_native("fail", FunctionSignature { parameter: vec![], return_type: Type::Unit }, |_| {
+            Err("always fails".to_string())
+        });
+
+        let mut ctx = TypeCheckContext::new();
+        p.declare_native(&mut ctx);
+        let mut parser = frontend::Parser::new("max(3, 10) + 1");
+        let (e, mut pool) = parser.parse_expression().unwrap();
+        assert_eq!(Ok(Type::UInt64), ctx.check_expression(&mut pool, parser.location(), e));
+        assert_eq!(Ok(Object::UInt64(11)), p.evaluate(&pool, e));
+
+        let (e, pool) = frontend::Parser::new("fail()").parse_expression().unwrap();
+        assert_eq!(
+            Err(InterpreterError::Native { name: "fail".to_string(), message: "always fails".to_string() }),
+            p.evaluate(&pool, e)
+        );
+    }
+
+    #[test]
+    fn evaluate_with_fuel() {
+        let mut p = Processor::new();
+        p.set_fuel(Some(3));
+        let (e, pool) = frontend::Parser::new("1u64 + 2u64").parse_expression().unwrap();
+        assert_eq!(Ok(Object::UInt64(3)), p.evaluate(&pool, e));
+        assert_eq!(Some(0), p.fuel());
+        assert_eq!(Err(InterpreterError::FuelExhausted), p.evaluate(&pool, e));
+
+        let code = r#"
+fn main() -> u64 {
+main()
+}
+        "#;
+        let program = frontend::Parser::new(code).parse_program().unwrap();
+        p.set_fuel(Some(100));
+        assert_eq!(Err(InterpreterError::FuelExhausted), p.execute_program(&program));
+    }
+
+    #[test]
+    fn execute_memoized() {
+        // 2^40 calls without the memo
+        let code = r#"
+#[memoize]
+fn fib(n: u64) -> u64 {
+if n < 2u64 { n } else { fib(n - 1u64) + fib(n - 2u64) }
+}
+fn main() -> u64 {
+fib(80u64)
+}
+        "#;
+        let program = frontend::Parser::new(code).parse_program().unwrap();
+        let mut p = Processor::new();
+        p.set_fuel(Some(100_000));
+        assert_eq!(Ok(Object::UInt64(23416728348467685)), p.execute_program(&program));
+    }
+
+    #[test]
+    fn evaluate_cancelled() {
+        // more steps than CANCELLATION_CHECK_INTERVAL, in a loop so that
+        // the test doesn't depend on the stack size
+        let code = r#"
+fn count(n: u64) -> u64 {
+var i = 0u64
+while i < n { i = i + 1 }
+i
+}
+
+fn main() -> u64 {
+count(1000)
+}
+        "#;
+        let program = frontend::Parser::new(code).parse_program().unwrap();
+        let mut p = Processor::new();
+        let token = CancellationToken::new();
+        p.set_cancellation(Some(token.clone()));
+        assert_eq!(Ok(Object::UInt64(1000)), p.execute_program(&program));
+        token.cancel();
+        assert_eq!(Err(InterpreterError::Cancelled), p.execute_program(&program));
+
+        // `recv` of a channel which nothing sends to stops waiting too, also in a task
+        let code = "fn wait(ch: channel<u64>) -> u64 {\nrecv(ch)\n}\nfn main() -> u64 {\nval ch: channel<u64> = channel()\nrecv(ch)\n}";
+        let program = frontend::Parser::new(code).parse_program().unwrap();
+        assert_eq!(Err(InterpreterError::Cancelled), p.execute_program(&program));
+        p.set_cancellation(Some(CancellationToken::with_timeout(std::time::Duration::from_millis(50))));
+        assert_eq!(Err(InterpreterError::Cancelled), p.execute_program(&program));
+        let task = code.replace("= channel()\nrecv(ch)", "= channel()\njoin(spawn wait(ch))");
+        let program = frontend::Parser::new(&task).parse_program().unwrap();
+        p.set_cancellation(Some(CancellationToken::with_timeout(std::time::Duration::from_millis(50))));
+        assert_eq!(Err(InterpreterError::Cancelled), p.execute_program(&program));
+    }
+
+    #[test]
+    fn evaluate_deep_recursion() {
+        // deeper than the stack of the test thread holds
+        let code = r#"
+fn count(n: u64) -> u64 {
+if n == 0u64 { 0u64 } else { count(n - 1u64) + 1u64 }
+}
+
+fn main() -> u64 {
+count(2000u64)
+}
+        "#;
+        let program = frontend::Parser::new(code).parse_program().unwrap();
+        let mut p = Processor::new();
+        assert_eq!(Ok(Object::UInt64(2000)), p.execute_program(&program));
+
+        p.set_max_depth(100);
+        match p.execute_program(&program) {
+            Err(InterpreterError::RecursionLimit(Some(_))) => (),
+            other => panic!("{:?}", other),
+        }
+        // the depth is back to 0 after the error
+        p.set_max_depth(DEFAULT_MAX_DEPTH);
+        assert_eq!(Ok(Object::UInt64(2000)), p.execute_program(&program));
+    }
+
+    #[test]
+    fn execute_with_policy() {
+        let mut p = Processor::new();
+        assert_eq!(Ok(()), p.require(Capability::Io));
+
+        let mut policy = ExecutionPolicy::sandboxed();
+        policy.fuel = Some(3);
+        p.set_policy(policy);
+        assert_eq!(Err(InterpreterError::PermissionDenied(Capability::Env)), p.require(Capability::Env));
+
+        // fuel is refilled for each run
+        let program = frontend::Parser::new("fn main() -> u64 { 1u64 }").parse_program().unwrap();
+        assert_eq!(Ok(Object::UInt64(1)), p.execute_program(&program));
+        assert_eq!(Ok(Object::UInt64(1)), p.execute_program(&program));
+        let program = frontend::Parser::new("fn main() -> u64 { 1u64 + 2u64 + 3u64 }").parse_program().unwrap();
+        assert_eq!(Err(InterpreterError::FuelExhausted), p.execute_program(&program));
+    }
+
+    #[test]
+    fn execute_with_debugger() {
+        use crate::debugger::{DebugAction, StatementEvent};
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        struct Recorder(Rc<RefCell<Vec<usize>>>);
+        impl Debugger for Recorder {
+            fn on_statement(&mut self, event: &StatementEvent) -> DebugAction {
+                let mut lines = self.0.borrow_mut();
+                lines.push(event.line.unwrap());
+                if lines.len() == 1 { DebugAction::Step } else { DebugAction::Continue }
+            }
+        }
+
+        let code = r#"
+fn main() -> u64 {
+val a = 1u64
+val b = a + 1
+b * 2
+}
+        "#;
+        let program = frontend::Parser::new(code).parse_program().unwrap();
+        let lines = Rc::new(RefCell::new(vec![]));
+        let mut p = Processor::new();
+        p.set_debugger(Box::new(Recorder(lines.clone())), code);
+        p.add_breakpoint(4);
+        assert_eq!(Ok(Object::UInt64(4)), p.execute_program(&program));
+        assert_eq!(vec![4, 5], *lines.borrow());
+    }
+
+    #[test]
+    fn execute_with_profiler() {
+        let code = r#"
+fn fib(n: u64) -> u64 {
+if n <= 1 { n } else { fib(n - 1) + fib(n - 2) }
+}
+
+fn main() -> u64 {
+fib(10)
+}
+        "#;
+        let program = frontend::Parser::new(code).parse_program().unwrap();
+        let mut p = Processor::new();
+        p.enable_profiling();
+        assert_eq!(Ok(Object::UInt64(55)), p.execute_program(&program));
+        let profiler = p.profiler().unwrap();
+        assert_eq!(1, profiler.get("main").unwrap().calls);
+        assert_eq!(177, profiler.get("fib").unwrap().calls);
+        assert!(profiler.get("main").unwrap().total >= profiler.get("fib").unwrap().total);
+        assert_eq!(vec!["main", "fib"], profiler.report().iter().map(|(name, _)| *name).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn execute_with_coverage() {
+        let code = r#"
+fn abs(n: i64) -> i64 {
+if n < 0 {
+0 - n
+} else {
+n
+}
+}
+
+fn main() -> i64 {
+val a = abs(3)
+a
+}
+        "#;
+        let program = frontend::Parser::new(code).parse_program().unwrap();
+        let mut p = Processor::new();
+        p.enable_coverage();
+        assert_eq!(Ok(Object::Int64(3)), p.execute_program(&program));
+        let report = p.coverage().unwrap().report(&program, code);
+        assert_eq!(vec![3, 6, 11, 12], report.covered);
+        assert_eq!(vec![4], report.uncovered);
+        assert_eq!("coverage: 4/5 lines (80.0%)\nnot executed: 4\n", report.to_string());
+
+        // moved to the program with a line inserted
+        let edited = code.replace("val a = abs(3)", "val b = 1\nval a = abs(3)");
+        let new = frontend::Parser::new(&edited).parse_program().unwrap();
+        let (from, to) = (NodeIds::new(&program), NodeIds::new(&new));
+        let report = p.coverage().unwrap().remap(&from, &to).report(&new, &edited);
+        assert_eq!(vec![3, 6, 12, 13], report.covered);
+        assert_eq!(vec![4, 11], report.uncovered);
+    }
+
+    #[test]
+    fn execute_with_observer() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        struct Logger(Rc<RefCell<Vec<String>>>);
+        impl EvalObserver for Logger {
+            fn on_call(&mut self, name: &str, args: &[Object]) {
+                self.0.borrow_mut().push(format!("call {} {:?}", name, args));
+            }
+            fn on_return(&mut self, name: &str, result: &Object) {
+                self.0.borrow_mut().push(format!("return {} {:?}", name, result));
+            }
+            fn on_assign(&mut self, name: &str, value: &Object) {
+                self.0.borrow_mut().push(format!("assign {} {:?}", name, value));
+            }
+        }
+
+        let code = r#"
+fn double(n: u64) -> u64 {
+n * 2
+}
+
+fn main() -> u64 {
+var a = 1u64
+a = double(a)
+a
+}
+        "#;
+        let program = frontend::Parser::new(code).parse_program().unwrap();
+        let log = Rc::new(RefCell::new(vec![]));
+        let mut p = Processor::new();
+        p.add_observer(Box::new(Logger(log.clone())));
+        assert_eq!(Ok(Object::UInt64(2)), p.execute_program(&program));
+        assert_eq!(vec![
+            "call main []",
+            "assign a UInt64(1)",
+            "call double [UInt64(1)]",
+            "return double UInt64(2)",
+            "assign a UInt64(2)",
+            "return main UInt64(2)",
+        ], *log.borrow());
+    }
+
+    #[test]
+    fn evaluate_block_scope() {
+        let code = r#"
+fn main() -> u64 {
+var a = 1u64
+val b = 10u64
+if a == 1 {
+val b = 20u64
+a = a + b
+}
+a + b
+}
+        "#;
+        let program = frontend::Parser::new(code).parse_program().unwrap();
+        assert_eq!(Ok(Object::UInt64(31)), Processor::new().execute_program(&program));
+    }
+
+    #[test]
+    fn snapshot_and_restore() {
+        let mut p = Processor::new();
+        evaluate(&mut p, "var a = 1i64");
+        let saved = p.snapshot();
+        evaluate(&mut p, "a = 5i64");
+        evaluate(&mut p, "val b = 2i64");
+        assert_eq!(Object::Int64(7), evaluate(&mut p, "a + b"));
+
+        p.restore(&saved);
+        assert_eq!(Object::Int64(1), evaluate(&mut p, "a"));
+        let (e, pool) = frontend::Parser::new("b").parse_expression().unwrap();
+        assert_eq!(Err(InterpreterError::UndefinedVariable("b".to_string())), p.evaluate(&pool, e));
+
+        // restoring again starts from the same state
+        evaluate(&mut p, "a = 3i64");
+        p.restore(&saved);
+        assert_eq!(Object::Int64(1), evaluate(&mut p, "a"));
+    }
+
+    #[test]
+    fn division_by_zero_location() {
+        let code = "fn main() -> u64 {\nval a = 0u64\n10u64 / a\n}";
+        let program = frontend::Parser::new(code).parse_program().unwrap();
+        let err = Processor::new().execute_program(&program).unwrap_err();
+        let node = match &err {
+            InterpreterError::DivisionByZero(Some(node)) => node.clone(),
+            e => panic!("unexpected error {:?}", e),
+        };
+        assert_eq!("10u64 / a", &code[node.start()..node.end()]);
+        assert_eq!("32..41: division by zero", err.to_string());
+
+        let mut parser = frontend::Parser::new("1i64 / 0i64");
+        let (e, pool) = parser.parse_expression().unwrap();
+        let mut p = Processor::new();
+        p.set_location(parser.location().clone());
+        assert_eq!(Err(InterpreterError::DivisionByZero(Some(Node::new(0, 11)))), p.evaluate(&pool, e));
+    }
+
+    #[test]
+    fn evaluate_overflow_mode() {
+        let mut p = Processor::new();
+        let eval = |p: &mut Processor, code: &str| {
+            let (e, pool) = frontend::Parser::new(code).parse_expression().unwrap();
+            p.evaluate(&pool, e)
+        };
+        assert_eq!(OverflowMode::Trap, p.overflow_mode());
+        assert_eq!(Err(InterpreterError::Overflow(None)), eval(&mut p, "9223372036854775807i64 + 1i64"));
+        assert_eq!(Err(InterpreterError::Overflow(None)), eval(&mut p, "0u64 - 1u64"));
+        assert_eq!(Ok(Object::Int64(i64::MIN)), eval(&mut p, "-9223372036854775807i64 - 1i64"));
+
+        p.set_overflow_mode(OverflowMode::Wrap);
+        assert_eq!(Ok(Object::Int64(i64::MIN)), eval(&mut p, "9223372036854775807i64 + 1i64"));
+        assert_eq!(Ok(Object::UInt64(u64::MAX)), eval(&mut p, "0u64 - 1u64"));
+        assert_eq!(Ok(Object::UInt64(0)), eval(&mut p, "4294967296u64 * 4294967296u64"));
+
+        p.set_overflow_mode(OverflowMode::Saturate);
+        assert_eq!(Ok(Object::Int64(i64::MAX)), eval(&mut p, "9223372036854775807i64 + 1i64"));
+        assert_eq!(Ok(Object::UInt64(0)), eval(&mut p, "0u64 - 1u64"));
+        assert_eq!(Ok(Object::UInt64(u64::MAX)), eval(&mut p, "4294967296u64 * 4294967296u64"));
+    }
+
+    #[test]
+    fn execute_loop() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        struct Counter(Rc<RefCell<Vec<u64>>>);
+        impl EvalObserver for Counter {
+            fn on_loop_iteration(&mut self, iteration: u64) {
+                self.0.borrow_mut().push(iteration);
+            }
+        }
+
+        let code = r#"
+fn main() -> u64 {
+var sum = 0u64
+for i in 1u64..4 {
+sum = sum + i
+}
+var n = 0u64
+while n < 2 {
+n = n + 1
+}
+if sum > 10 { 0 } else if n == 2 { sum * 10 + n } else { 1 }
+}
+        "#;
+        let program = frontend::Parser::new(code).parse_program().unwrap();
+        let iterations = Rc::new(RefCell::new(vec![]));
+        let mut p = Processor::new();
+        p.add_observer(Box::new(Counter(iterations.clone())));
+        assert_eq!(Ok(Object::UInt64(62)), p.execute_program(&program));
+        assert_eq!(vec![0, 1, 2, 0, 1], *iterations.borrow());
+
+        // an endless loop is stopped by the fuel
+        let program = frontend::Parser::new("fn main() -> u64 {\nwhile 1 < 2 { }\n0\n}").parse_program().unwrap();
+        let mut p = Processor::new();
+        p.set_fuel(Some(100));
+        assert_eq!(Err(InterpreterError::FuelExhausted), p.execute_program(&program));
+    }
+
+    #[test]
+    fn execute_compiled_function() {
+        let code = "fn twice(n: u64) -> u64 {\nn * 2u64\n}\nfn main() -> u64 {\ntwice(20u64) + 2u64\n}";
+        let program = frontend::Parser::new(code).parse_program().unwrap();
+        let mut p = Processor::new();
+        p.load_program(&program);
+        p.set_compiled("twice", Rc::new(|args: &[Object]| match args {
+            [Object::UInt64(n)] => Ok(Object::UInt64(n * 3)),
+            _ => Err(InterpreterError::TypeMismatch("twice".to_string())),
+        }));
+        assert_eq!(Ok(Object::UInt64(62)), p.evaluate_function(&program.expression, "main", &[]));
+
+        // loading a program drops the compiled code
+        assert_eq!(Ok(Object::UInt64(42)), p.execute_program(&program));
+    }
+
+    #[test]
+    fn execute_spawn() {
+        let code = r#"
+fn sum(n: u64) -> u64 {
+var s = 0u64
+for i in 0..n {
+s = s + i
+}
+s
+}
+
+fn broken(n: u64) -> u64 {
+n / 0u64
+}
+
+fn main() -> u64 {
+val a = spawn sum(10)
+val b = spawn sum(100)
+join(a) + join(b)
+}
+        "#;
+        let mut program = frontend::Parser::new(code).parse_program().unwrap();
+        frontend::literal::resolve_program(&mut program).unwrap();
+        let mut p = Processor::new();
+        let mut ctx = TypeCheckContext::new();
+        p.declare_native(&mut ctx);
+        assert_eq!(Ok(()), ctx.check_program(&program));
+        assert_eq!(Ok(Object::UInt64(45 + 4950)), p.execute_program(&program));
+
+        // the error of a task is returned by join, which takes the result once
+        let failed = code.replace("join(a) + join(b)", "val c = spawn broken(1)\njoin(c)");
+        let program = frontend::Parser::new(&failed).parse_program().unwrap();
+        let err = p.execute_program(&program).unwrap_err();
+        assert_eq!("join: task 4 failed: 98..106: division by zero", err.to_string());
+        let twice = code.replace("join(a) + join(b)", "join(a) + join(a)");
+        let program = frontend::Parser::new(&twice).parse_program().unwrap();
+        let err = p.execute_program(&program).unwrap_err();
+        assert_eq!("join: task 5 is not running or already joined", err.to_string());
+
+        // a local function is evaluated in the pool of the caller
+        let local = code.replace("join(a) + join(b)", "fn twice(n: u64) -> u64 { sum(n) * 2u64 }\nval c = spawn twice(10)\njoin(c)");
+        let program = frontend::Parser::new(&local).parse_program().unwrap();
+        assert_eq!(Ok(Object::UInt64(90)), p.execute_program(&program));
+
+        // the tasks use up the fuel of the run: `sum(100)` alone takes
+        // 505 steps, the run 576
+        let program = frontend::Parser::new(code).parse_program().unwrap();
+        p.set_policy(ExecutionPolicy { fuel: Some(576), ..ExecutionPolicy::sandboxed() });
+        assert_eq!(Ok(Object::UInt64(45 + 4950)), p.execute_program(&program));
+        assert_eq!(Some(0), p.fuel());
+        p.set_policy(ExecutionPolicy { fuel: Some(520), ..ExecutionPolicy::sandboxed() });
+        assert!(p.execute_program(&program).is_err());
+
+        // and cannot run more tasks at once than the policy allows
+        let waiting = "fn wait(ch: channel<u64>) -> u64 {\nrecv(ch)\n}\nfn main() -> u64 {\nval ch: channel<u64> = channel()\nval a = spawn wait(ch)\nval b = spawn wait(ch)\n0u64\n}";
+        let program = frontend::Parser::new(waiting).parse_program().unwrap();
+        p.set_policy(ExecutionPolicy { max_tasks: Some(1), ..ExecutionPolicy::sandboxed() });
+        let err = p.execute_program(&program).unwrap_err();
+        assert_eq!("spawn: at most 1 tasks can run at once", err.to_string());
+    }
+
+    #[test]
+    fn execute_shared_program() {
+        let code = "fn f() -> u64 {\n1u64\n}\nfn main() -> u64 {\nf() + 1u64\n}";
+        let program = Arc::new(frontend::Parser::new(code).parse_program().unwrap());
+        let mut p = Processor::new();
+        assert_eq!(Ok(Object::UInt64(2)), p.execute_shared(program.clone()));
+        // the functions and the locations refer to the program, not to a copy
+        assert_eq!(4, Arc::strong_count(&program));
+        p.load_program(&program);
+        assert_eq!(1, Arc::strong_count(&program));
+    }
+
+    #[test]
+    fn execute_channel() {
+        let code = r#"
+fn produce(ch: channel<u64>, n: u64) -> u64 {
+for i in 0..n {
+send(ch, i * i)
+}
+close(ch)
+n
+}
+
+fn main() -> u64 {
+val ch: channel<u64> = channel()
+val t = spawn produce(ch, 4)
+var sum = 0u64
+for i in 0..4u64 {
+sum = sum + recv(ch)
+}
+sum * 10 + join(t)
+}
+        "#;
+        let mut program = frontend::Parser::new(code).parse_program().unwrap();
+        frontend::literal::resolve_program(&mut program).unwrap();
+        let mut p = Processor::new();
+        let mut ctx = TypeCheckContext::new();
+        p.declare_native(&mut ctx);
+        assert_eq!(Ok(()), ctx.check_program(&program));
+        assert_eq!(Ok(Object::UInt64(144)), p.execute_program(&program));
+
+        // a closed channel fails once it is empty
+        let closed = code.replace("0..4u64", "0..5u64");
+        let program = frontend::Parser::new(&closed).parse_program().unwrap();
+        let err = p.execute_program(&program).unwrap_err();
+        assert_eq!("recv: channel 1 is closed", err.to_string());
+    }
+
+    #[test]
+    fn execute_nested_function() {
+        let code = r#"
+fn twice(n: u64) -> u64 {
+n * 2
+}
+
+fn apply(n: u64) -> u64 {
+twice(n)
+}
+
+fn main() -> u64 {
+fn twice(n: u64) -> u64 {
+n * 3
+}
+fn fact(n: u64) -> u64 {
+if n == 0 { 1 } else { n * fact(n - 1u64) }
+}
+val t = spawn fact(4)
+twice(1) * 1000 + apply(1) * 100 + join(t)
+}
+        "#;
+        let program = frontend::Parser::new(code).parse_program().unwrap();
+        let mut ctx = TypeCheckContext::new();
+        let mut p = Processor::new();
+        p.declare_native(&mut ctx);
+        assert_eq!(Ok(()), ctx.check_program(&program));
+        // `apply` calls the outer `twice`, not the one of its caller
+        assert_eq!(Ok(Object::UInt64(3224)), p.execute_program(&program));
+    }
+
+    #[test]
+    fn replace_function() {
+        let code = "fn fib(n: u64) -> u64 {\nif n < 2 { n } else { fib(n - 1) + fib(n - 2) }\n}\nfn main() -> u64 {\nfib(10)\n}";
+        let program = frontend::Parser::new(code).parse_program().unwrap();
+        let mut p = Processor::new();
+        assert_eq!(Ok(Object::UInt64(55)), p.execute_program(&program));
+        evaluate(&mut p, "val kept = 1u64");
+
+        // the new body is in a pool of its own, e.g. a line of the REPL
+        let (e, pool) = frontend::Parser::new("fn fib(n: u64) -> u64 { n * 2u64 }").parse_expression().unwrap();
+        let f = match pool.get(e.0 as usize) {
+            Some(Expr::Function(f)) => f.as_ref().clone(),
+            x => panic!("{:?}", x),
+        };
+        p.replace_function(&pool, &f);
+        assert_eq!(Ok(Object::UInt64(20)), p.evaluate_function(&program.expression, "main", &[]));
+        assert_eq!(Object::UInt64(21), evaluate(&mut p, "fib(10u64) + kept"));
+    }
+
+    // `dbg` output kept for the test
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn execute_dbg() {
+        let code = "fn main() -> u64 {\nval a = 2u64\ndbg(a * 3) + 1\n}";
+        let program = frontend::Parser::new(code).parse_program().unwrap();
+        let output = Rc::new(RefCell::new(vec![]));
+        let mut p = Processor::new();
+        p.set_dbg_output(Box::new(Output(output.clone())));
+        p.set_source("a.toy", code);
+        assert_eq!(Ok(Object::UInt64(7)), p.execute_program(&program));
+        assert_eq!("a.toy:3: a * 3 = 6\n", String::from_utf8_lossy(&output.borrow()));
+
+        // without the source only the value is known
+        output.borrow_mut().clear();
+        let mut p = Processor::new();
+        p.set_dbg_output(Box::new(Output(output.clone())));
+        assert_eq!(Object::Bool(true), evaluate(&mut p, "dbg(1 < 2)"));
+        assert_eq!("dbg: true\n", String::from_utf8_lossy(&output.borrow()));
+
+        // the sandbox doesn't allow the output
+        output.borrow_mut().clear();
+        p.set_policy(ExecutionPolicy::sandboxed());
+        assert_eq!(Err(InterpreterError::PermissionDenied(Capability::Io)), p.execute_program(&program));
+        assert!(output.borrow().is_empty());
     }
 }
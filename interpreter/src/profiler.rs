@@ -0,0 +1,62 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct FunctionProfile {
+    pub calls: u64,
+    // inclusive time: callees are included, and a recursive call is
+    // measured once at the outermost level
+    pub total: Duration,
+}
+
+// Call counts and time per function, recorded by the processor
+// when profiling is enabled
+#[derive(Debug, Default)]
+pub struct Profiler {
+    functions: HashMap<String, FunctionProfile>,
+    depth: HashMap<String, u32>,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn enter(&mut self, name: &str) -> bool {
+        self.functions.entry(name.to_string()).or_default().calls += 1;
+        let depth = self.depth.entry(name.to_string()).or_insert(0);
+        *depth += 1;
+        *depth == 1
+    }
+
+    pub(crate) fn exit(&mut self, name: &str, elapsed: Option<Duration>) {
+        if let Some(depth) = self.depth.get_mut(name) {
+            *depth -= 1;
+        }
+        if let (Some(elapsed), Some(f)) = (elapsed, self.functions.get_mut(name)) {
+            f.total += elapsed;
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&FunctionProfile> {
+        self.functions.get(name)
+    }
+
+    // Sorted by total time, longest first
+    pub fn report(&self) -> Vec<(&str, &FunctionProfile)> {
+        let mut report: Vec<_> = self.functions.iter().map(|(name, f)| (name.as_str(), f)).collect();
+        report.sort_by(|a, b| b.1.total.cmp(&a.1.total).then(a.0.cmp(b.0)));
+        report
+    }
+}
+
+impl fmt::Display for Profiler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{:<24} {:>10} {:>14}", "function", "calls", "total (us)")?;
+        for (name, profile) in self.report() {
+            writeln!(f, "{:<24} {:>10} {:>14}", name, profile.calls, profile.total.as_micros())?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,456 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use frontend::ast::{Node, Program};
+use frontend::diagnostic::{Diagnostic, ErrorFormatter};
+use frontend::line::LineIndex;
+use frontend::type_checker::TypeCheckContext;
+use frontend::ParseError;
+use crate::timings;
+
+// Project of several modules described by a `toy.toml` manifest:
+//
+//   [package]
+//   name = "app"
+//   entry = "src/main.toy"       # module defining `main`, optional for a library
+//   source-dirs = ["src"]        # default
+//
+//   [dependencies]
+//   util = { path = "../util" }  # another toylang package
+//
+// Each `.toy` file under the source directories (and those of the
+// dependencies) is a module. The language has no namespaces yet, so the
+// functions of all the modules share one scope and a name can be defined
+// only once. The modules are parsed in parallel and merged into one
+// program; its source offsets are those of the modules laid out in order
+// (see `Project::source`).
+//
+// A module may `include "file.toy"` (relative to the module) which adds
+// the file as a module before it, once even if several modules include
+// it. A file outside of a project with includes is built as a project of
+// its own (`Project::include`).
+
+pub const MANIFEST: &str = "toy.toml";
+
+#[derive(Debug, PartialEq)]
+pub struct Manifest {
+    pub name: String,
+    pub entry: Option<PathBuf>,
+    pub source_dirs: Vec<PathBuf>,
+    pub dependencies: Vec<(String, PathBuf)>,
+}
+
+impl Manifest {
+    // The subset of TOML used by the manifest: tables, strings, arrays of
+    // strings and inline tables of strings
+    pub fn parse(text: &str) -> Result<Manifest, String> {
+        let mut manifest = Manifest { name: String::new(), entry: None, source_dirs: vec![], dependencies: vec![] };
+        let mut table = String::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split_once('#').map_or(line, |(line, _)| line).trim();
+            if line.is_empty() {
+                continue;
+            }
+            let error = |message: &str| format!("{}:{}: {}", MANIFEST, n + 1, message);
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                table = name.trim().to_string();
+                continue;
+            }
+            let (key, value) = line.split_once('=').ok_or_else(|| error("expected key = value"))?;
+            let (key, value) = (key.trim(), value.trim());
+            match (table.as_str(), key) {
+                ("package", "name") => manifest.name = string(value).ok_or_else(|| error("name must be a string"))?,
+                ("package", "entry") => manifest.entry = Some(string(value).ok_or_else(|| error("entry must be a string"))?.into()),
+                ("package", "source-dirs") => {
+                    let dirs = array(value).ok_or_else(|| error("source-dirs must be an array of strings"))?;
+                    manifest.source_dirs = dirs.into_iter().map(PathBuf::from).collect();
+                }
+                ("dependencies", name) => {
+                    let path = inline_table(value).and_then(|table| table.get("path").cloned())
+                        .ok_or_else(|| error("a dependency must be { path = \"...\" }"))?;
+                    manifest.dependencies.push((name.to_string(), path.into()));
+                }
+                _ => return Err(error(&format!("unknown key `{}` in [{}]", key, table))),
+            }
+        }
+        if manifest.name.is_empty() {
+            return Err(format!("{}: package name is missing", MANIFEST));
+        }
+        if manifest.source_dirs.is_empty() {
+            manifest.source_dirs.push(PathBuf::from("src"));
+        }
+        Ok(manifest)
+    }
+}
+
+fn string(value: &str) -> Option<String> {
+    let s = value.strip_prefix('"')?.strip_suffix('"')?;
+    (!s.contains('"')).then(|| s.to_string())
+}
+
+fn array(value: &str) -> Option<Vec<String>> {
+    let items = value.strip_prefix('[')?.strip_suffix(']')?.trim();
+    items.split(',').map(str::trim).filter(|item| !item.is_empty()).map(string).collect()
+}
+
+fn inline_table(value: &str) -> Option<HashMap<String, String>> {
+    let items = value.strip_prefix('{')?.strip_suffix('}')?.trim();
+    items.split(',').filter(|item| !item.trim().is_empty()).map(|item| {
+        let (key, value) = item.split_once('=')?;
+        Some((key.trim().to_string(), string(value.trim())?))
+    }).collect()
+}
+
+// Errors of `Project::build`, located by file and line
+#[derive(Debug, PartialEq)]
+pub enum BuildError {
+    Module(Vec<String>), // a module doesn't parse, or the modules conflict
+    Check(Vec<String>),  // type errors
+}
+
+impl BuildError {
+    pub fn messages(&self) -> &[String] {
+        match self {
+            BuildError::Module(messages) | BuildError::Check(messages) => messages,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Module {
+    pub package: String,
+    pub path: PathBuf,
+    pub source: String,
+    offset: usize, // of the source in `Project::source`
+}
+
+#[derive(Debug)]
+pub struct Project {
+    pub manifest: Manifest,
+    pub modules: Vec<Module>,
+    entry: Option<PathBuf>,
+    files: HashSet<PathBuf>, // of the modules, canonicalized
+}
+
+impl Project {
+    // `path` is the directory of the manifest or the manifest itself
+    pub fn load(path: &Path) -> Result<Project, String> {
+        let dir = if path.is_dir() { path } else { path.parent().unwrap_or(Path::new(".")) };
+        let mut project = Project { manifest: read_manifest(dir)?, modules: vec![], entry: None, files: HashSet::new() };
+        project.entry = project.manifest.entry.as_ref().map(|entry| dir.join(entry));
+        let mut loaded = HashSet::new();
+        let name = project.manifest.name.clone();
+        project.load_package(dir, &name, &mut vec![], &mut loaded)?;
+        Ok(project)
+    }
+
+    // Modules of the package and of its dependencies, which are loaded
+    // first. `stack` is the chain of packages to detect a cycle.
+    fn load_package(&mut self, dir: &Path, name: &str, stack: &mut Vec<PathBuf>, loaded: &mut HashSet<PathBuf>) -> Result<(), String> {
+        let key = dir.canonicalize().map_err(|e| format!("cannot read {}: {}", dir.display(), e))?;
+        if stack.contains(&key) {
+            return Err(format!("dependency cycle at package `{}`", name));
+        }
+        if !loaded.insert(key.clone()) {
+            return Ok(()); // a dependency shared by several packages
+        }
+        let manifest = read_manifest(dir)?;
+        stack.push(key);
+        for (dependency, path) in &manifest.dependencies {
+            self.load_package(&dir.join(path), dependency, stack, loaded)?;
+        }
+        stack.pop();
+
+        for source_dir in &manifest.source_dirs {
+            let mut files = vec![];
+            find_modules(&dir.join(source_dir), &mut files)?;
+            files.sort();
+            for path in files {
+                let source = std::fs::read_to_string(&path).map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+                self.add_module(&path, source, &manifest.name, &mut vec![])?;
+            }
+        }
+        Ok(())
+    }
+
+    // Project of the file `path` whose text is `source`, and of the files
+    // it includes
+    pub fn include(path: &Path, source: String) -> Result<Project, String> {
+        let name = path.file_stem().map_or(String::new(), |s| s.to_string_lossy().to_string());
+        let manifest = Manifest { name: name.clone(), entry: Some(path.to_path_buf()), source_dirs: vec![], dependencies: vec![] };
+        let mut project = Project { manifest, modules: vec![], entry: Some(path.to_path_buf()), files: HashSet::new() };
+        project.add_module(path, source, &name, &mut vec![])?;
+        Ok(project)
+    }
+
+    // Add the module after the files it includes, unless it is added
+    // already. `stack` is the chain of including modules to detect a cycle.
+    // The includes are found by parsing the module, which is parsed again
+    // by `build`; it reports the errors of the parser.
+    fn add_module(&mut self, path: &Path, source: String, package: &str, stack: &mut Vec<PathBuf>) -> Result<(), String> {
+        let key = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        if let Some(i) = stack.iter().position(|p| *p == key) {
+            let cycle: Vec<String> = stack[i..].iter().chain([&key]).map(|p| p.file_name().unwrap_or_default().to_string_lossy().to_string()).collect();
+            return Err(format!("include cycle {}", cycle.join(" -> ")));
+        }
+        if !self.files.insert(key.clone()) {
+            return Ok(());
+        }
+        let includes = frontend::Parser::new(&source).parse_program().map_or(vec![], |program| program.import);
+        stack.push(key);
+        for include in includes {
+            let included = path.parent().unwrap_or(Path::new("")).join(&include);
+            let text = std::fs::read_to_string(&included)
+                .map_err(|e| format!("{}: cannot include {}: {}", path.display(), include, e))?;
+            self.add_module(&included, text, package, stack)?;
+        }
+        stack.pop();
+        let offset = self.modules.last().map_or(0, |m| m.offset + m.source.len() + 1);
+        self.modules.push(Module { package: package.to_string(), path: path.to_path_buf(), source, offset });
+        Ok(())
+    }
+
+    // Sources of all the modules separated by a newline, the offsets of the
+    // built program point into it
+    pub fn source(&self) -> String {
+        self.modules.iter().map(|m| m.source.as_str()).collect::<Vec<_>>().join("\n")
+    }
+
+    fn module_at(&self, offset: usize) -> Option<&Module> {
+        self.modules.iter().rev().find(|m| m.offset <= offset)
+    }
+
+    // File, line and column of an offset of the built program
+    pub fn locate(&self, offset: usize) -> Option<(&Path, usize, usize)> {
+        let module = self.module_at(offset)?;
+        let (line, column) = LineIndex::new(&module.source).line_col(offset - module.offset);
+        Some((&module.path, line, column))
+    }
+
+    // Each module is parsed into its own program, on up to `threads`
+    // threads. The result is in the order of the modules.
+    fn parse_modules(&self, threads: usize) -> Vec<Result<Program, String>> {
+        let parse = |module: &Module| frontend::Parser::new(&module.source).parse_program().map_err(|e| match ParseError::code_of(&e) {
+            Some(code) => format!("{}: parse failed [{}] {}", module.path.display(), code, e),
+            None => format!("{}: parse failed {}", module.path.display(), e),
+        });
+        let threads = threads.min(self.modules.len());
+        if threads <= 1 {
+            return self.modules.iter().map(parse).collect();
+        }
+        let chunk = self.modules.len().div_ceil(threads);
+        std::thread::scope(|scope| {
+            let workers: Vec<_> = self.modules.chunks(chunk)
+                .map(|modules| scope.spawn(move || modules.iter().map(parse).collect::<Vec<_>>()))
+                .collect();
+            workers.into_iter().flat_map(|worker| worker.join().unwrap_or_else(|e| std::panic::resume_unwind(e))).collect()
+        })
+    }
+
+    // Parse, configure and type check the modules as one program. The
+    // builtins and the features must be set in `ctx`.
+    pub fn build(&self, ctx: &mut TypeCheckContext) -> Result<Program, BuildError> {
+        let mut program: Option<Program> = None;
+        let mut defined: HashMap<String, &Path> = HashMap::new();
+        let mut errors = vec![];
+        let parsed_modules = tracing::info_span!(timings::PARSE).in_scope(|| {
+            self.parse_modules(std::thread::available_parallelism().map_or(1, |n| n.get()))
+        });
+        for (module, parsed) in self.modules.iter().zip(parsed_modules) {
+            let mut parsed = match parsed {
+                Ok(parsed) => parsed,
+                Err(e) => {
+                    errors.push(e);
+                    continue;
+                }
+            };
+            frontend::cfg::configure(&mut parsed, ctx.features());
+            for f in &parsed.function {
+                if let Some(other) = defined.insert(f.name.clone(), &module.path) {
+                    errors.push(format!("{}: `{}` is already defined in {}", module.path.display(), f.name, other.display()));
+                }
+            }
+            if defines_main(&parsed) && self.entry.as_ref().is_some_and(|entry| !same_file(entry, &module.path)) {
+                errors.push(format!("{}: `main` must be defined in the entry module", module.path.display()));
+            }
+            match &mut program {
+                Some(program) => program.append(parsed, module.offset),
+                None => program = Some(parsed),
+            }
+        }
+        if let Some(entry) = &self.entry {
+            if !self.modules.iter().any(|m| same_file(entry, &m.path)) {
+                errors.push(format!("entry module {} is not found", entry.display()));
+            }
+        }
+        let program = match program {
+            Some(program) if errors.is_empty() => program,
+            None if errors.is_empty() => return Err(BuildError::Module(vec![format!("package `{}` has no module", self.manifest.name)])),
+            _ => return Err(BuildError::Module(errors)),
+        };
+        if let Err(type_errors) = tracing::info_span!(timings::CHECK).in_scope(|| ctx.check_program(&program)) {
+            // rendered with the line of the module
+            return Err(BuildError::Check(type_errors.iter().map(|e| {
+                let mut diagnostic = Diagnostic::from(e);
+                match e.location.as_ref().and_then(|node| self.module_at(node.start())) {
+                    Some(module) => {
+                        diagnostic.location = e.location.as_ref().map(|node| Node::new(node.start() - module.offset, node.end() - module.offset));
+                        let mut formatter = ErrorFormatter::new(&module.source);
+                        formatter.set_file(&module.path.display().to_string());
+                        formatter.format(&diagnostic)
+                    }
+                    None => ErrorFormatter::new("").format(&diagnostic),
+                }
+            }).collect()));
+        }
+        Ok(program)
+    }
+}
+
+fn defines_main(program: &Program) -> bool {
+    program.function.iter().any(|f| f.name == "main")
+}
+
+fn same_file(a: &Path, b: &Path) -> bool {
+    a == b || a.canonicalize().ok().is_some_and(|a| b.canonicalize().ok() == Some(a))
+}
+
+fn read_manifest(dir: &Path) -> Result<Manifest, String> {
+    let path = dir.join(MANIFEST);
+    let text = std::fs::read_to_string(&path).map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+    Manifest::parse(&text).map_err(|e| format!("{}: {}", dir.display(), e))
+}
+
+// `.toy` files in the directory and its subdirectories
+fn find_modules(dir: &Path, files: &mut Vec<PathBuf>) -> Result<(), String> {
+    let entries = std::fs::read_dir(dir).map_err(|e| format!("cannot read {}: {}", dir.display(), e))?;
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            find_modules(&path, files)?;
+        } else if path.extension().is_some_and(|ext| ext == "toy") {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_manifest() {
+        let manifest = Manifest::parse(r#"
+[package]
+name = "app"  # the application
+entry = "src/main.toy"
+
+[dependencies]
+util = { path = "../util" }
+"#).unwrap();
+        assert_eq!(Manifest {
+            name: "app".to_string(),
+            entry: Some(PathBuf::from("src/main.toy")),
+            source_dirs: vec![PathBuf::from("src")],
+            dependencies: vec![("util".to_string(), PathBuf::from("../util"))],
+        }, manifest);
+        let manifest = Manifest::parse("[package]\nname = \"lib\"\nsource-dirs = [\"a\", \"b\"]").unwrap();
+        assert_eq!(vec![PathBuf::from("a"), PathBuf::from("b")], manifest.source_dirs);
+
+        assert_eq!(Err("toy.toml:2: unknown key `version` in [package]".to_string()), Manifest::parse("[package]\nversion = \"1\""));
+        assert!(Manifest::parse("[dependencies]\nutil = \"../util\"").is_err());
+        assert!(Manifest::parse("[package]\nentry = \"main.toy\"").is_err());
+    }
+
+    fn write(dir: &Path, files: &[(&str, &str)]) {
+        for (path, text) in files {
+            let path = dir.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, text).unwrap();
+        }
+    }
+
+    #[test]
+    fn build_project() {
+        let dir = std::env::temp_dir().join(format!("toylang-project-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        write(&dir, &[
+            ("util/toy.toml", "[package]\nname = \"util\"\n"),
+            ("util/src/math.toy", "fn double(x: u64) -> u64 {\n    x * 2u64\n}\n"),
+            ("app/toy.toml", "[package]\nname = \"app\"\nentry = \"src/main.toy\"\n\n[dependencies]\nutil = { path = \"../util\" }\n"),
+            ("app/src/main.toy", "fn main() -> u64 {\n    double(triple(1u64))\n}\n"),
+            ("app/src/lib/triple.toy", "fn triple(x: u64) -> u64 {\n    x * 3u64\n}\n"),
+        ]);
+        let project = Project::load(&dir.join("app")).unwrap();
+        let modules: Vec<_> = project.modules.iter().map(|m| (m.package.as_str(), m.path.file_name().unwrap().to_str().unwrap())).collect();
+        assert_eq!(vec![("util", "math.toy"), ("app", "triple.toy"), ("app", "main.toy")], modules);
+
+        let program = project.build(&mut TypeCheckContext::new()).unwrap();
+        let mut p = crate::processor::Processor::new();
+        assert_eq!(Ok(crate::object::Object::UInt64(6)), p.execute_program(&program));
+
+        // errors are located in the module
+        write(&dir, &[("app/src/lib/triple.toy", "fn triple(x: u64) -> u64 {\n    x * 3i64\n}\n")]);
+        let errors = match Project::load(&dir.join("app/toy.toml")).unwrap().build(&mut TypeCheckContext::new()) {
+            Err(BuildError::Check(errors)) => errors,
+            x => panic!("unexpected {:?}", x.err()),
+        };
+        assert_eq!(1, errors.len());
+        assert!(errors[0].contains("triple.toy:2:") && errors[0].contains("[E0001]"), "{}", errors[0]);
+
+        write(&dir, &[("app/src/lib/triple.toy", "fn double(x: u64) -> u64 {\n    x\n}\nfn main() -> u64 {\n    0u64\n}\n")]);
+        let error = Project::load(&dir.join("app")).unwrap().build(&mut TypeCheckContext::new()).err().unwrap();
+        let errors = error.messages();
+        assert!(errors[0].contains("`double` is already defined in ") && errors[0].ends_with("math.toy"), "{}", errors[0]);
+        assert!(errors[1].ends_with("`main` must be defined in the entry module"));
+
+        // the modules are parsed in parallel, the errors stay in their order
+        write(&dir, &[("app/src/lib/triple.toy", "fn triple(x: u64) u64 {\n    x\n}\n"), ("util/src/math.toy", "val x = 1u64\n")]);
+        let project = Project::load(&dir.join("app")).unwrap();
+        let parse_errors = |threads| project.parse_modules(threads).into_iter().map(|m| m.err()).collect::<Vec<_>>();
+        assert_eq!(parse_errors(1), parse_errors(3));
+        let error = project.build(&mut TypeCheckContext::new()).err().unwrap();
+        let errors = error.messages();
+        assert_eq!(2, errors.len());
+        assert!(errors[0].contains("math.toy: parse failed [E0101]") && errors[1].contains("triple.toy: parse failed [E0100]"), "{:?}", errors);
+
+        write(&dir, &[("util/toy.toml", "[package]\nname = \"util\"\n\n[dependencies]\napp = { path = \"../app\" }\n")]);
+        assert_eq!("dependency cycle at package `app`", Project::load(&dir.join("app")).unwrap_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn include_files() {
+        let dir = std::env::temp_dir().join(format!("toylang-include-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        write(&dir, &[
+            ("main.toy", "include \"lib/a.toy\"\ninclude \"lib/b.toy\"\nfn main() -> u64 {\n    a() + b()\n}\n"),
+            ("lib/a.toy", "include \"c.toy\"\nfn a() -> u64 {\n    c() * 2u64\n}\n"),
+            ("lib/b.toy", "include \"c.toy\"\nfn b() -> u64 {\n    c() + 1i64\n}\n"),
+            ("lib/c.toy", "fn c() -> u64 {\n    3u64\n}\n"),
+        ]);
+        let main = dir.join("main.toy");
+        let project = Project::include(&main, std::fs::read_to_string(&main).unwrap()).unwrap();
+        let modules: Vec<_> = project.modules.iter().map(|m| m.path.file_name().unwrap().to_str().unwrap()).collect();
+        assert_eq!(vec!["c.toy", "a.toy", "b.toy", "main.toy"], modules);
+
+        // errors are located in the included file
+        let error = project.build(&mut TypeCheckContext::new()).err().unwrap();
+        let errors = error.messages();
+        assert!(errors[0].contains("b.toy:3:") && errors[0].contains("[E0001]"), "{:?}", errors);
+
+        write(&dir, &[("lib/b.toy", "include \"c.toy\"\nfn b() -> u64 {\n    c() + 1u64\n}\n")]);
+        let project = Project::include(&main, std::fs::read_to_string(&main).unwrap()).unwrap();
+        let program = project.build(&mut TypeCheckContext::new()).unwrap();
+        let mut p = crate::processor::Processor::new();
+        assert_eq!(Ok(crate::object::Object::UInt64(10)), p.execute_program(&program));
+
+        write(&dir, &[("lib/c.toy", "include \"../main.toy\"\nfn c() -> u64 {\n    3u64\n}\n")]);
+        let error = Project::include(&main, std::fs::read_to_string(&main).unwrap()).err().unwrap();
+        assert_eq!("include cycle main.toy -> a.toy -> c.toy -> main.toy", error);
+        write(&dir, &[("lib/c.toy", "include \"d.toy\"\n")]);
+        let error = Project::include(&main, std::fs::read_to_string(&main).unwrap()).err().unwrap();
+        assert!(error.contains("c.toy: cannot include d.toy: "), "{}", error);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
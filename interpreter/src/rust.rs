@@ -0,0 +1,565 @@
+use std::collections::{HashMap, HashSet};
+use frontend::ast::*;
+
+// Rust backend, to turn a toylang program into the source of a crate.
+//
+// `transpile` emits a Rust function for each function of a type checked
+// program; blocks, `if` and loops are expressions in both languages, so
+// the structure is kept as it is. Integer types map to i64 and u64 and
+// arithmetic uses the plain operators, which panic on overflow (in debug
+// builds, or with `overflow-checks = true`) where the interpreter stops
+// with an error. Builtins become methods of the integer types or helper
+// functions emitted with the program.
+//
+// Names are mangled where Rust would read them differently: keywords,
+// and variables which hide a function (Rust has one namespace for both).
+// `main` returning a value is renamed and called by a generated `main`
+// which exits with the value as the interpreter does.
+
+const KEYWORDS: &[&str] = &[
+    "abstract", "as", "async", "await", "become", "box", "break", "const", "continue", "do", "dyn", "else",
+    "enum", "extern", "false", "final", "fn", "for", "gen", "if", "impl", "in", "let", "loop", "macro",
+    "match", "mod", "move", "mut", "override", "priv", "pub", "ref", "return", "static", "struct", "trait",
+    "true", "try", "type", "typeof", "unsafe", "unsized", "use", "virtual", "where", "while", "yield",
+];
+
+// keywords which cannot be raw identifiers
+const PATH_KEYWORDS: &[&str] = &["crate", "self", "Self", "super"];
+
+// builtins of `crate::builtin` which are not methods of the integer types
+const HELPERS: &[(&str, &str)] = &[
+    ("random_u64", r#"thread_local! {
+    static RANDOM: std::cell::Cell<u64> = std::cell::Cell::new(
+        std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64));
+}
+
+// SplitMix64
+fn random_u64() -> u64 {
+    RANDOM.with(|state| {
+        let s = state.get().wrapping_add(0x9e37_79b9_7f4a_7c15);
+        state.set(s);
+        let mut z = s;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    })
+}
+"#),
+    ("random_range", r#"// lo <= x < hi
+fn random_range<T: Copy + Into<i128> + TryFrom<i128>>(lo: T, hi: T) -> T {
+    let (lo, hi) = (lo.into(), hi.into());
+    assert!(lo < hi, "empty range {}..{}", lo, hi);
+    let span = (hi - lo) as u128;
+    let x = lo + ((random_u64() as u128 * span) >> 64) as i128;
+    T::try_from(x).ok().unwrap()
+}
+"#),
+    ("now_millis", r#"fn now_millis() -> u64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64)
+}
+"#),
+    ("monotonic_nanos", r#"fn monotonic_nanos() -> u64 {
+    static START: std::sync::OnceLock<std::time::Instant> = std::sync::OnceLock::new();
+    START.get_or_init(std::time::Instant::now).elapsed().as_nanos() as u64
+}
+"#),
+];
+
+pub type Unsupported = String;
+
+// Rust type of a toylang type
+pub fn rust_type(ty: &Type) -> Result<String, Unsupported> {
+    match ty {
+        Type::Int64 => Ok("i64".to_string()),
+        Type::UInt64 => Ok("u64".to_string()),
+        Type::Bool => Ok("bool".to_string()),
+        Type::Unit => Ok("()".to_string()),
+        Type::Identifier(name) => Ok(mangle(name)),
+        Type::Unknown => Err("unknown type".to_string()),
+        Type::Task(_) => Err("task type".to_string()),
+        Type::Channel(_) => Err("channel type".to_string()),
+    }
+}
+
+// `name` as a Rust identifier
+pub fn mangle(name: &str) -> String {
+    if PATH_KEYWORDS.contains(&name) {
+        format!("{}_", name)
+    } else if KEYWORDS.contains(&name) {
+        format!("r#{}", name)
+    } else {
+        name.to_string()
+    }
+}
+
+// Rust source of the program, the helpers for builtins first.
+// The program must be type checked.
+pub fn transpile(program: &Program) -> Result<String, Unsupported> {
+    let mut functions = HashMap::new();
+    for f in &program.function {
+        let return_type = f.return_type.clone().unwrap_or(Type::Unit);
+        functions.insert(f.name.clone(), (mangle(&f.name), return_type));
+    }
+    // the value of `main` is the exit status, which a Rust `main` cannot return
+    let wrap_main = matches!(functions.get("main"), Some((_, ty)) if *ty != Type::Unit);
+    if wrap_main {
+        let mut name = "toy_main".to_string();
+        while functions.contains_key(&name) {
+            name += "_";
+        }
+        functions.get_mut("main").unwrap().0 = name;
+    }
+
+    let mut used = HashSet::new();
+    let mut code = String::new();
+    for f in &program.function {
+        let mut e = Emitter::new(&program.expression, &functions, &mut used);
+        e.rename(f);
+        let mut parameter = vec![];
+        for (name, ty) in &f.parameter {
+            parameter.push(format!("{}: {}", e.declare(name, ty.clone()), rust_type(ty)?));
+        }
+        let (return_type, body) = e.function(f).map_err(|reason| format!("`{}`: {}", f.name, reason))?;
+        let (name, ty) = &functions[&f.name];
+        match ty {
+            Type::Unit => code += &format!("\nfn {}({}) {}\n", name, parameter.join(", "), body),
+            _ if *ty != return_type => return Err(format!("`{}` returns {:?} but {:?}", f.name, ty, return_type)),
+            _ => code += &format!("\nfn {}({}) -> {} {}\n", name, parameter.join(", "), rust_type(ty)?, body),
+        }
+    }
+    if wrap_main {
+        let (name, ty) = &functions["main"];
+        let status = match ty {
+            Type::Bool => "if result { 0 } else { 1 }",
+            _ => "(result & 0xff) as i32",
+        };
+        code += &format!("\nfn main() {{\n    let result = {}();\n    println!(\"Result: {{}}\", result);\n    std::process::exit({});\n}}\n", name, status);
+    }
+
+    let mut out = String::new();
+    for (name, helper) in HELPERS {
+        // random_range needs random_u64
+        let needed = used.contains(*name) || (*name == "random_u64" && used.contains("random_range"));
+        if needed && !functions.contains_key(*name) {
+            out += helper;
+            out += "\n";
+        }
+    }
+    if out.is_empty() {
+        return Ok(code);
+    }
+    Ok(out.trim_end().to_string() + "\n" + &code)
+}
+
+struct Emitter<'a> {
+    pool: &'a ExprPool,
+    functions: &'a HashMap<String, (String, Type)>,
+    used: &'a mut HashSet<String>, // builtins called by the program
+    renamed: HashMap<String, String>, // variables which hide a function
+    scopes: Vec<HashMap<String, (String, Type)>>,
+}
+
+impl<'a> Emitter<'a> {
+    fn new(pool: &'a ExprPool, functions: &'a HashMap<String, (String, Type)>, used: &'a mut HashSet<String>) -> Self {
+        Emitter { pool, functions, used, renamed: HashMap::new(), scopes: vec![HashMap::new()] }
+    }
+
+    fn get(&self, e: ExprRef) -> Result<&'a Expr, Unsupported> {
+        self.pool.get(e.0 as usize).ok_or(format!("invalid expression reference {:?}", e))
+    }
+
+    // Choose names for the variables named like a function, different
+    // from all the names in the program
+    fn rename(&mut self, f: &Function) {
+        let mut names: HashSet<String> = f.parameter.iter().map(|(name, _)| name.clone()).collect();
+        for e in &self.pool.0 {
+            match e {
+                Expr::Val(name, _, _) | Expr::Var(name, _, _) | Expr::Identifier(name) | Expr::For(name, ..) => {
+                    names.insert(name.clone());
+                }
+                _ => (),
+            }
+        }
+        let builtin = |name: &str| HELPERS.iter().any(|(helper, _)| *helper == name);
+        for name in &names {
+            if self.functions.contains_key(name) || builtin(name) {
+                let mut renamed = format!("{}_", name);
+                while names.contains(&renamed) || self.functions.contains_key(&renamed) {
+                    renamed += "_";
+                }
+                self.renamed.insert(name.clone(), renamed);
+            }
+        }
+    }
+
+    fn declare(&mut self, name: &str, ty: Type) -> String {
+        let rust = match self.renamed.get(name) {
+            Some(renamed) => renamed.clone(),
+            None => mangle(name),
+        };
+        self.scopes.last_mut().unwrap().insert(name.to_string(), (rust.clone(), ty));
+        rust
+    }
+
+    fn lookup(&self, name: &str) -> Result<(String, Type), Unsupported> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name).cloned())
+            .ok_or(format!("undefined variable `{}`", name))
+    }
+
+    fn function(&mut self, f: &Function) -> Result<(Type, String), Unsupported> {
+        let value = !matches!(f.return_type, None | Some(Type::Unit));
+        match self.get(f.code)? {
+            Expr::Block(expressions) => self.block(expressions, 0, value),
+            _ => self.block(&[f.code], 0, value),
+        }
+    }
+
+    // `{ ... }` indented by `indent` levels; the last expression is the
+    // value if `value`, otherwise the block is unit
+    fn block(&mut self, expressions: &[ExprRef], indent: usize, value: bool) -> Result<(Type, String), Unsupported> {
+        self.scopes.push(HashMap::new());
+        let result = self.statements(expressions, indent + 1, value);
+        self.scopes.pop();
+        let (ty, lines) = result?;
+        if lines.is_empty() {
+            return Ok((Type::Unit, "{}".to_string()));
+        }
+        Ok((ty, format!("{{\n{}{}}}", lines, "    ".repeat(indent))))
+    }
+
+    fn statements(&mut self, expressions: &[ExprRef], indent: usize, value: bool) -> Result<(Type, String), Unsupported> {
+        let mut lines = String::new();
+        let mut ty = Type::Unit;
+        for (i, e) in expressions.iter().enumerate() {
+            let last = value && i + 1 == expressions.len();
+            let line = match self.get(*e)? {
+                Expr::Val(name, declared, Some(rhs)) | Expr::Var(name, declared, Some(rhs)) => {
+                    let keyword = if matches!(self.get(*e)?, Expr::Val(..)) { "let" } else { "let mut" };
+                    let (rhs_ty, rhs) = self.expr(*rhs, indent, true)?;
+                    let name = self.declare(name, rhs_ty.clone());
+                    ty = Type::Unit;
+                    match declared {
+                        Some(declared) if *declared != Type::Unknown =>
+                            format!("{} {}: {} = {};", keyword, name, rust_type(declared)?, rhs),
+                        _ => format!("{} {} = {};", keyword, name, rhs),
+                    }
+                }
+                expr => {
+                    let block_like = matches!(expr, Expr::Block(_) | Expr::IfElse(..) | Expr::While(..) | Expr::For(..));
+                    let (expr_ty, code) = self.expr(*e, indent, last)?;
+                    ty = expr_ty;
+                    if last || (block_like && ty == Type::Unit) {
+                        code
+                    } else {
+                        code + ";"
+                    }
+                }
+            };
+            lines += &format!("{}{}\n", "    ".repeat(indent), line);
+        }
+        if !value {
+            ty = Type::Unit;
+        }
+        Ok((ty, lines))
+    }
+
+    // `e` as an operand of an operator or a receiver of a method call
+    fn operand(&mut self, e: ExprRef, indent: usize, parent: Option<(&Operator, bool)>) -> Result<(Type, String), Unsupported> {
+        let (ty, code) = self.expr(e, indent, true)?;
+        let parens = match self.get(e)? {
+            Expr::Binary(op, _, _) => match parent {
+                // `rhs` is whether `e` is the right operand
+                Some((parent, rhs)) => {
+                    let (p, q) = (precedence(op), precedence(parent));
+                    p < q || (p == q && (rhs || p == precedence(&Operator::EQ)))
+                }
+                None => true,
+            },
+            Expr::Int64(i) => *i < 0,
+            Expr::UInt64(_) | Expr::Identifier(_) | Expr::Call(..) => false,
+            _ => true,
+        };
+        if parens {
+            Ok((ty, format!("({})", code)))
+        } else {
+            Ok((ty, code))
+        }
+    }
+
+    fn expr(&mut self, e: ExprRef, indent: usize, value: bool) -> Result<(Type, String), Unsupported> {
+        match self.get(e)? {
+            // with the suffix, so that a method called on the expression has a type
+            Expr::Int64(i) => Ok((Type::Int64, format!("{}i64", i))),
+            Expr::UInt64(u) => Ok((Type::UInt64, format!("{}u64", u))),
+            Expr::Int(text) => Err(format!("unresolved integer literal {}", text)),
+            Expr::Null => Err("null has no Rust type".to_string()),
+            Expr::Identifier(name) => {
+                let (name, ty) = self.lookup(name)?;
+                Ok((ty, name))
+            }
+            Expr::Val(..) | Expr::Var(..) => self.block(&[e], indent, false),
+            Expr::Block(expressions) => self.block(expressions, indent, value),
+            Expr::Binary(Operator::Assign, lhs, rhs) => {
+                let name = match self.get(*lhs)? {
+                    Expr::Identifier(name) => self.lookup(name)?.0,
+                    x => return Err(format!("assignment to {:?}", x)),
+                };
+                let value = self.expr(*rhs, indent, true)?.1;
+                Ok((Type::Unit, format!("{} = {}", name, value)))
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                let (ty, l) = self.operand(*lhs, indent, Some((op, false)))?;
+                let r = self.operand(*rhs, indent, Some((op, true)))?.1;
+                let (ty, op) = match op {
+                    Operator::IAdd => (ty, "+"),
+                    Operator::ISub => (ty, "-"),
+                    Operator::IMul => (ty, "*"),
+                    Operator::IDiv => (ty, "/"),
+                    Operator::EQ => (Type::Bool, "=="),
+                    Operator::NE => (Type::Bool, "!="),
+                    Operator::LT => (Type::Bool, "<"),
+                    Operator::LE => (Type::Bool, "<="),
+                    Operator::GT => (Type::Bool, ">"),
+                    Operator::GE => (Type::Bool, ">="),
+                    Operator::LogicalAnd => (Type::Bool, "&&"),
+                    Operator::LogicalOr => (Type::Bool, "||"),
+                    Operator::Assign => unreachable!(),
+                };
+                Ok((ty, format!("{} {} {}", l, op, r)))
+            }
+            Expr::IfElse(cond, then_block, else_block) => {
+                let cond = self.expr(*cond, indent, true)?.1;
+                let (ty, then_code) = self.branch(*then_block, indent, value)?;
+                let code = match self.get(*else_block)? {
+                    Expr::Block(expressions) if expressions.is_empty() => format!("if {} {}", cond, then_code),
+                    Expr::IfElse(..) => {
+                        let else_code = self.expr(*else_block, indent, value)?.1;
+                        format!("if {} {} else {}", cond, then_code, else_code)
+                    }
+                    _ => {
+                        let else_code = self.branch(*else_block, indent, value)?.1;
+                        format!("if {} {} else {}", cond, then_code, else_code)
+                    }
+                };
+                Ok((ty, code))
+            }
+            Expr::While(cond, body) => {
+                let cond = self.expr(*cond, indent, true)?.1;
+                let body = self.branch(*body, indent, false)?.1;
+                Ok((Type::Unit, format!("while {} {}", cond, body)))
+            }
+            Expr::For(name, start, end, body) => {
+                let (ty, start) = self.expr(*start, indent, true)?;
+                let end = self.operand(*end, indent, Some((&Operator::LT, true)))?.1;
+                self.scopes.push(HashMap::new());
+                let name = self.declare(name, ty);
+                let body = self.branch(*body, indent, false);
+                self.scopes.pop();
+                Ok((Type::Unit, format!("for {} in {}..{} {}", name, start, end, body?.1)))
+            }
+            Expr::Call(name, args) => self.call(name, *args, indent),
+            Expr::Spawn(_) => Err("spawn".to_string()),
+            Expr::Function(f) => Err(format!("nested function `{}`", f.name)),
+        }
+    }
+
+    fn branch(&mut self, e: ExprRef, indent: usize, value: bool) -> Result<(Type, String), Unsupported> {
+        match self.get(e)? {
+            Expr::Block(expressions) => self.block(expressions, indent, value),
+            _ => self.block(&[e], indent, value),
+        }
+    }
+
+    fn call(&mut self, name: &str, args: ExprRef, indent: usize) -> Result<(Type, String), Unsupported> {
+        let args = match self.get(args)? {
+            Expr::Block(args) => args,
+            x => return Err(format!("arguments of `{}` are {:?}", name, x)),
+        };
+        if let Some((rust, ty)) = self.functions.get(name) {
+            let mut values = vec![];
+            for arg in args {
+                values.push(self.expr(*arg, indent, true)?.1);
+            }
+            return Ok((ty.clone(), format!("{}({})", rust, values.join(", "))));
+        }
+
+        // the first argument of a builtin which becomes a method is its receiver
+        let receiver = matches!(name, "abs" | "min" | "max" | "pow" | "sqrt" | "clamp");
+        let mut values = vec![];
+        let mut ty = Type::Unit;
+        for (i, arg) in args.iter().enumerate() {
+            let (arg_ty, value) = if i == 0 && receiver { self.operand(*arg, indent, None)? } else { self.expr(*arg, indent, true)? };
+            if i == 0 {
+                ty = arg_ty;
+            }
+            values.push(value);
+        }
+        let code = match (name, values.as_slice()) {
+            ("abs", [x]) if ty == Type::UInt64 => x.clone(),
+            ("abs", [x]) => format!("{}.abs()", x),
+            ("min", [a, b]) => format!("{}.min({})", a, b),
+            ("max", [a, b]) => format!("{}.max({})", a, b),
+            ("pow", [base, exp]) => format!("{}.pow(u32::try_from({}).expect(\"invalid exponent\"))", base, exp),
+            ("sqrt", [x]) => format!("{}.isqrt()", x),
+            ("clamp", [x, lo, hi]) => format!("{}.clamp({}, {})", x, lo, hi),
+            ("random_range", [lo, hi]) => {
+                self.used.insert(name.to_string());
+                format!("random_range({}, {})", lo, hi)
+            }
+            ("dbg", [x]) => format!("dbg!({})", x),
+            ("assert", [cond]) => return Ok((Type::Unit, format!("assert!({})", cond))),
+            ("print", [x]) => return Ok((Type::Unit, format!("println!(\"{{}}\", {})", x))),
+            ("to_i64", [x]) => return Ok((Type::Int64, format!("i64::try_from({}).expect(\"out of the range of i64\")", x))),
+            ("to_u64", [x]) => return Ok((Type::UInt64, format!("u64::try_from({}).expect(\"out of the range of u64\")", x))),
+            ("random_u64" | "now_millis" | "monotonic_nanos", []) => {
+                self.used.insert(name.to_string());
+                return Ok((Type::UInt64, format!("{}()", name)));
+            }
+            _ => return Err(format!("call of `{}`", name)),
+        };
+        Ok((ty, code))
+    }
+}
+
+// Rust precedence of the operators, which is also the one of toylang
+// except that toylang groups the operators of the same level to the right
+fn precedence(op: &Operator) -> u8 {
+    match op {
+        Operator::IMul | Operator::IDiv => 5,
+        Operator::IAdd | Operator::ISub => 4,
+        Operator::EQ | Operator::NE | Operator::LT | Operator::LE | Operator::GT | Operator::GE => 3,
+        Operator::LogicalAnd => 2,
+        Operator::LogicalOr => 1,
+        Operator::Assign => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transpile_code(code: &str) -> String {
+        transpile(&frontend::Parser::new(code).parse_program().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn transpile_functions() {
+        let code = r#"
+fn fib(n: u64) -> u64 {
+    if n < 2u64 { n } else { fib(n - 1u64) + fib(n - 2u64) }
+}
+fn sum(n: i64) -> i64 {
+    var total = 0i64
+    for i in 0i64..n {
+        if i == 3i64 || i == 5i64 { total = total + i * 2i64 } else if i > 7i64 { total = total / 2i64 * 2i64 }
+    }
+    val type = total
+    val fib = abs(type) - min(-1i64, type)
+    fib
+}
+fn main() -> u64 {
+    fib(pow(2u64, 3u64) + random_range(0u64, 1u64))
+}
+"#;
+        assert_eq!(r#"thread_local! {
+    static RANDOM: std::cell::Cell<u64> = std::cell::Cell::new(
+        std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64));
+}
+
+// SplitMix64
+fn random_u64() -> u64 {
+    RANDOM.with(|state| {
+        let s = state.get().wrapping_add(0x9e37_79b9_7f4a_7c15);
+        state.set(s);
+        let mut z = s;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    })
+}
+
+// lo <= x < hi
+fn random_range<T: Copy + Into<i128> + TryFrom<i128>>(lo: T, hi: T) -> T {
+    let (lo, hi) = (lo.into(), hi.into());
+    assert!(lo < hi, "empty range {}..{}", lo, hi);
+    let span = (hi - lo) as u128;
+    let x = lo + ((random_u64() as u128 * span) >> 64) as i128;
+    T::try_from(x).ok().unwrap()
+}
+
+fn fib(n: u64) -> u64 {
+    if n < 2u64 {
+        n
+    } else {
+        fib(n - 1u64) + fib(n - 2u64)
+    }
+}
+
+fn sum(n: i64) -> i64 {
+    let mut total = 0i64;
+    for i in 0i64..n {
+        if i == 3i64 || i == 5i64 {
+            total = total + i * 2i64;
+        } else if i > 7i64 {
+            total = total / (2i64 * 2i64);
+        }
+    }
+    let r#type = total;
+    let fib_ = r#type.abs() - (-1i64).min(r#type);
+    fib_
+}
+
+fn toy_main() -> u64 {
+    fib(2u64.pow(u32::try_from(3u64).expect("invalid exponent")) + random_range(0u64, 1u64))
+}
+
+fn main() {
+    let result = toy_main();
+    println!("Result: {}", result);
+    std::process::exit((result & 0xff) as i32);
+}
+"#, transpile_code(code));
+    }
+
+    #[test]
+    fn statements_of_unit() {
+        let code = "fn f(a: i64) -> i64 {\nval x = (if a > 0 { 1 } else { 2 })\nif a < 0 { 3 } else { 4 }\nwhile a < 0 { a }\nx\n}\nfn main() -> bool {\nf(1) == 1\n}";
+        assert_eq!(r#"
+fn f(a: i64) -> i64 {
+    let x = if a > 0i64 {
+        1i64
+    } else {
+        2i64
+    };
+    if a < 0i64 {
+        3i64;
+    } else {
+        4i64;
+    }
+    while a < 0i64 {
+        a;
+    }
+    x
+}
+
+fn toy_main() -> bool {
+    f(1i64) == 1i64
+}
+
+fn main() {
+    let result = toy_main();
+    println!("Result: {}", result);
+    std::process::exit(if result { 0 } else { 1 });
+}
+"#, transpile_code(code));
+        // a receiver which is not a literal has a type too
+        let code = transpile_code("fn main() -> i64 {\nabs(0i64 - 4i64)\n}");
+        assert!(code.contains("\n    (0i64 - 4i64).abs()\n"), "{}", code);
+        // the prelude
+        let code = transpile_code("fn main() -> u64 {\nprint(1i64)\nto_u64(2i64) + to_u64(1i64 < 2i64)\n}");
+        assert!(code.contains("    println!(\"{}\", 1i64);\n    u64::try_from(2i64).expect(\"out of the range of u64\") + u64::try_from(1i64 < 2i64)"), "{}", code);
+        assert_eq!("r#match", mangle("match"));
+        assert_eq!("self_", mangle("self"));
+        assert_eq!(Ok("u64".to_string()), rust_type(&Type::UInt64));
+    }
+}
@@ -0,0 +1,52 @@
+use std::collections::HashMap;
+use std::thread::JoinHandle;
+use crate::error::InterpreterError;
+use crate::object::Object;
+
+// Functions started by `spawn`, each on its own OS thread. A task is
+// referred to by `Object::Task(id)` and its result is taken by `join`,
+// once. Tasks which are never joined keep running detached.
+#[derive(Debug, Default)]
+pub struct Tasks {
+    next: u64,
+    running: HashMap<u64, JoinHandle<Result<Object, InterpreterError>>>,
+}
+
+impl Tasks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn spawn<F>(&mut self, f: F) -> Result<Object, String>
+    where
+        F: FnOnce() -> Result<Object, InterpreterError> + Send + 'static,
+    {
+        let id = self.next;
+        let handle = std::thread::Builder::new()
+            .name(format!("task-{}", id))
+            .spawn(f)
+            .map_err(|e| format!("cannot start a thread: {}", e))?;
+        self.next += 1;
+        self.running.insert(id, handle);
+        Ok(Object::Task(id))
+    }
+
+    // Wait for the task to finish. An error of the task is returned as
+    // its message.
+    pub fn join(&mut self, id: u64) -> Result<Object, String> {
+        let handle = self.running.remove(&id).ok_or_else(|| format!("task {} is not running or already joined", id))?;
+        match handle.join() {
+            Ok(Ok(value)) => Ok(value),
+            Ok(Err(e)) => Err(format!("task {} failed: {}", id, e)),
+            Err(_) => Err(format!("task {} panicked", id)),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.running.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.running.is_empty()
+    }
+}
@@ -0,0 +1,138 @@
+use std::fmt;
+use frontend::ast::{Function, Program};
+use frontend::line::LineIndex;
+use crate::object::Object;
+use crate::processor::Processor;
+
+// Runner of the tests written in toylang: each function named `test_*`
+// or marked `#[test]` is called without arguments, in the order of the source. A test fails
+// when it returns an error (e.g. a false `assert`) or returns false.
+
+#[derive(Debug, PartialEq)]
+pub struct TestResult {
+    pub name: String,
+    // 1-origin line and column of the failed `assert`, or of the function
+    pub line: usize,
+    pub column: usize,
+    pub failure: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Default)]
+pub struct TestReport {
+    pub results: Vec<TestResult>,
+}
+
+impl TestReport {
+    pub fn passed(&self) -> usize {
+        self.results.iter().filter(|r| r.failure.is_none()).count()
+    }
+
+    pub fn failed(&self) -> usize {
+        self.results.len() - self.passed()
+    }
+
+    pub fn success(&self) -> bool {
+        self.failed() == 0
+    }
+}
+
+impl fmt::Display for TestReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for r in &self.results {
+            match &r.failure {
+                None => writeln!(f, "test {} ... ok", r.name)?,
+                Some(message) => writeln!(f, "test {} ... FAILED\n  {}:{}: {}", r.name, r.line, r.column, message)?,
+            }
+        }
+        let status = if self.success() { "ok" } else { "FAILED" };
+        writeln!(f, "\ntest result: {}. {} passed; {} failed", status, self.passed(), self.failed())
+    }
+}
+
+pub fn is_test(f: &Function) -> bool {
+    f.name.starts_with("test_") || f.attribute("test").is_some()
+}
+
+// The program must be type checked. `source` is used for the locations.
+pub fn run_tests(p: &mut Processor, program: &Program, source: &str) -> TestReport {
+    let lines = LineIndex::new(source);
+    p.load_program(program);
+    let mut report = TestReport::default();
+    for f in program.function.iter().filter(|f| is_test(f)) {
+        let mut offset = f.node.start();
+        let failure = if !f.parameter.is_empty() {
+            Some("a test takes no parameters".to_string())
+        } else {
+            p.refuel();
+            match p.evaluate_function(&program.expression, &f.name, &[]) {
+                Ok(Object::Bool(false)) => Some("returned false".to_string()),
+                Ok(_) => None,
+                Err(e) => {
+                    offset = e.location().map_or(offset, |node| node.start());
+                    Some(e.without_location().to_string())
+                }
+            }
+        };
+        let (line, column) = lines.line_col(offset);
+        report.results.push(TestResult { name: f.name.clone(), line, column, failure });
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use frontend::type_checker::TypeCheckContext;
+
+    const CODE: &str = r#"
+fn add(a: u64, b: u64) -> u64 {
+    a + b
+}
+fn test_add() -> u64 {
+    assert(add(1u64, 2u64) == 3u64)
+    add(0u64, 0u64)
+}
+fn test_assert() -> u64 {
+    assert(add(1u64, 1u64) == 2u64)
+    assert(add(1u64, 2u64) == 4u64)
+    0u64
+}
+fn test_false() -> bool {
+    add(1u64, 1u64) == 3u64
+}
+fn test_overflow() -> u64 {
+    add(18446744073709551615u64, 1u64)
+}
+fn test_division() -> u64 {
+    val zero = 0u64
+    1u64 / zero
+}
+#[test]
+fn adds() -> bool {
+    add(1u64, 1u64) == 2u64
+}
+"#;
+
+    #[test]
+    fn run_test_functions() {
+        let program = frontend::Parser::new(CODE).parse_program().unwrap();
+        let mut p = Processor::new();
+        let mut ctx = TypeCheckContext::new();
+        p.declare_native(&mut ctx);
+        ctx.check_program(&program).unwrap();
+
+        let report = run_tests(&mut p, &program, CODE);
+        let results: Vec<_> = report.results.iter().map(|r| (r.name.as_str(), r.line, r.column, r.failure.as_deref())).collect();
+        assert_eq!(vec![
+            ("test_add", 5, 1, None),
+            ("test_assert", 11, 5, Some("assertion failed")),
+            ("test_false", 14, 1, Some("returned false")),
+            ("test_overflow", 3, 5, Some("integer overflow")),
+            ("test_division", 22, 5, Some("division by zero")),
+            ("adds", 24, 1, None),
+        ], results);
+        assert_eq!((2, 4), (report.passed(), report.failed()));
+        assert!(report.to_string().contains("test test_division ... FAILED\n  22:5: division by zero\n"));
+        assert!(report.to_string().ends_with("test result: FAILED. 2 passed; 4 failed\n"));
+    }
+}
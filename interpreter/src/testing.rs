@@ -0,0 +1,135 @@
+use std::path::{Path, PathBuf};
+use frontend::diagnostic::{Diagnostic, ErrorFormatter};
+use frontend::type_checker::TypeCheckContext;
+use frontend::ParseError;
+use crate::processor::Processor;
+
+// Snapshot tests of toylang programs. A `.toy` file is parsed, type
+// checked and run, and the tree of each function, the diagnostics and the
+// result are compared with the `.snap` file next to it:
+//
+//   -- ast
+//   fn main
+//   Block
+//     UInt64(1)
+//   -- diagnostics
+//   -- result
+//   UInt64(1)
+//
+// With UPDATE_SNAPSHOTS=1 the `.snap` files are written instead, so a
+// change of the language shows in review as a change of the snapshots.
+
+pub const UPDATE: &str = "UPDATE_SNAPSHOTS";
+
+// Snapshot of a source. `name` is shown in the diagnostics.
+pub fn snapshot(name: &str, source: &str) -> String {
+    let mut ast = String::new();
+    let mut diagnostics = String::new();
+    let mut result = String::new();
+    let mut formatter = ErrorFormatter::new(source);
+    formatter.set_file(name);
+    match frontend::Parser::new(source).parse_program() {
+        Err(e) => {
+            let message = format!("parse_program failed {}", e);
+            diagnostics += &formatter.format(&Diagnostic::new(ParseError::code_of(&e), message, None));
+        }
+        Ok(program) => {
+            ast = crate::cli::ast(&program);
+            let mut p = Processor::new();
+            p.set_random_seed(0);
+            let mut ctx = TypeCheckContext::new();
+            p.declare_native(&mut ctx);
+            match ctx.check_program(&program) {
+                Err(errors) => errors.iter().for_each(|e| diagnostics += &formatter.format(&Diagnostic::from(e))),
+                Ok(()) if program.function.iter().any(|f| f.name == "main") => {
+                    result = match p.execute_program(&program) {
+                        Ok(value) => format!("{:?}\n", value),
+                        Err(e) => format!("error: {}\n", e),
+                    };
+                }
+                Ok(()) => (),
+            }
+        }
+    }
+    format!("-- ast\n{}-- diagnostics\n{}-- result\n{}", ast, diagnostics, result)
+}
+
+pub fn snapshot_path(file: &Path) -> PathBuf {
+    file.with_extension("snap")
+}
+
+// Compare the snapshot of `file` with its `.snap` file. The error is a
+// line diff of the expected (-) and actual (+) snapshots.
+pub fn check_snapshot(file: &Path) -> Result<(), String> {
+    let source = std::fs::read_to_string(file).map_err(|e| format!("cannot read {}: {}", file.display(), e))?;
+    let name = file.file_name().map_or(String::new(), |name| name.to_string_lossy().to_string());
+    let actual = snapshot(&name, &source);
+    let path = snapshot_path(file);
+    if std::env::var_os(UPDATE).is_some_and(|v| v == "1") {
+        return std::fs::write(&path, &actual).map_err(|e| format!("cannot write {}: {}", path.display(), e));
+    }
+    let expected = std::fs::read_to_string(&path)
+        .map_err(|e| format!("cannot read {}: {} (run with {}=1 to create it)", path.display(), e, UPDATE))?;
+    if expected == actual {
+        return Ok(());
+    }
+    Err(format!("{} differs from the snapshot:\n{}", file.display(), diff(&expected, &actual)))
+}
+
+// Check the `.toy` files of the directory, the result is the errors
+pub fn check_snapshots(dir: &Path) -> Vec<String> {
+    let mut files: Vec<PathBuf> = match std::fs::read_dir(dir) {
+        Ok(entries) => entries.flatten().map(|e| e.path()).filter(|p| p.extension().is_some_and(|ext| ext == "toy")).collect(),
+        Err(e) => return vec![format!("cannot read {}: {}", dir.display(), e)],
+    };
+    files.sort();
+    files.iter().filter_map(|file| check_snapshot(file).err()).collect()
+}
+
+// Lines of a longest common subsequence diff
+fn diff(expected: &str, actual: &str) -> String {
+    let a: Vec<&str> = expected.lines().collect();
+    let b: Vec<&str> = actual.lines().collect();
+    // lcs[i][j]: length of the common subsequence of a[i..] and b[j..]
+    let mut lcs = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = if a[i] == b[j] { lcs[i + 1][j + 1] + 1 } else { lcs[i + 1][j].max(lcs[i][j + 1]) };
+        }
+    }
+    let (mut i, mut j, mut out) = (0, 0, String::new());
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            out += &format!("  {}\n", a[i]);
+            (i, j) = (i + 1, j + 1);
+        } else if j == b.len() || (i < a.len() && lcs[i + 1][j] >= lcs[i][j + 1]) {
+            out += &format!("- {}\n", a[i]);
+            i += 1;
+        } else {
+            out += &format!("+ {}\n", b[j]);
+            j += 1;
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshot_sections() {
+        assert_eq!(
+            "-- ast\nfn main\nBlock\n  UInt64(1)\n-- diagnostics\n-- result\nUInt64(1)\n",
+            snapshot("a.toy", "fn main() -> u64 {\n1u64\n}")
+        );
+        assert_eq!("  a\n- b\n+ c\n  d\n", diff("a\nb\nd\n", "a\nc\nd\n"));
+    }
+
+    #[test]
+    fn checked_in_snapshots() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("snapshots");
+        let errors = check_snapshots(&dir);
+        assert!(errors.is_empty(), "{}", errors.join("\n"));
+    }
+}
@@ -0,0 +1,103 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::span::Id;
+use tracing::Subscriber;
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+
+// Time spent in each phase of a run. The phases are `tracing` spans
+// (`parse`, `check`, `compile`, `execute`), so any subscriber can record
+// them; `Timings` is a layer which sums the time of each span name and is
+// installed only when asked for (`--timings`).
+
+pub const PARSE: &str = "parse";
+pub const CHECK: &str = "check";
+pub const COMPILE: &str = "compile";
+pub const EXECUTE: &str = "execute";
+
+#[derive(Debug, Clone, Default)]
+pub struct Timings {
+    phases: Arc<Mutex<Vec<(&'static str, Duration)>>>, // in the order of the first entry
+}
+
+// start of the span, kept in the extensions of the span while it is entered
+struct Entered(Instant);
+
+impl Timings {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Record the spans of all the threads from now on. It fails when
+    // another subscriber is already installed.
+    pub fn install(&self) -> Result<(), String> {
+        let subscriber = tracing_subscriber::registry().with(self.clone());
+        tracing::subscriber::set_global_default(subscriber).map_err(|e| e.to_string())
+    }
+
+    pub fn phases(&self) -> Vec<(&'static str, Duration)> {
+        self.phases.lock().map_or(vec![], |phases| phases.clone())
+    }
+
+    fn add(&self, name: &'static str, elapsed: Duration) {
+        if let Ok(mut phases) = self.phases.lock() {
+            match phases.iter_mut().find(|(phase, _)| *phase == name) {
+                Some((_, total)) => *total += elapsed,
+                None => phases.push((name, elapsed)),
+            }
+        }
+    }
+}
+
+impl<S> Layer<S> for Timings
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_enter(&self, id: &Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().replace(Entered(Instant::now()));
+        }
+    }
+
+    fn on_exit(&self, id: &Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(Entered(start)) = span.extensions_mut().remove::<Entered>() {
+                self.add(span.name(), start.elapsed());
+            }
+        }
+    }
+}
+
+impl fmt::Display for Timings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let phases = self.phases();
+        writeln!(f, "{:<24} {:>14}", "phase", "time (us)")?;
+        for (name, time) in &phases {
+            writeln!(f, "{:<24} {:>14}", name, time.as_micros())?;
+        }
+        let total: Duration = phases.iter().map(|(_, time)| *time).sum();
+        writeln!(f, "{:<24} {:>14}", "total", total.as_micros())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn time_phases() {
+        let timings = Timings::new();
+        let subscriber = tracing_subscriber::registry().with(timings.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info_span!(PARSE).in_scope(|| std::thread::sleep(Duration::from_millis(2)));
+            tracing::info_span!(CHECK).in_scope(|| ());
+            tracing::info_span!(PARSE).in_scope(|| std::thread::sleep(Duration::from_millis(1)));
+        });
+        let phases = timings.phases();
+        assert_eq!(vec![PARSE, CHECK], phases.iter().map(|(name, _)| *name).collect::<Vec<_>>());
+        assert!(phases[0].1 >= Duration::from_millis(3));
+        let report = timings.to_string();
+        assert!(report.starts_with("phase ") && report.lines().last().unwrap().starts_with("total "));
+    }
+}
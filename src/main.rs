@@ -23,13 +23,13 @@ struct Compiler<'a, 'ctx> {
 }
 
 impl<'a, 'ctx> Compiler<'a, 'ctx> {
-    fn compile_expr(&mut self, expr: &Expr) -> Result<IntValue<'ctx>, &'static str> {
-        match expr {
+    fn compile_expr(&mut self, pool: &ExprPool, e: ExprRef) -> Result<IntValue<'ctx>, &'static str> {
+        match pool.get(e.0 as usize).ok_or("invalid expression reference")? {
             Expr::IfElse(_, _, _) => Err("IfElse is not implemented"),
-            Expr::Binary(bop) => {
-                let lhs = self.compile_expr(&bop.lhs)?;
-                let rhs = self.compile_expr(&bop.rhs)?;
-                match bop.op {
+            Expr::Binary(op, lhs, rhs) => {
+                let lhs = self.compile_expr(pool, *lhs)?;
+                let rhs = self.compile_expr(pool, *rhs)?;
+                match op {
                     Operator::IAdd => Ok(self.builder.build_int_add(lhs, rhs, "tmpadd")),
                     Operator::ISub => Ok(self.builder.build_int_sub(lhs, rhs, "tmpsub")),
                     Operator::IMul => Ok(self.builder.build_int_mul(lhs, rhs, "tmpmul")),
@@ -37,9 +37,10 @@ impl<'a, 'ctx> Compiler<'a, 'ctx> {
                     _ => Err("not implemented yet (Binary Operator)"),
                 }
             }
+            Expr::Block(_) => Err("not implemented yet (Block)"),
             Expr::Int64(i) => Ok(self.context.i64_type().const_int(*i as u64, true)),
             Expr::UInt64(u) => Ok(self.context.i64_type().const_int(*u, false)),
-            Expr::Int(i_str) => Err("not implemented yet (Int(String))"),
+            Expr::Int(_) => Err("not implemented yet (Int(String))"),
             Expr::Identifier(_) => Err("not implemented yet (Identifier)"),
             Expr::Call(_, _) => Err("not implemented yet (Call)"),
             Expr::Null => {
@@ -47,6 +48,9 @@ impl<'a, 'ctx> Compiler<'a, 'ctx> {
                 //Ok(self.context.ptr_sized_int_type(0, None))
             }
             Expr::Val(_name, _ty, _expr) => Err("not implemented yet (Val)"),
+            Expr::Var(_name, _ty, _expr) => Err("not implemented yet (Var)"),
+            Expr::While(_, _) => Err("not implemented yet (While)"),
+            Expr::For(_, _, _, _) => Err("not implemented yet (For)"),
         }
     }
 
@@ -55,7 +59,8 @@ impl<'a, 'ctx> Compiler<'a, 'ctx> {
         builder: &'a Builder<'ctx>,
         pass_manager: &'a PassManager<FunctionValue<'ctx>>,
         module: &'a Module<'ctx>,
-        expr: &Expr,
+        pool: &ExprPool,
+        expr: ExprRef,
     ) -> Result<(), &'static str> {
         let mut compiler = Compiler {
             context,
@@ -67,7 +72,7 @@ impl<'a, 'ctx> Compiler<'a, 'ctx> {
             //variables: HashMap::new()
         };
 
-        let ret = compiler.compile_expr(expr)?;
+        let ret = compiler.compile_expr(pool, expr)?;
         let ret = ret.const_cast(context.i32_type(), true);
         builder.build_return(Some(&ret));
         Ok(())
@@ -86,9 +91,9 @@ fn main() -> std::io::Result<()> {
     file.read_to_string(&mut contents)?;
 
     let mut parser = frontend::Parser::new(contents.as_str());
-    let expr = parser.parse_expr();
+    let expr = parser.parse_expression();
     if expr.is_err() {
-        println!("parser_expr failed");
+        println!("parse_expression failed");
         return Ok(());
     }
 
@@ -114,7 +119,7 @@ fn main() -> std::io::Result<()> {
     let basic_block = context.append_basic_block(function, "entry");
     builder.position_at_end(basic_block);
 
-    let mut expr = expr.unwrap();
+    let (expr, pool) = expr.unwrap();
 
     let mut env = Environment::new();
     //let ty = typing(&mut expr, &mut env);
@@ -123,7 +128,7 @@ fn main() -> std::io::Result<()> {
     //    return Ok(());
     //}
 
-    let res = Compiler::compile(&context, &builder, &fpm, &module, &expr);
+    let res = Compiler::compile(&context, &builder, &fpm, &module, &pool, expr);
     if res.is_err() {
         println!("compile error: {}", res.unwrap_err());
         return Ok(());